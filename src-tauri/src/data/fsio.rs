@@ -1,21 +1,32 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::Read,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
 
 use crate::{demucs, util::get_base_directory};
 
-pub fn copy_song_to_project(song_path: PathBuf, project_id: String) -> Result<(), String> {
-    let base_dir_path = get_base_directory();
-    let project_dir_path = base_dir_path.join("project_data").join(project_id.clone());
+/// プロジェクトにコピーした曲のファイル名（拡張子を除く）
+const SONG_FILE_STEM: &str = "main";
+/// カバー画像がない曲の代わりに生成する波形サムネイルの大きさ（正方形）
+const THUMBNAIL_SIZE: u32 = 512;
+
+/// 曲をプロジェクトディレクトリに `main.<拡張子>` としてコピーし、実際に保存したパスを返す
+///
+/// 拡張子は小文字に正規化する（`.MP3` -> `main.mp3`）。拡張子がない場合は `main` として保存し、
+/// デコード時にはファイルの内容からフォーマットを判定する
+pub fn copy_song_to_project(
+    song_path: PathBuf,
+    base_dir_path: &Path,
+    project_id: String,
+) -> Result<PathBuf, String> {
+    let project_dir_path = base_dir_path.join("project_data").join(project_id);
     fs::create_dir_all(&project_dir_path)
         .map_err(|e| format!("Unable to create project directory: {}", e))?;
-    let dest_path = song_path
-        .extension()
-        .map(|extension| {
-            base_dir_path
-                .join("project_data")
-                .join(project_id)
-                .join(format!("main.{}", extension.to_string_lossy()))
-        })
-        .ok_or_else(|| String::from("File has no extension"))?;
+    let dest_path = project_dir_path.join(song_file_name(&song_path));
 
     // println!("{}, {}", song_path.to_string_lossy().to_string(), dest_path.to_string_lossy().to_string());
     fs::copy(&song_path, &dest_path)
@@ -24,10 +35,92 @@ pub fn copy_song_to_project(song_path: PathBuf, project_id: String) -> Result<()
             dest_path.display(), 
             e))?;
 
-    let _cover_image = demucs::get_cover_image(&dest_path, &project_dir_path)
+    let cover_image = demucs::get_cover_image(&dest_path, &project_dir_path)
         .map_err(|e| format!("failed to fetch cover image: {e}"))?;
 
-    Ok(())
+    // 埋め込みのカバー画像がない場合は波形のサムネイルで代用する（失敗してもプロジェクトは作成する）
+    if cover_image.is_none() {
+        if let Err(e) = demucs::generate_waveform_thumbnail(
+            &dest_path,
+            &project_dir_path,
+            THUMBNAIL_SIZE,
+            THUMBNAIL_SIZE,
+        ) {
+            eprintln!("[copy_song_to_project] Failed to generate waveform thumbnail: {}", e);
+        }
+    }
+
+    Ok(dest_path)
+}
+
+/// プロジェクト内で使うファイル名（`main.<小文字の拡張子>`、拡張子がなければ `main`）
+fn song_file_name(song_path: &Path) -> String {
+    match song_path.extension() {
+        Some(extension) if !extension.is_empty() => {
+            format!("main.{}", extension.to_string_lossy().to_lowercase())
+        }
+        _ => String::from(SONG_FILE_STEM),
+    }
+}
+
+/// プロジェクトディレクトリに保存された曲ファイル（`main` または `main.*`）を探す
+pub fn find_song_in_project(project_dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(project_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .find(|path| {
+            path.is_file()
+                && path
+                    .file_stem()
+                    .map_or(false, |stem| stem == SONG_FILE_STEM)
+        })
+}
+
+/// ファイル先頭のマジックナンバーから音声フォーマットの拡張子を判定する
+pub fn detect_audio_extension(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("wav"),
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => Some("aiff"),
+        [b'f', b'L', b'a', b'C', ..] => Some("flac"),
+        // Oggの最初のページはOpusならOpusHeadヘッダーから始まる
+        [b'O', b'g', b'g', b'S', rest @ ..] if rest.get(24..32) == Some(b"OpusHead") => {
+            Some("opus")
+        }
+        [b'O', b'g', b'g', b'S', ..] => Some("ogg"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("m4a"),
+        [b'I', b'D', b'3', ..] => Some("mp3"),
+        // フレーム同期（11bit）の後のlayerビットが00ならADTS（AAC）、それ以外はMPEG audio
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => Some("aac"),
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => Some("mp3"),
+        _ => None,
+    }
+}
+
+/// ハッシュ計算に使うファイル先頭のバイト数
+const FINGERPRINT_HEAD_BYTES: u64 = 1024 * 1024;
+
+/// ファイルが差し替えられたかを判定するためのハッシュ（サイズ・更新日時・先頭1MBから計算）
+///
+/// 暗号学的なハッシュではない。ツールチェーンの更新で値が変わった場合は再解析が1回走るだけ
+pub fn fingerprint_file(path: &Path) -> Result<String, String> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| format!("Unable to read metadata of {}: {}", path.display(), e))?;
+
+    let mut hasher = DefaultHasher::new();
+    metadata.len().hash(&mut hasher);
+    if let Ok(modified) = metadata.modified() {
+        if let Ok(since_epoch) = modified.duration_since(UNIX_EPOCH) {
+            since_epoch.as_nanos().hash(&mut hasher);
+        }
+    }
+
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(FINGERPRINT_HEAD_BYTES).read_to_end(&mut head))
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    head.hash(&mut hasher);
+
+    Ok(format!("{:016x}", hasher.finish()))
 }
 
 pub fn delete_project_data(project_id: String) -> Result<(), String> {
@@ -39,3 +132,85 @@ pub fn delete_project_data(project_id: String) -> Result<(), String> {
         Err(_) => Err(String::from("Error deleting project.")),
     }
 }
+
+/// ディレクトリ以下のファイルサイズの合計（バイト）。読めないファイルやシンボリックリンクは数えない
+pub fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            Ok(file_type) if file_type.is_file() => entry.metadata().map_or(0, |m| m.len()),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 440Hzのサイン波を0.1秒だけ含む16bitステレオWAVを書き出す
+    fn write_test_wav(path: &Path) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for i in 0..4410 {
+            let t = i as f32 / 44100.0;
+            let sample = ((2.0 * std::f32::consts::PI * 440.0 * t).sin() * 8000.0) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("stemsplit-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn copies_an_extensionless_song_and_finds_it_again() {
+        let dir = temp_dir("extensionless");
+        let song_path = dir.join("song");
+        write_test_wav(&song_path);
+
+        let stored = copy_song_to_project(song_path, &dir, String::from("project")).unwrap();
+        assert_eq!(stored.file_name().unwrap(), "main");
+
+        let project_dir = dir.join("project_data").join("project");
+        assert_eq!(find_song_in_project(&project_dir), Some(stored.clone()));
+
+        // 拡張子がなくても内容からWAVと判定して解析できる
+        let audio = demucs::audio::decode_file(&stored).unwrap();
+        assert_eq!(audio.sample_rate, 44100);
+        assert_eq!(audio.nb_channels, 2);
+        assert_eq!(audio.length, 4410);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn normalizes_uppercase_extensions() {
+        let dir = temp_dir("uppercase");
+        let song_path = dir.join("Song.WAV");
+        write_test_wav(&song_path);
+
+        let stored = copy_song_to_project(song_path, &dir, String::from("project")).unwrap();
+        assert_eq!(stored.file_name().unwrap(), "main.wav");
+        assert_eq!(
+            find_song_in_project(&dir.join("project_data").join("project")),
+            Some(stored)
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
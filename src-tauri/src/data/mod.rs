@@ -1,10 +1,24 @@
 use crate::util::{current_unix_timestamp, generate_random_string, get_base_directory};
-use crate::demucs::{detect_bpm, detect_key};
-use polodb_core::{bson::doc, Collection, Database};
+use crate::demucs::{
+    audio::decode_file, detect_bpm_from_pcm, detect_clipping, detect_key_from_pcm, measure_lufs,
+    metadata::read_metadata, stem_source_name, BpmEstimate,
+};
+use polodb_core::{
+    bson::{doc, to_bson, Document},
+    Collection, Database,
+};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, warn};
 
-use self::fsio::{copy_song_to_project, delete_project_data};
+use self::fsio::{
+    copy_song_to_project, delete_project_data, detect_audio_extension, directory_size,
+    fingerprint_file,
+};
+
+pub use self::fsio::find_song_in_project;
 
 mod fsio;
 
@@ -16,6 +30,26 @@ pub enum AppMetadata {
 
     #[serde(alias = "num_songs_processed")]
     Error { value: u32 },
+
+    #[serde(alias = "settings")]
+    Settings(AppSettings),
+}
+
+/// ユーザー設定（dbのmetadataコレクションに1件だけ保存される）
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AppSettings {
+    /// プロジェクトデータの保存先（Noneの場合はデフォルトのディレクトリ）
+    #[serde(default)]
+    pub output_directory: Option<PathBuf>,
+    /// 推論に使うデバイス（"cpu" / "cuda" / "mps"、Noneの場合は自動検出）
+    #[serde(default)]
+    pub device: Option<String>,
+    /// CPU推論のスレッド数（Noneの場合は1）。起動時にだけ反映される
+    #[serde(default)]
+    pub cpu_threads: Option<usize>,
+    /// 一度に推論するトラックの長さの上限（秒）。Noneの場合はデバイスごとの既定値（MPSのみ上限あり）
+    #[serde(default)]
+    pub max_segment_secs: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -23,12 +57,182 @@ pub struct Project {
     pub _id: String,
     pub name: String,
     pub created_at: i64,
+    /// 最後に変更された日時（stemの追加・名前変更・再解析で更新）。古いデータではcreated_atで補完する
+    #[serde(default)]
+    pub updated_at: i64,
     pub base_dir: PathBuf,
     pub stem_paths: Vec<String>,
     #[serde(default)]
     pub bpm: Option<f64>,
+    /// bpmの半分のテンポの候補（検出範囲外の場合はNone）
+    #[serde(default)]
+    pub bpm_half: Option<f64>,
+    /// bpmの倍のテンポの候補（検出範囲外の場合はNone）
+    #[serde(default)]
+    pub bpm_double: Option<f64>,
+    /// 最初の強拍の位置（秒）。拍の位置の推定の信頼度が低い場合はNone
+    #[serde(default)]
+    pub beat_offset_secs: Option<f64>,
     #[serde(default)]
     pub key: Option<String>,
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
+    #[serde(default)]
+    pub sample_rate: Option<usize>,
+    /// 元の曲の統合ラウドネス（LUFS）
+    #[serde(default)]
+    pub lufs: Option<f64>,
+    /// ミキサーでのstemごとの設定（未設定のstemはゲイン0dB・ミュートなし・センター）
+    #[serde(default)]
+    pub stem_settings: Vec<StemSetting>,
+    /// stemを生成したモデルの名前（まだ分離していない場合はNone）
+    #[serde(default)]
+    pub model_name: Option<String>,
+    /// 解析時の曲ファイルのハッシュ（サイズ・更新日時・先頭1MBから計算）
+    #[serde(default)]
+    pub audio_hash: Option<String>,
+    /// 元の曲がクリップしている（stemもクリップするため、UIで警告する）
+    #[serde(default)]
+    pub source_clipped: bool,
+    /// プロジェクトのディレクトリや曲ファイルが見つからない（`gc_orphaned_projects` で設定）
+    #[serde(default)]
+    pub missing_files: bool,
+    /// ジャンルやセッションなどで整理するためのタグ（小文字・前後の空白なしで保存）
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// 分離に成功したら、プロジェクトにコピーした曲ファイルを削除する（ディスク使用量を抑えるため）
+    #[serde(default)]
+    pub delete_source_after_split: bool,
+    /// `delete_source_after_split` によって曲ファイルを削除済み（再分離・プレビューはできない）
+    #[serde(default)]
+    pub source_deleted: bool,
+    /// 作成直後のBPM/Keyなどの解析がバックグラウンドで実行中（完了すると `project_analyzed` イベントが送られる）
+    #[serde(default)]
+    pub analysis_pending: bool,
+    /// BPMをユーザーが手動で設定した（再解析でBPMと拍の位置を上書きしない）
+    #[serde(default)]
+    pub bpm_user_set: bool,
+    /// Keyをユーザーが手動で設定した（再解析でKeyを上書きしない）
+    #[serde(default)]
+    pub key_user_set: bool,
+}
+
+/// `gc_orphaned_projects` で整理した内容
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OrphanCleanupReport {
+    /// DBに対応するプロジェクトがなく、削除したディレクトリ
+    pub removed_dirs: Vec<PathBuf>,
+    /// 曲ファイルが見つからないため `missing_files` を立てたプロジェクトのID
+    pub missing_file_projects: Vec<String>,
+    /// ファイルが見つかったため `missing_files` を解除したプロジェクトのID
+    pub restored_projects: Vec<String>,
+}
+
+/// ライブラリ全体の統計（ダッシュボード用）
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LibraryStats {
+    pub project_count: usize,
+    pub total_stems: usize,
+    /// すべてのプロジェクトのディレクトリ（`project_data/<id>`）の合計サイズ（バイト）
+    pub total_disk_bytes: u64,
+    /// BPMが分かっているプロジェクトの平均（1つもない場合はNone）
+    pub avg_bpm: Option<f64>,
+}
+
+impl LibraryStats {
+    /// プロジェクトを集計する。ディレクトリを辿ってサイズを測るため、プロジェクトが多いと時間がかかる
+    pub fn collect(projects: &[Project]) -> Self {
+        let bpms: Vec<f64> = projects.iter().filter_map(|project| project.bpm).collect();
+
+        Self {
+            project_count: projects.len(),
+            total_stems: projects.iter().map(|project| project.stem_paths.len()).sum(),
+            total_disk_bytes: projects
+                .iter()
+                .map(|project| directory_size(&project.project_dir()))
+                .sum(),
+            avg_bpm: (!bpms.is_empty()).then(|| bpms.iter().sum::<f64>() / bpms.len() as f64),
+        }
+    }
+}
+
+/// ミキサーでの1つのstemの設定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StemSetting {
+    /// stemの種類（"vocals"など、拡張子を除いたファイル名）
+    pub kind: String,
+    pub gain_db: f64,
+    pub muted: bool,
+    /// -1.0（左）〜 1.0（右）
+    pub pan: f64,
+}
+
+impl StemSetting {
+    /// 設定できるゲインの範囲（dB）
+    pub const MIN_GAIN_DB: f64 = -60.0;
+    pub const MAX_GAIN_DB: f64 = 12.0;
+
+    /// 値が範囲内かを確認する（問題がある場合は理由を返す）
+    pub fn validate(&self) -> Result<(), String> {
+        if self.kind.trim().is_empty() {
+            return Err(String::from("stem kind must not be empty"));
+        }
+        if !(Self::MIN_GAIN_DB..=Self::MAX_GAIN_DB).contains(&self.gain_db) {
+            return Err(format!(
+                "gain_db for \"{}\" must be between {} and {} (got {})",
+                self.kind,
+                Self::MIN_GAIN_DB,
+                Self::MAX_GAIN_DB,
+                self.gain_db
+            ));
+        }
+        if !(-1.0..=1.0).contains(&self.pan) {
+            return Err(format!(
+                "pan for \"{}\" must be between -1.0 and 1.0 (got {})",
+                self.kind, self.pan
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// タグを小文字・前後の空白なしに正規化する（空の場合はNone）
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim().to_lowercase();
+    (!tag.is_empty()).then_some(tag)
+}
+
+impl Project {
+    /// このプロジェクトのファイルが置かれているディレクトリ
+    pub fn project_dir(&self) -> PathBuf {
+        self.base_dir.join("project_data").join(&self._id)
+    }
+
+    /// 種類（"vocals"、"drums"など、拡張子を除いたファイル名）からstemのパスを探す
+    ///
+    /// ファイル名が一致するstemがなければ、ファイル名を変えたstem（lead.wavなど）を
+    /// 分離時のソース名で探す
+    pub fn find_stem(&self, stem_kind: &str) -> Option<PathBuf> {
+        let stem_paths: Vec<PathBuf> = self.stem_paths.iter().map(PathBuf::from).collect();
+        stem_paths
+            .iter()
+            .find(|path| path.file_stem().map_or(false, |stem| stem == stem_kind))
+            .or_else(|| {
+                stem_paths
+                    .iter()
+                    .find(|path| stem_source_name(path) == stem_kind)
+            })
+            .cloned()
+    }
+
+    /// updated_atが保存されていない古いプロジェクトはcreated_atで補完する
+    fn with_updated_at_backfilled(mut self) -> Self {
+        if self.updated_at == 0 {
+            self.updated_at = self.created_at;
+        }
+        self
+    }
 }
 
 pub struct AppDb {
@@ -46,17 +250,64 @@ impl AppDb {
         }
     }
 
-    pub fn create_project(&self, audio_filepath: PathBuf) -> Result<Project, String> {
-        let name = audio_filepath
+    /// `use_track_title` が有効でタグにタイトルがある場合は "{artist} - {title}" を、なければファイル名をプロジェクト名にする
+    ///
+    /// `delete_source_after_split` を有効にすると、分離に成功した時点でコピーした曲ファイルを削除する
+    pub fn create_project(
+        &self,
+        audio_filepath: PathBuf,
+        use_track_title: bool,
+        delete_source_after_split: bool,
+    ) -> Result<Project, String> {
+        let file_name = audio_filepath
             .file_name()
             .ok_or_else(String::new)?
             .to_string_lossy()
             .to_string();
 
+        let name = if use_track_title {
+            project_name_from_tags(&audio_filepath).unwrap_or(file_name)
+        } else {
+            file_name
+        };
+
+        self.create_project_named(name, audio_filepath, delete_source_after_split)
+    }
+
+    /// メモリ上の音声データからプロジェクトを作成する（ドラッグ&ドロップなどでファイルパスが無い場合）
+    ///
+    /// フォーマットはデータの先頭（マジックナンバー）から判定し、一時ファイル経由で通常の作成処理を行う
+    pub fn create_project_from_bytes(&self, name: String, data: &[u8]) -> Result<Project, String> {
+        let extension = detect_audio_extension(data)
+            .ok_or_else(|| String::from("Unsupported or unrecognized audio format"))?;
+
+        let temp_path = std::env::temp_dir().join(format!(
+            "tune-prism-import-{}.{}",
+            generate_random_string(),
+            extension
+        ));
+        std::fs::write(&temp_path, data)
+            .map_err(|e| format!("Failed to write imported audio: {}", e))?;
+
+        let result = self.create_project_named(name, temp_path.clone(), false);
+
+        if let Err(e) = std::fs::remove_file(&temp_path) {
+            warn!("[create_project_from_bytes] Failed to remove temporary file {:?}: {}", temp_path, e);
+        }
+
+        result
+    }
+
+    fn create_project_named(
+        &self,
+        name: String,
+        audio_filepath: PathBuf,
+        delete_source_after_split: bool,
+    ) -> Result<Project, String> {
         let created_at = current_unix_timestamp();
         let projects = self.polo_instance.collection("projects");
         let base_dir = get_base_directory();
-        let id = generate_random_string();
+        let id = self.generate_project_id(&base_dir)?;
         let stem_paths: Vec<String> = vec![];
         let base_dir_clone = base_dir.clone();
 
@@ -64,116 +315,129 @@ impl AppDb {
             _id: id.clone(), // Not sure if polo_db will work if this is an Option<T>
             name,
             created_at,
+            updated_at: created_at,
             base_dir: base_dir_clone.clone(),
             stem_paths,
             bpm: None,
+            bpm_half: None,
+            bpm_double: None,
+            beat_offset_secs: None,
             key: None,
+            duration_secs: None,
+            sample_rate: None,
+            lufs: None,
+            stem_settings: Vec::new(),
+            model_name: None,
+            audio_hash: None,
+            source_clipped: false,
+            missing_files: false,
+            tags: Vec::new(),
+            delete_source_after_split,
+            source_deleted: false,
+            analysis_pending: true,
+            bpm_user_set: false,
+            key_user_set: false,
         };
 
-        projects
-            .insert_one(proj.clone())
-            .map_err(|_| String::new())?;
-        copy_song_to_project(audio_filepath.clone(), id.clone()).expect("Failed to copy song");
-
-        // BPMとKeyを計算してProjectを更新
-        let project_dir = base_dir_clone.join("project_data").join(id.clone());
-        let audio_path = project_dir.join(
-            audio_filepath
-                .extension()
-                .map(|ext| format!("main.{}", ext.to_string_lossy()))
-                .unwrap_or_else(|| "main.mp3".to_string())
-        );
+        // 曲のコピーとDBへの追加のどちらかが失敗した場合は、もう一方も元に戻す
+        if let Err(e) = copy_song_to_project(audio_filepath.clone(), &base_dir_clone, id.clone()) {
+            error!("[create_project] Failed to copy song: {}", e);
+            remove_partial_project_dir(&proj);
+            return Err(e);
+        }
+        if let Err(e) = projects.insert_one(proj.clone()) {
+            error!("[create_project] Failed to insert project: {:?}", e);
+            remove_partial_project_dir(&proj);
+            return Err(format!("Failed to save project: {:?}", e));
+        }
 
-        // BPMとKeyを計算してProjectを更新（エラーログを追加）
-        eprintln!("[create_project] Detecting BPM and Key for: {:?}", audio_path);
-        let bpm_result = detect_bpm(&audio_path);
-        let key_result = detect_key(&audio_path);
-        
-        match &bpm_result {
-            Ok(Some(bpm)) => eprintln!("[create_project] BPM detected: {}", bpm),
-            Ok(None) => eprintln!("[create_project] BPM detection returned None"),
-            Err(e) => eprintln!("[create_project] BPM detection error: {:?}", e),
-        }
-        
-        match &key_result {
-            Ok(Some(key)) => eprintln!("[create_project] Key detected: {}", key),
-            Ok(None) => eprintln!("[create_project] Key detection returned None"),
-            Err(e) => eprintln!("[create_project] Key detection error: {:?}", e),
-        }
-        
-        let bpm = bpm_result.ok().flatten();
-        let key = key_result.ok().flatten();
-
-        // BPMとKeyを更新（Noneでも更新を試みる）
+        // BPM/Keyの解析は時間がかかるため、呼び出し側で `analyze_project_song` をバックグラウンドで実行し、
+        // `save_song_analysis` で保存する
+        info!("[create_project] Project created, analysis pending for project ID: {}", id);
+
+        Ok(proj)
+    }
+
+    /// DBにもディスクにも存在しないプロジェクトIDを作成する
+    ///
+    /// `create_project` はDBのロックを持ったまま呼ばれるため、確認してから追加するまでに他のプロジェクトは作成されない
+    fn generate_project_id(&self, base_dir: &Path) -> Result<String, String> {
         let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
-        let mut update_doc = doc! {};
-        
-        // BPMが検出された場合、更新ドキュメントに追加
-        if let Some(bpm_val) = bpm {
-            update_doc.insert("bpm", bpm_val);
-            eprintln!("[create_project] Adding BPM to update: {}", bpm_val);
-        } else {
-            eprintln!("[create_project] BPM is None, skipping BPM update");
-        }
-        
-        // Keyが検出された場合、更新ドキュメントに追加
-        if let Some(key_val) = &key {
-            update_doc.insert("key", key_val);
-            eprintln!("[create_project] Adding Key to update: {}", key_val);
-        } else {
-            eprintln!("[create_project] Key is None, skipping Key update");
-        }
-        
-        // 更新ドキュメントが空でない場合のみ、データベースを更新
-        if !update_doc.is_empty() {
-            eprintln!("[create_project] Updating database with: {:?}", update_doc);
-            match projects_collection.update_one(
-                doc! { "_id": id.clone() },
-                doc! { "$set": update_doc.clone() },
-            ) {
-                Ok(_) => {
-                    eprintln!("[create_project] Database update successful for project ID: {}", id);
-                }
-                Err(e) => {
-                    eprintln!("[create_project] ERROR: Failed to update BPM and Key in database: {:?}", e);
-                    eprintln!("[create_project] Update document was: {:?}", update_doc);
-                    // エラーを返すのではなく、警告だけを出す（プロジェクト作成は続行）
-                    // BPM/Keyの更新失敗は致命的ではないため、プロジェクト作成は成功として扱う
-                }
+
+        for _ in 0..MAX_PROJECT_ID_ATTEMPTS {
+            let id = generate_random_string();
+            let in_db = projects_collection
+                .find_one(doc! { "_id": id.clone() })
+                .map_err(|e| format!("Failed to check project ID: {:?}", e))?
+                .is_some();
+            // DBにない残骸のディレクトリ（gc前のもの）とも重ならないようにする
+            let on_disk = base_dir.join("project_data").join(&id).exists();
+            if !in_db && !on_disk {
+                return Ok(id);
             }
-        } else {
-            eprintln!("[create_project] WARNING: No BPM or Key to update (both are None or empty)");
+            debug!("[generate_project_id] Project ID {} is already in use, retrying", id);
         }
 
-        // 更新されたProjectを取得
-        let updated_proj = projects_collection
-            .find_one(doc! { "_id": id.clone() })
-            .map_err(|_| String::from("Failed to find updated project"))?
-            .ok_or_else(|| String::from("Project not found after update"))?;
+        Err(String::from("Failed to generate a unique project ID"))
+    }
 
-        eprintln!("[create_project] Project created with BPM: {:?}, Key: {:?}", updated_proj.bpm, updated_proj.key);
+    /// `analyze_project_song` の結果を保存し、更新後のProjectを返す（存在しない場合はNone）
+    ///
+    /// 解析に失敗した場合（`update_doc` が空）も、解析中の印は外す
+    pub fn save_song_analysis(
+        &self,
+        id: &str,
+        mut update_doc: Document,
+    ) -> Result<Option<Project>, String> {
+        let Some(project) = self.get_project_by_id(id.to_string())? else {
+            return Ok(None);
+        };
+        keep_user_set_values(&mut update_doc, &project);
+        if update_doc.is_empty() {
+            warn!("[save_song_analysis] No BPM or Key to update for project ID: {}", id);
+        }
+        update_doc.insert("analysis_pending", false);
 
-        Ok(updated_proj)
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(doc! { "_id": id }, doc! { "$set": update_doc })
+            .map_err(|e| format!("Failed to save analysis: {:?}", e))?;
+
+        let project = self.get_project_by_id(id.to_string())?;
+        if let Some(project) = &project {
+            info!(
+                "[save_song_analysis] Analysis saved with BPM: {:?}, Key: {:?} for project ID: {}",
+                project.bpm, project.key, id
+            );
+        }
+
+        Ok(project)
     }
 
+    /// stemのパスを保存する。model_nameを指定した場合は、stemを生成したモデルとして記録する
     pub fn add_stems_to_project(
         &self,
         project_id: String,
         stem_paths: Vec<PathBuf>,
+        model_name: Option<&str>,
     ) -> Result<(), String> {
         let paths: Vec<String> = stem_paths
             .into_iter()
             .map(|p| p.to_string_lossy().to_string())
             .collect();
+        let mut update_doc = doc! {
+            // "stem_paths": paths.into_iter().map(Bson::String).collect(),
+            "stem_paths": paths.clone(),
+            "updated_at": current_unix_timestamp(),
+        };
+        if let Some(model_name) = model_name {
+            update_doc.insert("model_name", model_name);
+        }
+
         let projects: Collection<Project> = self.polo_instance.collection("projects");
         let result = projects.update_one(
             doc! { "_id": project_id.clone() },
-            doc! {
-                "$set": doc! {
-                    // "stem_paths": paths.into_iter().map(Bson::String).collect(),
-                    "stem_paths": paths.clone(),
-                }
-            },
+            doc! { "$set": update_doc },
         );
 
         result.map_err(|_| String::new())?;
@@ -189,7 +453,7 @@ impl AppDb {
                 let mut all_projects: Vec<Project> = vec![];
                 for proj_res in res {
                     let project = proj_res.expect("Couldn't read the project.");
-                    all_projects.push(project);
+                    all_projects.push(project.with_updated_at_backfilled());
                 }
                 Ok(dbg!(all_projects))
             }
@@ -204,11 +468,380 @@ impl AppDb {
         });
 
         match find_result {
-            Ok(result) => Ok(result),
+            Ok(result) => Ok(result.map(Project::with_updated_at_backfilled)),
             Err(_e) => Err(String::from("Error finding project by ID")),
         }
     }
 
+    /// プロジェクトのディレクトリを取得（作成時の保存先を優先し、見つからない場合は現在の保存先）
+    pub fn get_project_dir(&self, id: &str) -> Result<PathBuf, String> {
+        Ok(self
+            .get_project_by_id(id.to_string())?
+            .map_or_else(
+                || get_base_directory().join("project_data").join(id),
+                |project| project.project_dir(),
+            ))
+    }
+
+    pub fn get_settings(&self) -> Result<AppSettings, String> {
+        let metadata: Collection<AppMetadata> = self.polo_instance.collection("metadata");
+        let found = metadata
+            .find_one(doc! { "type": "Settings" })
+            .map_err(|e| format!("Failed to read settings: {:?}", e))?;
+
+        match found {
+            Some(AppMetadata::Settings(settings)) => Ok(settings),
+            _ => Ok(AppSettings::default()),
+        }
+    }
+
+    pub fn save_settings(&self, settings: &AppSettings) -> Result<(), String> {
+        let metadata: Collection<AppMetadata> = self.polo_instance.collection("metadata");
+        metadata
+            .delete_many(doc! { "type": "Settings" })
+            .map_err(|e| format!("Failed to clear settings: {:?}", e))?;
+        metadata
+            .insert_one(AppMetadata::Settings(settings.clone()))
+            .map_err(|e| format!("Failed to save settings: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// プロジェクト名を変更し、更新後のProjectを返す（存在しない場合はNone）
+    pub fn rename_project(&self, id: &str, new_name: &str) -> Result<Option<Project>, String> {
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "name": new_name, "updated_at": current_unix_timestamp() } },
+            )
+            .map_err(|e| format!("Failed to rename project: {:?}", e))?;
+
+        self.get_project_by_id(id.to_string())
+    }
+
+    /// ミキサーのstemごとの設定を保存し、更新後のProjectを返す（存在しない場合はNone）
+    pub fn update_stem_settings(
+        &self,
+        id: &str,
+        settings: &[StemSetting],
+    ) -> Result<Option<Project>, String> {
+        let settings_bson = to_bson(settings)
+            .map_err(|e| format!("Failed to serialize stem settings: {:?}", e))?;
+
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "stem_settings": settings_bson,
+                        "updated_at": current_unix_timestamp(),
+                    }
+                },
+            )
+            .map_err(|e| format!("Failed to update stem settings: {:?}", e))?;
+
+        self.get_project_by_id(id.to_string())
+    }
+
+    /// タグを追加し、更新後のProjectを返す（存在しない場合はNone）。既に付いているタグは重複させない
+    ///
+    /// polodbの `$push`/`$pull` は使わず、読み込んでから `tags` ごと書き戻す
+    pub fn add_project_tag(&self, id: &str, tag: &str) -> Result<Option<Project>, String> {
+        let Some(project) = self.get_project_by_id(id.to_string())? else {
+            return Ok(None);
+        };
+        if project.tags.iter().any(|t| t == tag) {
+            return Ok(Some(project));
+        }
+
+        let mut tags = project.tags;
+        tags.push(tag.to_string());
+        self.set_project_tags(id, &tags)
+    }
+
+    /// タグを外し、更新後のProjectを返す（存在しない場合はNone）
+    pub fn remove_project_tag(&self, id: &str, tag: &str) -> Result<Option<Project>, String> {
+        let Some(project) = self.get_project_by_id(id.to_string())? else {
+            return Ok(None);
+        };
+        if !project.tags.iter().any(|t| t == tag) {
+            return Ok(Some(project));
+        }
+
+        let tags: Vec<String> = project.tags.into_iter().filter(|t| t != tag).collect();
+        self.set_project_tags(id, &tags)
+    }
+
+    fn set_project_tags(&self, id: &str, tags: &[String]) -> Result<Option<Project>, String> {
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "tags": tags.to_vec(), "updated_at": current_unix_timestamp() } },
+            )
+            .map_err(|e| format!("Failed to update tags: {:?}", e))?;
+
+        self.get_project_by_id(id.to_string())
+    }
+
+    /// `delete_source_after_split` が有効で、すべてのstemが書き出されている場合だけ曲ファイルを削除する
+    ///
+    /// 戻り値は削除した曲ファイルのパス（削除しなかった場合はNone）
+    pub fn delete_source_if_requested(&self, id: &str) -> Result<Option<PathBuf>, String> {
+        let project = self
+            .get_project_by_id(id.to_string())?
+            .ok_or_else(|| format!("Project not found: {}", id))?;
+
+        let stems_exist = !project.stem_paths.is_empty()
+            && project.stem_paths.iter().all(|path| Path::new(path).is_file());
+        if !project.delete_source_after_split || !stems_exist {
+            return Ok(None);
+        }
+        let Some(song_path) = find_song_in_project(&project.project_dir()) else {
+            return Ok(None);
+        };
+
+        fs::remove_file(&song_path)
+            .map_err(|e| format!("Failed to delete {}: {}", song_path.display(), e))?;
+        info!("[delete_source_if_requested] Deleted source file {:?} for project ID: {}", song_path, id);
+
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "source_deleted": true, "updated_at": current_unix_timestamp() } },
+            )
+            .map_err(|e| format!("Failed to update project {}: {:?}", id, e))?;
+
+        Ok(Some(song_path))
+    }
+
+    /// ライブラリのフォルダを移動した後、プロジェクトの `base_dir` とstemのパスを新しい場所に書き換える
+    ///
+    /// stemは旧プロジェクトディレクトリからの相対パスを保ったまま `new_base_dir/project_data/<id>` に付け替える。
+    /// 曲ファイル（削除済みの場合を除く）とすべてのstemが新しい場所にない場合はDBを変更せずエラーにする
+    pub fn relink_project(&self, id: &str, new_base_dir: &Path) -> Result<Option<Project>, String> {
+        let Some(project) = self.get_project_by_id(id.to_string())? else {
+            return Ok(None);
+        };
+
+        let old_project_dir = project.project_dir();
+        let new_project_dir = new_base_dir.join("project_data").join(id);
+        if !new_project_dir.is_dir() {
+            return Err(format!(
+                "Project directory not found: {}",
+                new_project_dir.display()
+            ));
+        }
+        if !project.source_deleted && find_song_in_project(&new_project_dir).is_none() {
+            return Err(format!(
+                "Audio file not found in {}",
+                new_project_dir.display()
+            ));
+        }
+
+        let mut stem_paths = Vec::with_capacity(project.stem_paths.len());
+        for stem_path in &project.stem_paths {
+            let stem_path = Path::new(stem_path);
+            // プロジェクトディレクトリ外に保存されたstemは、ファイル名だけを引き継ぐ
+            let relative = stem_path
+                .strip_prefix(&old_project_dir)
+                .map(Path::to_path_buf)
+                .or_else(|_| stem_path.file_name().map(PathBuf::from).ok_or(()))
+                .map_err(|_| format!("Invalid stem path: {}", stem_path.display()))?;
+
+            let relinked = new_project_dir.join(relative);
+            if !relinked.is_file() {
+                return Err(format!("Stem not found: {}", relinked.display()));
+            }
+            stem_paths.push(relinked.to_string_lossy().to_string());
+        }
+
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "base_dir": new_base_dir.to_string_lossy().to_string(),
+                        "stem_paths": stem_paths,
+                        "missing_files": false,
+                        "updated_at": current_unix_timestamp(),
+                    }
+                },
+            )
+            .map_err(|e| format!("Failed to relink project {}: {:?}", id, e))?;
+        info!(
+            "[relink_project] Relinked project ID: {} from {:?} to {:?}",
+            id, project.base_dir, new_base_dir
+        );
+
+        self.get_project_by_id(id.to_string())
+    }
+
+    /// BPMを手動で設定し、更新後のProjectを返す（存在しない場合はNone）
+    ///
+    /// 半分・倍のテンポの候補も設定したBPMから計算し直す。拍の位置は検出した値をそのまま残す
+    pub fn set_project_bpm(&self, id: &str, bpm: f64) -> Result<Option<Project>, String> {
+        let estimate = BpmEstimate::new(bpm);
+
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "bpm": bpm,
+                        "bpm_half": estimate.half,
+                        "bpm_double": estimate.double,
+                        "bpm_user_set": true,
+                        "updated_at": current_unix_timestamp(),
+                    }
+                },
+            )
+            .map_err(|e| format!("Failed to set BPM: {:?}", e))?;
+
+        self.get_project_by_id(id.to_string())
+    }
+
+    /// Keyを手動で設定し、更新後のProjectを返す（存在しない場合はNone）
+    pub fn set_project_key(&self, id: &str, key: &str) -> Result<Option<Project>, String> {
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "key": key,
+                        "key_user_set": true,
+                        "updated_at": current_unix_timestamp(),
+                    }
+                },
+            )
+            .map_err(|e| format!("Failed to set key: {:?}", e))?;
+
+        self.get_project_by_id(id.to_string())
+    }
+
+    /// 指定したタグが付いたプロジェクトを取得する
+    pub fn find_projects_by_tag(&self, tag: &str) -> Result<Vec<Project>, String> {
+        Ok(self
+            .get_projects()?
+            .into_iter()
+            .filter(|project| project.tags.iter().any(|t| t == tag))
+            .collect())
+    }
+
+    /// 曲ファイルが解析時から変わっている場合のみBPM/Keyなどを再解析する
+    ///
+    /// 戻り値は最新のProjectと、再解析を行ったかどうか
+    pub fn refresh_analysis_if_stale(&self, id: &str) -> Result<(Project, bool), String> {
+        let project = self
+            .get_project_by_id(id.to_string())?
+            .ok_or_else(|| format!("Project not found: {}", id))?;
+
+        let audio_path = find_song_in_project(&project.project_dir())
+            .ok_or_else(|| format!("Audio file not found for project {}", id))?;
+        let current_hash = fingerprint_file(&audio_path)?;
+
+        if project.audio_hash.as_deref() == Some(current_hash.as_str()) {
+            debug!("[refresh_analysis_if_stale] Analysis is up to date for project ID: {}", id);
+            return Ok((project, false));
+        }
+
+        info!(
+            "[refresh_analysis_if_stale] Audio changed ({:?} -> {}), re-analyzing project ID: {}",
+            project.audio_hash, current_hash, id
+        );
+        let mut update_doc = analyze_song(&audio_path);
+        keep_user_set_values(&mut update_doc, &project);
+        update_doc.insert("updated_at", current_unix_timestamp());
+        update_doc.insert("analysis_pending", false);
+
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        projects_collection
+            .update_one(doc! { "_id": id }, doc! { "$set": update_doc })
+            .map_err(|e| format!("Failed to update analysis: {:?}", e))?;
+
+        let updated = self
+            .get_project_by_id(id.to_string())?
+            .ok_or_else(|| format!("Project not found after update: {}", id))?;
+
+        Ok((updated, true))
+    }
+
+    /// DBとファイルシステムを突き合わせ、作成途中で失敗したプロジェクトの残骸を整理する
+    ///
+    /// - DBにないプロジェクトのディレクトリ（`project_data/<id>`）は削除する
+    /// - 曲ファイルがないDBのプロジェクトは削除せず、`missing_files` を立てる
+    pub fn gc_orphaned_projects(&self) -> Result<OrphanCleanupReport, String> {
+        let projects = self.get_projects()?;
+        let mut report = OrphanCleanupReport::default();
+
+        let project_ids: HashSet<&str> = projects.iter().map(|p| p._id.as_str()).collect();
+        let base_dirs: HashSet<PathBuf> = projects
+            .iter()
+            .map(|p| p.base_dir.clone())
+            .chain(std::iter::once(get_base_directory()))
+            .collect();
+
+        for base_dir in base_dirs {
+            let data_dir = base_dir.join("project_data");
+            let Ok(entries) = fs::read_dir(&data_dir) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let is_orphan = path.is_dir()
+                    && path
+                        .file_name()
+                        .map_or(false, |name| !project_ids.contains(&*name.to_string_lossy()));
+                if !is_orphan {
+                    continue;
+                }
+
+                match fs::remove_dir_all(&path) {
+                    Ok(_) => {
+                        info!("[gc_orphaned_projects] Removed orphaned directory: {:?}", path);
+                        report.removed_dirs.push(path);
+                    }
+                    Err(e) => {
+                        warn!("[gc_orphaned_projects] Failed to remove {:?}: {}", path, e);
+                    }
+                }
+            }
+        }
+
+        let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
+        for project in &projects {
+            // 意図して曲ファイルを削除したプロジェクトは、ファイルがなくても正常とみなす
+            let missing =
+                !project.source_deleted && find_song_in_project(&project.project_dir()).is_none();
+            if missing == project.missing_files {
+                continue;
+            }
+
+            projects_collection
+                .update_one(
+                    doc! { "_id": project._id.clone() },
+                    doc! { "$set": { "missing_files": missing } },
+                )
+                .map_err(|e| format!("Failed to flag project {}: {:?}", project._id, e))?;
+
+            if missing {
+                warn!("[gc_orphaned_projects] Files missing for project ID: {}", project._id);
+                report.missing_file_projects.push(project._id.clone());
+            } else {
+                report.restored_projects.push(project._id.clone());
+            }
+        }
+
+        Ok(report)
+    }
+
     pub fn delete_project_by_id(&self, project_id: String) -> Result<(), String> {
         let projects_collection: Collection<Project> = self.polo_instance.collection("projects");
         let deleted_result = projects_collection.delete_many(doc! {
@@ -224,3 +857,162 @@ impl AppDb {
         }
     }
 }
+
+/// プロジェクトIDの重複を確認し直す回数の上限
+const MAX_PROJECT_ID_ATTEMPTS: usize = 16;
+
+/// 作成に失敗したプロジェクトのディレクトリ（コピー途中の曲ファイルやカバー画像）を削除する
+fn remove_partial_project_dir(project: &Project) {
+    let project_dir = project.project_dir();
+    if !project_dir.exists() {
+        return;
+    }
+    if let Err(e) = fs::remove_dir_all(&project_dir) {
+        warn!(
+            "[create_project] Failed to remove partially created {:?}: {}",
+            project_dir, e
+        );
+    }
+}
+
+/// ユーザーが手動で設定した値を、解析結果の更新ドキュメントから取り除く
+fn keep_user_set_values(update_doc: &mut Document, project: &Project) {
+    if project.bpm_user_set {
+        for field in ["bpm", "bpm_half", "bpm_double", "beat_offset_secs"] {
+            update_doc.remove(field);
+        }
+    }
+    if project.key_user_set {
+        update_doc.remove("key");
+    }
+}
+
+/// プロジェクトの曲ファイルを解析し、`AppDb::save_song_analysis` に渡す更新ドキュメントを返す
+///
+/// 曲全体をデコードするため時間がかかる。DBのロックを持たずに実行できるよう、`AppDb` のメソッドにはしていない
+pub fn analyze_project_song(project: &Project) -> Result<Document, String> {
+    let audio_path = find_song_in_project(&project.project_dir())
+        .ok_or_else(|| format!("Audio file not found for project {}", project._id))?;
+
+    Ok(analyze_song(&audio_path))
+}
+
+/// 曲を解析し、Projectに保存する解析結果（BPM/Key/長さなど）の更新ドキュメントを作成
+fn analyze_song(audio_path: &Path) -> Document {
+    // 解析用に一度だけデコードし、BPM/Key/長さ/サンプルレートを取得
+    info!("[analyze_song] Detecting BPM and Key for: {:?}", audio_path);
+    let track_result = decode_file(audio_path);
+    if let Err(e) = &track_result {
+        warn!("[analyze_song] Failed to decode audio file: {:?}", e);
+    }
+    let track = track_result.ok();
+
+    let bpm_result = track.as_ref().map_or(Ok(None), detect_bpm_from_pcm);
+    let key_result = track.as_ref().map_or(Ok(None), detect_key_from_pcm);
+    let duration_secs = track
+        .as_ref()
+        .map(|t| t.length as f64 / t.sample_rate as f64);
+    let sample_rate = track.as_ref().map(|t| t.sample_rate);
+    let lufs = track
+        .as_ref()
+        .map(|t| measure_lufs(&t.samples, t.sample_rate))
+        .filter(|lufs| lufs.is_finite());
+    let clipping = track.as_ref().map(|t| detect_clipping(&t.samples));
+    drop(track);
+    
+    match &bpm_result {
+        Ok(Some(estimate)) => info!("[analyze_song] BPM detected: {:?}", estimate),
+        Ok(None) => debug!("[analyze_song] BPM detection returned None"),
+        Err(e) => warn!("[analyze_song] BPM detection error: {:?}", e),
+    }
+    
+    match &key_result {
+        Ok(Some(key)) => info!("[analyze_song] Key detected: {}", key),
+        Ok(None) => debug!("[analyze_song] Key detection returned None"),
+        Err(e) => warn!("[analyze_song] Key detection error: {:?}", e),
+    }
+    
+    let bpm_estimate = bpm_result.ok().flatten();
+    let bpm = bpm_estimate.map(|estimate| estimate.bpm);
+    let key = key_result.ok().flatten();
+
+    // BPMとKeyを更新（Noneでも更新を試みる）
+    let mut update_doc = doc! {};
+    
+    // BPMが検出された場合、更新ドキュメントに追加
+    if let Some(bpm_val) = bpm {
+        update_doc.insert("bpm", bpm_val);
+        debug!("[analyze_song] Adding BPM to update: {}", bpm_val);
+
+        // 半分・倍のテンポの候補（範囲外の場合は以前の値を残さないようnullにする）
+        let half = bpm_estimate.and_then(|estimate| estimate.half);
+        let double = bpm_estimate.and_then(|estimate| estimate.double);
+        update_doc.insert("bpm_half", half);
+        update_doc.insert("bpm_double", double);
+
+        // 信頼度の低い拍の位置は、小節の書き出しがずれる原因になるため保存しない
+        let beat_offset = bpm_estimate
+            .filter(|estimate| !estimate.beat_offset_low_confidence)
+            .and_then(|estimate| estimate.beat_offset_secs);
+        update_doc.insert("beat_offset_secs", beat_offset);
+    } else {
+        debug!("[analyze_song] BPM is None, skipping BPM update");
+    }
+    
+    // Keyが検出された場合、更新ドキュメントに追加
+    if let Some(key_val) = &key {
+        update_doc.insert("key", key_val);
+        debug!("[analyze_song] Adding Key to update: {}", key_val);
+    } else {
+        debug!("[analyze_song] Key is None, skipping Key update");
+    }
+    
+    if let Some(duration_val) = duration_secs {
+        update_doc.insert("duration_secs", duration_val);
+    }
+
+    if let Some(sample_rate_val) = sample_rate {
+        update_doc.insert("sample_rate", sample_rate_val as i64);
+    }
+
+    if let Some(lufs_val) = lufs {
+        update_doc.insert("lufs", lufs_val);
+    }
+
+    if let Some(report) = clipping {
+        if report.is_clipped() {
+            warn!(
+                "[analyze_song] Source is clipped: {} samples, peak {:.3}",
+                report.sample_count, report.max_peak
+            );
+        }
+        update_doc.insert("source_clipped", report.is_clipped());
+    }
+
+    // 解析した時点のファイルのハッシュ（ファイルが差し替えられたかの判定に使う）
+    match fingerprint_file(audio_path) {
+        Ok(hash) => {
+            update_doc.insert("audio_hash", hash);
+        }
+        Err(e) => warn!("[analyze_song] Failed to fingerprint audio file: {}", e),
+    }
+
+    update_doc
+}
+
+/// タグのタイトル（とアーティスト）からプロジェクト名を作る。タイトルがない場合はNone
+fn project_name_from_tags(audio_filepath: &Path) -> Option<String> {
+    let metadata = match read_metadata(audio_filepath) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            warn!("[create_project] Failed to read tags from {:?}: {}", audio_filepath, e);
+            return None;
+        }
+    };
+
+    let title = metadata.title?;
+    Some(match metadata.artist {
+        Some(artist) => format!("{} - {}", artist, title),
+        None => title,
+    })
+}
@@ -1,77 +1,832 @@
 // BPMとKeyの検出機能
 // 基本的な実装。後で改善可能
 
-use crate::demucs::audio::decode_file;
+use crate::demucs::audio::{
+    decode_file, decode_file_frames, resample, supported_input_extensions, PcmAudioData,
+    ResampleQuality,
+};
 use crate::demucs::error::Result;
-use std::path::Path;
+use rayon::prelude::*;
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::{Deserialize, Serialize};
+use snafu::whatever;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// 検出するBPMの範囲
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+
+/// BPMの検出結果
+///
+/// テンポ検出は実際の半分・倍のテンポを拾いやすいため、範囲内に収まる場合は候補として一緒に返す
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BpmEstimate {
+    pub bpm: f64,
+    /// bpm / 2（検出範囲外の場合はNone）
+    pub half: Option<f64>,
+    /// bpm * 2（検出範囲外の場合はNone）
+    pub double: Option<f64>,
+    /// 最初の強拍の位置（秒）。ループやグリッドの位置合わせに使う（拍を検出できない場合はNone）
+    #[serde(default)]
+    pub beat_offset_secs: Option<f64>,
+    /// 拍の位置のばらつきが大きく、`beat_offset_secs` が信頼できない
+    #[serde(default)]
+    pub beat_offset_low_confidence: bool,
+}
+
+impl BpmEstimate {
+    pub fn new(bpm: f64) -> Self {
+        let in_range = |candidate: f64| Some(candidate).filter(|c| (MIN_BPM..=MAX_BPM).contains(c));
+
+        Self {
+            bpm,
+            half: in_range(bpm / 2.0),
+            double: in_range(bpm * 2.0),
+            beat_offset_secs: None,
+            beat_offset_low_confidence: false,
+        }
+    }
+}
+
+/// プロジェクトを作成せずに行う解析の結果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioAnalysis {
+    pub bpm: Option<BpmEstimate>,
+    pub key: Option<String>,
+    pub duration_secs: f64,
+    pub sample_rate: usize,
+    pub channels: usize,
+}
+
+/// オーディオファイルを一度だけデコードし、BPM・Key・基本情報を返す（分離は行わない）
+pub fn analyze_audio(audio_path: &Path) -> Result<AudioAnalysis> {
+    let track = decode_file(audio_path)?;
+
+    let bpm = detect_bpm_from_pcm(&track)?;
+    let key = detect_key_from_pcm(&track)?;
+
+    Ok(AudioAnalysis {
+        bpm,
+        key,
+        duration_secs: track.length as f64 / track.sample_rate as f64,
+        sample_rate: track.sample_rate,
+        channels: track.nb_channels,
+    })
+}
+
+/// フォルダの一括解析で同時に解析するファイル数の上限（デコード中のPCMでメモリを使いすぎないため）
+const MAX_FOLDER_ANALYSIS_WORKERS: usize = 8;
+
+/// フォルダ内の1ファイルの解析結果（失敗したファイルは `error` に理由が入る）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalyzeResult {
+    pub path: String,
+    pub analysis: Option<AudioAnalysis>,
+    pub error: Option<String>,
+}
+
+/// フォルダ直下の対応する音声ファイルをすべて解析する（サブフォルダは見ない）
+///
+/// 解析はTorchを使わないCPU処理のため、上限付きのスレッドプールで並列に行う。
+/// 1ファイルの失敗は他のファイルに影響せず、結果はパス順に並ぶ
+pub fn analyze_folder(dir: &Path) -> Result<Vec<AnalyzeResult>> {
+    let entries = whatever!(
+        std::fs::read_dir(dir),
+        "failed to read directory {}",
+        dir.display()
+    );
+
+    let extensions = supported_input_extensions();
+    let mut files: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path.extension().map_or(false, |ext| {
+                    extensions.contains(&ext.to_string_lossy().to_lowercase().as_str())
+                })
+        })
+        .collect();
+    files.sort();
+
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_FOLDER_ANALYSIS_WORKERS);
+    let pool = whatever!(
+        rayon::ThreadPoolBuilder::new().num_threads(workers).build(),
+        "failed to start analysis threads"
+    );
+    info!(
+        "[analyze_folder] Analyzing {} files in {:?} with {} threads",
+        files.len(),
+        dir,
+        workers
+    );
+
+    Ok(pool.install(|| {
+        files
+            .par_iter()
+            .map(|path| {
+                let (analysis, error) = match analyze_audio(path) {
+                    Ok(analysis) => (Some(analysis), None),
+                    Err(e) => {
+                        warn!("[analyze_folder] Failed to analyze {:?}: {}", path, e);
+                        (None, Some(e.to_string()))
+                    }
+                };
+                AnalyzeResult {
+                    path: path.to_string_lossy().to_string(),
+                    analysis,
+                    error,
+                }
+            })
+            .collect()
+    }))
+}
+
+/// 波形表示用のピーク計算で、ストリーミング中にまとめるフレーム数
+const PEAK_BLOCK_FRAMES: usize = 256;
+
+/// 波形描画用に、モノラルにダウンミックスした信号の (min, max) を `buckets` 個に集約する
+///
+/// 戻り値は `[min0, max0, min1, max1, ...]` の長さ `buckets * 2` の配列。
+/// ファイル全体をメモリに載せず、デコードしながら `PEAK_BLOCK_FRAMES` ごとのピークだけを保持する
+pub fn waveform_peaks(audio_path: &Path, buckets: usize) -> Result<Vec<f32>> {
+    if buckets == 0 {
+        whatever!("buckets must be greater than 0");
+    }
+
+    let mut blocks: Vec<(f32, f32)> = Vec::new();
+
+    decode_file_frames(audio_path, PEAK_BLOCK_FRAMES, |block| {
+        let nb_channels = block.len() as f32;
+        let length = block.iter().map(|channel| channel.len()).min().unwrap_or(0);
+
+        let peak = (0..length)
+            .map(|i| block.iter().map(|channel| channel[i]).sum::<f32>() / nb_channels)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), sample| {
+                (min.min(sample), max.max(sample))
+            });
+        if length > 0 {
+            blocks.push(peak);
+        }
+    })?;
+
+    if blocks.is_empty() {
+        whatever!("Decoded audio data is empty");
+    }
+
+    // ブロック数よりbucketsが多い場合は同じブロックを複数のbucketに割り当てる
+    let mut peaks = Vec::with_capacity(buckets * 2);
+    for bucket in 0..buckets {
+        let start = bucket * blocks.len() / buckets;
+        let end = ((bucket + 1) * blocks.len() / buckets).clamp(start + 1, blocks.len());
+        let (min, max) = blocks[start..end]
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), (lo, hi)| {
+                (min.min(*lo), max.max(*hi))
+            });
+        peaks.push(min);
+        peaks.push(max);
+    }
+
+    Ok(peaks)
+}
+
+/// ステレオの場合、モノラルに変換（両チャンネルの平均）
+fn to_mono(track: &PcmAudioData) -> Vec<f32> {
+    if track.nb_channels == 2 {
+        track.samples[0]
+            .iter()
+            .zip(track.samples[1].iter())
+            .map(|(a, b)| (a + b) / 2.0)
+            .collect()
+    } else {
+        track.samples[0].clone()
+    }
+}
+
+/// スペクトログラムの時間方向の列数の上限（長い曲でも計算するフレーム数をこの数に抑える）
+const MAX_SPECTROGRAM_COLUMNS: usize = 1024;
+/// スペクトログラムの周波数方向の行数の上限
+const MAX_SPECTROGRAM_ROWS: usize = 256;
+/// スペクトログラムの下限（dBFS）。これより小さい値はこの値に揃える
+const SPECTROGRAM_FLOOR_DB: f32 = -120.0;
+
+/// スペクトログラム表示用のデータ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpectrogramData {
+    /// 時間方向の列数
+    pub columns: usize,
+    /// 周波数方向の行数（0Hz〜ナイキスト周波数を等間隔に分割）
+    pub rows: usize,
+    /// 列ごとの振幅（dBFS）を並べた `columns * rows` の配列（`[列0の行0, 列0の行1, ...]`）
+    pub magnitudes_db: Vec<f32>,
+    /// 1列あたりの時間（秒）
+    pub secs_per_column: f64,
+    /// 最上行の周波数（ナイキスト周波数、Hz）
+    pub max_freq: f64,
+    pub duration_secs: f64,
+}
+
+/// モノラルにダウンミックスした信号のスペクトログラムを計算する
+///
+/// 表示用に、列数と行数はそれぞれ `MAX_SPECTROGRAM_COLUMNS`・`MAX_SPECTROGRAM_ROWS` 以下に間引く。
+/// 長い曲ではホップを広げ、計算するフレーム数も列数までに抑える
+pub fn spectrogram(audio_path: &Path, fft_size: usize, hop: usize) -> Result<SpectrogramData> {
+    if !fft_size.is_power_of_two() || !(256..=16384).contains(&fft_size) {
+        whatever!("fft_size must be a power of two between 256 and 16384, got {}", fft_size);
+    }
+    if hop == 0 {
+        whatever!("hop must be greater than 0");
+    }
+
+    let track = decode_file(audio_path)?;
+    let samples = to_mono(&track);
+    let sample_rate = track.sample_rate as f64;
+    if samples.len() < fft_size {
+        whatever!("Audio is shorter than fft_size ({} samples)", fft_size);
+    }
+
+    let nb_frames = (samples.len() - fft_size) / hop + 1;
+    let columns = nb_frames.min(MAX_SPECTROGRAM_COLUMNS);
+    let nb_bins = fft_size / 2 + 1;
+    let rows = nb_bins.min(MAX_SPECTROGRAM_ROWS);
+
+    let window = hann_window(fft_size);
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(fft_size);
+    let mut buffer = vec![Complex::new(0.0, 0.0); fft_size];
+    // 窓関数を掛けたフルスケールの正弦波が0dBになるように正規化する
+    let scale = 2.0 / window.iter().sum::<f32>();
+
+    let mut magnitudes_db = Vec::with_capacity(columns * rows);
+    for column in 0..columns {
+        let start = column * nb_frames / columns * hop;
+        for ((slot, sample), w) in buffer
+            .iter_mut()
+            .zip(&samples[start..start + fft_size])
+            .zip(&window)
+        {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+
+        // 隣り合う周波数ビンを平均して行数を減らす
+        for row in 0..rows {
+            let bin_start = row * nb_bins / rows;
+            let bin_end = ((row + 1) * nb_bins / rows).max(bin_start + 1);
+            let magnitude = buffer[bin_start..bin_end]
+                .iter()
+                .map(|bin| bin.norm())
+                .sum::<f32>()
+                / (bin_end - bin_start) as f32
+                * scale;
+            magnitudes_db.push((20.0 * magnitude.log10()).max(SPECTROGRAM_FLOOR_DB));
+        }
+    }
+
+    Ok(SpectrogramData {
+        columns,
+        rows,
+        magnitudes_db,
+        secs_per_column: (nb_frames as f64 / columns as f64) * hop as f64 / sample_rate,
+        max_freq: sample_rate / 2.0,
+        duration_secs: samples.len() as f64 / sample_rate,
+    })
+}
+
+pub(crate) fn hann_window(size: usize) -> Vec<f32> {
+    (0..size)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / size as f32).cos())
+        .collect()
+}
+
+/// ダウンビート推定に使うエンベロープのフレーム長（秒）
+const DOWNBEAT_FRAME_SECS: f64 = 0.01;
+/// 最大エネルギーに対してこの割合を超えた最初のフレームを曲の始まり（最初のダウンビート）とみなす
+const DOWNBEAT_THRESHOLD: f32 = 0.1;
+
+/// 最初のダウンビートの位置（秒）を推定する
+///
+/// 冒頭の無音を飛ばし、RMSエンベロープが最大値の `DOWNBEAT_THRESHOLD` を超えた最初のフレームを返す。
+/// 拍の強弱までは見ていないため、弱起（アウフタクト）の曲では1拍以上ずれることがある
+pub fn estimate_downbeat(track: &PcmAudioData) -> f64 {
+    let mono = to_mono(track);
+    let frame_size = ((track.sample_rate as f64 * DOWNBEAT_FRAME_SECS) as usize).max(1);
+
+    let envelope: Vec<f32> = mono
+        .chunks(frame_size)
+        .map(|frame| (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt())
+        .collect();
+    let max = envelope.iter().copied().fold(0.0, f32::max);
+    if max <= 0.0 {
+        return 0.0;
+    }
+
+    envelope
+        .iter()
+        .position(|rms| *rms > max * DOWNBEAT_THRESHOLD)
+        .map_or(0.0, |frame| (frame * frame_size) as f64 / track.sample_rate as f64)
+}
+
+/// ITU-R BS.1770のK特性フィルタ（2段のbiquad）
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+}
+
+impl KWeighting {
+    /// 任意のサンプルレート用の係数を計算（libebur128と同じ式）
+    fn new(sample_rate: usize) -> Self {
+        let fs = sample_rate as f64;
+
+        // 1段目: 頭部の音響効果を模したハイシェルフ
+        let f0 = 1681.974_450_955_533;
+        let gain_db = 3.999_843_853_973_347;
+        let q = 0.707_175_236_955_419_6;
+        let k = (std::f64::consts::PI * f0 / fs).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+
+        // 2段目: RLBハイパス
+        let f0 = 38.135_470_876_024_44;
+        let q = 0.500_327_037_323_877_3;
+        let k = (std::f64::consts::PI * f0 / fs).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+
+        Self {
+            stages: [shelf, high_pass],
+        }
+    }
+
+    fn apply(&self, samples: &[f32]) -> Vec<f64> {
+        let mut output: Vec<f64> = samples.iter().map(|&s| f64::from(s)).collect();
+
+        for stage in &self.stages {
+            let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+            for sample in output.iter_mut() {
+                let x0 = *sample;
+                let y0 = stage.b[0] * x0 + stage.b[1] * x1 + stage.b[2] * x2
+                    - stage.a[1] * y1
+                    - stage.a[2] * y2;
+                x2 = x1;
+                x1 = x0;
+                y2 = y1;
+                y1 = y0;
+                *sample = y0;
+            }
+        }
+
+        output
+    }
+}
+
+/// 統合ラウドネス（LUFS）を測定（ITU-R BS.1770-4: K特性 + 絶対/相対ゲート）
+///
+/// 400msブロック（75%オーバーラップ）の平均二乗から算出する。
+/// 全ブロックが無音でゲートを通過しない場合は `f64::NEG_INFINITY` を返す
+pub fn measure_lufs(samples: &[Vec<f32>], sample_rate: usize) -> f64 {
+    const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+    const RELATIVE_GATE_LU: f64 = -10.0;
+
+    let length = samples.iter().map(Vec::len).min().unwrap_or(0);
+    let block_size = (sample_rate as f64 * 0.4).round() as usize;
+    let hop = (block_size / 4).max(1);
+
+    if sample_rate == 0 || block_size == 0 || length < block_size {
+        return f64::NEG_INFINITY;
+    }
+
+    let filter = KWeighting::new(sample_rate);
+    let weighted: Vec<Vec<f64>> = samples.iter().map(|ch| filter.apply(&ch[..length])).collect();
+
+    // 各ブロックのチャンネル合計の平均二乗（L/R/Cの重みは1.0）
+    let block_powers: Vec<f64> = (0..=(length - block_size))
+        .step_by(hop)
+        .map(|start| {
+            weighted
+                .iter()
+                .map(|ch| {
+                    ch[start..start + block_size].iter().map(|s| s * s).sum::<f64>()
+                        / block_size as f64
+                })
+                .sum()
+        })
+        .collect();
+
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated_mean = |threshold: f64| {
+        let gated: Vec<f64> = block_powers
+            .iter()
+            .copied()
+            .filter(|&p| p > 0.0 && loudness(p) > threshold)
+            .collect();
+        if gated.is_empty() {
+            None
+        } else {
+            Some(gated.iter().sum::<f64>() / gated.len() as f64)
+        }
+    };
+
+    let Some(absolute_mean) = gated_mean(ABSOLUTE_GATE_LUFS) else {
+        return f64::NEG_INFINITY;
+    };
+    let relative_threshold = loudness(absolute_mean) + RELATIVE_GATE_LU;
+
+    gated_mean(relative_threshold.max(ABSOLUTE_GATE_LUFS)).map_or(f64::NEG_INFINITY, loudness)
+}
+
+/// フルスケールとみなす振幅（整数PCMの最大値は1.0をわずかに下回るため）
+const CLIP_LEVEL: f32 = 0.999;
+/// フルスケールが何サンプル連続したらクリップとみなすか（単発のピークは除外する）
+const CLIP_MIN_RUN: usize = 3;
+
+/// 元の音源のクリッピングの検出結果
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClippingReport {
+    /// クリップしているサンプル数（全チャンネルの合計）
+    pub sample_count: usize,
+    /// 最大の振幅（1.0を超えていれば0dBFSオーバー）
+    pub max_peak: f32,
+}
+
+impl ClippingReport {
+    pub fn is_clipped(&self) -> bool {
+        self.sample_count > 0
+    }
+}
+
+/// フルスケールに張り付いたサンプルの連続と、0dBFSを超えるサンプル（浮動小数点の音源）を数える
+pub fn detect_clipping(samples: &[Vec<f32>]) -> ClippingReport {
+    let mut sample_count = 0;
+    let mut max_peak = 0.0f32;
+
+    for channel in samples {
+        // フルスケールが続いているサンプル数と、そのうち0dBFSを超えているサンプル数
+        let mut run = 0;
+        let mut over = 0;
+        let mut flush = |run: &mut usize, over: &mut usize| {
+            sample_count += if *run >= CLIP_MIN_RUN { *run } else { *over };
+            *run = 0;
+            *over = 0;
+        };
+
+        for sample in channel {
+            let level = sample.abs();
+            max_peak = max_peak.max(level);
+
+            if level >= CLIP_LEVEL {
+                run += 1;
+                if level > 1.0 {
+                    over += 1;
+                }
+            } else {
+                flush(&mut run, &mut over);
+            }
+        }
+        flush(&mut run, &mut over);
+    }
+
+    ClippingReport {
+        sample_count,
+        max_peak,
+    }
+}
+
+/// オーディオファイルの統合ラウドネス（LUFS）を測定
+pub fn measure_file_lufs(audio_path: &Path) -> Result<f64> {
+    let track = decode_file(audio_path)?;
+
+    Ok(measure_lufs(&track.samples, track.sample_rate))
+}
+
+/// 2つの音声ファイルの差分信号のRMS（モデル同士の出力の違いの目安、0なら完全に一致）
+///
+/// 長さが異なる場合は短い方に合わせて比較する。サンプルレートかチャンネル数が異なる場合はエラー
+pub fn file_difference_rms(a_path: &Path, b_path: &Path) -> Result<f64> {
+    let a = decode_file(a_path)?;
+    let b = decode_file(b_path)?;
+    if a.sample_rate != b.sample_rate || a.nb_channels != b.nb_channels {
+        whatever!(
+            "cannot compare {} ({} Hz, {} ch) with {} ({} Hz, {} ch)",
+            a_path.display(),
+            a.sample_rate,
+            a.nb_channels,
+            b_path.display(),
+            b.sample_rate,
+            b.nb_channels
+        );
+    }
+
+    Ok(difference_rms(&a.samples, &b.samples))
+}
+
+/// 2つのバッファ（チャンネルごとのサンプル）の差分信号のRMS（0なら完全に一致）
+///
+/// 長さが異なる場合は短い方に合わせて比較する
+pub fn difference_rms(a: &[Vec<f32>], b: &[Vec<f32>]) -> f64 {
+    let length = a.iter().chain(b).map(Vec::len).min().unwrap_or(0);
+    let mut sum = 0.0f64;
+    for (channel_a, channel_b) in a.iter().zip(b) {
+        for (x, y) in channel_a[..length].iter().zip(&channel_b[..length]) {
+            let diff = f64::from(x - y);
+            sum += diff * diff;
+        }
+    }
+
+    let count = length * a.len().min(b.len());
+    if count == 0 {
+        0.0
+    } else {
+        (sum / count as f64).sqrt()
+    }
+}
+
+/// 位置合わせで探すずれの最大値（秒）
+const MAX_ALIGNMENT_SECS: f64 = 1.0;
+/// 位置合わせの相互相関に使う先頭の長さ（秒）。曲全体だとFFTのサイズが大きくなりすぎる
+const ALIGNMENT_WINDOW_SECS: f64 = 30.0;
+
+/// 分離したstemと単独の参照音源（公式のアカペラなど）を比べたSDRの目安（dB、高いほど参照に近い）
+///
+/// 先頭の相互相関でずれを合わせてから、音量の違いを無視するスケール不変SDRを計算する。
+/// 参照音源に他の楽器が含まれていると値は意味を持たないため、クリーンな参照が必要
+pub fn evaluate_separation(stem_path: &Path, reference_path: &Path) -> Result<f64> {
+    let stem = decode_file(stem_path)?;
+    let mut reference = decode_file(reference_path)?;
+    if reference.sample_rate != stem.sample_rate {
+        reference = resample(reference, stem.sample_rate, ResampleQuality::default())?;
+    }
+
+    let sample_rate = stem.sample_rate as f64;
+    let stem = to_mono(&stem);
+    let reference = to_mono(&reference);
+    let max_lag = (MAX_ALIGNMENT_SECS * sample_rate) as usize;
+    let window = (ALIGNMENT_WINDOW_SECS * sample_rate) as usize;
+    let lag = find_alignment_lag(&stem, &reference, window, max_lag);
+    debug!(
+        "[evaluate_separation] Aligned {:?} to {:?} with a lag of {} samples",
+        reference_path, stem_path, lag
+    );
+
+    // lagが正の場合は、stemが参照よりlagサンプル遅れている
+    let (stem_samples, reference_samples) = if lag >= 0 {
+        (stem.get(lag as usize..).unwrap_or_default(), &reference[..])
+    } else {
+        (
+            &stem[..],
+            reference.get(lag.unsigned_abs()..).unwrap_or_default(),
+        )
+    };
+    let length = stem_samples.len().min(reference_samples.len());
+    if length == 0 {
+        whatever!("no overlapping audio after alignment");
+    }
+
+    let (stem_samples, reference_samples) = (&stem_samples[..length], &reference_samples[..length]);
+    let reference_energy: f64 = reference_samples
+        .iter()
+        .map(|&r| f64::from(r).powi(2))
+        .sum();
+    if reference_energy <= f64::EPSILON {
+        whatever!("reference {} is silent", reference_path.display());
+    }
+
+    // 参照を最適な倍率に合わせた成分を目的信号、残りを歪みとみなす
+    let dot: f64 = stem_samples
+        .iter()
+        .zip(reference_samples)
+        .map(|(&s, &r)| f64::from(s) * f64::from(r))
+        .sum();
+    let scale = dot / reference_energy;
+    let (target_energy, distortion_energy) = stem_samples.iter().zip(reference_samples).fold(
+        (0.0f64, 0.0f64),
+        |(target, distortion), (&s, &r)| {
+            let t = scale * f64::from(r);
+            (target + t * t, distortion + (f64::from(s) - t).powi(2))
+        },
+    );
+
+    Ok(10.0 * (target_energy / distortion_energy.max(f64::EPSILON)).log10())
+}
+
+/// 先頭 `window` サンプルの相互相関をFFTで求め、`max_lag` の範囲で最も相関が高いずれを返す
+///
+/// 正の値はaがbより遅れていることを表す
+fn find_alignment_lag(a: &[f32], b: &[f32], window: usize, max_lag: usize) -> isize {
+    let a = &a[..a.len().min(window)];
+    let b = &b[..b.len().min(window)];
+    if a.is_empty() || b.is_empty() {
+        return 0;
+    }
+
+    let size = (a.len() + b.len()).next_power_of_two();
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(size);
+    let ifft = planner.plan_fft_inverse(size);
+
+    let to_spectrum = |samples: &[f32]| {
+        let mut buffer: Vec<Complex<f32>> = samples
+            .iter()
+            .map(|&sample| Complex::new(sample, 0.0))
+            .chain(std::iter::repeat(Complex::new(0.0, 0.0)))
+            .take(size)
+            .collect();
+        fft.process(&mut buffer);
+        buffer
+    };
+    let mut correlation: Vec<Complex<f32>> = to_spectrum(a)
+        .iter()
+        .zip(to_spectrum(b))
+        .map(|(x, y)| x * y.conj())
+        .collect();
+    ifft.process(&mut correlation);
+
+    // 循環相関なので、負のずれは配列の末尾に入っている
+    let max_lag = max_lag.min(size / 2 - 1) as isize;
+    (-max_lag..=max_lag)
+        .max_by(|&x, &y| {
+            let value = |lag: isize| correlation[lag.rem_euclid(size as isize) as usize].re;
+            value(x).total_cmp(&value(y))
+        })
+        .unwrap_or(0)
+}
 
 /// オーディオファイルからBPMを検出
 /// 
 /// 基本的な実装: エンベロープを使用してBPMを推定
 /// より高精度な実装には、FFTベースの方法やオートコリレーションを使用
-pub fn detect_bpm(audio_path: &Path) -> Result<Option<f64>> {
-    eprintln!("[detect_bpm] Starting BPM detection for: {:?}", audio_path);
+pub fn detect_bpm(audio_path: &Path) -> Result<Option<BpmEstimate>> {
+    info!("[detect_bpm] Starting BPM detection for: {:?}", audio_path);
     
     let track = match decode_file(audio_path) {
         Ok(t) => {
-            eprintln!("[detect_bpm] Audio file decoded: {} channels, {} Hz, {} samples", 
+            debug!("[detect_bpm] Audio file decoded: {} channels, {} Hz, {} samples", 
                      t.nb_channels, t.sample_rate, t.length);
             t
         }
         Err(e) => {
-            eprintln!("[detect_bpm] Failed to decode audio file: {:?}", e);
+            warn!("[detect_bpm] Failed to decode audio file: {:?}", e);
             return Err(e);
         }
     };
-    
-    // ステレオの場合、モノラルに変換（両チャンネルの平均）
-    let samples = if track.nb_channels == 2 {
-        track.samples[0]
-            .iter()
-            .zip(track.samples[1].iter())
-            .map(|(a, b)| (a + b) / 2.0)
-            .collect()
-    } else {
-        track.samples[0].clone()
-    };
 
-    eprintln!("[detect_bpm] Processing {} samples", samples.len());
+    detect_bpm_from_pcm(&track)
+}
+
+/// デコード済みのPCMデータからBPMを検出
+pub fn detect_bpm_from_pcm(track: &PcmAudioData) -> Result<Option<BpmEstimate>> {
+    let samples = to_mono(track);
+
+    debug!("[detect_bpm] Processing {} samples", samples.len());
 
     // 基本的なBPM検出: エンベロープを使用
     // より高精度な実装には、FFTベースの方法やオートコリレーションを使用
-    let bpm = match estimate_bpm_from_envelope(&samples, track.sample_rate) {
-        Ok(b) => {
-            eprintln!("[detect_bpm] BPM detected successfully: {}", b);
-            b
+    let tempo = match estimate_bpm_from_envelope(&samples, track.sample_rate) {
+        Ok(tempo) => {
+            info!(
+                "[detect_bpm] BPM detected successfully: {} (beat offset: {:?})",
+                tempo.bpm, tempo.beat_offset
+            );
+            tempo
         }
         Err(e) => {
-            eprintln!("[detect_bpm] Failed to estimate BPM: {:?}", e);
+            warn!("[detect_bpm] Failed to estimate BPM: {:?}", e);
             return Err(e);
         }
     };
-    
-    Ok(Some(bpm))
+
+    let mut estimate = BpmEstimate::new(tempo.bpm);
+    if let Some(offset) = tempo.beat_offset {
+        estimate.beat_offset_secs = Some(offset.secs);
+        estimate.beat_offset_low_confidence = offset.low_confidence;
+    }
+
+    Ok(Some(estimate))
+}
+
+/// ピークを検出できない場合のBPM
+const FALLBACK_BPM: f64 = 120.0;
+/// 拍の位置を推定するのに必要なピークの数
+const MIN_BEAT_PEAKS: usize = 4;
+/// 拍の位置の揃い具合（0〜1）がこれ未満なら信頼度が低いとみなす
+const BEAT_PHASE_MIN_CONFIDENCE: f64 = 0.5;
+
+/// エンベロープから推定したテンポ
+#[derive(Debug)]
+struct EnvelopeTempo {
+    bpm: f64,
+    beat_offset: Option<BeatOffset>,
+}
+
+impl EnvelopeTempo {
+    fn fallback() -> Self {
+        Self {
+            bpm: FALLBACK_BPM,
+            beat_offset: None,
+        }
+    }
+}
+
+/// 最初の強拍の位置
+#[derive(Debug, Clone, Copy)]
+struct BeatOffset {
+    secs: f64,
+    low_confidence: bool,
+}
+
+/// オンセットのピーク（秒）と強さから、拍のグリッドの位相と最初の強拍の位置を推定する
+///
+/// 各ピークを拍の周期で割った余りを角度とみなし、強さで重み付けした円周平均を位相とする。
+/// 平均ベクトルの長さ（0〜1）はピークがどれだけグリッドに揃っているかを表し、信頼度の目安に使う
+fn estimate_beat_offset(peaks: &[(f64, f32)], bpm: f64) -> Option<BeatOffset> {
+    if peaks.len() < MIN_BEAT_PEAKS || bpm <= 0.0 {
+        return None;
+    }
+    let period = 60.0 / bpm;
+
+    let (mut cos_sum, mut sin_sum, mut weight_sum) = (0.0f64, 0.0f64, 0.0f64);
+    for &(time, strength) in peaks {
+        let angle = 2.0 * std::f64::consts::PI * (time % period) / period;
+        cos_sum += strength as f64 * angle.cos();
+        sin_sum += strength as f64 * angle.sin();
+        weight_sum += strength as f64;
+    }
+    if weight_sum <= 0.0 {
+        return None;
+    }
+
+    let confidence = (cos_sum * cos_sum + sin_sum * sin_sum).sqrt() / weight_sum;
+    let phase = sin_sum
+        .atan2(cos_sum)
+        .rem_euclid(2.0 * std::f64::consts::PI)
+        / (2.0 * std::f64::consts::PI)
+        * period;
+
+    // 冒頭の無音を飛ばし、最初のピークに最も近いグリッドの拍を最初の強拍とする
+    let first_peak = peaks[0].0;
+    let beats = ((first_peak - phase) / period).round().max(0.0);
+
+    Some(BeatOffset {
+        secs: phase + beats * period,
+        low_confidence: confidence < BEAT_PHASE_MIN_CONFIDENCE,
+    })
+}
+
+/// BPM解析用にエンベロープを間引く際の目標レート（Hz）
+/// テンポの情報は200Hz以下に収まるため、100Hzまで落としても精度は変わらない
+const ENVELOPE_RATE: usize = 100;
+
+/// エンベロープ（絶対値）をブロックごとに平均して低いレートに間引く
+///
+/// 戻り値は間引いたエンベロープと、1要素あたりの元のサンプル数（hop）
+fn decimate_envelope(samples: &[f32], sample_rate: usize) -> (Vec<f32>, usize) {
+    let hop = (sample_rate / ENVELOPE_RATE).max(1);
+
+    let envelope = samples
+        .chunks(hop)
+        .map(|block| block.iter().map(|s| s.abs()).sum::<f32>() / block.len() as f32)
+        .collect();
+
+    (envelope, hop)
 }
 
 /// エンベロープを使用してBPMを推定（簡易版）
-fn estimate_bpm_from_envelope(samples: &[f32], sample_rate: usize) -> Result<f64> {
+fn estimate_bpm_from_envelope(samples: &[f32], sample_rate: usize) -> Result<EnvelopeTempo> {
     if samples.is_empty() {
-        return Ok(120.0);
+        return Ok(EnvelopeTempo::fallback());
     }
     
-    // エンベロープを抽出（絶対値）
-    let envelope: Vec<f32> = samples
-        .iter()
-        .map(|s| s.abs())
-        .collect();
+    // エンベロープを抽出（絶対値）し、約100Hzに間引く
+    let (envelope, hop) = decimate_envelope(samples, sample_rate);
+    let envelope_rate = sample_rate as f64 / hop as f64;
     
     // 移動平均でスムーズ化（固定ウィンドウサイズ）
-    let window_size = (sample_rate as f64 * 0.1) as usize; // 100ms
-    let window_size = window_size.max(1).min(samples.len() / 4); // 安全な範囲に制限
+    let window_size = (envelope_rate * 0.1) as usize; // 100ms
+    let window_size = window_size.max(1).min(envelope.len() / 4); // 安全な範囲に制限
     
-    if envelope.len() < window_size * 2 {
+    if window_size == 0 || envelope.len() < window_size * 2 {
         // サンプルが少なすぎる場合、デフォルト値を返す
-        return Ok(120.0);
+        return Ok(EnvelopeTempo::fallback());
     }
     
     // 移動平均を計算
@@ -82,15 +837,15 @@ fn estimate_bpm_from_envelope(samples: &[f32], sample_rate: usize) -> Result<f64
     }
     
     if smoothed.is_empty() {
-        return Ok(120.0);
+        return Ok(EnvelopeTempo::fallback());
     }
     
     // ピーク検出
-    let peaks = find_peaks(&smoothed, window_size / 4); // 検出ウィンドウを小さくする
+    let peaks = find_peaks(&smoothed, (window_size / 4).max(1)); // 検出ウィンドウを小さくする
     
     if peaks.len() < 2 {
         // ピークが少ない場合、デフォルト値を返す
-        return Ok(120.0);
+        return Ok(EnvelopeTempo::fallback());
     }
     
     // ピーク間隔からBPMを計算（手動で隣接する要素を比較）
@@ -103,39 +858,50 @@ fn estimate_bpm_from_envelope(samples: &[f32], sample_rate: usize) -> Result<f64
     }
     
     if intervals.is_empty() {
-        eprintln!("[estimate_bpm_from_envelope] No intervals found, returning default 120.0");
-        return Ok(120.0);
+        warn!("[estimate_bpm_from_envelope] No intervals found, returning default 120.0");
+        return Ok(EnvelopeTempo::fallback());
     }
     
     let avg_interval = intervals.iter().sum::<f64>() / intervals.len() as f64;
     
     if avg_interval <= 0.0 {
-        eprintln!("[estimate_bpm_from_envelope] Invalid avg_interval: {}, returning default 120.0", avg_interval);
-        return Ok(120.0);
+        warn!("[estimate_bpm_from_envelope] Invalid avg_interval: {}, returning default 120.0", avg_interval);
+        return Ok(EnvelopeTempo::fallback());
     }
     
-    // ピーク間隔はスムーズ化後のインデックス間隔
-    // スムーズ化後の1インデックス = 元のサンプルのwindow_size個
-    // したがって、ピーク間隔（スムーズ化後インデックス）を元のサンプル数に変換
-    let samples_per_peak = avg_interval * window_size as f64;
+    // ピーク間隔は間引いたエンベロープのインデックス間隔
+    // 1インデックス = 元のサンプルのhop個なので、元のサンプル数に変換
+    let samples_per_peak = avg_interval * hop as f64;
     
     if samples_per_peak <= 0.0 {
-        eprintln!("[estimate_bpm_from_envelope] Invalid samples_per_peak: {}, returning default 120.0", samples_per_peak);
-        return Ok(120.0);
+        warn!("[estimate_bpm_from_envelope] Invalid samples_per_peak: {}, returning default 120.0", samples_per_peak);
+        return Ok(EnvelopeTempo::fallback());
     }
     
     // BPMを計算: (サンプルレート / ピークあたりのサンプル数) * 60秒
     let bpm = (sample_rate as f64 / samples_per_peak) * 60.0;
     
-    eprintln!("[estimate_bpm_from_envelope] Calculated BPM: {} (avg_interval: {}, window_size: {}, samples_per_peak: {}, sample_rate: {})", 
-              bpm, avg_interval, window_size, samples_per_peak, sample_rate);
+    debug!("[estimate_bpm_from_envelope] Calculated BPM: {} (avg_interval: {}, window_size: {}, hop: {}, samples_per_peak: {}, sample_rate: {})", 
+              bpm, avg_interval, window_size, hop, samples_per_peak, sample_rate);
     
     // BPMの範囲を制限（通常は60-200 BPM）
-    let bpm = bpm.clamp(60.0, 200.0);
-    
-    eprintln!("[estimate_bpm_from_envelope] Final BPM (clamped): {}", bpm);
+    let bpm = bpm.clamp(MIN_BPM, MAX_BPM);
     
-    Ok(bpm)
+    debug!("[estimate_bpm_from_envelope] Final BPM (clamped): {}", bpm);
+
+    // 移動平均のインデックスiは envelope[i..i + window_size] の平均なので、その中心をピークの時刻とする
+    let peak_times: Vec<(f64, f32)> = peaks
+        .iter()
+        .map(|&i| {
+            let time = (i as f64 + window_size as f64 / 2.0) / envelope_rate;
+            (time, smoothed[i])
+        })
+        .collect();
+
+    Ok(EnvelopeTempo {
+        bpm,
+        beat_offset: estimate_beat_offset(&peak_times, bpm),
+    })
 }
 
 /// ピークを検出
@@ -173,65 +939,324 @@ fn find_peaks(signal: &[f32], window_size: usize) -> Vec<usize> {
     peaks
 }
 
+/// Key検出に使うキープロファイル（ジャンルによって合うプロファイルが異なる）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyProfile {
+    /// Krumhansl-Kessler（クラシック・ポップス向けの標準的なプロファイル）
+    #[default]
+    Krumhansl,
+    /// Temperley（Kostka-Payne）。主音・属音の重みが強い
+    Temperley,
+    /// Shaath（KeyFinder）。EDMなど電子音楽向けに調整されたプロファイル
+    Shaath,
+}
+
+impl KeyProfile {
+    /// C始まりの（メジャー, マイナー）プロファイル
+    fn weights(self) -> (&'static [f32; 12], &'static [f32; 12]) {
+        match self {
+            KeyProfile::Krumhansl => (
+                &[6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88],
+                &[6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17],
+            ),
+            KeyProfile::Temperley => (
+                &[0.748, 0.060, 0.488, 0.082, 0.670, 0.460, 0.096, 0.715, 0.104, 0.366, 0.057, 0.400],
+                &[0.712, 0.084, 0.474, 0.618, 0.049, 0.460, 0.105, 0.747, 0.404, 0.067, 0.133, 0.330],
+            ),
+            // Ibrahim Sha'ath, "Estimation of key in digital music recordings"
+            // (MSc thesis, Birkbeck, University of London, 2011) の値。KeyFinderと
+            // Essentiaの key.cpp（profileType "shaath"）で使われているものと同じ
+            KeyProfile::Shaath => (
+                &[6.6, 2.0, 3.5, 2.3, 4.6, 4.0, 2.5, 5.2, 2.4, 3.7, 2.3, 3.4],
+                &[6.5, 2.7, 3.5, 5.4, 2.6, 3.5, 2.5, 5.2, 4.0, 2.7, 4.3, 3.2],
+            ),
+        }
+    }
+}
+
+/// Keyの検出結果
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyEstimate {
+    /// "C major" / "A minor" の形式
+    pub key: String,
+    /// 検出に使ったキープロファイル
+    pub profile: KeyProfile,
+    /// クロマとプロファイルの相関（-1.0〜1.0、大きいほど確からしい）
+    pub correlation: f32,
+}
+
+const PITCH_CLASSES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+/// "c# MINOR" のような表記を24種類のキー名（"C# minor" の形式）に正規化する（キー名でない場合はNone）
+pub fn normalize_key_name(key: &str) -> Option<String> {
+    let mut parts = key.split_whitespace();
+    let (tonic, mode) = (parts.next()?, parts.next()?.to_lowercase());
+    if parts.next().is_some() || !matches!(mode.as_str(), "major" | "minor") {
+        return None;
+    }
+
+    let tonic = PITCH_CLASSES
+        .iter()
+        .find(|pitch_class| pitch_class.eq_ignore_ascii_case(tonic))?;
+    Some(format!("{} {}", tonic, mode))
+}
+
+/// `from` のキーを `to` のキーにするために移調する半音数（-6〜5、近い方向を選ぶ）
+///
+/// 主音どうしを比べる（A minorからC majorは+3）。`match_relative` の場合は平行調
+/// （A minorとC majorなど）を同じ調号とみなし、短調を平行長調に置き換えて比べる。
+/// どちらかがキー名でない場合はNone
+pub fn key_semitone_shift(from: &str, to: &str, match_relative: bool) -> Option<i32> {
+    let pitch_class = |key: &str| -> Option<i32> {
+        let key = normalize_key_name(key)?;
+        let (tonic, mode) = key.split_once(' ')?;
+        let index = PITCH_CLASSES.iter().position(|p| *p == tonic)? as i32;
+        // 平行長調の主音は短調の主音の短3度上
+        Some(if match_relative && mode == "minor" {
+            (index + 3) % 12
+        } else {
+            index
+        })
+    };
+
+    let shift = (pitch_class(to)? - pitch_class(from)?).rem_euclid(12);
+    Some(if shift > 5 { shift - 12 } else { shift })
+}
+
+/// DJソフト向けのキーの表記
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyNotation {
+    /// ID3のTKEYの標準的な表記（"C" / "Am" / "F#m"）
+    Standard,
+    /// Camelot（"8B" / "8A"）。Mixed In Keyなどが使う表記で、RekordboxやSeratoでもそのまま表示される
+    #[default]
+    Camelot,
+    /// Open Key（"1d" / "1m"）。Traktorが使う表記
+    OpenKey,
+}
+
+/// キーを指定した表記に変換する（キー名でない場合はNone）
+pub fn format_key(key: &str, notation: KeyNotation) -> Option<String> {
+    match notation {
+        KeyNotation::Standard => {
+            let key = normalize_key_name(key)?;
+            let (tonic, mode) = key.split_once(' ')?;
+            Some(format!("{}{}", tonic, if mode == "minor" { "m" } else { "" }))
+        }
+        KeyNotation::Camelot => to_camelot(key),
+        KeyNotation::OpenKey => {
+            let (number, minor) = camelot_position(key)?;
+            // Open KeyはC majorを1とし、Camelotから7つずれている
+            let number = (number + 4) % 12 + 1;
+            Some(format!("{}{}", number, if minor { "m" } else { "d" }))
+        }
+    }
+}
+
+/// キーをCamelot表記（"C major" → "8B"、"A minor" → "8A"）に変換する（キー名でない場合はNone）
+pub fn to_camelot(key: &str) -> Option<String> {
+    let (number, minor) = camelot_position(key)?;
+    Some(format!("{}{}", number, if minor { "A" } else { "B" }))
+}
+
+/// Camelotホイール上の番号（1〜12）と短調かどうか
+///
+/// 番号は5度圏の順で、平行調（A minorとC majorなど）は同じ番号になる
+fn camelot_position(key: &str) -> Option<(usize, bool)> {
+    let key = normalize_key_name(key)?;
+    let (tonic, mode) = key.split_once(' ')?;
+    let index = PITCH_CLASSES.iter().position(|p| *p == tonic)?;
+    let minor = mode == "minor";
+    let major_index = if minor { (index + 3) % 12 } else { index };
+    // 5度上がるごとに番号が1つ進み、C majorが8B
+    Some(((major_index * 7 + 7) % 12 + 1, minor))
+}
+
+/// クロマ計算のFFTサイズとホップサイズ
+const CHROMA_FRAME_SIZE: usize = 8192;
+const CHROMA_HOP_SIZE: usize = CHROMA_FRAME_SIZE / 2;
+/// クロマに含める周波数の範囲（低すぎるとキック、高すぎると倍音やシンバルの影響が大きくなる）
+const CHROMA_MIN_FREQ: f32 = 55.0;
+const CHROMA_MAX_FREQ: f32 = 5000.0;
+
 /// オーディオファイルからKeyを検出
-/// 
-/// 基本的な実装: クロマグラムを使用してKeyを推定
-/// より高精度な実装には、キープロファイルと比較する方法を使用
-pub fn detect_key(audio_path: &Path) -> Result<Option<String>> {
+pub fn detect_key(audio_path: &Path, profile: KeyProfile) -> Result<Option<KeyEstimate>> {
     let track = decode_file(audio_path)?;
-    
-    // ステレオの場合、モノラルに変換
-    let samples = if track.nb_channels == 2 {
-        track.samples[0]
-            .iter()
-            .zip(track.samples[1].iter())
-            .map(|(a, b)| (a + b) / 2.0)
-            .collect()
-    } else {
-        track.samples[0].clone()
+
+    detect_key_with_profile(&track, profile)
+}
+
+/// デコード済みのPCMデータからKeyを検出（デフォルトのキープロファイルを使う）
+pub fn detect_key_from_pcm(track: &PcmAudioData) -> Result<Option<String>> {
+    Ok(detect_key_with_profile(track, KeyProfile::default())?.map(|estimate| estimate.key))
+}
+
+/// デコード済みのPCMデータから、指定したキープロファイルでKeyを検出（無音の場合はNone）
+pub fn detect_key_with_profile(
+    track: &PcmAudioData,
+    profile: KeyProfile,
+) -> Result<Option<KeyEstimate>> {
+    let samples = to_mono(track);
+    let Some(chroma) = compute_chroma(&samples, track.sample_rate) else {
+        debug!("[detect_key_with_profile] No tonal content, skipping key detection");
+        return Ok(None);
     };
 
-    // 基本的なKey検出: ピッチクラスプロファイルを使用
-    let key = estimate_key_from_chroma(&samples, track.sample_rate)?;
-    
-    Ok(Some(key))
+    let estimate = match_key_profile(&chroma, profile);
+    info!(
+        "[detect_key_with_profile] Estimated key: {} ({:?}, r={:.3})",
+        estimate.key, estimate.profile, estimate.correlation
+    );
+
+    Ok(Some(estimate))
 }
 
-/// クロマグラムを使用してKeyを推定（簡易版）
-/// 
-/// 現在は基本的な実装。より高精度な実装には：
-/// 1. FFTを使用してスペクトログラムを計算
-/// 2. クロマグラムを作成（12音階のエネルギーの分布）
-/// 3. キープロファイルと比較（24種類のキー: 12メジャー + 12マイナー）
-/// 4. 最も一致するキーを返す
-fn estimate_key_from_chroma(samples: &[f32], sample_rate: usize) -> Result<String> {
-    eprintln!("[estimate_key_from_chroma] Starting key detection: {} samples, {} Hz", 
-             samples.len(), sample_rate);
-    
-    // 簡易的な実装: 基本的な統計から推定
-    // 実際の実装では、rustfftなどのライブラリを使用してFFTを計算し、
-    // クロマグラムを作成して、キープロファイルと比較する必要があります
-    
-    // 今のところ、基本的な実装として、サンプルから推定
-    // 後で改善: FFTベースのクロマグラム解析を実装
-    
-    // TODO: 実際のKey検出を実装
-    // キーは12音階: C, C#, D, D#, E, F, F#, G, G#, A, A#, B
-    // マイナーとメジャー: minor, major
-    
-    // 簡易的な実装: サンプルの平均値から推定（暫定）
-    // これは実際のKey検出ではありませんが、テスト用に値を返す
-    if samples.is_empty() {
-        eprintln!("[estimate_key_from_chroma] No samples, returning default key");
-        return Ok("C major".to_string());
+/// FFTのスペクトルを12音階に畳み込んだクロマ（曲全体の平均）を計算する
+fn compute_chroma(samples: &[f32], sample_rate: usize) -> Option<[f32; 12]> {
+    if samples.len() < CHROMA_FRAME_SIZE || sample_rate == 0 {
+        return None;
+    }
+
+    // 各FFTビンが属するピッチクラス（範囲外のビンはNone）
+    let bin_hz = sample_rate as f32 / CHROMA_FRAME_SIZE as f32;
+    let bin_classes: Vec<Option<usize>> = (0..CHROMA_FRAME_SIZE / 2)
+        .map(|bin| {
+            let freq = bin as f32 * bin_hz;
+            (CHROMA_MIN_FREQ..=CHROMA_MAX_FREQ).contains(&freq).then(|| {
+                // A4=440Hzを基準にした半音の番号（Cを0とする）
+                let semitone = (12.0 * (freq / 440.0).log2()).round() as i64 + 9;
+                semitone.rem_euclid(12) as usize
+            })
+        })
+        .collect();
+
+    let window = hann_window(CHROMA_FRAME_SIZE);
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(CHROMA_FRAME_SIZE);
+    let mut buffer = vec![Complex::new(0.0, 0.0); CHROMA_FRAME_SIZE];
+    let mut chroma = [0.0f32; 12];
+
+    for start in (0..=samples.len() - CHROMA_FRAME_SIZE).step_by(CHROMA_HOP_SIZE) {
+        for ((slot, sample), w) in buffer
+            .iter_mut()
+            .zip(&samples[start..start + CHROMA_FRAME_SIZE])
+            .zip(&window)
+        {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+
+        for (bin, class) in buffer.iter().zip(&bin_classes) {
+            if let Some(class) = class {
+                chroma[*class] += bin.norm();
+            }
+        }
+    }
+
+    (chroma.iter().sum::<f32>() > 0.0).then_some(chroma)
+}
+
+/// クロマと24種類のキー（12メジャー + 12マイナー）のプロファイルの相関を取り、最も一致するキーを返す
+fn match_key_profile(chroma: &[f32; 12], profile: KeyProfile) -> KeyEstimate {
+    let (major, minor) = profile.weights();
+    let mut best = (f32::MIN, 0, "major");
+
+    for tonic in 0..12 {
+        for (weights, mode) in [(major, "major"), (minor, "minor")] {
+            // プロファイルを主音の位置まで回転させる
+            let rotated: Vec<f32> = (0..12).map(|i| weights[(i + 12 - tonic) % 12]).collect();
+            let correlation = pearson_correlation(chroma, &rotated);
+            if correlation > best.0 {
+                best = (correlation, tonic, mode);
+            }
+        }
+    }
+
+    let (correlation, tonic, mode) = best;
+    KeyEstimate {
+        key: format!("{} {}", PITCH_CLASSES[tonic], mode),
+        profile,
+        correlation,
+    }
+}
+
+fn pearson_correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (dx, dy) = (x - mean_a, y - mean_b);
+        cov += dx * dy;
+        var_a += dx * dx;
+        var_b += dy * dy;
+    }
+
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 指定したピーク（dBFS）の1kHzサイン波をステレオで生成する（EBU Tech 3341の基準信号）
+    fn stereo_sine(peak_dbfs: f32, sample_rate: usize, seconds: f32) -> Vec<Vec<f32>> {
+        let amplitude = 10f32.powf(peak_dbfs / 20.0);
+        let channel: Vec<f32> = (0..(sample_rate as f32 * seconds) as usize)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                amplitude * (2.0 * std::f32::consts::PI * 1000.0 * t).sin()
+            })
+            .collect();
+        vec![channel.clone(), channel]
+    }
+
+    #[test]
+    fn measure_lufs_matches_the_reference_tone() {
+        for sample_rate in [44100, 48000] {
+            let lufs = measure_lufs(&stereo_sine(-23.0, sample_rate, 20.0), sample_rate);
+            assert!(
+                (lufs + 23.0).abs() <= 0.5,
+                "-23 dBFS tone at {} Hz measured {:.2} LUFS",
+                sample_rate,
+                lufs
+            );
+        }
+    }
+
+    #[test]
+    fn measure_lufs_of_silence_is_negative_infinity() {
+        let silence = vec![vec![0.0f32; 48000 * 2]; 2];
+        assert_eq!(measure_lufs(&silence, 48000), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn key_semitone_shift_compares_tonics_unless_matching_relative_keys() {
+        assert_eq!(key_semitone_shift("A minor", "C major", false), Some(3));
+        assert_eq!(key_semitone_shift("A minor", "C major", true), Some(0));
+        assert_eq!(key_semitone_shift("C major", "A minor", true), Some(0));
+        assert_eq!(key_semitone_shift("C major", "C minor", false), Some(0));
+        assert_eq!(key_semitone_shift("C major", "C minor", true), Some(3));
+        // 近い方向を選ぶ
+        assert_eq!(key_semitone_shift("C major", "B major", false), Some(-1));
+        assert_eq!(key_semitone_shift("C major", "F# major", false), Some(-6));
+        assert_eq!(key_semitone_shift("C major", "H major", false), None);
+    }
+
+    #[test]
+    fn difference_rms_compares_the_overlapping_samples() {
+        let a = vec![vec![0.5f32; 4], vec![-0.5f32; 4]];
+        assert_eq!(difference_rms(&a, &a), 0.0);
+
+        // 長い方の末尾は比較しない
+        let silence = vec![vec![0.0f32; 6]; 2];
+        assert!((difference_rms(&a, &silence) - 0.5).abs() < 1e-9);
+        assert_eq!(difference_rms(&[], &silence), 0.0);
     }
-    
-    // 暫定的な実装: ランダムなキーを返すのではなく、より意味のある推定を試みる
-    // ここでは、簡易的にメジャーキーを返す（実際の実装では改善が必要）
-    let keys = vec!["C major", "D major", "E major", "F major", "G major", "A major", "B major"];
-    let estimated_key = keys[samples.len() % keys.len()];
-    
-    eprintln!("[estimate_key_from_chroma] Estimated key: {}", estimated_key);
-    
-    Ok(estimated_key.to_string())
 }
@@ -1,7 +1,7 @@
 use std::{fs::File, path::Path};
 
-use dasp::Signal as _;
-
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use snafu::{whatever, ResultExt};
 use symphonia::core::audio::Signal as _;
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
@@ -11,7 +11,7 @@ use symphonia::core::{errors::Error, io::MediaSourceStream, probe::Hint};
 
 use super::Result;
 
-use super::error::HoundSnafu;
+use super::error::{FileOpenSnafu, HoundSnafu, ResampleSnafu};
 
 #[derive(Clone)]
 pub struct PcmAudioData {
@@ -33,6 +33,14 @@ impl PcmAudioData {
 
         buffer
     }
+
+    /// 先頭 `max_length` サンプルだけを残す
+    pub fn truncate(&mut self, max_length: usize) {
+        for channel in self.samples.iter_mut() {
+            channel.truncate(max_length);
+        }
+        self.length = self.length.min(max_length);
+    }
 }
 
 impl std::fmt::Debug for PcmAudioData {
@@ -46,14 +54,370 @@ impl std::fmt::Debug for PcmAudioData {
 }
 
 pub fn decode_file(path: &Path) -> Result<PcmAudioData> {
-    let ext = path.extension();
+    let mut buffer: Vec<Vec<f32>> = Vec::new();
+    let (sample_rate, nb_channels) = decode_file_blocks(path, |block| {
+        if buffer.is_empty() {
+            buffer = vec![Vec::new(); block.len()];
+        }
+        for (channel, samples) in buffer.iter_mut().zip(block) {
+            channel.extend_from_slice(samples);
+        }
+    })?;
 
-    let src = File::open(path)
-        .map_err(|e| super::Error::UnexpectedError {
-            message: format!("Failed to open file: {}", path.display()),
-            source: Some(Box::new(e)),
+    let samples = if buffer.is_empty() {
+        vec![Vec::new(); nb_channels]
+    } else {
+        buffer
+    };
+
+    if samples.is_empty() {
+        return Err(super::Error::UnexpectedError {
+            message: "No audio data was decoded from the file".to_string(),
+            source: None,
+        });
+    }
+
+    let length = samples[0].len();
+
+    if length == 0 {
+        return Err(super::Error::UnexpectedError {
+            message: "Decoded audio data is empty".to_string(),
+            source: None,
+        });
+    }
+
+    Ok(PcmAudioData {
+        samples,
+        sample_rate,
+        nb_channels,
+        length,
+    })
+}
+
+/// ファイルをデコードし、`block_frames` フレームずつのブロック（チャンネルごとのスライス）をコールバックに渡す
+///
+/// パケットの長さはフォーマットによってまちまちなので、固定長にまとめ直してから渡す
+/// （最後のブロックだけ短くなる）。保持するのは1ブロック分だけなので、長い曲でもメモリ使用量は一定。
+/// 戻り値は (サンプルレート, チャンネル数)
+pub fn decode_file_frames<F>(
+    path: &Path,
+    block_frames: usize,
+    mut on_block: F,
+) -> Result<(usize, usize)>
+where
+    F: FnMut(&[&[f32]]),
+{
+    if block_frames == 0 {
+        whatever!("block_frames must be greater than 0");
+    }
+
+    let mut pending: Vec<Vec<f32>> = Vec::new();
+    let emit = |pending: &mut Vec<Vec<f32>>, on_block: &mut F| {
+        let channels: Vec<&[f32]> = pending.iter().map(Vec::as_slice).collect();
+        on_block(&channels);
+        for channel in pending.iter_mut() {
+            channel.clear();
+        }
+    };
+
+    let format = decode_file_blocks(path, |block| {
+        if pending.is_empty() {
+            pending = vec![Vec::with_capacity(block_frames); block.len()];
+        }
+
+        let length = block.iter().map(|channel| channel.len()).min().unwrap_or(0);
+        let mut offset = 0;
+        while offset < length {
+            let take = (block_frames - pending[0].len()).min(length - offset);
+            for (channel, samples) in pending.iter_mut().zip(block) {
+                channel.extend_from_slice(&samples[offset..offset + take]);
+            }
+            offset += take;
+
+            if pending[0].len() == block_frames {
+                emit(&mut pending, &mut on_block);
+            }
+        }
+    })?;
+
+    if pending.first().map_or(false, |channel| !channel.is_empty()) {
+        emit(&mut pending, &mut on_block);
+    }
+
+    Ok(format)
+}
+
+/// 読み込みに対応している音声ファイルの拡張子（ファイル選択ダイアログのフィルタ用）
+pub fn supported_input_extensions() -> &'static [&'static str] {
+    &["wav", "aiff", "aif", "flac", "mp3", "m4a", "aac", "ogg", "oga", "opus"]
+}
+
+/// ヘッダーから読み取った音声ファイルの情報
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioFileInfo {
+    pub sample_rate: Option<usize>,
+    pub channels: Option<usize>,
+    /// ヘッダーに記録されたフレーム数（記録されない形式ではNone）
+    pub frames: Option<u64>,
+    /// フレーム数がヘッダーにない形式（一部のMP3など）ではNone
+    pub duration_secs: Option<f64>,
+}
+
+/// デコードせずに、コンテナのヘッダーだけからサンプルレート・チャンネル数・長さを読み取る
+pub fn probe_audio_info(path: &Path) -> Result<AudioFileInfo> {
+    let src = File::open(path).context(FileOpenSnafu { path })?;
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension() {
+        hint.with_extension(&ext.to_string_lossy());
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .map_err(|e| super::Error::SymphoniaError { source: e })?;
+
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| super::Error::UnexpectedError {
+            message: "No supported audio tracks found in the file".to_string(),
+            source: None,
         })?;
 
+    let params = &track.codec_params;
+    let sample_rate = params.sample_rate.map(|rate| rate as usize);
+    let duration_secs = match (params.n_frames, params.sample_rate) {
+        (Some(frames), Some(rate)) if rate > 0 => Some(frames as f64 / rate as f64),
+        _ => None,
+    };
+
+    Ok(AudioFileInfo {
+        sample_rate,
+        channels: params.channels.map(|channels| channels.count()),
+        frames: params.n_frames,
+        duration_secs,
+    })
+}
+
+/// インポート前の確認用に、ファイルの形式と音声の情報を読み取った結果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioProbe {
+    /// コンテナの形式（ファイル先頭のシグネチャから判定した "wav" / "flac" / "mp3" など）
+    pub format: String,
+    /// コーデック（symphoniaの短い名前。"pcm_s16le" / "mp3" / "aac" など）
+    pub codec: String,
+    pub sample_rate: usize,
+    pub channels: usize,
+    /// フレーム数がヘッダーにない形式（一部のMP3など）ではNone
+    pub duration_secs: Option<f64>,
+    /// ファイルサイズと長さから求めた平均ビットレート（bps、コンテナ分を含む）。長さが分からない場合はNone
+    pub bitrate: Option<u64>,
+}
+
+/// 試しにデコードするパケット数の上限（先頭にデコードできないパケットがある形式に備える）
+const PROBE_DECODE_PACKETS: usize = 8;
+
+/// ファイル全体はデコードせずに形式と音声の情報を読み取り、先頭のパケットを試しにデコードする
+///
+/// 対応していない形式や壊れたファイルは、理由を添えて `UnsupportedAudioError` を返す
+pub fn probe_audio(path: &Path) -> Result<AudioProbe> {
+    let unsupported = |reason: String| super::Error::UnsupportedAudioError {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let src = File::open(path).context(FileOpenSnafu { path })?;
+    let file_size = src.metadata().map(|metadata| metadata.len()).ok();
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension() {
+        hint.with_extension(&ext.to_string_lossy());
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .map_err(|e| unsupported(format!("unrecognized format ({})", e)))?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| unsupported(String::from("no audio track")))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let registry = super::opus_codec::codec_registry();
+    let codec = registry
+        .get_codec(params.codec)
+        .map(|descriptor| descriptor.short_name.to_string())
+        .ok_or_else(|| unsupported(String::from("unsupported codec")))?;
+    let sample_rate = params
+        .sample_rate
+        .ok_or_else(|| unsupported(String::from("no sample rate information")))?
+        as usize;
+    let channels = params
+        .channels
+        .ok_or_else(|| unsupported(String::from("no channel information")))?
+        .count();
+    let mut decoder = registry
+        .make(&params, &Default::default())
+        .map_err(|e| unsupported(format!("unable to create a {} decoder ({})", codec, e)))?;
+
+    // ヘッダーだけ正しく中身が壊れたファイルを、インポートの途中ではなくここで検出する
+    let mut decode_error = String::from("no audio packets");
+    let mut decoded = false;
+    for _ in 0..PROBE_DECODE_PACKETS {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(e) => {
+                decode_error = e.to_string();
+                break;
+            }
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(_) => {
+                decoded = true;
+                break;
+            }
+            Err(e) => decode_error = e.to_string(),
+        }
+    }
+    if !decoded {
+        return Err(unsupported(format!(
+            "unable to decode audio ({})",
+            decode_error
+        )));
+    }
+
+    let duration_secs = params
+        .n_frames
+        .filter(|_| sample_rate > 0)
+        .map(|frames| frames as f64 / sample_rate as f64);
+    let bitrate = file_size
+        .zip(duration_secs)
+        .filter(|(_, secs)| *secs > 0.0)
+        .map(|(size, secs)| (size as f64 * 8.0 / secs).round() as u64);
+
+    Ok(AudioProbe {
+        format: container_format(path),
+        codec,
+        sample_rate,
+        channels,
+        duration_secs,
+        bitrate,
+    })
+}
+
+/// ファイル先頭のシグネチャからコンテナの形式を判定する（判定できない場合は拡張子）
+fn container_format(path: &Path) -> String {
+    use std::io::Read;
+
+    let mut header = [0u8; 12];
+    let read = File::open(path)
+        .and_then(|mut file| file.read(&mut header))
+        .unwrap_or(0);
+    let header = &header[..read];
+
+    let format = match header {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E'] => "wav",
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', _] => "aiff",
+        [b'f', b'L', b'a', b'C', ..] => "flac",
+        [b'O', b'g', b'g', b'S', ..] => "ogg",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "mp4",
+        [b'I', b'D', b'3', ..] => "mp3",
+        // ADTS（AAC）のフレームヘッダーはレイヤーのビットが0
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => "aac",
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => "mp3",
+        _ => {
+            return path.extension().map_or_else(
+                || String::from("unknown"),
+                |ext| ext.to_string_lossy().to_lowercase(),
+            )
+        }
+    };
+
+    String::from(format)
+}
+
+/// ヘッダーのフレーム数と実際にデコードできたフレーム数の差の許容量
+/// （MP3/AACはエンコーダーの遅延・パディングでわずかにずれるため）
+const VERIFY_FRAME_TOLERANCE: u64 = 4096;
+
+/// 音声ファイルの整合性チェックの結果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyResult {
+    pub path: String,
+    pub ok: bool,
+    /// ヘッダーに記録されたフレーム数（記録されない形式ではNone）
+    pub header_frames: Option<u64>,
+    /// 最後までデコードできたフレーム数（デコードに失敗した場合はNone）
+    pub decoded_frames: Option<u64>,
+    /// 問題がある場合の詳細
+    pub error: Option<String>,
+}
+
+/// ファイル全体をデコードし、ヘッダーに記録された長さ分のデータがあるかを確認する
+///
+/// 分離が途中で中断されたWAVは、ヘッダーの長さに対してデータが足りず、DAWで読み込むとクラッシュすることがある
+pub fn verify_audio_file(path: &Path) -> VerifyResult {
+    let mut result = VerifyResult {
+        path: path.to_string_lossy().to_string(),
+        ok: false,
+        header_frames: None,
+        decoded_frames: None,
+        error: None,
+    };
+
+    let info = match probe_audio_info(path) {
+        Ok(info) => info,
+        Err(e) => {
+            result.error = Some(format!("failed to read header: {}", e));
+            return result;
+        }
+    };
+    result.header_frames = info.frames;
+
+    let mut decoded_frames = 0u64;
+    if let Err(e) = decode_file_blocks(path, |block| {
+        decoded_frames += block.first().map_or(0, |channel| channel.len() as u64);
+    }) {
+        result.error = Some(format!("failed to decode: {}", e));
+        return result;
+    }
+    result.decoded_frames = Some(decoded_frames);
+
+    result.error = match info.frames {
+        _ if decoded_frames == 0 => Some(String::from("no audio data")),
+        Some(header_frames) if header_frames > decoded_frames + VERIFY_FRAME_TOLERANCE => {
+            Some(format!(
+                "header declares {} frames but only {} could be decoded (file may be truncated)",
+                header_frames, decoded_frames
+            ))
+        }
+        _ => None,
+    };
+    result.ok = result.error.is_none();
+
+    result
+}
+
+/// ファイルをデコードし、パケットごとのPCM（チャンネルごとのスライス）をコールバックに渡す
+///
+/// ファイル全体をメモリに載せずに処理できる。戻り値は (サンプルレート, チャンネル数)
+pub fn decode_file_blocks<F>(path: &Path, mut on_block: F) -> Result<(usize, usize)>
+where
+    F: FnMut(&[&[f32]]),
+{
+    let ext = path.extension();
+
+    let src = File::open(path).context(FileOpenSnafu { path })?;
+
     let mss = MediaSourceStream::new(Box::new(src), Default::default());
     let mut hint = Hint::new();
 
@@ -86,7 +450,7 @@ pub fn decode_file(path: &Path) -> Result<PcmAudioData> {
 
     dbg!(track);
 
-    let mut decoder = symphonia::default::get_codecs()
+    let mut decoder = super::opus_codec::codec_registry()
         .make(&track.codec_params, &dec_opts)
         .map_err(|e| super::Error::SymphoniaError { source: e })?;
 
@@ -106,7 +470,6 @@ pub fn decode_file(path: &Path) -> Result<PcmAudioData> {
             source: None,
         })? as usize;
 
-    let mut buffer: Vec<Vec<f32>> = (0..nb_channels).map(|_| Vec::new()).collect();
     'decode: loop {
         // Get the next packet from the media format.
         let packet = match format.next_packet() {
@@ -136,12 +499,16 @@ pub fn decode_file(path: &Path) -> Result<PcmAudioData> {
         match decoder.decode(&packet) {
             Ok(decoded) => match decoded {
                 symphonia::core::audio::AudioBufferRef::F32(buf) => {
-                    for ch in 0..nb_channels {
-                        buffer[ch].extend_from_slice(buf.chan(ch));
-                    }
+                    let channels: Vec<&[f32]> = (0..nb_channels).map(|ch| buf.chan(ch)).collect();
+                    on_block(&channels);
                 }
-                _ => {
-                    unimplemented!()
+                // 整数PCM（16/24bit WAVなど）はf32に変換する
+                other => {
+                    let mut converted = other.make_equivalent::<f32>();
+                    other.convert(&mut converted);
+                    let channels: Vec<&[f32]> =
+                        (0..nb_channels).map(|ch| converted.chan(ch)).collect();
+                    on_block(&channels);
                 }
             },
             Err(Error::IoError(_)) => {
@@ -156,39 +523,112 @@ pub fn decode_file(path: &Path) -> Result<PcmAudioData> {
         }
     }
 
-    let samples = buffer;
+    Ok((sample_rate, nb_channels))
+}
 
-    if samples.is_empty() {
-        return Err(super::Error::UnexpectedError {
-            message: "No audio data was decoded from the file".to_string(),
-            source: None,
-        });
+/// WAV書き出し時のビット深度
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WavBitDepth {
+    Int16,
+    Int24,
+    /// 32bit浮動小数点（量子化もクリッピングも行わない）
+    #[default]
+    Float32,
+}
+
+impl WavBitDepth {
+    fn spec(self, nb_channels: usize, sample_rate: usize) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            Self::Int16 => (16, hound::SampleFormat::Int),
+            Self::Int24 => (24, hound::SampleFormat::Int),
+            Self::Float32 => (32, hound::SampleFormat::Float),
+        };
+
+        hound::WavSpec {
+            channels: nb_channels as u16,
+            sample_rate: sample_rate as u32,
+            bits_per_sample,
+            sample_format,
+        }
     }
+}
 
-    let length = samples[0].len();
+/// 16bitに量子化する前に、±1LSBの三角分布（TPDF）のディザを加える
+///
+/// 丸めによる量子化誤差が信号と相関すると、小さな音（ボーカルの余韻など）で歪みとして聞こえるため、
+/// 信号と無相関な一定のノイズに置き換える。24bit/32bit floatでは量子化誤差が十分小さいため使わない
+pub fn apply_dither(samples: &mut [Vec<f32>]) {
+    let lsb = 1.0 / f32::from(i16::MAX);
+    let mut rng = rand::thread_rng();
+    for channel in samples.iter_mut() {
+        for sample in channel.iter_mut() {
+            // 一様分布（0〜1LSB）どうしの差は±1LSBの三角分布になる
+            let noise = rng.gen::<f32>() - rng.gen::<f32>();
+            *sample += noise * lsb;
+        }
+    }
+}
 
-    if length == 0 {
-        return Err(super::Error::UnexpectedError {
-            message: "Decoded audio data is empty".to_string(),
-            source: None,
-        });
+/// WAVを書き出す際の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// 書き出すWAVのビット深度
+    pub bit_depth: WavBitDepth,
+    /// 16bitで書き出す場合に、量子化の前にTPDFディザを加える（24bit/32bit floatでは常に加えない）
+    pub dither: bool,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            bit_depth: WavBitDepth::default(),
+            dither: true,
+        }
     }
+}
 
-    Ok(PcmAudioData {
-        samples,
-        sample_rate,
-        nb_channels,
-        length,
-    })
+impl ExportConfig {
+    /// 16bitで書き出す設定なら、サンプルにディザを加える
+    ///
+    /// リサンプリングなどすべての処理の後、量子化の直前に呼ぶこと
+    pub fn dither_if_needed(&self, samples: &mut [Vec<f32>]) {
+        if self.dither && self.bit_depth == WavBitDepth::Int16 {
+            apply_dither(samples);
+        }
+    }
+
+    /// 設定したビット深度でWAVとして書き出す（16bitの場合は量子化の前にディザを加える）
+    pub fn encode_wav(
+        &self,
+        mut audio: PcmAudioData,
+        path: &Path,
+        time_reference: Option<TimeReference>,
+    ) -> Result<()> {
+        self.dither_if_needed(&mut audio.samples);
+        encode_pcm_to_wav(audio, path, self.bit_depth, time_reference)
+    }
 }
 
-pub fn encode_pcm_to_wav(audio: PcmAudioData, path: &Path) -> Result<()> {
-    let wav_spec = hound::WavSpec {
-        channels: audio.nb_channels as u16,
-        sample_rate: audio.sample_rate as u32,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Float,
-    };
+/// BWF（Broadcast Wave）の `bext` チャンクに書き込むタイムライン上の位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeReference {
+    /// タイムラインの先頭からの位置（書き出すWAVのサンプルレートでのサンプル数）
+    pub sample_offset: u64,
+}
+
+/// `bext` チャンク（バージョン1）のうち、Coding Historyを除いた固定長部分のサイズ
+const BEXT_CHUNK_SIZE: usize = 602;
+
+/// WAVとして書き出す。`time_reference` を指定すると `bext` チャンクを付け、DAWのタイムライン上の位置を残す
+pub fn encode_pcm_to_wav(
+    audio: PcmAudioData,
+    path: &Path,
+    bit_depth: WavBitDepth,
+    time_reference: Option<TimeReference>,
+) -> Result<()> {
+    let wav_spec = bit_depth.spec(audio.nb_channels, audio.sample_rate);
 
     let mut writer = hound::WavWriter::create(path, wav_spec)
         .map_err(|e| super::Error::UnexpectedError {
@@ -196,40 +636,537 @@ pub fn encode_pcm_to_wav(audio: PcmAudioData, path: &Path) -> Result<()> {
             source: Some(Box::new(e)),
         })?;
 
+    // 整数フォーマットの場合はフルスケールにクランプしてから量子化
+    let int_scale = match bit_depth {
+        WavBitDepth::Int16 => Some(f32::from(i16::MAX)),
+        WavBitDepth::Int24 => Some(8_388_607.0),
+        WavBitDepth::Float32 => None,
+    };
+
     for i in 0..audio.length {
         for channel in audio.samples.iter() {
-            writer.write_sample(channel[i]).context(HoundSnafu)?;
+            match int_scale {
+                Some(scale) => {
+                    let quantized = (channel[i].clamp(-1.0, 1.0) * scale).round() as i32;
+                    writer.write_sample(quantized).context(HoundSnafu)?;
+                }
+                None => writer.write_sample(channel[i]).context(HoundSnafu)?,
+            }
         }
     }
 
     writer.finalize().context(HoundSnafu)?;
 
+    if let Some(time_reference) = time_reference {
+        insert_bext_chunk(path, time_reference).map_err(|e| super::Error::UnexpectedError {
+            message: format!("Failed to write bext chunk: {}", path.display()),
+            source: Some(Box::new(e)),
+        })?;
+    }
+
     Ok(())
 }
 
-pub fn resample(input: PcmAudioData, to_sample_rate: usize) -> Result<PcmAudioData> {
-    if input.nb_channels != 2 {
-        whatever!("resampling is currently implemented for stereo audio only.")
+/// houndは任意のチャンクを書けないため、書き出したWAVの `data` チャンクの前に `bext` チャンクを挿入する
+fn insert_bext_chunk(path: &Path, time_reference: TimeReference) -> std::io::Result<()> {
+    use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    let mut source = File::open(path)?;
+    let mut riff_header = [0u8; 12];
+    source.read_exact(&mut riff_header)?;
+    if &riff_header[0..4] != b"RIFF" || &riff_header[8..12] != b"WAVE" {
+        return Err(IoError::new(ErrorKind::InvalidData, "not a RIFF/WAVE file"));
+    }
+
+    // dataチャンクの直前までのチャンク（fmtなど）をそのまま引き継ぐ
+    let mut chunks_before_data = Vec::new();
+    loop {
+        let mut chunk_header = [0u8; 8];
+        source.read_exact(&mut chunk_header)?;
+        if &chunk_header[0..4] == b"data" {
+            source.seek(SeekFrom::Current(-8))?;
+            break;
+        }
+        let size = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as usize;
+        let mut body = vec![0u8; size + size % 2];
+        source.read_exact(&mut body)?;
+        chunks_before_data.extend_from_slice(&chunk_header);
+        chunks_before_data.extend_from_slice(&body);
     }
 
-    let samples = input.as_interleaved();
-    let mut signal = dasp::signal::from_interleaved_samples_iter::<_, [f32; 2]>(samples);
+    let mut bext = vec![0u8; BEXT_CHUNK_SIZE];
+    let originator = b"Tune Prism";
+    bext[256..256 + originator.len()].copy_from_slice(originator);
+    // Description(256) + Originator(32) + OriginatorReference(32) + 日付(10) + 時刻(8) の後ろがTimeReference
+    bext[338..346].copy_from_slice(&time_reference.sample_offset.to_le_bytes());
+    bext[346..348].copy_from_slice(&1u16.to_le_bytes());
 
-    let linear = dasp::interpolate::linear::Linear::new(signal.next(), signal.next());
-    let new_signal = signal.from_hz_to_hz(linear, input.sample_rate as f64, to_sample_rate as f64);
+    let riff_size = u32::from_le_bytes(riff_header[4..8].try_into().unwrap())
+        .checked_add(8 + BEXT_CHUNK_SIZE as u32)
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "WAV file is too large for bext"))?;
+    riff_header[4..8].copy_from_slice(&riff_size.to_le_bytes());
 
-    let mut pcm_buffer = vec![vec![]; input.nb_channels];
+    // 一時ファイルに書いてから置き換え、途中で失敗しても元のWAVを壊さない
+    let temp_path = path.with_extension("wav.bext.tmp");
+    let result = (|| {
+        let mut output = std::io::BufWriter::new(File::create(&temp_path)?);
+        output.write_all(&riff_header)?;
+        output.write_all(&chunks_before_data)?;
+        output.write_all(b"bext")?;
+        output.write_all(&(BEXT_CHUNK_SIZE as u32).to_le_bytes())?;
+        output.write_all(&bext)?;
+        std::io::copy(&mut source, &mut output)?;
+        output.flush()
+    })();
 
-    for frame in new_signal.until_exhausted() {
-        pcm_buffer[0].push(frame[0]);
-        pcm_buffer[1].push(frame[1]);
+    match result {
+        Ok(()) => std::fs::rename(&temp_path, path),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(e)
+        }
     }
-    let length = pcm_buffer[0].len();
+}
+
+/// stemの書き出しフォーマット
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Wav,
+    Flac,
+    Mp3,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+            Self::Mp3 => "mp3",
+        }
+    }
+
+    /// 拡張子からフォーマットを判定（大文字小文字は区別しない）
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "wav" => Some(Self::Wav),
+            "flac" => Some(Self::Flac),
+            "mp3" => Some(Self::Mp3),
+            _ => None,
+        }
+    }
+}
+
+/// 指定したフォーマットでエンコードして書き出す
+pub fn encode_pcm(audio: PcmAudioData, path: &Path, format: ExportFormat) -> Result<()> {
+    match format {
+        ExportFormat::Wav => encode_pcm_to_wav(audio, path, WavBitDepth::default(), None),
+        ExportFormat::Flac => encode_pcm_to_flac(audio, path),
+        ExportFormat::Mp3 => encode_pcm_to_mp3(audio, path),
+    }
+}
+
+/// f32のサンプルを16bit整数に量子化（フルスケールでクランプ）
+fn quantize_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)).round() as i16
+}
+
+/// 24bit FLACとして書き出す
+pub fn encode_pcm_to_flac(audio: PcmAudioData, path: &Path) -> Result<()> {
+    use flacenc::component::BitRepr;
+    use flacenc::error::Verify;
+
+    const BITS_PER_SAMPLE: usize = 24;
+    const SCALE: f32 = 8_388_607.0;
+
+    let interleaved: Vec<i32> = audio
+        .as_interleaved()
+        .into_iter()
+        .map(|sample| (sample.clamp(-1.0, 1.0) * SCALE).round() as i32)
+        .collect();
+
+    let config = match flacenc::config::Encoder::default().into_verified() {
+        Ok(config) => config,
+        Err((_, e)) => {
+            whatever!("invalid FLAC encoder config: {:?}", e);
+        }
+    };
+    let source = flacenc::source::MemSource::from_samples(
+        &interleaved,
+        audio.nb_channels,
+        BITS_PER_SAMPLE,
+        audio.sample_rate,
+    );
+    let stream = match flacenc::encode_with_fixed_block_size(&config, source, config.block_size) {
+        Ok(stream) => stream,
+        Err(e) => {
+            whatever!("failed to encode FLAC: {:?}", e);
+        }
+    };
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    if let Err(e) = stream.write(&mut sink) {
+        whatever!("failed to write FLAC stream: {:?}", e);
+    }
+
+    whatever!(
+        std::fs::write(path, sink.as_slice()),
+        "failed to write FLAC file {}",
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// MP3（320kbps CBR）として書き出す
+pub fn encode_pcm_to_mp3(audio: PcmAudioData, path: &Path) -> Result<()> {
+    use mp3lame_encoder::{Bitrate, Builder, DualPcm, FlushNoGap, MonoPcm, Quality};
+
+    if audio.nb_channels == 0 || audio.nb_channels > 2 {
+        whatever!("MP3 encoding supports mono or stereo audio only, got {} channels", audio.nb_channels);
+    }
+
+    let mut builder = match Builder::new() {
+        Some(builder) => builder,
+        None => {
+            whatever!("failed to create LAME encoder");
+        }
+    };
+    if let Err(e) = builder.set_num_channels(audio.nb_channels as u8) {
+        whatever!("failed to set MP3 channel count: {:?}", e);
+    }
+    if let Err(e) = builder.set_sample_rate(audio.sample_rate as u32) {
+        whatever!("unsupported MP3 sample rate {}: {:?}", audio.sample_rate, e);
+    }
+    if let Err(e) = builder.set_brate(Bitrate::Kbps320) {
+        whatever!("failed to set MP3 bitrate: {:?}", e);
+    }
+    if let Err(e) = builder.set_quality(Quality::Best) {
+        whatever!("failed to set MP3 quality: {:?}", e);
+    }
+    let mut encoder = match builder.build() {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            whatever!("failed to initialize LAME encoder: {:?}", e);
+        }
+    };
+
+    let left: Vec<i16> = audio.samples[0].iter().copied().map(quantize_i16).collect();
+    let right: Vec<i16> = if audio.nb_channels == 2 {
+        audio.samples[1].iter().copied().map(quantize_i16).collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut output: Vec<u8> = Vec::new();
+    output.reserve(mp3lame_encoder::max_required_buffer_size(left.len()));
+
+    let encoded = if audio.nb_channels == 2 {
+        encoder.encode(
+            DualPcm {
+                left: &left,
+                right: &right,
+            },
+            output.spare_capacity_mut(),
+        )
+    } else {
+        encoder.encode(MonoPcm(&left), output.spare_capacity_mut())
+    };
+    let encoded_size = match encoded {
+        Ok(size) => size,
+        Err(e) => {
+            whatever!("failed to encode MP3: {:?}", e);
+        }
+    };
+    // SAFETY: LAMEが書き込んだバイト数だけ長さを伸ばす
+    unsafe {
+        output.set_len(output.len() + encoded_size);
+    }
+
+    let flushed_size = match encoder.flush::<FlushNoGap>(output.spare_capacity_mut()) {
+        Ok(size) => size,
+        Err(e) => {
+            whatever!("failed to flush MP3 encoder: {:?}", e);
+        }
+    };
+    // SAFETY: LAMEが書き込んだバイト数だけ長さを伸ばす
+    unsafe {
+        output.set_len(output.len() + flushed_size);
+    }
+
+    whatever!(
+        std::fs::write(path, &output),
+        "failed to write MP3 file {}",
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// リサンプリングの品質（処理時間はフィルタ長にほぼ比例する）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResampleQuality {
+    /// 短いHann窓のフィルタ。Bestの約4倍速いが、高域の減衰とエイリアシングがわずかに増える（プレビュー向け）
+    Fast,
+    /// Bestの約2倍速く、通常の用途では聴き分けにくい
+    Balanced,
+    /// 長いBlackman窓のフィルタ（従来の品質）
+    #[default]
+    Best,
+}
+
+impl ResampleQuality {
+    /// 窓関数付きsinc補間で片側に使うゼロ交差の数（大きいほど高品質だが遅い）
+    fn zero_crossings(self) -> usize {
+        match self {
+            Self::Fast => 8,
+            Self::Balanced => 16,
+            Self::Best => 32,
+        }
+    }
+
+    /// 窓関数（xは-1..1の範囲）
+    fn window(self, x: f64) -> f64 {
+        match self {
+            // フィルタが短い場合は、メインローブの狭いHann窓で通過帯域を広く保つ
+            Self::Fast => hann(x),
+            Self::Balanced | Self::Best => blackman(x),
+        }
+    }
+}
+
+/// 窓関数付きsinc補間でサンプルレートを変換する
+///
+/// ダウンサンプリング時はカットオフを変換先のナイキスト周波数まで下げるため、
+/// 96kHz/192kHzの音源でもエイリアシングが出ない
+pub fn resample(
+    input: PcmAudioData,
+    to_sample_rate: usize,
+    quality: ResampleQuality,
+) -> Result<PcmAudioData> {
+    let from_sample_rate = input.sample_rate;
+    if from_sample_rate == 0 || to_sample_rate == 0 {
+        return ResampleSnafu {
+            from: from_sample_rate,
+            to: to_sample_rate,
+            reason: "sample rate must be greater than 0",
+        }
+        .fail();
+    }
+    if input.samples.len() != input.nb_channels
+        || input.samples.iter().any(|channel| channel.len() < input.length)
+    {
+        return ResampleSnafu {
+            from: from_sample_rate,
+            to: to_sample_rate,
+            reason: "channel buffers do not match the declared layout",
+        }
+        .fail();
+    }
+
+    if from_sample_rate == to_sample_rate {
+        return Ok(input);
+    }
+
+    let length = (input.length as u128 * to_sample_rate as u128)
+        .div_ceil(from_sample_rate as u128) as usize;
+
+    // チャンネルごとに独立しているので並列に処理する
+    let samples = std::thread::scope(|scope| {
+        let handles: Vec<_> = input
+            .samples
+            .iter()
+            .map(|channel| {
+                let channel = &channel[..input.length];
+                scope.spawn(move || {
+                    resample_channel(channel, from_sample_rate, to_sample_rate, length, quality)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join())
+            .collect::<std::result::Result<Vec<_>, _>>()
+    });
+    let samples = match samples {
+        Ok(samples) => samples,
+        Err(_) => {
+            return ResampleSnafu {
+                from: from_sample_rate,
+                to: to_sample_rate,
+                reason: "resampling thread panicked",
+            }
+            .fail();
+        }
+    };
 
     Ok(PcmAudioData {
-        samples: pcm_buffer,
+        samples,
         sample_rate: to_sample_rate,
-        nb_channels: 2,
+        nb_channels: input.nb_channels,
         length,
     })
 }
+
+fn resample_channel(
+    input: &[f32],
+    from_sample_rate: usize,
+    to_sample_rate: usize,
+    length: usize,
+    quality: ResampleQuality,
+) -> Vec<f32> {
+    let step = from_sample_rate as f64 / to_sample_rate as f64;
+    // ダウンサンプリング時は変換先のナイキスト周波数をカットオフにする
+    let cutoff = (to_sample_rate as f64 / from_sample_rate as f64).min(1.0);
+    let half_width = quality.zero_crossings() as f64 / cutoff;
+    let taps = half_width.ceil() as isize;
+
+    (0..length)
+        .map(|i| {
+            let position = i as f64 * step;
+            let center = position.floor() as isize;
+
+            let mut sum = 0.0;
+            for j in (center - taps + 1)..=(center + taps) {
+                if j < 0 || j as usize >= input.len() {
+                    continue;
+                }
+                let distance = position - j as f64;
+                if distance.abs() >= half_width {
+                    continue;
+                }
+                let weight =
+                    cutoff * sinc(cutoff * distance) * quality.window(distance / half_width);
+                sum += input[j as usize] as f64 * weight;
+            }
+
+            sum as f32
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        let px = std::f64::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+/// Hann窓（xは-1..1の範囲）
+fn hann(x: f64) -> f64 {
+    0.5 + 0.5 * (std::f64::consts::PI * x).cos()
+}
+
+/// Blackman窓（xは-1..1の範囲）
+fn blackman(x: f64) -> f64 {
+    let phase = std::f64::consts::PI * (x + 1.0);
+    0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: usize, length: usize, nb_channels: usize) -> PcmAudioData {
+        let channel: Vec<f32> = (0..length)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                0.5 * (2.0 * std::f32::consts::PI * frequency * t).sin()
+            })
+            .collect();
+
+        PcmAudioData {
+            samples: vec![channel; nb_channels],
+            sample_rate,
+            nb_channels,
+            length,
+        }
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn resample_96k_to_model_rate_keeps_duration() {
+        for quality in [
+            ResampleQuality::Fast,
+            ResampleQuality::Balanced,
+            ResampleQuality::Best,
+        ] {
+            // 2秒と端数のサンプル（96kHz -> 44.1kHzで割り切れない長さ）
+            let input = sine(1000.0, 96000, 192_007, 2);
+            let output = resample(input, 44100, quality).unwrap();
+
+            let expected = (192_007u64 * 44100).div_ceil(96000) as usize;
+            assert_eq!(output.sample_rate, 44100);
+            assert_eq!(output.nb_channels, 2);
+            assert_eq!(output.length, expected);
+            assert!(output
+                .samples
+                .iter()
+                .all(|channel| channel.len() == expected));
+
+            // フィルタの立ち上がりを除いた部分で、通過帯域のトーンの音量が保たれている
+            let middle = &output.samples[0][4410..output.length - 4410];
+            let level = rms(middle);
+            assert!(
+                (level - 0.5 / 2f32.sqrt()).abs() < 0.01,
+                "{:?}: RMS after resampling was {}",
+                quality,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn resample_rejects_a_zero_sample_rate() {
+        let input = sine(1000.0, 96000, 9600, 1);
+        assert!(matches!(
+            resample(input, 0, ResampleQuality::default()),
+            Err(crate::demucs::Error::ResampleError { .. })
+        ));
+    }
+
+    #[test]
+    fn streamed_decode_concatenates_to_the_full_decode() {
+        let path =
+            std::env::temp_dir().join(format!("stemsplit-stream-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        // ブロック長で割り切れない長さにして、最後の短いブロックも確かめる
+        let input = sine(440.0, 44100, 10_007, 2);
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in input.as_interleaved() {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let full = decode_file(&path).unwrap();
+        let mut streamed = vec![Vec::new(); 2];
+        let mut block_lengths = Vec::new();
+        let format = decode_file_frames(&path, 1000, |block| {
+            block_lengths.push(block[0].len());
+            for (channel, samples) in streamed.iter_mut().zip(block) {
+                channel.extend_from_slice(samples);
+            }
+        });
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(format.unwrap(), (44100, 2));
+        assert_eq!(full.length, 10_007);
+        assert_eq!(streamed, full.samples);
+        assert_eq!(block_lengths.len(), 11);
+        assert!(block_lengths[..10].iter().all(|&length| length == 1000));
+        assert_eq!(block_lengths[10], 7);
+    }
+}
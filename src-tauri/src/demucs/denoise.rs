@@ -0,0 +1,204 @@
+use rustfft::{num_complex::Complex, FftPlanner};
+
+use super::analysis::hann_window;
+
+/// STFTのフレームサイズ
+const FRAME_SIZE: usize = 2048;
+/// STFTのホップサイズ（75%オーバーラップ）
+const HOP_SIZE: usize = FRAME_SIZE / 4;
+/// ノイズフロアの推定に使う、エネルギーの小さいフレームの割合
+const NOISE_FRAME_RATIO: f32 = 0.1;
+/// ノイズフロアの何倍を超えたビンを信号とみなすか
+const GATE_THRESHOLD: f32 = 2.0;
+/// ゲートが閉じたビンの減衰量（約-20dB）
+const GATE_ATTENUATION: f32 = 0.1;
+/// ゲートが閉じるときのフレームごとの減衰率（急に閉じるとミュージカルノイズが出るため）
+const GATE_RELEASE: f32 = 0.7;
+
+/// FFTベースのスペクトラルゲートによるノイズ除去
+///
+/// 静かなフレームから周波数ビンごとのノイズフロアを推定し、それを下回るビンだけを減衰させる。
+/// 移動平均によるノイズ除去と違い、ノイズフロアより大きい成分（音色の高域など）はそのまま残る
+pub fn apply_spectral_gate(samples: &mut [f32]) {
+    if samples.len() < FRAME_SIZE {
+        return;
+    }
+
+    // 先頭・末尾もすべてのサンプルが同じ数のフレームに含まれるようにゼロパディングする
+    let pad_front = FRAME_SIZE - HOP_SIZE;
+    let mut padded = vec![0.0; pad_front + samples.len() + FRAME_SIZE];
+    padded[pad_front..pad_front + samples.len()].copy_from_slice(samples);
+    let nb_frames = (padded.len() - FRAME_SIZE) / HOP_SIZE + 1;
+    let nb_bins = FRAME_SIZE / 2 + 1;
+
+    let window = hann_window(FRAME_SIZE);
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(FRAME_SIZE);
+    let ifft = planner.plan_fft_inverse(FRAME_SIZE);
+    let mut buffer = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+
+    // エネルギーの小さいフレームからノイズフロアを推定（デジタル無音のフレームは除外）
+    let mut frame_energies: Vec<(usize, f32)> = (0..nb_frames)
+        .map(|frame| {
+            let start = frame * HOP_SIZE;
+            let energy = padded[start..start + FRAME_SIZE].iter().map(|s| s * s).sum();
+            (frame, energy)
+        })
+        .filter(|(_, energy)| *energy > 0.0)
+        .collect();
+    if frame_energies.is_empty() {
+        return;
+    }
+    frame_energies.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let nb_noise_frames =
+        ((frame_energies.len() as f32 * NOISE_FRAME_RATIO).ceil() as usize).max(1);
+
+    let mut noise_floor = vec![0.0; nb_bins];
+    for &(frame, _) in &frame_energies[..nb_noise_frames] {
+        load_frame(&padded, frame, &window, &mut buffer);
+        fft.process(&mut buffer);
+        for (floor, bin) in noise_floor.iter_mut().zip(&buffer[..nb_bins]) {
+            *floor += bin.norm();
+        }
+    }
+    for floor in noise_floor.iter_mut() {
+        *floor /= nb_noise_frames as f32;
+    }
+
+    // ゲートを適用してオーバーラップ加算で再合成
+    let mut output = vec![0.0; padded.len()];
+    let mut window_sum = vec![0.0; padded.len()];
+    let mut gains = vec![1.0; nb_bins];
+    let scale = 1.0 / FRAME_SIZE as f32;
+
+    for frame in 0..nb_frames {
+        load_frame(&padded, frame, &window, &mut buffer);
+        fft.process(&mut buffer);
+
+        for k in 0..nb_bins {
+            let target = if buffer[k].norm() > noise_floor[k] * GATE_THRESHOLD {
+                1.0
+            } else {
+                GATE_ATTENUATION
+            };
+            // 開くときは即座に、閉じるときはゆっくり
+            gains[k] = if target >= gains[k] {
+                target
+            } else {
+                (gains[k] * GATE_RELEASE).max(target)
+            };
+
+            buffer[k] *= gains[k];
+            // 実信号なので負の周波数側は共役で対称にする
+            if k > 0 && k < FRAME_SIZE - k {
+                buffer[FRAME_SIZE - k] = buffer[k].conj();
+            }
+        }
+
+        ifft.process(&mut buffer);
+
+        let start = frame * HOP_SIZE;
+        for (i, (bin, w)) in buffer.iter().zip(&window).enumerate() {
+            output[start + i] += bin.re * scale * w;
+            window_sum[start + i] += w * w;
+        }
+    }
+
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let norm = window_sum[pad_front + i];
+        if norm > 1e-6 {
+            *sample = output[pad_front + i] / norm;
+        }
+    }
+}
+
+fn load_frame(padded: &[f32], frame: usize, window: &[f32], buffer: &mut [Complex<f32>]) {
+    let start = frame * HOP_SIZE;
+    for ((slot, sample), w) in buffer
+        .iter_mut()
+        .zip(&padded[start..start + FRAME_SIZE])
+        .zip(window)
+    {
+        *slot = Complex::new(sample * w, 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 44100;
+
+    /// 再現性のあるホワイトノイズ（xorshift32、-amplitude..amplitude）
+    fn white_noise(length: usize, amplitude: f32) -> Vec<f32> {
+        let mut state = 0x1234_5678u32;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                amplitude * (state as f32 / u32::MAX as f32 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// `range` の区間に含まれる `frequency` の成分の振幅
+    fn tone_amplitude(samples: &[f32], frequency: f32, range: std::ops::Range<usize>) -> f32 {
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, &sample) in range.clone().zip(&samples[range.clone()]) {
+            let phase =
+                2.0 * std::f64::consts::PI * frequency as f64 * i as f64 / SAMPLE_RATE as f64;
+            re += sample as f64 * phase.cos();
+            im += sample as f64 * phase.sin();
+        }
+        (2.0 * re.hypot(im) / range.len() as f64) as f32
+    }
+
+    #[test]
+    fn spectral_gate_reduces_noise_and_keeps_a_tone() {
+        // 前半はノイズだけ（ノイズフロアの推定に使われる）、後半はノイズに1kHzのトーンを重ねる
+        let length = SAMPLE_RATE * 2;
+        let half = length / 2;
+        let mut samples = white_noise(length, 0.05);
+        for (i, sample) in samples.iter_mut().enumerate().skip(half) {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            *sample += 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+        }
+        let original = samples.clone();
+
+        apply_spectral_gate(&mut samples);
+
+        // フレームの境界をまたぐ部分は除いて比べる
+        let margin = SAMPLE_RATE / 10;
+        let noise_before = rms(&original[margin..half - margin]);
+        let noise_after = rms(&samples[margin..half - margin]);
+        assert!(
+            noise_after < noise_before * 0.6,
+            "noise RMS went from {} to {}",
+            noise_before,
+            noise_after
+        );
+
+        let tone = half + margin..length - margin;
+        let tone_before = tone_amplitude(&original, 1000.0, tone.clone());
+        let tone_after = tone_amplitude(&samples, 1000.0, tone);
+        assert!(
+            (tone_after / tone_before - 1.0).abs() < 0.02,
+            "tone amplitude went from {} to {}",
+            tone_before,
+            tone_after
+        );
+    }
+
+    #[test]
+    fn spectral_gate_leaves_short_input_untouched() {
+        let mut samples = white_noise(FRAME_SIZE - 1, 0.05);
+        let original = samples.clone();
+        apply_spectral_gate(&mut samples);
+        assert_eq!(samples, original);
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::util::get_app_directory;
+
+use super::error::{Error, Result};
+use super::model::ModelInfo;
+
+/// ダウンロードを試行する回数（途中まで受信したデータは `.part` に残し、続きから再開する）
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+/// 再試行までの待ち時間（試行回数に比例して延ばす）
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// 進行状況を通知する間隔（バイト）
+const PROGRESS_INTERVAL_BYTES: u64 = 1024 * 1024;
+
+/// モデルのダウンロードの進行状況
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadProgress {
+    pub model_name: String,
+    /// 受信済みのバイト数（再開した場合は前回までの分を含む）
+    pub downloaded_bytes: u64,
+    /// ファイル全体のサイズ（サーバーが返さない場合はNone）
+    pub total_bytes: Option<u64>,
+}
+
+/// ダウンロードしたモデルの保存先（アプリのリソースは書き込めない場合があるため、アプリのディレクトリに置く）
+pub fn downloaded_model_path(model_name: &str) -> PathBuf {
+    get_app_directory()
+        .join("models")
+        .join(format!("{}.pt", model_name))
+}
+
+/// models.jsonの `url` からモデルの重みをダウンロードし、保存したパスを返す
+///
+/// 通信が途切れた場合はHTTPのRangeで続きから再開し、`MAX_DOWNLOAD_ATTEMPTS` 回まで再試行する。
+/// models.jsonに `sha256` がある場合は、チェックサムが一致したファイルだけを保存する
+pub async fn download_model(
+    info: &ModelInfo,
+    on_progress: impl Fn(DownloadProgress) + Send + Sync,
+) -> Result<PathBuf> {
+    let fail = |reason: String| Error::ModelDownloadError {
+        name: info.name.clone(),
+        reason,
+    };
+    let url = info
+        .url
+        .as_deref()
+        .ok_or_else(|| fail(String::from("models.json has no \"url\" for this model")))?;
+
+    let dest = downloaded_model_path(&info.name);
+    if dest.exists() && verify_checksum(&dest, info.sha256.as_deref()).is_ok() {
+        eprintln!(
+            "[download_model] {} is already downloaded: {:?}",
+            info.name, dest
+        );
+        return Ok(dest);
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| fail(format!("failed to create {}: {}", parent.display(), e)))?;
+    }
+
+    let part_path = dest.with_extension("pt.part");
+    let client = reqwest::Client::new();
+    let mut last_error = String::new();
+
+    for attempt in 1..=MAX_DOWNLOAD_ATTEMPTS {
+        let result = fetch_to_part(&client, url, &part_path, &info.name, &on_progress)
+            .await
+            .and_then(|()| {
+                verify_checksum(&part_path, info.sha256.as_deref()).map_err(|reason| {
+                    // 壊れたデータから再開しないよう、次の試行は最初からやり直す
+                    let _ = fs::remove_file(&part_path);
+                    reason
+                })
+            });
+
+        match result {
+            Ok(()) => {
+                fs::rename(&part_path, &dest)
+                    .map_err(|e| fail(format!("failed to save {}: {}", dest.display(), e)))?;
+                eprintln!("[download_model] Downloaded {} to {:?}", info.name, dest);
+                return Ok(dest);
+            }
+            Err(reason) => {
+                eprintln!(
+                    "[download_model] Attempt {}/{} for {} failed: {}",
+                    attempt, MAX_DOWNLOAD_ATTEMPTS, info.name, reason
+                );
+                last_error = reason;
+            }
+        }
+
+        if attempt < MAX_DOWNLOAD_ATTEMPTS {
+            tokio::time::sleep(RETRY_DELAY * attempt).await;
+        }
+    }
+
+    Err(fail(last_error))
+}
+
+/// `part_path` に受信済みのデータがあれば続きから、なければ最初から受信する
+async fn fetch_to_part(
+    client: &reqwest::Client,
+    url: &str,
+    part_path: &Path,
+    model_name: &str,
+    on_progress: &(impl Fn(DownloadProgress) + Send + Sync),
+) -> std::result::Result<(), String> {
+    let resume_from = fs::metadata(part_path).map_or(0, |metadata| metadata.len());
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        eprintln!(
+            "[download_model] Resuming {} from {} bytes",
+            model_name, resume_from
+        );
+        request = request.header(RANGE, format!("bytes={}-", resume_from));
+    }
+
+    let mut response = request.send().await.map_err(|e| e.to_string())?;
+    let open_error = |e: std::io::Error| format!("failed to open {}: {}", part_path.display(), e);
+    let (mut file, mut downloaded) = match response.status() {
+        StatusCode::PARTIAL_CONTENT => (
+            OpenOptions::new()
+                .append(true)
+                .open(part_path)
+                .map_err(open_error)?,
+            resume_from,
+        ),
+        // 受信済みのデータで全体が揃っている（チェックサムで確認する）
+        StatusCode::RANGE_NOT_SATISFIABLE if resume_from > 0 => return Ok(()),
+        // サーバーがRangeに対応していない場合は最初から受信し直す
+        status if status.is_success() => (File::create(part_path).map_err(open_error)?, 0),
+        status => return Err(format!("server returned {}", status)),
+    };
+
+    let total_bytes = response.content_length().map(|length| length + downloaded);
+    let report = |downloaded_bytes: u64| {
+        on_progress(DownloadProgress {
+            model_name: model_name.to_string(),
+            downloaded_bytes,
+            total_bytes,
+        })
+    };
+    report(downloaded);
+
+    let mut last_reported = downloaded;
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        file.write_all(&chunk)
+            .map_err(|e| format!("failed to write {}: {}", part_path.display(), e))?;
+        downloaded += chunk.len() as u64;
+        if downloaded - last_reported >= PROGRESS_INTERVAL_BYTES {
+            report(downloaded);
+            last_reported = downloaded;
+        }
+    }
+    file.flush()
+        .map_err(|e| format!("failed to write {}: {}", part_path.display(), e))?;
+    report(downloaded);
+
+    match total_bytes {
+        Some(total) if downloaded < total => Err(format!(
+            "connection closed after {} of {} bytes",
+            downloaded, total
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// ファイルのSHA-256がmodels.jsonの値と一致するか確認する（`expected` がない場合は確認しない）
+pub(crate) fn verify_checksum(path: &Path, expected: Option<&str>) -> std::result::Result<(), String> {
+    let Some(expected) = expected else {
+        eprintln!(
+            "[verify_checksum] No sha256 in models.json, skipping checksum for {:?}",
+            path
+        );
+        return Ok(());
+    };
+
+    let mut file =
+        File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let actual: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(format!(
+            "checksum mismatch (expected {}, got {})",
+            expected, actual
+        ));
+    }
+
+    Ok(())
+}
@@ -13,6 +13,53 @@ pub enum Error {
     #[snafu(display("Model not found. Check if it is available in the right place."))]
     ModelNotFoundError { name: String },
 
+    #[snafu(display("Invalid model entry \"{name}\" in models.json: {reason}"))]
+    InvalidModelConfigError { name: String, reason: String },
+
+    #[snafu(display(
+        "Model file for \"{name}\" at {} is corrupt: {reason}. Download the model again.",
+        path.display()
+    ))]
+    ModelCorruptError {
+        name: String,
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[snafu(display("Failed to download model \"{name}\": {reason}"))]
+    ModelDownloadError { name: String, reason: String },
+
+    #[snafu(display("Source \"{name}\" is not provided by the model (available: {available:?})"))]
+    UnknownSourceError { name: String, available: Vec<String> },
+
+    #[snafu(display("Invalid stem file name \"{name}\": {reason}"))]
+    InvalidStemNameError { name: String, reason: String },
+
+    #[snafu(display("Unable to open audio file {}: {source}", path.display()))]
+    FileOpenError {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unsupported or corrupt audio file {}: {reason}", path.display()))]
+    UnsupportedAudioError {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[snafu(display("Output directory {} is not writable: {source}", path.display()))]
+    OutputNotWritableError {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to resample from {from}Hz to {to}Hz: {reason}"))]
+    ResampleError {
+        from: usize,
+        to: usize,
+        reason: String,
+    },
+
     #[snafu(display("Symphonia Error: {source:?}"))]
     SymphoniaError {
         source: symphonia::core::errors::Error,
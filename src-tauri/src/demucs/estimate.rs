@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tch::Device;
+
+use super::device_name;
+
+/// 実測値を平均に反映する重み（大きいほど直近の分離を重視する）
+const SMOOTHING: f64 = 0.3;
+
+/// 実測値がないデバイスで使う、ステレオ1秒・シフト1回あたりの推論時間（秒）
+fn calibration_factor(device: Device) -> f64 {
+    match device {
+        Device::Cuda(_) => 0.05,
+        Device::Mps => 0.2,
+        _ => 0.8,
+    }
+}
+
+/// デバイスごとの推論時間の係数（実測の指数移動平均）。アプリの起動中だけ保持する
+static MEASURED_FACTORS: Lazy<Mutex<HashMap<String, f64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 推論にかかる時間の見積もり
+#[derive(Debug, Clone, Serialize)]
+pub struct SplitEstimate {
+    /// 推論するデバイス（"cpu" / "cuda" / "mps"）
+    pub device: String,
+    /// 分離する音声の長さ（秒）
+    pub audio_secs: f64,
+    /// 推論にかかる時間の見積もり（秒）
+    pub estimated_secs: f64,
+    /// このデバイスでの実測値から見積もったか（falseなら初期値の係数による）
+    pub calibrated: bool,
+}
+
+/// 推論の手間（ステレオ1秒・シフト1回を1とした量）
+fn workload(audio_secs: f64, nb_channels: usize, shifts: usize) -> f64 {
+    audio_secs * (nb_channels as f64 / 2.0) * shifts.max(1) as f64
+}
+
+/// トラックの長さ・チャンネル数・デバイスから推論時間を見積もる
+pub fn estimate_split(
+    device: Device,
+    audio_secs: f64,
+    nb_channels: usize,
+    shifts: usize,
+) -> SplitEstimate {
+    let name = device_name(device);
+    let measured = MEASURED_FACTORS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&name)
+        .copied();
+
+    SplitEstimate {
+        estimated_secs: workload(audio_secs, nb_channels, shifts)
+            * measured.unwrap_or_else(|| calibration_factor(device)),
+        device: name,
+        audio_secs,
+        calibrated: measured.is_some(),
+    }
+}
+
+/// 実際にかかった推論時間を記録し、以降の見積もりに反映する
+pub fn record_split(
+    device: Device,
+    audio_secs: f64,
+    nb_channels: usize,
+    shifts: usize,
+    elapsed: Duration,
+) {
+    let workload = workload(audio_secs, nb_channels, shifts);
+    if workload <= 0.0 {
+        return;
+    }
+    let observed = elapsed.as_secs_f64() / workload;
+
+    let mut factors = MEASURED_FACTORS.lock().unwrap_or_else(|e| e.into_inner());
+    // 最初の実測値は初期値の係数より信頼できるため、そのまま使う
+    factors
+        .entry(device_name(device))
+        .and_modify(|factor| *factor += SMOOTHING * (observed - *factor))
+        .or_insert(observed);
+}
@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::path::Path;
+
+use id3::TagLike;
+use serde::Serialize;
+use snafu::{whatever, ResultExt};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+
+use super::error::{FileOpenSnafu, Id3Snafu};
+use super::Result;
+
+/// 音声ファイルに埋め込まれたタグ情報（タグがない項目はNone）
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    pub track_number: Option<u32>,
+    /// カバー画像が埋め込まれているか
+    pub has_cover: bool,
+}
+
+impl TrackMetadata {
+    fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.artist.is_none()
+            && self.album.is_none()
+            && self.year.is_none()
+            && self.genre.is_none()
+            && self.track_number.is_none()
+            && !self.has_cover
+    }
+}
+
+/// 音声ファイルのタグを読み込む（プロジェクトは作成しない）
+///
+/// ID3v2（MP3/WAV/AIFF）を優先し、なければFLAC/OGGのVorbisコメントなどsymphoniaが読めるタグを使う。
+/// タグがない・読めない場合はすべてNoneのメタデータを返す
+pub fn read_metadata(path: &Path) -> Result<TrackMetadata> {
+    // ファイル自体が開けない場合だけはエラーにする
+    File::open(path).context(FileOpenSnafu { path })?;
+
+    match id3::Tag::read_from_path(path) {
+        Ok(tag) => {
+            let metadata = metadata_from_id3(&tag);
+            if !metadata.is_empty() {
+                return Ok(metadata);
+            }
+        }
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => {}
+        Err(e) => eprintln!("[read_metadata] Failed to read ID3 tag from {:?}: {}", path, e),
+    }
+
+    Ok(read_container_metadata(path).unwrap_or_default())
+}
+
+/// DJソフト（Rekordbox/Serato）が読むBPM（TBPM）とキー（TKEY）をID3v2タグに書き込む
+///
+/// ID3v2を埋め込めるMP3/WAV/AIFFのみ対応し、タグの他のフレームはそのまま残す。
+/// TBPMはID3の仕様どおり整数に丸める
+pub fn write_dj_tags(path: &Path, bpm: Option<f64>, key: Option<&str>) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    let read = match extension.as_deref() {
+        Some("mp3") => id3::Tag::read_from_path(path),
+        Some("wav") => id3::Tag::read_from_wav_path(path),
+        Some("aiff" | "aif") => id3::Tag::read_from_aiff_path(path),
+        _ => whatever!(
+            "DJ tags can only be written to MP3, WAV or AIFF files, got {}",
+            path.display()
+        ),
+    };
+    let mut tag = match read {
+        Ok(tag) => tag,
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => id3::Tag::new(),
+        Err(e) => return Err(e).context(Id3Snafu),
+    };
+
+    if let Some(bpm) = bpm {
+        tag.set_text("TBPM", (bpm.round() as i64).to_string());
+    }
+    if let Some(key) = key {
+        tag.set_text("TKEY", key);
+    }
+
+    match extension.as_deref() {
+        Some("wav") => tag.write_to_wav_path(path, id3::Version::Id3v24),
+        Some("aiff" | "aif") => tag.write_to_aiff_path(path, id3::Version::Id3v24),
+        _ => tag.write_to_path(path, id3::Version::Id3v24),
+    }
+    .context(Id3Snafu)
+}
+
+fn metadata_from_id3(tag: &id3::Tag) -> TrackMetadata {
+    TrackMetadata {
+        title: non_empty(tag.title()),
+        artist: non_empty(tag.artist()),
+        album: non_empty(tag.album()),
+        year: tag
+            .year()
+            .or_else(|| tag.date_recorded().map(|date| date.year)),
+        genre: non_empty(tag.genre_parsed().as_deref()),
+        track_number: tag.track(),
+        has_cover: tag.pictures().next().is_some(),
+    }
+}
+
+/// symphoniaでコンテナのタグ（Vorbisコメントなど）を読む
+fn read_container_metadata(path: &Path) -> Option<TrackMetadata> {
+    let src = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension() {
+        hint.with_extension(&ext.to_string_lossy());
+    }
+
+    let mut probed = match symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(e) => {
+            eprintln!("[read_metadata] Failed to probe {:?}: {}", path, e);
+            return None;
+        }
+    };
+
+    // コンテナ内のタグを優先し、なければコンテナの前に置かれたタグを使う
+    if let Some(revision) = probed.format.metadata().current() {
+        return Some(metadata_from_revision(revision));
+    }
+    let probed_metadata = probed.metadata.get()?;
+    probed_metadata.current().map(metadata_from_revision)
+}
+
+fn metadata_from_revision(revision: &MetadataRevision) -> TrackMetadata {
+    let mut metadata = TrackMetadata {
+        has_cover: !revision.visuals().is_empty(),
+        ..Default::default()
+    };
+
+    for tag in revision.tags() {
+        let value = tag.value.to_string();
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => metadata.title = non_empty(Some(&value)),
+            Some(StandardTagKey::Artist) => metadata.artist = non_empty(Some(&value)),
+            Some(StandardTagKey::Album) => metadata.album = non_empty(Some(&value)),
+            Some(StandardTagKey::Genre) => metadata.genre = non_empty(Some(&value)),
+            // "2021-05-01" のような日付から年だけを取り出す
+            Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate) => {
+                if metadata.year.is_none() {
+                    metadata.year = value.get(..4).and_then(|year| year.parse().ok());
+                }
+            }
+            // "3/12" のような「トラック番号/総数」にも対応する
+            Some(StandardTagKey::TrackNumber) => {
+                metadata.track_number = value
+                    .split('/')
+                    .next()
+                    .and_then(|number| number.trim().parse().ok());
+            }
+            _ => {}
+        }
+    }
+
+    metadata
+}
+
+fn non_empty(value: Option<&str>) -> Option<String> {
+    value
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(ToString::to_string)
+}
@@ -1,216 +1,2192 @@
 use std::{
-    fs::File,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
     io::Write,
     path::{Path, PathBuf},
+    sync::RwLock,
+    time::Instant,
 };
 
 pub mod analysis;
 pub mod audio;
+mod denoise;
+pub mod download;
 pub mod error;
+mod estimate;
+pub mod metadata;
 pub mod model;
+mod opus_codec;
+mod stretch;
 
-use mime::{Mime, IMAGE, JPEG};
+use id3::TagLike;
+use mime::{Mime, IMAGE, JPEG, PNG};
 use ndarray::{Array2, ArrayD};
+use serde::{Deserialize, Serialize};
 
 use snafu::{whatever, ResultExt};
 use tch::{Device, IndexOp, Kind, Tensor};
+use tracing::{debug, info, warn};
 
 use crate::demucs::{
-    audio::{decode_file, encode_pcm_to_wav, resample, PcmAudioData},
+    audio::{
+        decode_file, encode_pcm, encode_pcm_to_wav, resample, ExportConfig, ExportFormat,
+        PcmAudioData, ResampleQuality, TimeReference, WavBitDepth,
+    },
     error::TorchSnafu,
 };
 
-pub use analysis::{detect_bpm, detect_key};
+pub use analysis::{
+    analyze_audio, analyze_folder, detect_bpm, detect_bpm_from_pcm, detect_clipping, detect_key,
+    detect_key_from_pcm, detect_key_with_profile, difference_rms, estimate_downbeat,
+    evaluate_separation, file_difference_rms, format_key, key_semitone_shift, measure_file_lufs,
+    measure_lufs, normalize_key_name, spectrogram, to_camelot, waveform_peaks, AnalyzeResult,
+    AudioAnalysis, BpmEstimate, ClippingReport, KeyEstimate, KeyNotation, KeyProfile,
+    SpectrogramData,
+};
+pub use download::{download_model, downloaded_model_path, DownloadProgress};
 pub use error::{Error, Result};
-pub use model::{find_model, models, Demucs, LazyModelLoader};
+pub use estimate::SplitEstimate;
+pub use model::{
+    find_model, find_model_by_sources, models, Demucs, LazyModelLoader, DEFAULT_OVERLAP,
+    DEFAULT_SHIFTS,
+};
+pub use stretch::{
+    pitch_shift, shift_stem_to_key, stretch_stem_to_bpm, time_stretch, PitchShiftReport,
+    StretchReport, MAX_STRETCH_RATIO, MIN_STRETCH_RATIO,
+};
+
+use self::denoise::apply_spectral_gate;
+use self::estimate::{estimate_split, record_split};
+use self::error::{Id3Snafu, MimeParseSnafu, OutputNotWritableSnafu};
+
+/// デバイスを強制指定するための環境変数（"cpu" / "cuda" / "mps"）
+pub const DEVICE_ENV_VAR: &str = "TUNE_PRISM_DEVICE";
+
+/// 使用するデバイスを決定する
+///
+/// 優先順位: 環境変数 `TUNE_PRISM_DEVICE` > 保存された設定 > 自動検出（MPS > CUDA > CPU）
+pub fn get_available_device(preference: Option<&str>) -> Device {
+    let env_override = std::env::var(DEVICE_ENV_VAR).ok();
+    let requested = env_override
+        .as_deref()
+        .map(|name| (name, "environment variable"))
+        .or_else(|| preference.map(|name| (name, "saved setting")));
+
+    if let Some((name, origin)) = requested {
+        match parse_device(name) {
+            Some(device) if is_device_available(device) => {
+                info!("[get_available_device] Using {:?} ({} = {:?})", device, origin, name);
+                return device;
+            }
+            Some(device) => {
+                warn!(
+                    "[get_available_device] {:?} requested by {} is not available, falling back to autodetect",
+                    device, origin
+                );
+            }
+            None => {
+                warn!(
+                    "[get_available_device] Unknown device {:?} requested by {}, falling back to autodetect",
+                    name, origin
+                );
+            }
+        }
+    }
+
+    let device = if tch::utils::has_mps() {
+        Device::Mps
+    } else if tch::utils::has_cuda() {
+        Device::Cuda(0)
+    } else {
+        Device::Cpu
+    };
+    info!("[get_available_device] Using {:?} (autodetected)", device);
+
+    device
+}
+
+/// "cpu" / "cuda" / "mps" をデバイスに変換
+pub fn parse_device(name: &str) -> Option<Device> {
+    match name.trim().to_lowercase().as_str() {
+        "cpu" => Some(Device::Cpu),
+        "cuda" => Some(Device::Cuda(0)),
+        "mps" => Some(Device::Mps),
+        _ => None,
+    }
+}
+
+/// CPU推論のスレッド数を強制指定するための環境変数
+pub const CPU_THREADS_ENV_VAR: &str = "TUNE_PRISM_CPU_THREADS";
+
+/// CPU推論のスレッド数の既定値
+///
+/// 同梱のlibtorchのOpenMPと、他のライブラリ（Homebrewのlibompなど）が読み込むOpenMPが
+/// 同じプロセスに共存すると、複数スレッドでの推論中にクラッシュすることがあるため1にしている
+pub const DEFAULT_CPU_THREADS: usize = 1;
+
+/// CPU推論のスレッド数を決めて、OpenMP/MKLの環境変数とlibtorchに設定する
+///
+/// OpenMPは初期化時に環境変数を読むため、libtorchを使う前（起動直後）に呼ぶ必要がある。
+/// 優先順位: 環境変数 `TUNE_PRISM_CPU_THREADS` > 保存された設定 > 既定値（1）。
+/// 論理コア数を超える値はコア数に切り詰める。戻り値は設定したスレッド数
+pub fn configure_cpu_threads(preference: Option<usize>) -> usize {
+    let env_override = std::env::var(CPU_THREADS_ENV_VAR)
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok());
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = env_override
+        .or(preference)
+        .unwrap_or(DEFAULT_CPU_THREADS)
+        .clamp(1, max_threads);
+
+    let value = threads.to_string();
+    std::env::set_var("OMP_NUM_THREADS", &value);
+    std::env::set_var("MKL_NUM_THREADS", &value);
+    std::env::set_var("NUMEXPR_NUM_THREADS", &value);
+    tch::set_num_threads(threads as i32);
+
+    info!(
+        "[configure_cpu_threads] Using {} CPU threads (env: {:?}, saved: {:?}, cores: {})",
+        threads, env_override, preference, max_threads
+    );
+    threads
+}
+
+/// MPSで一度に推論するトラックの長さの上限（秒）の既定値
+///
+/// MPSはユニファイドメモリを使うため、長いトラックを一度に推論するとメモリを使い切ってクラッシュする。
+/// これより長いトラックはチャンクに分けて推論し、結果はチャンクごとにCPUへ戻す
+const MPS_MAX_SEGMENT_SECS: f64 = 180.0;
+/// 設定できる上限の最小値（短すぎるとチャンクの境界が増えて品質が落ちる）
+pub const MIN_MAX_SEGMENT_SECS: f64 = 30.0;
+/// 分割して推論するときに、隣り合うチャンクを重ねてクロスフェードする長さ（秒）
+const CHUNK_CROSSFADE_SECS: f64 = 2.0;
+/// 推論する長さの下限（秒、モデルの1セグメント分）。これより短い入力は末尾を無音で埋めてから推論する
+const MIN_SEPARATION_SECS: f64 = 7.8;
+/// これより短い入力は実質的に空のファイルとみなしてエラーにする（秒）
+const MIN_INPUT_SECS: f64 = 0.01;
+
+/// 保存された設定による上限の上書き（Noneでデバイスごとの既定値）
+static MAX_SEGMENT_OVERRIDE: RwLock<Option<f64>> = RwLock::new(None);
+
+/// 一度に推論するトラックの長さの上限を上書きする（Noneでデバイスごとの既定値に戻す）
+pub fn set_max_segment_override(secs: Option<f64>) {
+    if let Ok(mut value) = MAX_SEGMENT_OVERRIDE.write() {
+        *value = secs;
+    }
+}
+
+/// デバイスで一度に推論するトラックの長さの上限（秒）。Noneの場合はトラック全体を一度に推論する
+///
+/// 設定で上書きされていない場合、MPSだけに上限を設け、CPU/CUDAは従来どおりトラック全体を推論する
+pub fn max_segment_secs(device: Device) -> Option<f64> {
+    let override_secs = MAX_SEGMENT_OVERRIDE.read().ok().and_then(|value| *value);
+    override_secs.or(match device {
+        Device::Mps => Some(MPS_MAX_SEGMENT_SECS),
+        _ => None,
+    })
+}
+
+/// デバイスを "cpu" / "cuda" / "mps" の文字列で表す
+pub fn device_name(device: Device) -> String {
+    match device {
+        Device::Cpu => "cpu".to_string(),
+        Device::Cuda(_) => "cuda".to_string(),
+        Device::Mps => "mps".to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+fn is_device_available(device: Device) -> bool {
+    match device {
+        Device::Cpu => true,
+        Device::Cuda(_) => tch::utils::has_cuda(),
+        Device::Mps => tch::utils::has_mps(),
+        _ => false,
+    }
+}
+
+/// stemの後処理・エンコードを並列実行する際のワーカースレッド数
+const ENCODE_WORKERS: usize = 4;
+
+/// split_trackのオプション
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SplitOptions {
+    /// 推論後のstem後処理・WAVエンコードを並列に行う
+    /// 推論はこの時点で完了しているためOpenMPとは競合しないが、不安定な場合は無効化できる
+    pub parallel_encode: bool,
+    /// 書き出すWAVのビット深度とディザ（JSONでは `bit_depth` / `dither` をそのまま並べる）
+    #[serde(flatten)]
+    pub export: ExportConfig,
+    /// stemの後処理の設定（省略時はモデルごとのデフォルト）
+    pub post_process: Option<PostProcessConfig>,
+    /// 後処理したstemを左右平均でモノラルにして書き出す（推論はステレオのまま）
+    pub mono: bool,
+    /// GPUでの推論に失敗した場合にCPUで1回だけ再試行する（無効にするとエラーをそのまま返す）
+    pub cpu_fallback: bool,
+    /// stemごとに先頭・末尾の無音を切り取る（元の位置は `stem_offsets.json` に記録）
+    pub trim_silence: bool,
+    /// 無音とみなす振幅の閾値（dBFS）
+    pub silence_threshold_db: f32,
+    /// クリック/ポップノイズ除去の設定
+    pub click_removal: ClickRemovalConfig,
+    /// ランダムにずらした入力で推論して平均する回数（0でずらさない）
+    /// 分離の品質が上がる代わりに、推論時間がおおよそ回数に比例して増える
+    pub shifts: u32,
+    /// 推論セグメント間のオーバーラップ（0.0〜0.95）
+    pub overlap: f32,
+    /// モデルのサンプルレートへのリサンプリングの品質（44.1kHz以外の音源のみ）
+    pub resample_quality: ResampleQuality,
+    /// 書き出す直前にソフトリミッターを掛けてクリッピングを防ぐ（stemごとのメータリングを返す）
+    pub limiter: bool,
+    /// 書き出すWAVのサンプルレート（48kHzのDAWセッション向けなど）。Noneでモデルのサンプルレートのまま
+    pub output_sample_rate: Option<usize>,
+    /// 後処理（フィルタ・クリック除去・無音の切り取り・モノラル化・リミッター）をすべて省き、
+    /// モデルの出力をそのままFloat32で書き出す（アーティファクトがモデルと後処理のどちらに由来するかの調査用）
+    pub raw: bool,
+    /// ソース名から書き出すファイル名（拡張子なし）への対応（`"other" -> "accompaniment"`、`"drums" -> "01_drums"` など）
+    ///
+    /// 指定しないソースはソース名のまま書き出す。stemの種類はファイル名で判断するため、
+    /// 書き出した後は変更後の名前で参照する
+    pub naming: HashMap<String, String>,
+    /// stemをファイルごとに書き出すか、1つのマルチチャンネルWAVにまとめるか
+    pub output_layout: OutputLayout,
+    /// stemの先頭と末尾に掛けるフェードの長さ（ミリ秒）。切り取った境界のクリックノイズを防ぐ
+    pub fade_ms: Option<f32>,
+}
+
+/// stemの書き出し方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLayout {
+    /// stemごとに `{名前}.wav` を書き出す
+    #[default]
+    Separate,
+    /// すべてのstemを `stems.wav`（stemのチャンネル数 × ソース数のチャンネル）にまとめる。
+    /// チャンネルの対応は `stems_channels.json` に書き出す。stemごとに長さが変わる無音の切り取りは行わない
+    SingleMultichannel,
+}
+
+/// ボーカル/インストなど、複数のsourceを合算したミックスの作り方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MixSumMode {
+    /// モデルの出力をそのまま合算してから、合算したミックスに1回だけ後処理を掛ける
+    ///
+    /// モデルの出力どうしは足すと元のミックスに戻るよう位相が揃っているため、打ち消し合いが起きにくい
+    #[default]
+    RawOutputs,
+    /// sourceごとに後処理（フィルタ・ノイズ除去・クリック除去）を掛けてから合算する
+    ///
+    /// 個別に書き出したstemを足したものと一致するが、フィルタの位相のずれやノイズゲートの違いにより、
+    /// クロスオーバー付近やゲートの境界でわずかに打ち消し合い、音がこもったりうねったりすることがある
+    ProcessedStems,
+}
+
+/// `split_vocal_instrumental` / `split_custom_mix` の設定
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MixSplitOptions {
+    /// 合算と後処理の順序
+    pub sum_mode: MixSumMode,
+    /// クリック/ポップノイズ除去の設定（`SplitOptions.click_removal` と同じ）
+    pub click_removal: ClickRemovalConfig,
+    /// GPUでの推論に失敗した場合にCPUで1回だけ再試行する（`SplitOptions.cpu_fallback` と同じ）
+    pub cpu_fallback: bool,
+    /// 書き出すWAVのビット深度とディザ（`SplitOptions` と同じく `bit_depth` / `dither` を並べる）
+    #[serde(flatten)]
+    pub export: ExportConfig,
+}
+
+impl Default for MixSplitOptions {
+    fn default() -> Self {
+        Self {
+            sum_mode: MixSumMode::default(),
+            click_removal: ClickRemovalConfig::default(),
+            cpu_fallback: true,
+            export: ExportConfig::default(),
+        }
+    }
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        Self {
+            parallel_encode: true,
+            export: ExportConfig::default(),
+            post_process: None,
+            mono: false,
+            cpu_fallback: true,
+            trim_silence: false,
+            silence_threshold_db: -60.0,
+            click_removal: ClickRemovalConfig::default(),
+            shifts: DEFAULT_SHIFTS as u32,
+            overlap: DEFAULT_OVERLAP,
+            resample_quality: ResampleQuality::default(),
+            limiter: false,
+            output_sample_rate: None,
+            raw: false,
+            naming: HashMap::new(),
+            output_layout: OutputLayout::default(),
+            fade_ms: None,
+        }
+    }
+}
+
+impl SplitOptions {
+    /// `raw` の場合に、書き出しを変える設定を無効にしたオプション
+    fn raw_bypass(&self) -> Self {
+        Self {
+            export: ExportConfig {
+                bit_depth: WavBitDepth::Float32,
+                dither: false,
+            },
+            mono: false,
+            trim_silence: false,
+            limiter: false,
+            output_sample_rate: None,
+            fade_ms: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// クリック/ポップノイズ除去の設定
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClickRemovalConfig {
+    /// この振幅を超えたサンプルだけをクリックの候補にする
+    pub threshold: f32,
+    /// 前後1msの平均振幅の何倍を超えたらクリックとみなすか
+    pub neighbor_ratio: f32,
+    /// クリック除去を行わないstemの種類（ドラムのアタックなど、正当な大きいトランジェントを守るため）
+    pub disabled_stems: Vec<String>,
+}
+
+impl Default for ClickRemovalConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.9,
+            neighbor_ratio: 3.0,
+            disabled_stems: Vec::new(),
+        }
+    }
+}
+
+impl ClickRemovalConfig {
+    fn is_enabled_for(&self, stem_type: &str) -> bool {
+        !self.disabled_stems.iter().any(|stem| stem == stem_type)
+    }
+}
+
+/// ノイズ除去の方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseReductionMode {
+    /// ノイズ除去を行わない
+    Off,
+    /// 時間領域の移動平均とのブレンド（高速だが信号全体がこもる）
+    MovingAverage,
+    /// FFTベースのスペクトラルゲート（ノイズフロア以下の成分だけを減衰）
+    SpectralGate,
+}
+
+/// stemに掛ける帯域フィルタ
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StemFilter {
+    /// cutoff_hz以下をカット
+    HighPass { cutoff_hz: f32 },
+    /// cutoff_hz以上をカット
+    LowPass { cutoff_hz: f32 },
+    /// low_hz〜high_hzだけを通す
+    BandPass { low_hz: f32, high_hz: f32 },
+}
+
+impl StemFilter {
+    /// カットオフがナイキスト周波数までの範囲に収まっているかを確認する（問題がある場合は理由を返す）
+    pub fn validate(self, sample_rate: usize) -> std::result::Result<(), String> {
+        let nyquist = sample_rate as f32 / 2.0;
+        let in_range = |hz: f32| hz.is_finite() && hz > 0.0 && hz < nyquist;
+        let valid = match self {
+            Self::HighPass { cutoff_hz } | Self::LowPass { cutoff_hz } => in_range(cutoff_hz),
+            Self::BandPass { low_hz, high_hz } => {
+                in_range(low_hz) && in_range(high_hz) && low_hz < high_hz
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!(
+                "invalid filter {:?} (cutoffs must be between 0 and {} Hz)",
+                self, nyquist
+            ))
+        }
+    }
+
+    fn apply(self, samples: &mut [f32], sample_rate: usize) {
+        match self {
+            Self::HighPass { cutoff_hz } => apply_high_pass_filter(samples, sample_rate, cutoff_hz),
+            Self::LowPass { cutoff_hz } => apply_low_pass_filter(samples, sample_rate, cutoff_hz),
+            Self::BandPass { low_hz, high_hz } => {
+                apply_band_pass_filter(samples, sample_rate, low_hz, high_hz)
+            }
+        }
+    }
+}
+
+/// stemの後処理の設定
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PostProcessConfig {
+    /// stemの種類（"other"など）ごとのノイズ除去の方式。指定のないstemはノイズ除去しない
+    pub noise_reduction: HashMap<String, NoiseReductionMode>,
+    /// stemの種類ごとの帯域フィルタ。指定のないstem（drumsなど）は広帯域のまま
+    pub filters: HashMap<String, StemFilter>,
+    /// drumsのアタックを強調する量（0.0で無効、1.0でアタックを最大約2倍）
+    pub transient_amount: f32,
+}
+
+impl Default for PostProcessConfig {
+    fn default() -> Self {
+        Self {
+            noise_reduction: HashMap::from([(
+                "other".to_string(),
+                NoiseReductionMode::MovingAverage,
+            )]),
+            filters: HashMap::from([
+                // other.wavのゴワゴワ感を改善するため、低周波ノイズを除去
+                ("other".to_string(), StemFilter::HighPass { cutoff_hz: 80.0 }),
+                // ベースの低周波数帯域を保持しつつ、不要な高周波ノイズを軽減
+                ("bass".to_string(), StemFilter::LowPass { cutoff_hz: 400.0 }),
+                // ボーカルの中周波数帯域を強調
+                (
+                    "vocals".to_string(),
+                    StemFilter::BandPass {
+                        low_hz: 300.0,
+                        high_hz: 3400.0,
+                    },
+                ),
+                // ギターの中高周波数帯域を強調
+                (
+                    "guitar".to_string(),
+                    StemFilter::BandPass {
+                        low_hz: 80.0,
+                        high_hz: 8000.0,
+                    },
+                ),
+                // ピアノの広帯域を維持
+                (
+                    "piano".to_string(),
+                    StemFilter::BandPass {
+                        low_hz: 80.0,
+                        high_hz: 15000.0,
+                    },
+                ),
+            ]),
+            transient_amount: 0.0,
+        }
+    }
+}
+
+/// モデルごとの後処理のデフォルト（models.jsonに `post_process` がない場合に使う）
+///
+/// 6stemモデルではギター・ピアノが別のstemに分かれるため、otherには残りの細かい成分しか残らない。
+/// 移動平均でこもらせるとそれらが埋もれてしまうため、スペクトラルゲートでノイズだけを落とす
+pub fn default_post_process_for(model_name: &str) -> PostProcessConfig {
+    let mut config = PostProcessConfig::default();
+
+    if model_name.ends_with("_6s") {
+        config
+            .noise_reduction
+            .insert("other".to_string(), NoiseReductionMode::SpectralGate);
+    }
+
+    config
+}
+
+impl PostProcessConfig {
+    fn noise_reduction_for(&self, stem_type: &str) -> NoiseReductionMode {
+        self.noise_reduction
+            .get(stem_type)
+            .copied()
+            .unwrap_or(NoiseReductionMode::Off)
+    }
+}
+
+/// 分離ジョブの進行状況
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobStatus {
+    /// モデルの空きを待っている
+    Queued,
+    Decoding,
+    Inferring,
+    PostProcessing,
+    Encoding,
+    Done,
+    Failed { message: String },
+}
+
+/// 分離の進行状況の通知先（エンコード中はワーカースレッドから呼ばれる）
+///
+/// `Fn(JobStatus)` のクロージャはそのまま使える（推論時間の見積もりは無視される）
+pub trait SplitProgress: Sync {
+    /// 分離の各段階に入るたびに呼ばれる
+    fn status(&self, status: JobStatus);
+
+    /// 推論を始める直前に、推論時間の見積もりを渡す
+    fn estimate(&self, _estimate: SplitEstimate) {}
+}
+
+impl<F: Fn(JobStatus) + Sync> SplitProgress for F {
+    fn status(&self, status: JobStatus) {
+        self(status)
+    }
+}
+
+pub type ProgressFn<'a> = &'a dyn SplitProgress;
+
+/// 分離して書き出したstem
+#[derive(Debug)]
+pub struct SplitOutput {
+    pub stem_paths: Vec<PathBuf>,
+    /// リミッターを掛けた場合の、stemの種類ごとのメータリング
+    pub metering: HashMap<String, LimiterMetering>,
+}
+
+pub fn split_track(
+    model: &Demucs,
+    input_path: &Path,
+    output_dir: &Path,
+    options: &SplitOptions,
+) -> Result<Vec<PathBuf>> {
+    split_track_with_progress(model, input_path, output_dir, options, &|_: JobStatus| {})
+        .map(|output| output.stem_paths)
+}
+
+/// split_trackと同じだが、処理の段階が変わるたびに `progress` を呼び、メータリングも返す
+pub fn split_track_with_progress(
+    model: &Demucs,
+    input_path: &Path,
+    output_dir: &Path,
+    options: &SplitOptions,
+    progress: ProgressFn,
+) -> Result<SplitOutput> {
+    progress.status(JobStatus::Decoding);
+    let track = load_track(model, input_path, options.resample_quality)?;
+
+    split_loaded_track(model, track, output_dir, options, progress)
+}
+
+/// 先頭N秒だけを分離して `output_dir/preview/` に書き出す（モデルの品質を素早く確認するため）
+pub fn preview_split(
+    model: &Demucs,
+    input_path: &Path,
+    output_dir: &Path,
+    seconds: f64,
+    options: &SplitOptions,
+) -> Result<Vec<PathBuf>> {
+    if !seconds.is_finite() || seconds <= 0.0 {
+        whatever!("preview length must be a positive number of seconds, got {}", seconds);
+    }
+
+    let mut track = load_track(model, input_path, options.resample_quality)?;
+    let max_length = (seconds * track.sample_rate as f64).round() as usize;
+    track.truncate(max_length.max(1));
+    debug!(
+        "[preview_split] Previewing first {:.2}s ({} samples)",
+        track.length as f64 / track.sample_rate as f64,
+        track.length
+    );
+
+    let preview_dir = output_dir.join("preview");
+    whatever!(
+        std::fs::create_dir_all(&preview_dir),
+        "failed to create preview directory {}",
+        preview_dir.display()
+    );
+
+    split_loaded_track(model, track, &preview_dir, options, &|_: JobStatus| {})
+        .map(|output| output.stem_paths)
+}
+
+/// 出力先のディレクトリに書き込めるかを、一時ファイルを作成して確認する
+///
+/// 推論に数分かかった後、最後の書き出しで失敗するのを避けるため、推論の前に呼ぶ
+pub fn ensure_output_writable(output_dir: &Path) -> Result<()> {
+    let probe_path = output_dir.join(format!(".write-test-{}", std::process::id()));
+    File::create(&probe_path).context(OutputNotWritableSnafu { path: output_dir })?;
+
+    if let Err(e) = fs::remove_file(&probe_path) {
+        warn!("[ensure_output_writable] Failed to remove {:?}: {}", probe_path, e);
+    }
+    Ok(())
+}
+
+/// デコード済み（モデルのサンプルレートにリサンプリング済み）のトラックを分離してstemを書き出す
+fn split_loaded_track(
+    model: &Demucs,
+    track: PcmAudioData,
+    output_dir: &Path,
+    options: &SplitOptions,
+    progress: ProgressFn,
+) -> Result<SplitOutput> {
+    ensure_output_writable(output_dir)?;
+    let mut options = if options.raw {
+        info!("[split_loaded_track] Raw mode: writing model output without post-processing");
+        options.raw_bypass()
+    } else {
+        options.clone()
+    };
+    // 1つのファイルにまとめるstemは同じ長さでなければならない
+    if options.output_layout == OutputLayout::SingleMultichannel && options.trim_silence {
+        info!("[split_loaded_track] Ignoring trim_silence for a single multichannel file");
+        options.trim_silence = false;
+    }
+    let options = &options;
+
+    let track_length = track.length;
+    let audio_secs = track.length as f64 / track.sample_rate as f64;
+    let nb_track_channels = track.nb_channels;
+    let shifts = options.shifts as usize;
+
+    let estimate = estimate_split(model.device, audio_secs, nb_track_channels, shifts);
+    info!(
+        "[split_loaded_track] Estimated inference time on {}: {:.1}s for {:.1}s of audio",
+        estimate.device, estimate.estimated_secs, audio_secs
+    );
+    progress.estimate(estimate);
+
+    // 書き出す前に検証し、ファイル名が重複した状態で推論に時間を使わないようにする
+    let file_names = stem_file_names(&model.config.sources, &options.naming)?;
+
+    progress.status(JobStatus::Inferring);
+    let inference_started = Instant::now();
+    let output = separate(model, track, shifts, options.overlap)?;
+    record_split(
+        model.device,
+        audio_secs,
+        nb_track_channels,
+        shifts,
+        inference_started.elapsed(),
+    );
+
+    // テンソルからのコピーはメインスレッドで順番に行う
+    let stems: Vec<(&String, Vec<Vec<f32>>)> = model
+        .config
+        .sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| (source, extract_source(&output, i, model.config.channels, track_length)))
+        .collect();
+
+    // ワーカースレッドにはモデル本体ではなく必要な値だけを渡す
+    let sample_rate = model.config.sample_rate;
+    let nb_channels = model.config.channels;
+    let output_sample_rate = options.output_sample_rate.unwrap_or(sample_rate);
+    let post_process = options
+        .post_process
+        .clone()
+        .unwrap_or_else(|| model_post_process(model));
+    let render = |source: &str, buffer: &[Vec<f32>]| -> Result<RenderedStem> {
+        let (mut audio_data, mut range, metering) = process_stem(
+            source,
+            buffer,
+            sample_rate,
+            nb_channels,
+            track_length,
+            options,
+            &post_process,
+        );
+
+        if output_sample_rate != sample_rate {
+            audio_data = resample(audio_data, output_sample_rate, options.resample_quality)?;
+            // stem_offsets.jsonの位置も書き出したWAVのサンプル単位に合わせる
+            let scale = |position: usize| {
+                (position as f64 * output_sample_rate as f64 / sample_rate as f64).round() as usize
+            };
+            range = (scale(range.0), scale(range.1));
+        }
+
+        // ディザはリサンプリングなどすべての処理の後、量子化の直前に加える
+        options.export.dither_if_needed(&mut audio_data.samples);
+
+        Ok((audio_data, range, metering))
+    };
+    let encode = |source: &str, buffer: &[Vec<f32>]| -> Result<EncodedStem> {
+        let (audio_data, range, metering) = render(source, buffer)?;
+
+        progress.status(JobStatus::Encoding);
+        let path = output_dir.join(format!("{}.wav", file_names[source]));
+        // 無音を切り取ったstemは、DAWで元のタイミングに置けるよう開始位置をbextに残す
+        let time_reference = options.trim_silence.then_some(TimeReference {
+            sample_offset: range.0 as u64,
+        });
+        encode_pcm_to_wav(audio_data, &path, options.export.bit_depth, time_reference)?;
+
+        Ok((path, range, metering))
+    };
+
+    progress.status(JobStatus::PostProcessing);
+    let started = Instant::now();
+
+    let (stem_paths, meterings) = match options.output_layout {
+        OutputLayout::Separate => {
+            let encoded = map_stems(&stems, options.parallel_encode, &encode)?;
+            write_stem_sources(output_dir, &file_names)?;
+            // 前回まとめて書き出したときの対応が残っていると、stems.wavという名前のstemをまとめたファイルと誤認する
+            remove_sidecar(output_dir, MULTICHANNEL_MAP_FILE_NAME);
+            if options.trim_silence {
+                write_stem_offsets(output_dir, &encoded, output_sample_rate)?;
+            } else {
+                // 前回の分離で切り取った位置が、切り取っていない新しいstemに適用されないようにする
+                remove_stem_offsets(output_dir);
+            }
+            encoded
+                .into_iter()
+                .map(|(path, _, metering)| (path, metering))
+                .unzip()
+        }
+        OutputLayout::SingleMultichannel => {
+            let rendered = map_stems(&stems, options.parallel_encode, &render)?;
+            let names: Vec<&str> = stems
+                .iter()
+                .map(|(source, _)| file_names[source.as_str()].as_str())
+                .collect();
+            let (audio_data, meterings): (Vec<_>, Vec<_>) = rendered
+                .into_iter()
+                .map(|(audio_data, _, metering)| (audio_data, metering))
+                .unzip();
+
+            progress.status(JobStatus::Encoding);
+            let path =
+                write_multichannel_stems(output_dir, &names, audio_data, options.export.bit_depth)?;
+            remove_sidecar(output_dir, STEM_SOURCES_FILE_NAME);
+            (vec![path], meterings)
+        }
+    };
+
+    info!(
+        "[split_track] Encoded {} stems in {:?} (parallel: {}, layout: {:?})",
+        stems.len(),
+        started.elapsed(),
+        options.parallel_encode,
+        options.output_layout
+    );
+
+    let metering = stems
+        .iter()
+        .zip(meterings)
+        .filter_map(|((source, _), metering)| Some(((*source).clone(), metering?)))
+        .collect();
+
+    Ok(SplitOutput {
+        stem_paths,
+        metering,
+    })
+}
+
+/// stemごとに `f` を実行し、stemの順番のまま結果を返す
+///
+/// 推論はmodel.applyで完了しているため、後処理とWAVエンコードは並列化しても
+/// OpenMP（libtorch）とは競合しない。rayonではなく固定数のスコープ付きスレッドを使用する
+fn map_stems<T: Send>(
+    stems: &[(&String, Vec<Vec<f32>>)],
+    parallel: bool,
+    f: impl Fn(&str, &[Vec<f32>]) -> Result<T> + Sync,
+) -> Result<Vec<T>> {
+    if !parallel || stems.len() <= 1 {
+        return stems
+            .iter()
+            .map(|(source, buffer)| f(source, buffer))
+            .collect();
+    }
+
+    let workers = ENCODE_WORKERS.min(stems.len());
+    let mut results: Vec<(usize, Result<T>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|worker| {
+                let f = &f;
+                scope.spawn(move || {
+                    stems
+                        .iter()
+                        .enumerate()
+                        .skip(worker)
+                        .step_by(workers)
+                        .map(|(i, (source, buffer))| (i, f(source, buffer)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("stem encoding worker panicked"))
+            .collect()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// `OutputLayout::SingleMultichannel` で書き出すファイル名（拡張子なし）
+const MULTICHANNEL_FILE_NAME: &str = "stems";
+/// マルチチャンネルWAVのチャンネルとstemの対応を記録するファイル名
+const MULTICHANNEL_MAP_FILE_NAME: &str = "stems_channels.json";
+
+/// `OutputLayout::SingleMultichannel` で書き出した、すべてのstemをまとめたファイルかどうか
+///
+/// 同じディレクトリにチャンネルの対応（`stems_channels.json`）があるものだけをそう判定する
+pub fn is_multichannel_stem_file(path: &Path) -> bool {
+    path.file_stem()
+        .map_or(false, |stem| stem == MULTICHANNEL_FILE_NAME)
+        && path
+            .parent()
+            .map_or(false, |dir| dir.join(MULTICHANNEL_MAP_FILE_NAME).is_file())
+}
+
+/// すべてのstemを1つのマルチチャンネルWAV（`stems.wav`）に書き出す
+///
+/// チャンネルはモデルのソースの順に、stemごとのチャンネル（ステレオならL, R）を並べる。
+/// 例えば4ソースのステレオなら8チャンネルで、`[vocals L, vocals R, drums L, drums R, ...]` のようになる。
+/// 対応は `stems_channels.json`（stemの名前 → 0始まりのチャンネル番号）にも書き出す
+fn write_multichannel_stems(
+    output_dir: &Path,
+    names: &[&str],
+    stems: Vec<PcmAudioData>,
+    bit_depth: WavBitDepth,
+) -> Result<PathBuf> {
+    let Some(first) = stems.first() else {
+        whatever!("no stems to write");
+    };
+    let (sample_rate, length) = (first.sample_rate, first.length);
+    if stems
+        .iter()
+        .any(|stem| stem.sample_rate != sample_rate || stem.length != length)
+    {
+        whatever!("stems must share the sample rate and length to be written as one file");
+    }
+
+    let mut channel_map: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let mut samples = Vec::new();
+    for (name, stem) in names.iter().zip(stems) {
+        let first_channel = samples.len();
+        channel_map.insert(
+            name.to_string(),
+            (first_channel..first_channel + stem.nb_channels).collect(),
+        );
+        samples.extend(stem.samples);
+    }
+
+    let audio_data = PcmAudioData {
+        nb_channels: samples.len(),
+        samples,
+        sample_rate,
+        length,
+    };
+    let path = output_dir.join(format!("{}.wav", MULTICHANNEL_FILE_NAME));
+    encode_pcm_to_wav(audio_data, &path, bit_depth, None)?;
+
+    let map_path = output_dir.join(MULTICHANNEL_MAP_FILE_NAME);
+    let json = whatever!(
+        serde_json::to_string_pretty(&channel_map),
+        "failed to serialize channel map"
+    );
+    whatever!(
+        fs::write(&map_path, json),
+        "failed to write {}",
+        map_path.display()
+    );
+    info!(
+        "[write_multichannel_stems] Saved {} channels to {:?}",
+        channel_map.values().map(Vec::len).sum::<usize>(),
+        path
+    );
+
+    Ok(path)
+}
+
+/// `SplitOptions::naming` を適用した、ソース名ごとの書き出すファイル名（拡張子なし）
+///
+/// モデルにないソースの指定、パスとして使えない名前、（大文字小文字を区別しないファイルシステムを考慮して）
+/// 重複するファイル名はエラーにする
+fn stem_file_names(
+    sources: &[String],
+    naming: &HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+    if let Some(unknown) = naming.keys().find(|source| !sources.contains(*source)) {
+        return Err(Error::UnknownSourceError {
+            name: unknown.clone(),
+            available: sources.to_vec(),
+        });
+    }
+
+    let mut file_names = HashMap::new();
+    let mut used: HashMap<String, &String> = HashMap::new();
+    for source in sources {
+        let file_name = naming
+            .get(source)
+            .map_or_else(|| source.clone(), |name| name.trim().to_string());
+        let invalid = |reason: String| Error::InvalidStemNameError {
+            name: file_name.clone(),
+            reason,
+        };
+
+        if file_name.is_empty() {
+            return Err(invalid(format!("\"{}\" has an empty file name", source)));
+        }
+        if file_name.contains(['/', '\\', ':']) || file_name.starts_with('.') {
+            return Err(invalid("not usable as a file name".into()));
+        }
+        // instrumental.wavは build_instrumental_from_stems が書き出す
+        if file_name.eq_ignore_ascii_case("instrumental") {
+            return Err(invalid("\"instrumental\" is reserved".into()));
+        }
+        if let Some(other) = used.insert(file_name.to_lowercase(), source) {
+            return Err(invalid(format!(
+                "both \"{}\" and \"{}\" map to the same file name",
+                other, source
+            )));
+        }
+
+        file_names.insert(source.clone(), file_name);
+    }
+
+    Ok(file_names)
+}
+
+/// stemのファイル名とモデルのソースの対応を記録するファイル名
+const STEM_SOURCES_FILE_NAME: &str = "stem_sources.json";
+
+/// stemのファイル名（拡張子なし） → ソース名の対応を `stem_sources.json` に書き出す
+///
+/// `SplitOptions::naming` で名前を変えたstem（`lead.wav` など）でも、元のソースが分かるようにする
+fn write_stem_sources(output_dir: &Path, file_names: &HashMap<String, String>) -> Result<()> {
+    let sources: BTreeMap<&str, &str> = file_names
+        .iter()
+        .map(|(source, file_name)| (file_name.as_str(), source.as_str()))
+        .collect();
+
+    let path = output_dir.join(STEM_SOURCES_FILE_NAME);
+    let json = whatever!(
+        serde_json::to_string_pretty(&sources),
+        "failed to serialize stem sources"
+    );
+    whatever!(fs::write(&path, json), "failed to write {}", path.display());
+
+    Ok(())
+}
+
+/// stemのファイルがどのソース（"vocals"など）のものかを返す
+///
+/// 同じディレクトリの `stem_sources.json` に記録がないstem（記録する前に分離したプロジェクトや、
+/// ソースを合算したinstrumentalなど）は、拡張子を除いたファイル名をそのままソース名とみなす
+pub fn stem_source_name(stem_path: &Path) -> String {
+    let file_stem = stem_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    stem_path
+        .parent()
+        .and_then(|dir| read_stem_sources(dir).remove(&file_stem))
+        .unwrap_or(file_stem)
+}
+
+/// `stem_sources.json` を読み込む（ファイルがない、または読めない場合は空）
+fn read_stem_sources(dir: &Path) -> HashMap<String, String> {
+    let path = dir.join(STEM_SOURCES_FILE_NAME);
+    let Ok(json) = fs::read_to_string(&path) else {
+        return HashMap::new();
+    };
+
+    serde_json::from_str(&json).unwrap_or_else(|e| {
+        warn!("[read_stem_sources] Ignoring unreadable {:?}: {}", path, e);
+        HashMap::new()
+    })
+}
+
+/// 無音を切り取ったstemの元の位置を記録するファイル名
+const STEM_OFFSETS_FILE_NAME: &str = "stem_offsets.json";
+
+/// 切り取ったstemを元の位置に戻すための情報
+#[derive(Debug, Serialize, Deserialize)]
+struct StemOffset {
+    /// 元の信号での開始位置（サンプル）
+    start_sample: usize,
+    /// 元の信号での終了位置（サンプル、この位置は含まない）
+    end_sample: usize,
+    /// 開始位置（秒）。DAWではこの位置に配置すれば元のタイミングに揃う
+    offset_secs: f64,
+}
+
+/// stemごとの切り取り範囲を `stem_offsets.json` に書き出す
+fn write_stem_offsets(
+    output_dir: &Path,
+    encoded: &[EncodedStem],
+    sample_rate: usize,
+) -> Result<()> {
+    let offsets: HashMap<String, StemOffset> = encoded
+        .iter()
+        .map(|(path, (start, end), _)| {
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let offset = StemOffset {
+                start_sample: *start,
+                end_sample: *end,
+                offset_secs: *start as f64 / sample_rate as f64,
+            };
+            (name, offset)
+        })
+        .collect();
+
+    let path = output_dir.join(STEM_OFFSETS_FILE_NAME);
+    let json = whatever!(
+        serde_json::to_string_pretty(&offsets),
+        "failed to serialize stem offsets"
+    );
+    whatever!(fs::write(&path, json), "failed to write {}", path.display());
+
+    Ok(())
+}
+
+/// stemをデコードし、無音を切り取ったstemは元の位置に戻す
+///
+/// 同じディレクトリの `stem_offsets.json` に記録された開始位置の分だけ先頭を無音で埋める。
+/// 記録のないstem（切り取っていないstem）はそのまま返すため、他のstemと足し合わせたり
+/// 元の曲の拍の位置で切り分けたりする場合は `decode_file` ではなくこちらを使う
+pub fn decode_stem_aligned(stem_path: &Path) -> Result<PcmAudioData> {
+    let mut stem = decode_file(stem_path)?;
+    let start_sample = stem_path
+        .parent()
+        .zip(stem_path.file_stem())
+        .and_then(|(dir, name)| {
+            read_stem_offsets(dir)
+                .remove(name.to_string_lossy().as_ref())
+                .map(|offset| offset.start_sample)
+        })
+        .unwrap_or(0);
+
+    if start_sample > 0 {
+        debug!(
+            "[decode_stem_aligned] Restoring {} samples of leading silence to {:?}",
+            start_sample, stem_path
+        );
+        for channel in stem.samples.iter_mut() {
+            channel.splice(0..0, std::iter::repeat(0.0).take(start_sample));
+        }
+        stem.length += start_sample;
+    }
+
+    Ok(stem)
+}
+
+/// `stem_offsets.json` を削除する（ファイルがない場合は何もしない）
+fn remove_stem_offsets(output_dir: &Path) {
+    remove_sidecar(output_dir, STEM_OFFSETS_FILE_NAME);
+}
+
+/// stemと一緒に書き出した記録ファイルを削除する（ファイルがない場合は何もしない）
+fn remove_sidecar(output_dir: &Path, file_name: &str) {
+    let path = output_dir.join(file_name);
+    match fs::remove_file(&path) {
+        Ok(()) => debug!("[remove_sidecar] Removed stale {:?}", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("[remove_sidecar] Failed to remove {:?}: {}", path, e),
+    }
+}
+
+/// `stem_offsets.json` を読み込む（ファイルがない、または読めない場合は空）
+fn read_stem_offsets(dir: &Path) -> HashMap<String, StemOffset> {
+    let path = dir.join(STEM_OFFSETS_FILE_NAME);
+    let Ok(json) = fs::read_to_string(&path) else {
+        return HashMap::new();
+    };
+
+    serde_json::from_str(&json).unwrap_or_else(|e| {
+        warn!("[read_stem_offsets] Ignoring unreadable {:?}: {}", path, e);
+        HashMap::new()
+    })
+}
+
+/// 保存したstemのパスと、元の信号で残した範囲 `(開始サンプル, 終了サンプル)`、リミッターのメータリング
+type EncodedStem = (PathBuf, (usize, usize), Option<LimiterMetering>);
+/// 後処理して書き出す直前のstemと、元の信号で残した範囲、リミッターのメータリング
+type RenderedStem = (PcmAudioData, (usize, usize), Option<LimiterMetering>);
+
+/// 1つのstemに後処理を適用し、書き出すPCMと元の信号で残した範囲、リミッターのメータリングを返す
+fn process_stem(
+    source: &str,
+    buffer: &[Vec<f32>],
+    sample_rate: usize,
+    nb_channels: usize,
+    length: usize,
+    options: &SplitOptions,
+    post_process: &PostProcessConfig,
+) -> (PcmAudioData, (usize, usize), Option<LimiterMetering>) {
+    if options.raw {
+        let audio_data = PcmAudioData {
+            samples: buffer.to_vec(),
+            sample_rate,
+            nb_channels,
+            length,
+        };
+        return (audio_data, (0, length), None);
+    }
+
+    // 後処理: ノイズ除去とフィルタリング
+    let mut processed_buffer = post_process_stem(buffer, source, sample_rate, post_process);
+    
+    // クリック/ポップノイズを除去
+    if options.click_removal.is_enabled_for(source) {
+        remove_clicks_pops(&mut processed_buffer, sample_rate, &options.click_removal);
+    }
+
+    let range = if options.trim_silence {
+        trim_silence(&mut processed_buffer, sample_rate, options.silence_threshold_db)
+    } else {
+        (0, length)
+    };
+    let length = range.1 - range.0;
+
+    let (mut samples, nb_channels) = if options.mono && nb_channels > 1 {
+        (vec![downmix_to_mono(&processed_buffer, length)], 1)
+    } else {
+        (processed_buffer, nb_channels)
+    };
+
+    if let Some(fade_ms) = options.fade_ms {
+        apply_fades(&mut samples, sample_rate, fade_ms);
+    }
+
+    // リミッターは書き出す直前の信号（モノラル化した後）に掛ける
+    let metering = options.limiter.then(|| limit_with_metering(&mut samples));
+
+    let audio_data = PcmAudioData {
+        samples,
+        sample_rate,
+        nb_channels,
+        length,
+    };
+
+    (audio_data, range, metering)
+}
+
+/// 先頭と末尾に `fade_ms` ミリ秒のフェードイン/アウトを掛ける
+///
+/// 等パワー（正弦）カーブを使い、線形より立ち上がりの音量感を保つ。
+/// フェードが信号の半分より長い場合は半分に縮め、0以下や有限でない値の場合は何もしない
+pub fn apply_fades(samples: &mut [Vec<f32>], sample_rate: usize, fade_ms: f32) {
+    if !fade_ms.is_finite() || fade_ms <= 0.0 {
+        return;
+    }
+
+    let length = samples.iter().map(Vec::len).min().unwrap_or(0);
+    let fade_length =
+        ((f64::from(fade_ms) / 1000.0 * sample_rate as f64).round() as usize).min(length / 2);
+    if fade_length == 0 {
+        return;
+    }
+
+    for i in 0..fade_length {
+        let gain = (std::f32::consts::FRAC_PI_2 * i as f32 / fade_length as f32).sin();
+        for channel in samples.iter_mut() {
+            channel[i] *= gain;
+            channel[length - 1 - i] *= gain;
+        }
+    }
+}
+
+/// 無音の判定で、音の立ち上がりを削らないように残す余白（秒）
+const TRIM_MARGIN_SECS: f64 = 0.01;
+
+/// 先頭・末尾の無音（全チャンネルの振幅が `threshold_db` 未満）を切り取る
+///
+/// 戻り値は元の信号で残した範囲 `(開始サンプル, 終了サンプル)`。全体が無音の場合は何も切り取らない
+pub fn trim_silence(
+    samples: &mut [Vec<f32>],
+    sample_rate: usize,
+    threshold_db: f32,
+) -> (usize, usize) {
+    let length = samples.iter().map(Vec::len).min().unwrap_or(0);
+    let threshold = 10f32.powf(threshold_db / 20.0);
+    let is_audible = |i: usize| samples.iter().any(|channel| channel[i].abs() >= threshold);
+
+    let Some(first) = (0..length).find(|&i| is_audible(i)) else {
+        return (0, length);
+    };
+    let last = (0..length).rev().find(|&i| is_audible(i)).unwrap_or(first);
+
+    let margin = (sample_rate as f64 * TRIM_MARGIN_SECS) as usize;
+    let start = first.saturating_sub(margin);
+    let end = (last + 1 + margin).min(length);
+
+    for channel in samples.iter_mut() {
+        channel.truncate(end);
+        channel.drain(..start);
+    }
+
+    (start, end)
+}
+
+/// 全チャンネルを平均してモノラルにする
+fn downmix_to_mono(buffer: &[Vec<f32>], length: usize) -> Vec<f32> {
+    let nb_channels = buffer.len() as f32;
+    (0..length)
+        .map(|i| buffer.iter().map(|channel| channel[i]).sum::<f32>() / nb_channels)
+        .collect()
+}
+
+/// libtorchの例外はtch内でpanicになるため、捕捉してTorchErrorとして返す
+fn catch_torch_panic<T>(f: impl FnOnce() -> T) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| String::from("libtorch panicked"));
+
+        Error::TorchError {
+            source: tch::TchError::Torch(message),
+        }
+    })
+}
+
+/// 自己診断の結果
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    /// 推論を実行したデバイス（"cpu" / "cuda" / "mps"）
+    pub device: String,
+    /// 推論にかかった時間（ミリ秒）
+    pub elapsed_ms: f64,
+    /// 出力テンソルの形状（[1, source数, チャンネル数, サンプル数]）
+    pub output_shape: Vec<i64>,
+}
+
+/// 1秒のランダムなステレオ信号でモデルを実行し、libtorchとモデルが動作するかを確認する
+pub fn run_self_test(model: &Demucs) -> Result<SelfTestReport> {
+    let channels = model.config.channels as i64;
+    let length = model.config.sample_rate as i64;
+    let input = Tensor::rand([1, channels, length], (Kind::Float, Device::Cpu)) * 0.2 - 0.1;
+
+    let started = Instant::now();
+    let output = catch_torch_panic(|| model.apply(input))?;
+    let elapsed = started.elapsed();
+
+    let output_shape = output.size();
+    let expected_shape = vec![1, model.config.sources.len() as i64, channels, length];
+    if output_shape != expected_shape {
+        whatever!(
+            "unexpected output shape {:?} (expected {:?})",
+            output_shape,
+            expected_shape
+        );
+    }
+
+    info!(
+        "[run_self_test] Inference on {:?} succeeded in {:?}",
+        model.device, elapsed
+    );
+
+    Ok(SelfTestReport {
+        device: device_name(model.device),
+        elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+        output_shape,
+    })
+}
+
+/// `verify_device_parity` で比較する区間の長さ（秒）
+const PARITY_SEGMENT_SECS: f64 = 10.0;
+/// CPUとの差分のRMSがこれ以下なら一致とみなす（浮動小数点の演算順序の違いによる誤差の範囲）
+const PARITY_RMS_TOLERANCE: f64 = 1e-3;
+
+/// 1つのsourceのCPUとGPUの出力の差
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceParity {
+    pub source: String,
+    pub max_abs_diff: f64,
+    pub mean_abs_diff: f64,
+    /// 差分信号のRMS（`compare_models` と同じく `difference_rms` で測る）
+    pub rms_diff: f64,
+}
+
+/// `verify_device_parity` の結果
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceParityReport {
+    /// CPUと比較したデバイス（"cuda" / "mps"）
+    pub device: String,
+    /// 比較した区間の開始位置と長さ（秒）
+    pub segment_start_secs: f64,
+    pub segment_secs: f64,
+    pub sources: Vec<SourceParity>,
+    /// すべてのsourceの差分のRMSが `PARITY_RMS_TOLERANCE` 以下
+    pub within_tolerance: bool,
+}
+
+/// 曲の中央の `PARITY_SEGMENT_SECS` 秒を `model` のデバイスとCPUの両方で分離し、sourceごとの出力の差を測る
+///
+/// 「GPUだと音がおかしい」という報告を数値で確認するための保守用。CPUのモデルは `model_path` から
+/// 別にロードする。ランダムなシフトで出力が変わらないよう、shiftsは0で推論する
+pub fn verify_device_parity(
+    model: &Demucs,
+    model_path: &Path,
+    model_info: &model::ModelInfo,
+    input_path: &Path,
+) -> Result<DeviceParityReport> {
+    if model.device == Device::Cpu {
+        whatever!("the model is running on the CPU; there is no GPU device to compare");
+    }
+
+    let track = load_track(model, input_path, ResampleQuality::default())?;
+    let segment_length =
+        ((PARITY_SEGMENT_SECS * track.sample_rate as f64).round() as usize).min(track.length);
+    // 曲の頭は無音のことが多いため、中央の区間を使う
+    let start = (track.length - segment_length) / 2;
+    let segment = PcmAudioData {
+        samples: track
+            .samples
+            .iter()
+            .map(|channel| channel[start..start + segment_length].to_vec())
+            .collect(),
+        sample_rate: track.sample_rate,
+        nb_channels: track.nb_channels,
+        length: segment_length,
+    };
+
+    let cpu_model = Demucs::init(model_path, model_info, Device::Cpu)?;
+    let device_output = separate(model, segment.clone(), 0, DEFAULT_OVERLAP)?;
+    let cpu_output = separate(&cpu_model, segment, 0, DEFAULT_OVERLAP)?;
+
+    let channels = model.config.channels;
+    let sources: Vec<SourceParity> = model
+        .config
+        .sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| {
+            let device_buffer = extract_source(&device_output, i, channels, segment_length);
+            let cpu_buffer = extract_source(&cpu_output, i, channels, segment_length);
+            source_parity(source, &device_buffer, &cpu_buffer)
+        })
+        .collect();
+
+    let within_tolerance = sources
+        .iter()
+        .all(|parity| parity.rms_diff <= PARITY_RMS_TOLERANCE);
+    info!(
+        "[verify_device_parity] {:?} vs CPU: {:?} (within tolerance: {})",
+        model.device, sources, within_tolerance
+    );
+
+    Ok(DeviceParityReport {
+        device: device_name(model.device),
+        segment_start_secs: start as f64 / track.sample_rate as f64,
+        segment_secs: segment_length as f64 / track.sample_rate as f64,
+        sources,
+        within_tolerance,
+    })
+}
+
+/// デバイスとCPUで分離した1つのsourceの出力の差を測る
+fn source_parity(
+    source: &str,
+    device_buffer: &[Vec<f32>],
+    cpu_buffer: &[Vec<f32>],
+) -> SourceParity {
+    let (mut max_abs, mut sum_abs, mut count) = (0.0f64, 0.0f64, 0usize);
+    for (device_channel, cpu_channel) in device_buffer.iter().zip(cpu_buffer) {
+        for (x, y) in device_channel.iter().zip(cpu_channel) {
+            let diff = f64::from(x - y).abs();
+            max_abs = max_abs.max(diff);
+            sum_abs += diff;
+            count += 1;
+        }
+    }
+
+    SourceParity {
+        source: source.to_string(),
+        max_abs_diff: max_abs,
+        mean_abs_diff: sum_abs / count.max(1) as f64,
+        rms_diff: difference_rms(device_buffer, cpu_buffer),
+    }
+}
+
+/// ファイルをデコードし、モデルのチャンネル数とサンプルレートに合わせる
+fn load_track(
+    model: &Demucs,
+    input_path: &Path,
+    quality: ResampleQuality,
+) -> Result<PcmAudioData> {
+    let track = match_model_channels(decode_file(input_path)?, model.config.channels)?;
+    if track.sample_rate != model.config.sample_rate {
+        debug!(
+            "[load_track] Resampling {}Hz -> {}Hz ({} channels)",
+            track.sample_rate, model.config.sample_rate, track.nb_channels
+        );
+    }
+    resample(track, model.config.sample_rate, quality)
+}
+
+/// 入力のチャンネル数をモデルに合わせる
+///
+/// モノラルの入力は両チャンネルに複製する（stemは左右が同じステレオになる）。
+/// モノラルのモデルにはステレオを左右平均で渡す。3チャンネル以上の入力はエラー
+fn match_model_channels(track: PcmAudioData, channels: usize) -> Result<PcmAudioData> {
+    let samples = match (track.nb_channels, channels) {
+        (from, to) if from == to => return Ok(track),
+        (1, 2) => {
+            debug!("[load_track] Duplicating mono input to stereo");
+            let mono = track.samples[0].clone();
+            vec![mono.clone(), mono]
+        }
+        (2, 1) => {
+            debug!("[load_track] Downmixing stereo input to mono");
+            let (left, right) = (&track.samples[0], &track.samples[1]);
+            vec![left.iter().zip(right).map(|(l, r)| (l + r) * 0.5).collect()]
+        }
+        (from, _) => whatever!(
+            "separation supports mono and stereo audio only, got {} channels",
+            from
+        ),
+    };
+
+    Ok(PcmAudioData {
+        samples,
+        sample_rate: track.sample_rate,
+        nb_channels: channels,
+        length: track.length,
+    })
+}
+
+/// 正規化・推論・非正規化を行い、モデルの出力テンソル（[1, sources, channels, length]）を返す
+fn separate(model: &Demucs, track: PcmAudioData, shifts: usize, overlap: f32) -> Result<Tensor> {
+    let sample_rate = model.config.sample_rate as f64;
+    ensure_separable_length(track.length, model.config.sample_rate)?;
+
+    let input_arr: ArrayD<f32> = Array2::from_shape_vec(
+        (track.nb_channels, track.length),
+        track.samples.into_iter().flatten().collect(),
+    )
+    .unwrap()
+    .into_dyn();
+
+    let mut input_tensor: Tensor = (&input_arr).try_into().context(TorchSnafu)?;
+
+    // HTDemucsの標準的な正規化: 全テンソルに対して平均と標準偏差を計算
+    // 以前の実装ではチャンネル次元で平均を取っていたが、全テンソルに対して正規化を行う方が適切
+    let mean_val: f32 = input_tensor.mean(Kind::Float).try_into().unwrap_or(0.0);
+    let std_val: f32 = input_tensor.std(true).try_into().unwrap_or(1.0);
+    
+    // ゼロ除算を避けるため、標準偏差が小さい場合は1e-8を使用
+    let std_safe_val = if std_val < 1e-8 { 1e-8 } else { std_val };
+
+    input_tensor -= mean_val;
+    input_tensor /= std_safe_val;
+
+    let length = input_tensor.size().pop().unwrap();
+    let channels = model.config.channels as i64;
+    let mut input = input_tensor.reshape([1, channels, length]);
+
+    // 1セグメントに満たない入力はテンソルの形が崩れて推論中にpanicすることがあるため、
+    // 正規化後の値で無音（元の信号の0）になるよう末尾を埋め、推論後に元の長さに切り詰める
+    let min_frames = (MIN_SEPARATION_SECS * sample_rate).ceil() as i64;
+    input = pad_to_min_frames(input, min_frames, f64::from(-mean_val / std_safe_val));
+
+    let max_frames = max_segment_secs(model.device)
+        .map(|secs| (secs.max(MIN_MAX_SEGMENT_SECS) * model.config.sample_rate as f64) as i64);
+    let mut output = match max_frames {
+        Some(max_frames) if length > max_frames => {
+            separate_in_chunks(model, &input, max_frames, shifts, overlap)?
+        }
+        _ => catch_torch_panic(|| model.apply_with(input, shifts, overlap))?,
+    };
+    output = crop_output(output, length);
+
+    // 非正規化: 標準偏差を掛けて、平均を足す
+    output *= std_safe_val;
+    output += mean_val;
+
+    Ok(output)
+}
+
+/// 実質的に空（`MIN_INPUT_SECS` 未満）の音声はエラーにする。それより長ければ、短くても分離できる
+fn ensure_separable_length(length: usize, sample_rate: usize) -> Result<()> {
+    if (length as f64) < (MIN_INPUT_SECS * sample_rate as f64).max(1.0) {
+        whatever!(
+            "audio is too short to separate ({} samples at {} Hz)",
+            length,
+            sample_rate
+        );
+    }
+    Ok(())
+}
+
+/// `[1, channels, length]` の入力が `min_frames` に満たなければ、末尾を `silence` で埋めて `min_frames` にする
+fn pad_to_min_frames(input: Tensor, min_frames: i64, silence: f64) -> Tensor {
+    let Ok((batch, channels, length)) = input.size3() else {
+        return input;
+    };
+    if length >= min_frames {
+        return input;
+    }
+
+    debug!(
+        "[separate] Padding short input from {} to {} samples",
+        length, min_frames
+    );
+    let padded = Tensor::full(
+        [batch, channels, min_frames],
+        silence,
+        (input.kind(), input.device()),
+    );
+    padded.narrow(2, 0, length).copy_(&input);
+    padded
+}
+
+/// `[1, sources, channels, frames]` の出力を、埋める前の `length` に切り詰める
+fn crop_output(output: Tensor, length: i64) -> Tensor {
+    if output.size()[3] > length {
+        output.narrow(3, 0, length).contiguous()
+    } else {
+        output
+    }
+}
+
+/// トラックを `max_frames` ごとのチャンクに分けて推論し、境界をクロスフェードしてつなぐ
+///
+/// デバイスには1チャンク分の入出力だけを載せ、結果はチャンクごとにCPUへ移して合成する
+/// （`input` はデコード済みのトラック全体で、CPU側のメモリ使用量はチャンク化しても変わらない）
+fn separate_in_chunks(
+    model: &Demucs,
+    input: &Tensor,
+    max_frames: i64,
+    shifts: usize,
+    overlap: f32,
+) -> Result<Tensor> {
+    let size = input.size();
+    let (channels, length) = (size[1], size[2]);
+    let crossfade =
+        ((model.config.sample_rate as f64 * CHUNK_CROSSFADE_SECS) as i64).min(max_frames / 2);
+    let step = max_frames - crossfade;
+    debug!(
+        "[separate_in_chunks] Separating {} samples in chunks of {} on {:?}",
+        length, max_frames, model.device
+    );
+
+    let sources = model.config.sources.len() as i64;
+    let output = Tensor::zeros([1, sources, channels, length], (Kind::Float, Device::Cpu));
+    let weight_sum = Tensor::zeros([length], (Kind::Float, Device::Cpu));
+
+    let mut start = 0;
+    loop {
+        let chunk_length = max_frames.min(length - start);
+        let is_last = start + chunk_length >= length;
+        let chunk = input.narrow(2, start, chunk_length);
+        let chunk_output = catch_torch_panic(|| {
+            model
+                .apply_with(chunk, shifts, overlap)
+                .to_device(Device::Cpu)
+        })?;
+
+        let weight = Tensor::from_slice(&crossfade_weights(
+            chunk_length as usize,
+            crossfade as usize,
+            start > 0,
+            !is_last,
+        ));
+        let mut output_view = output.narrow(3, start, chunk_length);
+        output_view += chunk_output * &weight;
+        let mut weight_view = weight_sum.narrow(0, start, chunk_length);
+        weight_view += weight;
+
+        if is_last {
+            break;
+        }
+        start += step;
+    }
+
+    Ok(output / weight_sum.clamp_min(1e-8))
+}
+
+/// チャンクの重み（前後のチャンクと重なる部分だけ線形にフェードする）
+fn crossfade_weights(length: usize, crossfade: usize, fade_in: bool, fade_out: bool) -> Vec<f32> {
+    (0..length)
+        .map(|i| {
+            let mut weight = 1.0f32;
+            if fade_in && i < crossfade {
+                weight = weight.min((i + 1) as f32 / (crossfade + 1) as f32);
+            }
+            if fade_out && i >= length.saturating_sub(crossfade) {
+                weight = weight.min((length - i) as f32 / (crossfade + 1) as f32);
+            }
+            weight
+        })
+        .collect()
+}
+
+/// モデル出力から指定したsourceのPCMバッファを取り出す
+fn extract_source(output: &Tensor, index: usize, channels: usize, length: usize) -> Vec<Vec<f32>> {
+    let mut buffer: Vec<Vec<f32>> = vec![vec![0.0; length]; channels];
+
+    let out = output.i((0, index as i64));
+
+    for ch in 0..channels {
+        out.i(ch as i64).copy_data(&mut buffer[ch], length);
+    }
+
+    buffer
+}
+
+/// トラックをVocalとInstrumental（それ以外の組み合わせ）の2つに分離
+pub fn split_vocal_instrumental(
+    model: &Demucs,
+    input_path: &Path,
+    output_dir: &Path,
+    options: &MixSplitOptions,
+) -> Result<Vec<PathBuf>> {
+    info!("[split_vocal_instrumental] Starting vocal/instrumental separation");
+
+    // vocalsを持たないモデルでは、中央定位の抽出で代用する
+    if !model.config.sources.iter().any(|s| s == "vocals") {
+        warn!(
+            "[split_vocal_instrumental] 'vocals' not found in sources {:?}, falling back to center-channel extraction",
+            model.config.sources
+        );
+        return split_center_channel(input_path, output_dir, &options.export);
+    }
+
+    split_mix(
+        model,
+        input_path,
+        output_dir,
+        &["vocals".to_string()],
+        "vocal",
+        "instrumental",
+        options,
+    )
+}
+
+/// 位相キャンセル（L-R）によるカラオケ用の簡易分離
+///
+/// 中央に定位した音（多くの場合ボーカル）を (L+R)/2、それ以外を L-R の成分として取り出す。
+/// モデルを使わないヒューリスティックのため、ベースやキックなど中央の楽器もvocal側に入り、
+/// 品質はDemucsより大きく劣る。vocalsを持たないモデルのフォールバック専用
+fn split_center_channel(
+    input_path: &Path,
+    output_dir: &Path,
+    export: &ExportConfig,
+) -> Result<Vec<PathBuf>> {
+    let track = decode_file(input_path)?;
+    if track.nb_channels != 2 {
+        whatever!("center-channel extraction requires stereo audio, got {} channels", track.nb_channels);
+    }
+
+    let (left, right) = (&track.samples[0], &track.samples[1]);
+    let center: Vec<f32> = left.iter().zip(right).map(|(l, r)| (l + r) * 0.5).collect();
+    let side_left: Vec<f32> = left.iter().zip(right).map(|(l, r)| (l - r) * 0.5).collect();
+    let side_right: Vec<f32> = side_left.iter().map(|s| -s).collect();
+
+    let outputs = [
+        ("vocal", vec![center.clone(), center]),
+        ("instrumental", vec![side_left, side_right]),
+    ];
+
+    let mut paths = Vec::with_capacity(outputs.len());
+    for (name, samples) in outputs {
+        let path = output_dir.join(format!("{}.wav", name));
+        export.encode_wav(
+            PcmAudioData {
+                samples,
+                sample_rate: track.sample_rate,
+                nb_channels: 2,
+                length: track.length,
+            },
+            &path,
+            None,
+        )?;
+        debug!("[split_center_channel] Saved {}.wav", name);
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+/// 指定したsourceの組み合わせ（例: vocals+piano）と、それ以外すべての組み合わせの2つに分離
+///
+/// 出力ファイル名は `{keepを"+"で連結}.wav` と `{rest_name}.wav`
+pub fn split_custom_mix(
+    model: &Demucs,
+    input_path: &Path,
+    output_dir: &Path,
+    keep: Vec<String>,
+    rest_name: String,
+    options: &MixSplitOptions,
+) -> Result<Vec<PathBuf>> {
+    if keep.is_empty() {
+        whatever!("at least one source must be selected to keep");
+    }
+
+    for source in &keep {
+        if !model.config.sources.contains(source) {
+            return Err(Error::UnknownSourceError {
+                name: source.clone(),
+                available: model.config.sources.clone(),
+            });
+        }
+    }
+
+    if model.config.sources.iter().all(|s| keep.contains(s)) {
+        whatever!("at least one source must be left for \"{}\"", rest_name);
+    }
+
+    let rest_name = rest_name.trim();
+    if rest_name.is_empty()
+        || rest_name.contains(['/', '\\'])
+        || rest_name.starts_with('.')
+        || keep.iter().any(|s| s == rest_name)
+    {
+        whatever!("invalid name for the remaining mix: {:?}", rest_name);
+    }
+
+    let keep_name = keep.join("+");
+
+    split_mix(
+        model, input_path, output_dir, &keep, &keep_name, rest_name, options,
+    )
+}
+
+/// keepに含まれるsourceを合算したミックスと、それ以外を合算したミックスを書き出す
+///
+/// 合算と後処理の順序は `options.sum_mode` で選ぶ
+fn split_mix(
+    model: &Demucs,
+    input_path: &Path,
+    output_dir: &Path,
+    keep: &[String],
+    keep_name: &str,
+    rest_name: &str,
+    options: &MixSplitOptions,
+) -> Result<Vec<PathBuf>> {
+    ensure_output_writable(output_dir)?;
+    let track = load_track(model, input_path, ResampleQuality::default())?;
+    let track_length = track.length;
+    let channels = model.config.channels;
+    let sample_rate = model.config.sample_rate;
+    // 合算したstemは元のミックスより大きくならないようにする
+    let mix_peak = peak_amplitude(&track.samples);
+    let ceiling = mix_peak.min(SUMMED_PEAK_CEILING);
+
+    let output = separate(model, track, DEFAULT_SHIFTS, DEFAULT_OVERLAP)?;
+
+    let mut keep_stems: Vec<(&str, Vec<Vec<f32>>)> = Vec::new();
+    let mut rest_stems: Vec<(&str, Vec<Vec<f32>>)> = Vec::new();
+    for (i, source) in model.config.sources.iter().enumerate() {
+        let buffer = extract_source(&output, i, channels, track_length);
+        if keep.contains(source) {
+            keep_stems.push((source, buffer));
+        } else {
+            rest_stems.push((source, buffer));
+        }
+    }
 
-use self::error::{Id3Snafu, MimeParseSnafu};
+    let post_process = model_post_process(model);
+    let click_removal = &options.click_removal;
+    let process = |buffer: &[Vec<f32>], stem_type: &str| {
+        let mut processed = post_process_stem(buffer, stem_type, sample_rate, &post_process);
+        if click_removal.is_enabled_for(stem_type) {
+            remove_clicks_pops(&mut processed, sample_rate, click_removal);
+        }
+        processed
+    };
+    let mix = |stems: Vec<(&str, Vec<Vec<f32>>)>, mix_type: &str| match options.sum_mode {
+        MixSumMode::RawOutputs => {
+            let buffers: Vec<Vec<Vec<f32>>> = stems.into_iter().map(|(_, buffer)| buffer).collect();
+            let mut summed = mix_stem_buffers(&buffers, channels);
+            normalize_summed_peak(&mut summed, ceiling);
+            process(&summed, mix_type)
+        }
+        MixSumMode::ProcessedStems => {
+            let buffers: Vec<Vec<Vec<f32>>> = stems
+                .iter()
+                .map(|(source, buffer)| process(buffer, source))
+                .collect();
+            let mut summed = mix_stem_buffers(&buffers, channels);
+            normalize_summed_peak(&mut summed, ceiling);
+            summed
+        }
+    };
 
-pub fn get_available_device() -> Device {
-    if tch::utils::has_mps() {
-        Device::Mps
-    } else if tch::utils::has_cuda() {
-        Device::Cuda(0)
-    } else {
-        Device::Cpu
+    // 生の出力を合算する場合、単一のsourceはそのsourceとして後処理し、組み合わせは"other"として処理
+    let keep_type = match keep {
+        [single] => single.as_str(),
+        _ => "other",
+    };
+    let processed_keep = mix(keep_stems, keep_type);
+    let processed_rest = mix(rest_stems, "other");
+
+    let mut paths = Vec::with_capacity(2);
+    for (name, samples) in [(keep_name, processed_keep), (rest_name, processed_rest)] {
+        let path = output_dir.join(format!("{}.wav", name));
+        options.export.encode_wav(
+            PcmAudioData {
+                samples,
+                sample_rate,
+                nb_channels: channels,
+                length: track_length,
+            },
+            &path,
+            None,
+        )?;
+        debug!("[split_mix] Saved {}.wav", name);
+        paths.push(path);
     }
+    // 合算したstemは切り取らず、ファイル名もソース名のままなので、前回の分離の記録を適用しない
+    remove_stem_offsets(output_dir);
+    remove_sidecar(output_dir, STEM_SOURCES_FILE_NAME);
+
+    Ok(paths)
 }
 
-pub fn split_track(model: &Demucs, input_path: &Path, output_dir: &Path) -> Result<Vec<PathBuf>> {
-    // let model = &MODEL;
-    let track = decode_file(input_path)?;
-    let track = resample(track, model.config.sample_rate)?;
+/// 1小節あたりの拍数（4/4拍子を前提とする）
+const BEATS_PER_BAR: f64 = 4.0;
 
-    let input_arr: ArrayD<f32> = Array2::from_shape_vec(
-        (track.nb_channels, track.length),
-        track.samples.into_iter().flatten().collect(),
-    )
-    .unwrap()
-    .into_dyn();
+/// stemを `bars_per_slice` 小節ごとのWAVに切り分けて `output_dir` に書き出す
+///
+/// `downbeat_secs` を最初の小節の頭とし、それより前の部分は `0.000s` から始まる1つのファイルにまとめる。
+/// ファイル名は `{stemの種類}_{開始時刻(秒)}s.wav`。無音を切り取ったstemは元の曲の位置に戻してから切り分ける
+pub fn slice_stem_into_bars(
+    stem_path: &Path,
+    output_dir: &Path,
+    bpm: f64,
+    downbeat_secs: f64,
+    bars_per_slice: usize,
+    export: &ExportConfig,
+) -> Result<Vec<PathBuf>> {
+    if !(bpm.is_finite() && bpm > 0.0) {
+        whatever!("BPM must be a positive number, got {}", bpm);
+    }
+    if bars_per_slice == 0 {
+        whatever!("bars_per_slice must be greater than 0");
+    }
 
-    let mut input_tensor: Tensor = (&input_arr).try_into().context(TorchSnafu)?;
+    let stem = decode_stem_aligned(stem_path)?;
+    let stem_kind = stem_path
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let sample_rate = stem.sample_rate as f64;
 
-    // HTDemucsの標準的な正規化: 全テンソルに対して平均と標準偏差を計算
-    // 以前の実装ではチャンネル次元で平均を取っていたが、全テンソルに対して正規化を行う方が適切
-    let mean_val: f32 = input_tensor.mean(Kind::Float).try_into().unwrap_or(0.0);
-    let std_val: f32 = input_tensor.std(true).try_into().unwrap_or(1.0);
-    
-    // ゼロ除算を避けるため、標準偏差が小さい場合は1e-8を使用
-    let std_safe_val = if std_val < 1e-8 { 1e-8 } else { std_val };
+    // 1スライスのサンプル数は整数にならないことが多いため、境界は毎回先頭から計算して丸める
+    // （丸め誤差はスライスごとに最大0.5サンプルで、累積しない）
+    let samples_per_slice = 60.0 / bpm * BEATS_PER_BAR * bars_per_slice as f64 * sample_rate;
+    if samples_per_slice < 1.0 {
+        whatever!("slice length is shorter than one sample ({} BPM)", bpm);
+    }
+    let drift = (samples_per_slice - samples_per_slice.round()).abs();
+    if drift > 0.0 {
+        debug!(
+            "[slice_stem_into_bars] {:.3} samples per slice is not an integer; slice lengths vary by up to 1 sample (no cumulative drift)",
+            samples_per_slice
+        );
+    }
 
-    input_tensor -= mean_val;
-    input_tensor /= std_safe_val;
+    let downbeat = ((downbeat_secs * sample_rate).round() as usize).min(stem.length);
+    let mut boundaries = Vec::new();
+    if downbeat > 0 {
+        boundaries.push(0);
+    }
+    let mut slice_index = 0;
+    loop {
+        let boundary = (downbeat as f64 + slice_index as f64 * samples_per_slice).round() as usize;
+        if boundary >= stem.length {
+            break;
+        }
+        boundaries.push(boundary);
+        slice_index += 1;
+    }
+    boundaries.push(stem.length);
 
-    let length = input_tensor.size().pop().unwrap();
-    let input = input_tensor.reshape([1, 2, length]);
+    fs::create_dir_all(output_dir).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to create directory {}", output_dir.display()),
+        source: Some(Box::new(e)),
+    })?;
 
-    let mut output = model.apply(input);
+    let mut paths = Vec::with_capacity(boundaries.len() - 1);
+    for range in boundaries.windows(2) {
+        let (start, end) = (range[0], range[1]);
+        let path = output_dir.join(format!(
+            "{}_{:08.3}s.wav",
+            stem_kind,
+            start as f64 / sample_rate
+        ));
 
-    // 非正規化: 標準偏差を掛けて、平均を足す
-    output *= std_safe_val;
-    output += mean_val;
+        export.encode_wav(
+            PcmAudioData {
+                samples: stem
+                    .samples
+                    .iter()
+                    .map(|channel| channel[start..end].to_vec())
+                    .collect(),
+                sample_rate: stem.sample_rate,
+                nb_channels: stem.nb_channels,
+                length: end - start,
+            },
+            &path,
+            None,
+        )?;
+        paths.push(path);
+    }
 
-    // let output = Arc::new(output);
+    info!(
+        "[slice_stem_into_bars] Wrote {} slices of {} bars ({} BPM, downbeat at {:.3}s)",
+        paths.len(),
+        bars_per_slice,
+        bpm,
+        downbeat_secs
+    );
 
-    // OpenMP（libtorchで使用）とrayonの並列処理が競合するため、通常のイテレータを使用
-    // WAVファイルのエンコードは比較的軽い処理なので、並列処理がなくても問題ない
-    model
-        .config
-        .sources
+    Ok(paths)
+}
+
+/// 複数のstemを合算する（長さが異なる場合は最長のstemに合わせてゼロパディング）
+fn mix_stem_buffers(stem_buffers: &[Vec<Vec<f32>>], channels: usize) -> Vec<Vec<f32>> {
+    let length = stem_buffers
         .iter()
-        .enumerate()
-        .map(|(i, source)| {
-            let mut buffer: Vec<Vec<f32>> = vec![vec![0.0; track.length]; model.config.channels];
+        .flat_map(|stem| stem.iter().map(Vec::len))
+        .max()
+        .unwrap_or(0);
 
-            let out = output.i((0, i as i64));
+    let mut mixed: Vec<Vec<f32>> = vec![vec![0.0; length]; channels];
 
-            for i in 0..model.config.channels {
-                out.i(i as i64).copy_data(&mut buffer[i], track.length);
+    for stem in stem_buffers {
+        for (ch, channel) in stem.iter().enumerate().take(channels) {
+            for (j, sample) in channel.iter().enumerate() {
+                mixed[ch][j] += sample;
             }
-            (source, buffer)
-        })
-        .map(|(source, buffer)| {
-            // 後処理: ノイズ除去とフィルタリング
-            let mut processed_buffer = post_process_stem(&buffer, source, model.config.sample_rate);
-            
-            // クリック/ポップノイズを除去
-            remove_clicks_pops(&mut processed_buffer, model.config.sample_rate);
+        }
+    }
 
-            let audio_data = PcmAudioData {
-                samples: processed_buffer,
-                sample_rate: model.config.sample_rate,
-                nb_channels: model.config.channels,
-                length: track.length,
-            };
+    mixed
+}
+
+/// 全チャンネルの最大振幅
+fn peak_amplitude(samples: &[Vec<f32>]) -> f32 {
+    samples
+        .iter()
+        .flat_map(|channel| channel.iter())
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+}
 
-            let mut stem = source.clone();
-            stem.push_str(".wav");
-            let path = output_dir.join(stem);
+/// 合算したstemのピークの上限（約-0.1dBFS）
+///
+/// 整数PCMやMP3に書き出したときにフルスケールに張り付かないよう、1.0よりわずかに下に置く
+const SUMMED_PEAK_CEILING: f32 = 0.989;
 
-            encode_pcm_to_wav(audio_data, &path)?;
+/// 合算したstemのピークが `ceiling` を超えていれば、全体のゲインを下げて収める
+///
+/// 各sourceを足すとピークが重なって0dBFSを超えることがあるため、合算後に一律のゲインで揃える。
+/// サンプルごとに潰すリミッターと違い、source間の位相・音量のバランスは変わらない。
+/// `ceiling` は `SUMMED_PEAK_CEILING` で頭打ちにする。戻り値は掛けたゲイン
+fn normalize_summed_peak(samples: &mut [Vec<f32>], ceiling: f32) -> f32 {
+    let ceiling = ceiling.min(SUMMED_PEAK_CEILING);
+    let peak = peak_amplitude(samples);
+    if ceiling <= 0.0 || peak <= ceiling {
+        return 1.0;
+    }
 
-            Ok(path)
-        })
-        .collect::<Result<Vec<_>>>()
+    let gain = ceiling / peak;
+    for channel in samples.iter_mut() {
+        apply_gain(channel, gain);
+    }
+    debug!(
+        "[normalize_summed_peak] Summed peak {:.3} exceeds {:.3}, applying {:.2} dB",
+        peak,
+        ceiling,
+        20.0 * gain.log10()
+    );
+    gain
 }
 
-/// トラックをVocalとInstrumental（それ以外の組み合わせ）の2つに分離
-pub fn split_vocal_instrumental(model: &Demucs, input_path: &Path, output_dir: &Path) -> Result<Vec<PathBuf>> {
-    eprintln!("[split_vocal_instrumental] Starting vocal/instrumental separation");
-    
-    let track = decode_file(input_path)?;
-    let track = resample(track, model.config.sample_rate)?;
+/// 既存のstem WAVを合算してinstrumental.wavを作成（Demucsは実行しない）
+///
+/// ボーカル系（vocals/vocal）と既存のinstrumentalは合算対象から除外する。
+/// ファイル名を変えたstemも除外できるよう、ファイル名ではなくソース名（`stem_source_name`）で判定する
+pub fn build_instrumental_from_stems(
+    stem_paths: &[PathBuf],
+    output_dir: &Path,
+    export: &ExportConfig,
+) -> Result<PathBuf> {
+    let mut stem_buffers: Vec<Vec<Vec<f32>>> = Vec::new();
+    let mut format: Option<(usize, usize)> = None;
 
-    let input_arr: ArrayD<f32> = Array2::from_shape_vec(
-        (track.nb_channels, track.length),
-        track.samples.into_iter().flatten().collect(),
-    )
-    .unwrap()
-    .into_dyn();
+    for stem_path in stem_paths {
+        let source = stem_source_name(stem_path);
+        if matches!(source.as_str(), "vocals" | "vocal" | "instrumental") {
+            continue;
+        }
 
-    let mut input_tensor: Tensor = (&input_arr).try_into().context(TorchSnafu)?;
+        debug!("[build_instrumental_from_stems] Adding stem: {:?}", stem_path);
+        let stem = decode_stem_aligned(stem_path)?;
 
-    // 正規化
-    let mean_val: f32 = input_tensor.mean(Kind::Float).try_into().unwrap_or(0.0);
-    let std_val: f32 = input_tensor.std(true).try_into().unwrap_or(1.0);
-    let std_safe_val = if std_val < 1e-8 { 1e-8 } else { std_val };
+        match format {
+            None => format = Some((stem.sample_rate, stem.nb_channels)),
+            Some((sample_rate, nb_channels)) => {
+                if stem.sample_rate != sample_rate || stem.nb_channels != nb_channels {
+                    whatever!(
+                        "stem {} has a different format ({} Hz, {} ch) from the other stems ({} Hz, {} ch)",
+                        stem_path.display(),
+                        stem.sample_rate,
+                        stem.nb_channels,
+                        sample_rate,
+                        nb_channels
+                    );
+                }
+            }
+        }
 
-    input_tensor -= mean_val;
-    input_tensor /= std_safe_val;
+        stem_buffers.push(stem.samples);
+    }
 
-    let length = input_tensor.size().pop().unwrap();
-    let input = input_tensor.reshape([1, 2, length]);
+    let Some((sample_rate, nb_channels)) = format else {
+        whatever!("no non-vocal stems found to build the instrumental from");
+    };
 
-    let mut output = model.apply(input);
+    let mut samples = mix_stem_buffers(&stem_buffers, nb_channels);
+    normalize_summed_peak(&mut samples, SUMMED_PEAK_CEILING);
+    let length = samples[0].len();
 
-    // 非正規化
-    output *= std_safe_val;
-    output += mean_val;
+    let instrumental_path = output_dir.join("instrumental.wav");
+    export.encode_wav(
+        PcmAudioData {
+            samples,
+            sample_rate,
+            nb_channels,
+            length,
+        },
+        &instrumental_path,
+        None,
+    )?;
+    info!("[build_instrumental_from_stems] Saved instrumental.wav");
 
-    // Vocalとその他のstemのインデックスを特定
-    let vocal_idx = model.config.sources.iter().position(|s| s == "vocals");
-    let vocal_idx = vocal_idx.unwrap_or_else(|| {
-        eprintln!("[split_vocal_instrumental] WARNING: 'vocals' not found in sources, using first source");
-        0
-    });
+    Ok(instrumental_path)
+}
 
-    // Vocal stemを取得
-    let mut vocal_buffer: Vec<Vec<f32>> = vec![vec![0.0; track.length]; model.config.channels];
-    let vocal_out = output.i((0, vocal_idx as i64));
-    for i in 0..model.config.channels {
-        vocal_out.i(i as i64).copy_data(&mut vocal_buffer[i], track.length);
-    }
+/// ミックスダウンに使う1つのstemと、そのゲイン・パン
+#[derive(Debug, Clone)]
+pub struct MixdownStem {
+    pub path: PathBuf,
+    pub gain_db: f32,
+    /// -1.0（左）〜 1.0（右）
+    pub pan: f32,
+}
 
-    // Instrumental（それ以外すべての組み合わせ）を作成
-    let mut instrumental_buffer: Vec<Vec<f32>> = vec![vec![0.0; track.length]; model.config.channels];
-    
-    for (i, source) in model.config.sources.iter().enumerate() {
-        if source != "vocals" {
-            let mut stem_buffer: Vec<Vec<f32>> = vec![vec![0.0; track.length]; model.config.channels];
-            let stem_out = output.i((0, i as i64));
-            for ch in 0..model.config.channels {
-                stem_out.i(ch as i64).copy_data(&mut stem_buffer[ch], track.length);
-            }
-            
-            // Instrumentalに加算
-            for ch in 0..model.config.channels {
-                for j in 0..track.length {
-                    instrumental_buffer[ch][j] += stem_buffer[ch][j];
-                }
+/// stemにゲインとパンを適用して合算し、ソフトリミッターをかけて書き出す（出力は常にステレオ）
+///
+/// 長さの異なるstemは最長のstemに合わせてゼロパディングする
+pub fn render_mixdown(
+    stems: &[MixdownStem],
+    output_path: &Path,
+    format: ExportFormat,
+    export: &ExportConfig,
+) -> Result<PathBuf> {
+    const OUTPUT_CHANNELS: usize = 2;
+
+    let mut stem_buffers: Vec<Vec<Vec<f32>>> = Vec::with_capacity(stems.len());
+    let mut sample_rate: Option<usize> = None;
+
+    for stem in stems {
+        debug!(
+            "[render_mixdown] Adding stem: {:?} (gain: {} dB, pan: {})",
+            stem.path, stem.gain_db, stem.pan
+        );
+        let audio = decode_stem_aligned(&stem.path)?;
+
+        match sample_rate {
+            None => sample_rate = Some(audio.sample_rate),
+            Some(rate) if rate != audio.sample_rate => {
+                whatever!(
+                    "stem {} has a different sample rate ({} Hz) from the other stems ({} Hz)",
+                    stem.path.display(),
+                    audio.sample_rate,
+                    rate
+                );
             }
+            Some(_) => {}
         }
-    }
 
-    // Vocalの後処理
-    let mut processed_vocal = post_process_stem(&vocal_buffer, "vocals", model.config.sample_rate);
-    remove_clicks_pops(&mut processed_vocal, model.config.sample_rate);
+        // モノラルのstemは両チャンネルに複製してからパンを適用する
+        let mut channels = match audio.nb_channels {
+            1 => vec![audio.samples[0].clone(), audio.samples[0].clone()],
+            _ => audio.samples.into_iter().take(OUTPUT_CHANNELS).collect(),
+        };
 
-    // Instrumentalの後処理（"other"として処理）
-    let mut processed_instrumental = post_process_stem(&instrumental_buffer, "other", model.config.sample_rate);
-    remove_clicks_pops(&mut processed_instrumental, model.config.sample_rate);
+        let gain = 10f32.powf(stem.gain_db / 20.0);
+        let pan = stem.pan.clamp(-1.0, 1.0);
+        let (left_gain, right_gain) = ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0));
+        apply_gain(&mut channels[0], gain * left_gain);
+        apply_gain(&mut channels[1], gain * right_gain);
 
-    // WAVファイルとして保存
-    let vocal_data = PcmAudioData {
-        samples: processed_vocal,
-        sample_rate: model.config.sample_rate,
-        nb_channels: model.config.channels,
-        length: track.length,
+        stem_buffers.push(channels);
+    }
+
+    let Some(sample_rate) = sample_rate else {
+        whatever!("no stems to mix down (all stems are muted or missing)");
     };
-    let vocal_path = output_dir.join("vocal.wav");
-    encode_pcm_to_wav(vocal_data, &vocal_path)?;
-    eprintln!("[split_vocal_instrumental] Saved vocal.wav");
-
-    let instrumental_data = PcmAudioData {
-        samples: processed_instrumental,
-        sample_rate: model.config.sample_rate,
-        nb_channels: model.config.channels,
-        length: track.length,
+
+    let mut samples = mix_stem_buffers(&stem_buffers, OUTPUT_CHANNELS);
+    for channel in samples.iter_mut() {
+        apply_soft_limiter(channel);
+    }
+    let length = samples[0].len();
+
+    let audio = PcmAudioData {
+        samples,
+        sample_rate,
+        nb_channels: OUTPUT_CHANNELS,
+        length,
     };
-    let instrumental_path = output_dir.join("instrumental.wav");
-    encode_pcm_to_wav(instrumental_data, &instrumental_path)?;
-    eprintln!("[split_vocal_instrumental] Saved instrumental.wav");
+    // ビット深度とディザの設定はWAVにだけ適用する（FLACは常に24bit）
+    match format {
+        ExportFormat::Wav => export.encode_wav(audio, output_path, None)?,
+        _ => encode_pcm(audio, output_path, format)?,
+    }
+    info!("[render_mixdown] Saved mixdown to {:?}", output_path);
+
+    Ok(output_path.to_path_buf())
+}
+
+/// stemに帯域フィルタだけを掛けたプレビューを書き出す（モデルは実行しない）
+///
+/// 分離し直さずにフィルタの設定を試聴するため。元のstemは変更しない
+pub fn render_filter_preview(
+    stem_path: &Path,
+    filters: &[StemFilter],
+    output_path: &Path,
+) -> Result<PathBuf> {
+    let mut audio = decode_file(stem_path)?;
+    for filter in filters {
+        if let Err(reason) = filter.validate(audio.sample_rate) {
+            whatever!("{}", reason);
+        }
+        for channel in audio.samples.iter_mut() {
+            filter.apply(channel, audio.sample_rate);
+        }
+    }
+
+    encode_pcm_to_wav(audio, output_path, WavBitDepth::Float32, None)?;
+    info!("[render_filter_preview] Saved filter preview to {:?}", output_path);
 
-    Ok(vec![vocal_path, instrumental_path])
+    Ok(output_path.to_path_buf())
+}
+
+/// モデルに設定された後処理（models.jsonで指定がなければモデル名から決まるデフォルト）
+fn model_post_process(model: &Demucs) -> PostProcessConfig {
+    model.config.post_process.clone().unwrap_or_default()
 }
 
 /// 後処理: 各stemタイプに応じたフィルタリング
@@ -218,54 +2194,83 @@ fn post_process_stem(
     buffer: &[Vec<f32>],
     stem_type: &str,
     sample_rate: usize,
+    config: &PostProcessConfig,
 ) -> Vec<Vec<f32>> {
     let mut processed = buffer.to_vec();
     
-    match stem_type {
-        "other" => {
-            // other.wavのゴワゴワ感を改善するため、ノイズリダクションを適用
-            // ハイパスフィルタで低周波ノイズを除去し、中周波数帯域を強調
-            for channel in processed.iter_mut() {
-                apply_high_pass_filter(channel, sample_rate, 80.0); // 80Hz以下をカット
-                apply_noise_reduction(channel, sample_rate);
-            }
-        }
-        "bass" => {
-            // ベースの低周波数帯域を保持（音量調整は行わない - 正確な分離結果を尊重）
-            // 必要に応じて、ユーザーが後からミキシング/マスタリングで調整可能
-            for channel in processed.iter_mut() {
-                // 低周波数を保持しつつ、不要な高周波ノイズを軽減（フィルタリングのみ、音量調整なし）
-                apply_low_pass_filter(channel, sample_rate, 400.0); // 400Hz以上をカット（周波数フィルタリングのみ）
-            }
+    // stemの種類ごとの帯域フィルタ（周波数フィルタリングのみ、音量調整は行わない）
+    if let Some(filter) = config.filters.get(stem_type) {
+        for channel in processed.iter_mut() {
+            filter.apply(channel, sample_rate);
         }
-        "vocals" => {
-            // ボーカルの中周波数帯域を強調（300-3400Hz）
-            for channel in processed.iter_mut() {
-                apply_band_pass_filter(channel, sample_rate, 300.0, 3400.0);
-            }
-        }
-        "drums" => {
-            // ドラムは広帯域を維持
-            // 特に処理なし
+    }
+
+    // ドラムは広帯域を維持（設定されている場合のみアタックを強調）
+    if stem_type == "drums" && config.transient_amount > 0.0 {
+        for channel in processed.iter_mut() {
+            enhance_transients(channel, sample_rate, config.transient_amount);
         }
-        "guitar" => {
-            // ギターの中高周波数帯域を強調（80-8000Hz）
+    }
+
+    // 設定されたstemにだけノイズリダクションを適用
+    match config.noise_reduction_for(stem_type) {
+        NoiseReductionMode::Off => {}
+        NoiseReductionMode::MovingAverage => {
             for channel in processed.iter_mut() {
-                apply_band_pass_filter(channel, sample_rate, 80.0, 8000.0);
+                apply_noise_reduction(channel, sample_rate);
             }
         }
-        "piano" => {
-            // ピアノの広帯域を維持（80-15000Hz）
+        NoiseReductionMode::SpectralGate => {
             for channel in processed.iter_mut() {
-                apply_band_pass_filter(channel, sample_rate, 80.0, 15000.0);
+                apply_spectral_gate(channel);
             }
         }
-        _ => {}
     }
     
     processed
 }
 
+/// トランジェントシェイパー: 立ち上がり（オンセット）だけを一時的に持ち上げる
+///
+/// 速いエンベロープと遅いエンベロープの差でアタックを検出するため、
+/// 減衰部分（サステイン）では差がほぼ0になりゲインは変わらない
+fn enhance_transients(samples: &mut [f32], sample_rate: usize, amount: f32) {
+    let amount = amount.clamp(0.0, 1.0);
+    if amount == 0.0 || sample_rate == 0 {
+        return;
+    }
+
+    let coefficient = |ms: f32| (-1.0 / (sample_rate as f32 * ms / 1000.0)).exp();
+    let fast_coef = coefficient(1.0); // 1ms
+    let slow_coef = coefficient(20.0); // 20ms
+    let gain_release = coefficient(50.0); // ブーストを戻す速さ（クリック音を避ける）
+
+    let mut fast_env = 0.0f32;
+    let mut slow_env = 0.0f32;
+    let mut gain = 1.0f32;
+
+    for sample in samples.iter_mut() {
+        let level = sample.abs();
+        fast_env = level + fast_coef * (fast_env - level);
+        slow_env = level + slow_coef * (slow_env - level);
+
+        // 立ち上がりの度合い（0〜1）
+        let transient = if fast_env > 1e-6 {
+            ((fast_env - slow_env) / fast_env).max(0.0)
+        } else {
+            0.0
+        };
+        let target = 1.0 + amount * transient;
+        gain = if target > gain {
+            target
+        } else {
+            target + gain_release * (gain - target)
+        };
+
+        *sample = (*sample * gain).clamp(-1.0, 1.0);
+    }
+}
+
 /// ハイパスフィルタ: 低周波数をカット
 fn apply_high_pass_filter(samples: &mut [f32], sample_rate: usize, cutoff: f32) {
     let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
@@ -337,9 +2342,12 @@ fn apply_gain(samples: &mut [f32], gain: f32) {
 }
 
 /// ソフトリミッター: クリッピングを防ぎつつ、音を自然に保持
-fn apply_soft_limiter(samples: &mut [f32]) {
+///
+/// 戻り値は圧縮したサンプル数
+fn apply_soft_limiter(samples: &mut [f32]) -> usize {
     let threshold = 0.95; // リミッターの閾値
     let ratio = 0.1; // 圧縮比（閾値を超えた部分をどれだけ圧縮するか）
+    let mut limited = 0;
     
     for sample in samples.iter_mut() {
         let abs_val = sample.abs();
@@ -348,16 +2356,59 @@ fn apply_soft_limiter(samples: &mut [f32]) {
             let excess = abs_val - threshold;
             let compressed = threshold + excess * ratio;
             *sample = compressed * sample.signum();
+            limited += 1;
         }
     }
+
+    limited
+}
+
+/// リミッターでどれだけ音が潰れたか（stemがどの程度加工されたかの目安）
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LimiterMetering {
+    /// リミッター前のピーク（全チャンネルの最大振幅）
+    pub peak_before: f32,
+    /// リミッター後のピーク
+    pub peak_after: f32,
+    /// ピークでのゲインリダクション（dB、0なら圧縮されていない）
+    pub gain_reduction_db: f32,
+    /// 圧縮されたサンプル数（全チャンネルの合計）
+    pub limited_samples: usize,
+}
+
+/// 全チャンネルにソフトリミッターを掛け、前後のピークと圧縮したサンプル数を返す
+fn limit_with_metering(samples: &mut [Vec<f32>]) -> LimiterMetering {
+    let peak_before = peak_amplitude(samples);
+    let limited_samples = samples
+        .iter_mut()
+        .map(|channel| apply_soft_limiter(channel))
+        .sum();
+    let peak_after = peak_amplitude(samples);
+
+    let gain_reduction_db = if peak_after > 0.0 {
+        20.0 * (peak_before / peak_after).log10()
+    } else {
+        0.0
+    };
+
+    LimiterMetering {
+        peak_before,
+        peak_after,
+        gain_reduction_db,
+        limited_samples,
+    }
 }
 
 /// クリック/ポップノイズを除去（デジタルクリップ検出と修正）
-fn remove_clicks_pops(samples: &mut [Vec<f32>], sample_rate: usize) {
-    let threshold = 0.9; // クリップの閾値
+fn remove_clicks_pops(samples: &mut [Vec<f32>], sample_rate: usize, config: &ClickRemovalConfig) {
+    let threshold = config.threshold; // クリップの閾値
+    let ratio = config.neighbor_ratio;
     let window_size = (sample_rate as f32 * 0.001) as usize; // 1ms
     
     for channel in samples.iter_mut() {
+        if window_size == 0 || channel.len() <= window_size * 2 {
+            continue;
+        }
         for i in window_size..(channel.len() - window_size) {
             let current = channel[i].abs();
             
@@ -375,7 +2426,7 @@ fn remove_clicks_pops(samples: &mut [Vec<f32>], sample_rate: usize) {
                     / window_size as f32;
                 
                 // 前後の平均と大きく異なる場合はクリック/ポップと判断
-                if current > prev_avg * 3.0 || current > next_avg * 3.0 {
+                if current > prev_avg * ratio || current > next_avg * ratio {
                     // 前後の平均で補間
                     channel[i] = (prev_avg + next_avg) / 2.0 * channel[i].signum();
                 }
@@ -385,12 +2436,25 @@ fn remove_clicks_pops(samples: &mut [Vec<f32>], sample_rate: usize) {
 }
 
 pub fn get_cover_image(path: &Path, output_dir: &Path) -> Result<Option<PathBuf>> {
-    let tags = id3::Tag::read_from_path(path).context(Id3Snafu)?;
+    let tags = match id3::Tag::read_from_path(path) {
+        Ok(tags) => tags,
+        // タグが存在しないファイル（WAVなど）はカバー画像なしとして扱う
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => return Ok(None),
+        Err(e) => return Err(e).context(Id3Snafu),
+    };
 
     let output = if let Some(image) = tags.pictures().next() {
         let mime: Mime = image.mime_type.parse().context(MimeParseSnafu)?;
-        if mime.type_() == IMAGE && mime.subtype() == JPEG {
-            let path = output_dir.join("cover.jpg");
+        let file_name = if mime.type_() == IMAGE && mime.subtype() == JPEG {
+            Some("cover.jpg")
+        } else if mime.type_() == IMAGE && mime.subtype() == PNG {
+            Some("cover.png")
+        } else {
+            None
+        };
+
+        if let Some(file_name) = file_name {
+            let path = output_dir.join(file_name);
             let mut output = whatever!(
                 File::options()
                     .create(true)
@@ -413,3 +2477,427 @@ pub fn get_cover_image(path: &Path, output_dir: &Path) -> Result<Option<PathBuf>
 
     output
 }
+
+/// 波形サムネイルの背景色と波形の色
+const THUMBNAIL_BACKGROUND: [u8; 3] = [0x1C, 0x1C, 0x1F];
+const THUMBNAIL_FOREGROUND: [u8; 3] = [0x8D, 0x8D, 0x92];
+
+/// カバー画像がない曲のために、波形を描いたPNGを `cover.png` として書き出す
+///
+/// 横1ピクセルあたり1区間のピークだけを計算するため、曲の長さに関わらず高速に描画できる
+pub fn generate_waveform_thumbnail(
+    path: &Path,
+    output_dir: &Path,
+    width: u32,
+    height: u32,
+) -> Result<PathBuf> {
+    if width == 0 || height == 0 {
+        whatever!("thumbnail size must be non-zero ({}x{})", width, height);
+    }
+
+    let peaks = waveform_peaks(path, width as usize)?;
+    let mut thumbnail =
+        image::RgbImage::from_pixel(width, height, image::Rgb(THUMBNAIL_BACKGROUND));
+
+    // 振幅の最大値で正規化し、小さい音の曲でも波形が見えるようにする
+    let max_peak = peaks.iter().fold(0.0f32, |max, p| max.max(p.abs())).max(1e-6);
+    let center = (height - 1) as f32 / 2.0;
+
+    for (x, bucket) in peaks.chunks_exact(2).enumerate() {
+        let (min, max) = (bucket[0] / max_peak, bucket[1] / max_peak);
+        let top = (center - max * center).round().clamp(0.0, (height - 1) as f32) as u32;
+        let bottom = (center - min * center).round().clamp(0.0, (height - 1) as f32) as u32;
+
+        for y in top..=bottom.max(top) {
+            thumbnail.put_pixel(x as u32, y, image::Rgb(THUMBNAIL_FOREGROUND));
+        }
+    }
+
+    let output_path = output_dir.join("cover.png");
+    whatever!(thumbnail.save(&output_path), "failed to write waveform thumbnail");
+
+    Ok(output_path)
+}
+
+/// プロジェクトディレクトリから抽出済みのカバー画像を探す
+pub fn find_cover_image(project_dir: &Path) -> Option<PathBuf> {
+    ["cover.jpg", "cover.png"]
+        .iter()
+        .map(|name| project_dir.join(name))
+        .find(|path| path.exists())
+}
+
+/// カバー画像をAPICフレームとしてstemに埋め込む（MP3のみ対応、それ以外はスキップ）
+pub fn embed_cover_art(stem_path: &Path, cover_path: &Path) -> Result<bool> {
+    let is_mp3 = stem_path
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("mp3"));
+    if !is_mp3 {
+        return Ok(false);
+    }
+
+    let mime_type = match cover_path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("png") => "image/png",
+        _ => "image/jpeg",
+    };
+    let data = whatever!(std::fs::read(cover_path), "failed to read cover image");
+
+    let mut tag = match id3::Tag::read_from_path(stem_path) {
+        Ok(tag) => tag,
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => id3::Tag::new(),
+        Err(e) => return Err(e).context(Id3Snafu),
+    };
+
+    tag.remove_picture_by_type(id3::frame::PictureType::CoverFront);
+    tag.add_frame(id3::frame::Picture {
+        mime_type: mime_type.to_string(),
+        picture_type: id3::frame::PictureType::CoverFront,
+        description: String::new(),
+        data,
+    });
+    tag.write_to_path(stem_path, id3::Version::Id3v24)
+        .context(Id3Snafu)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("stemsplit-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_stem(path: &Path, samples: Vec<Vec<f32>>) {
+        let audio = PcmAudioData {
+            nb_channels: samples.len(),
+            length: samples[0].len(),
+            samples,
+            sample_rate: 44100,
+        };
+        encode_pcm_to_wav(audio, path, WavBitDepth::Float32, None).unwrap();
+    }
+
+    #[test]
+    fn decode_stem_aligned_restores_trimmed_offsets() {
+        let dir = temp_dir("aligned");
+        write_stem(&dir.join("vocals.wav"), vec![vec![0.5; 100]; 2]);
+        write_stem(&dir.join("drums.wav"), vec![vec![0.25; 300]; 2]);
+        fs::write(
+            dir.join(STEM_OFFSETS_FILE_NAME),
+            r#"{"vocals": {"start_sample": 200, "end_sample": 300, "offset_secs": 0.0045}}"#,
+        )
+        .unwrap();
+
+        let vocals = decode_stem_aligned(&dir.join("vocals.wav")).unwrap();
+        assert_eq!(vocals.length, 300);
+        assert!(vocals.samples.iter().all(|channel| channel.len() == 300));
+        assert!(vocals.samples[0][..200].iter().all(|&s| s == 0.0));
+        assert_eq!(vocals.samples[0][200], 0.5);
+
+        // 記録のないstemはそのまま
+        let drums = decode_stem_aligned(&dir.join("drums.wav")).unwrap();
+        assert_eq!(drums.length, 300);
+        assert_eq!(drums.samples[1][0], 0.25);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn build_instrumental_sums_trimmed_stems_at_their_original_position() {
+        let dir = temp_dir("instrumental-aligned");
+        let mut bass = vec![0.0; 300];
+        bass[250] = 0.5;
+        write_stem(&dir.join("bass.wav"), vec![bass.clone(), bass]);
+        // 先頭200サンプルを切り取ったdrums（元の位置では250サンプル目）
+        let mut drums = vec![0.0; 100];
+        drums[50] = 0.25;
+        write_stem(&dir.join("drums.wav"), vec![drums.clone(), drums]);
+        fs::write(
+            dir.join(STEM_OFFSETS_FILE_NAME),
+            r#"{"drums": {"start_sample": 200, "end_sample": 300, "offset_secs": 0.0045}}"#,
+        )
+        .unwrap();
+
+        let stem_paths = vec![dir.join("bass.wav"), dir.join("drums.wav")];
+        let path =
+            build_instrumental_from_stems(&stem_paths, &dir, &ExportConfig::default()).unwrap();
+        let instrumental = decode_file(&path).unwrap();
+        assert!((instrumental.samples[0][250] - 0.75).abs() < 1e-3);
+        assert!(instrumental.samples[0][50].abs() < 1e-3);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn build_instrumental_excludes_renamed_vocals() {
+        let dir = temp_dir("instrumental-renamed");
+        let sources: Vec<String> = ["vocals", "drums"].iter().map(|s| s.to_string()).collect();
+        let naming = HashMap::from([(String::from("vocals"), String::from("01_lead"))]);
+        let file_names = stem_file_names(&sources, &naming).unwrap();
+        write_stem_sources(&dir, &file_names).unwrap();
+        write_stem(&dir.join("01_lead.wav"), vec![vec![0.5; 100]; 2]);
+        write_stem(&dir.join("drums.wav"), vec![vec![0.25; 100]; 2]);
+
+        assert_eq!(stem_source_name(&dir.join("01_lead.wav")), "vocals");
+        assert_eq!(stem_source_name(&dir.join("drums.wav")), "drums");
+        // 記録のないファイルはファイル名のまま
+        assert_eq!(
+            stem_source_name(&dir.join("instrumental.wav")),
+            "instrumental"
+        );
+
+        let stem_paths = vec![dir.join("01_lead.wav"), dir.join("drums.wav")];
+        let path =
+            build_instrumental_from_stems(&stem_paths, &dir, &ExportConfig::default()).unwrap();
+        let instrumental = decode_file(&path).unwrap();
+        assert!((instrumental.samples[0][10] - 0.25).abs() < 1e-3);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn multichannel_stem_file_is_detected_by_its_channel_map() {
+        let dir = temp_dir("multichannel-detect");
+        let stem = |value: f32| PcmAudioData {
+            samples: vec![vec![value; 100]; 2],
+            sample_rate: 44100,
+            nb_channels: 2,
+            length: 100,
+        };
+        let path = write_multichannel_stems(
+            &dir,
+            &["vocals", "drums"],
+            vec![stem(0.5), stem(0.25)],
+            WavBitDepth::Float32,
+        )
+        .unwrap();
+        assert!(is_multichannel_stem_file(&path));
+        assert!(!is_multichannel_stem_file(&dir.join("vocals.wav")));
+
+        // 対応の記録がなければ、たまたま同じ名前の普通のstem
+        fs::remove_file(dir.join(MULTICHANNEL_MAP_FILE_NAME)).unwrap();
+        assert!(!is_multichannel_stem_file(&path));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn normalize_summed_peak_stays_below_full_scale() {
+        // 0dBFSのピークが重なった合算
+        let stems = vec![
+            vec![vec![1.0, -0.5, 0.25]; 2],
+            vec![vec![0.6, -0.5, 0.0]; 2],
+        ];
+        let mut summed = mix_stem_buffers(&stems, 2);
+
+        let gain = normalize_summed_peak(&mut summed, 1.0);
+        let peak = peak_amplitude(&summed);
+        assert!(peak < 1.0, "summed peak {} reached full scale", peak);
+        assert!((peak - SUMMED_PEAK_CEILING).abs() < 1e-6);
+        assert!((gain - SUMMED_PEAK_CEILING / 1.6).abs() < 1e-6);
+
+        // 上限以下の合算はそのまま
+        let mut quiet = vec![vec![0.5, -0.25]; 2];
+        assert_eq!(normalize_summed_peak(&mut quiet, 1.0), 1.0);
+        assert_eq!(quiet, vec![vec![0.5, -0.25]; 2]);
+    }
+
+    #[test]
+    fn mono_input_is_duplicated_to_the_model_channels() {
+        let dir = temp_dir("mono-input");
+        let path = dir.join("mono.wav");
+        write_stem(&path, vec![sine(0.5, 440.0, 44100, 4410)]);
+
+        let mono = decode_file(&path).unwrap();
+        assert_eq!(mono.nb_channels, 1);
+        let track = match_model_channels(mono, 2).unwrap();
+        assert_eq!(track.nb_channels, 2);
+        assert_eq!(track.length, 4410);
+        assert_eq!(track.samples.len(), 2);
+        assert_eq!(track.samples[0], track.samples[1]);
+        assert_eq!(track.samples[0].len(), 4410);
+
+        // ステレオをモノラルのモデルに渡す場合は左右の平均
+        let stereo = PcmAudioData {
+            samples: vec![vec![0.5; 10], vec![0.25; 10]],
+            sample_rate: 44100,
+            nb_channels: 2,
+            length: 10,
+        };
+        let downmixed = match_model_channels(stereo, 1).unwrap();
+        assert_eq!(downmixed.samples, vec![vec![0.375; 10]]);
+
+        let surround = PcmAudioData {
+            samples: vec![vec![0.0; 10]; 6],
+            sample_rate: 44100,
+            nb_channels: 6,
+            length: 10,
+        };
+        assert!(match_model_channels(surround, 2).is_err());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn half_second_clip_is_padded_and_cropped_back() {
+        const SAMPLE_RATE: usize = 44100;
+        let length = SAMPLE_RATE / 2;
+        ensure_separable_length(length, SAMPLE_RATE).unwrap();
+        assert!(ensure_separable_length(0, SAMPLE_RATE).is_err());
+
+        let clip = sine(0.5, 440.0, SAMPLE_RATE, length);
+        let input =
+            Tensor::from_slice(&[clip.clone(), clip].concat()).reshape([1, 2, length as i64]);
+        let min_frames = (MIN_SEPARATION_SECS * SAMPLE_RATE as f64).ceil() as i64;
+        let padded = pad_to_min_frames(input.shallow_clone(), min_frames, -0.25);
+        assert_eq!(padded.size(), [1, 2, min_frames]);
+        assert!(padded.narrow(2, 0, length as i64).equal(&input));
+        let tail = padded.narrow(2, length as i64, min_frames - length as i64);
+        assert_eq!(tail.min().double_value(&[]), -0.25);
+        assert_eq!(tail.max().double_value(&[]), -0.25);
+
+        // 4ソース分の出力を模して、推論後に元の長さへ戻ることを確かめる
+        let output = padded.unsqueeze(1).repeat([1, 4, 1, 1]);
+        let cropped = crop_output(output, length as i64);
+        assert_eq!(cropped.size(), [1, 4, 2, length as i64]);
+        assert!(cropped.get(0).get(3).equal(&input.get(0)));
+
+        // 十分に長い入力はそのまま
+        let long = Tensor::zeros([1, 2, min_frames + 1], (Kind::Float, Device::Cpu));
+        assert_eq!(
+            pad_to_min_frames(long, min_frames, 0.0).size(),
+            [1, 2, min_frames + 1]
+        );
+    }
+
+    fn sine(amplitude: f32, frequency: f32, sample_rate: usize, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn remove_clicks_pops_interpolates_an_inserted_spike() {
+        let mut samples = vec![sine(0.1, 440.0, 44100, 4410)];
+        samples[0][2000] = 1.0;
+
+        remove_clicks_pops(&mut samples, 44100, &ClickRemovalConfig::default());
+
+        assert!(
+            samples[0][2000].abs() < 0.2,
+            "spike was left at {}",
+            samples[0][2000]
+        );
+    }
+
+    #[test]
+    fn remove_clicks_pops_keeps_a_loud_signal() {
+        // ピークが閾値（0.9）を超えるが、前後も同じくらい大きい正当な信号
+        let original = vec![sine(0.95, 440.0, 44100, 4410)];
+        let mut samples = original.clone();
+
+        remove_clicks_pops(&mut samples, 44100, &ClickRemovalConfig::default());
+
+        assert_eq!(samples, original);
+    }
+
+    #[test]
+    fn click_removal_can_be_disabled_per_stem() {
+        let config = ClickRemovalConfig {
+            disabled_stems: vec![String::from("drums")],
+            ..ClickRemovalConfig::default()
+        };
+        assert!(!config.is_enabled_for("drums"));
+        assert!(config.is_enabled_for("vocals"));
+    }
+
+    /// `range` の区間の最大振幅
+    fn peak_in(samples: &[f32], range: std::ops::Range<usize>) -> f32 {
+        samples[range]
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn enhance_transients_boosts_onsets_more_than_the_decay() {
+        const SAMPLE_RATE: usize = 44100;
+        let ms = |millis: usize| SAMPLE_RATE * millis / 1000;
+
+        // 100msの無音の後に、150Hzで150msかけて減衰するドラムのようなヒット
+        let onset = ms(100);
+        let mut original = vec![0.0f32; onset];
+        original.extend((0..SAMPLE_RATE).map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            0.4 * (-t / 0.15).exp() * (2.0 * std::f32::consts::PI * 150.0 * t).sin()
+        }));
+        let mut samples = original.clone();
+
+        enhance_transients(&mut samples, SAMPLE_RATE, 1.0);
+
+        let gain_in = |range: std::ops::Range<usize>| {
+            peak_in(&samples, range.clone()) / peak_in(&original, range)
+        };
+        let attack_gain = gain_in(onset..onset + ms(10));
+        let decay_gain = gain_in(onset + ms(200)..onset + ms(300));
+        assert!(
+            attack_gain > 1.5,
+            "onset was boosted by only {}",
+            attack_gain
+        );
+        assert!(
+            attack_gain > decay_gain * 1.3,
+            "onset gain {} is not above the decay gain {}",
+            attack_gain,
+            decay_gain
+        );
+    }
+
+    #[test]
+    fn enhance_transients_with_zero_amount_is_a_no_op() {
+        let original = sine(0.5, 150.0, 44100, 4410);
+        let mut samples = original.clone();
+        enhance_transients(&mut samples, 44100, 0.0);
+        assert_eq!(samples, original);
+    }
+
+    #[test]
+    fn split_options_read_export_settings_from_top_level_fields() {
+        let options: SplitOptions =
+            serde_json::from_str(r#"{"bit_depth": "int16", "dither": false, "mono": true}"#)
+                .unwrap();
+        assert_eq!(
+            options.export,
+            ExportConfig {
+                bit_depth: WavBitDepth::Int16,
+                dither: false,
+            }
+        );
+        assert!(options.mono);
+
+        let defaults: SplitOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(defaults.export, ExportConfig::default());
+    }
+
+    #[test]
+    fn source_parity_measures_the_device_and_cpu_difference() {
+        let cpu = vec![vec![0.0f32; 2]; 2];
+        let identical = source_parity("vocals", &cpu, &cpu);
+        assert_eq!(identical.max_abs_diff, 0.0);
+        assert_eq!(identical.rms_diff, 0.0);
+
+        let device = vec![vec![0.5, 0.0], vec![0.0, -0.5]];
+        let parity = source_parity("vocals", &device, &cpu);
+        assert_eq!(parity.source, "vocals");
+        assert_eq!(parity.max_abs_diff, 0.5);
+        assert_eq!(parity.mean_abs_diff, 0.25);
+        assert!((parity.rms_diff - 0.125f64.sqrt()).abs() < 1e-9);
+        assert!(parity.rms_diff > PARITY_RMS_TOLERANCE);
+    }
+}
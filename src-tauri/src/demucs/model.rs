@@ -7,24 +7,37 @@ use tch::{nn::ModuleT, CModule, Device, IndexOp, Tensor};
 
 use std::{
     cmp::{max, min},
+    collections::HashSet,
     fs::File,
     ops::AddAssign,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use super::error::{Result, TorchSnafu};
+use super::download::verify_checksum;
+use super::error::{Error, Result, TorchSnafu};
+use super::{default_post_process_for, PostProcessConfig};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ModelConfig {
     pub sample_rate: usize,
     pub sources: Vec<String>,
     pub channels: usize,
+    /// このモデル向けのstemの後処理（models.jsonで省略した場合は `default_post_process_for` の値）
+    #[serde(default)]
+    pub post_process: Option<PostProcessConfig>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ModelInfo {
     pub name: String,
     pub config: ModelConfig,
+    /// モデルの重み（.pt）のダウンロード元（同梱していないモデル用）
+    #[serde(default)]
+    pub url: Option<String>,
+    /// ダウンロードした重みのSHA-256（16進数）
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 #[derive(Debug)]
@@ -35,29 +48,98 @@ pub struct Demucs {
 }
 
 /// モデルの遅延ロードを管理する構造体
+///
+/// ロードしたモデルは `Arc` で共有するため、Mutexを保持するのはロード（と設定の変更）の間だけでよい。
+/// アンロードやデバイスの変更は保持している `Arc` を差し替えるだけなので、実行中の推論には影響しない
 #[derive(Debug)]
 pub struct LazyModelLoader {
     pub model_info: ModelInfo,
     pub model_path: PathBuf,
     pub device: Device,
-    pub loaded_model: Option<Demucs>,
+    pub loaded_model: Option<Arc<Demucs>>,
+    /// SHA-256を確認済みのモデルファイル（確認はファイルごとに一度だけ行う）
+    pub verified_paths: HashSet<PathBuf>,
 }
 
 pub fn models(path: &Path) -> Result<Vec<ModelInfo>> {
     let models_json = whatever!(File::open(path), "failed to read models.json");
 
-    let models: Vec<ModelInfo> = whatever!(
+    // エントリごとに検証して、どのモデルの設定が壊れているかを分かるようにする
+    let entries: Vec<serde_json::Value> = whatever!(
         serde_json::from_reader(models_json),
         "failed to read models.json"
     );
 
-    Ok(models)
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| parse_model_entry(index, entry))
+        .collect()
+}
+
+/// models.jsonの1エントリをパースし、設定値が妥当か検証する
+fn parse_model_entry(index: usize, entry: serde_json::Value) -> Result<ModelInfo> {
+    let name = entry
+        .get("name")
+        .and_then(serde_json::Value::as_str)
+        .map_or_else(|| format!("#{}", index), ToString::to_string);
+
+    let mut info: ModelInfo =
+        serde_json::from_value(entry).map_err(|e| Error::InvalidModelConfigError {
+            name: name.clone(),
+            reason: e.to_string(),
+        })?;
+
+    let invalid = |reason: &str| Error::InvalidModelConfigError {
+        name: name.clone(),
+        reason: reason.to_string(),
+    };
+
+    if info.config.sources.is_empty() {
+        return Err(invalid("\"sources\" must not be empty"));
+    }
+    if info.config.sources.iter().any(|source| source.trim().is_empty()) {
+        return Err(invalid("\"sources\" must not contain empty names"));
+    }
+    if info.config.sample_rate == 0 {
+        return Err(invalid("\"sample_rate\" must be positive"));
+    }
+    if !matches!(info.config.channels, 1 | 2) {
+        return Err(invalid("\"channels\" must be 1 or 2"));
+    }
+    if let Some(sha256) = &info.sha256 {
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid("\"sha256\" must be 64 hex characters"));
+        }
+    }
+
+    if info.config.post_process.is_none() {
+        info.config.post_process = Some(default_post_process_for(&info.name));
+    }
+
+    Ok(info)
 }
 
 pub fn find_model(models: Vec<ModelInfo>, name: &str) -> Option<ModelInfo> {
     models.iter().find(|m| m.name == name).cloned()
 }
 
+/// `required` のソースをすべて出力できる最初のモデルを返す（"6 stemで分けたい" のように名前ではなくstemで選ぶ）
+pub fn find_model_by_sources(models: Vec<ModelInfo>, required: &[&str]) -> Option<ModelInfo> {
+    models.into_iter().find(|m| {
+        required
+            .iter()
+            .all(|source| m.config.sources.iter().any(|s| s == source))
+    })
+}
+
+/// デフォルトのシフト数
+pub const DEFAULT_SHIFTS: usize = 1;
+/// デフォルトのセグメント間のオーバーラップ
+/// プツノイズを減らすため、0.25から0.5に増やしてセグメント間の境界を滑らかにしている
+pub const DEFAULT_OVERLAP: f32 = 0.5;
+const MAX_OVERLAP: f32 = 0.95;
+
 impl Demucs {
     pub fn init(path: &Path, info: &ModelInfo, device: Device) -> Result<Self> {
         let config = info.config.clone();
@@ -77,6 +159,14 @@ impl Demucs {
     }
 
     pub fn apply(&self, input: Tensor) -> Tensor {
+        self.apply_with(input, DEFAULT_SHIFTS, DEFAULT_OVERLAP)
+    }
+
+    /// シフト数とオーバーラップを指定して推論する
+    ///
+    /// shiftsはランダムにずらした入力での推論回数で、結果を平均する（0でずらさない）。
+    /// 推論時間はおおよそshiftsに比例して増える（shifts=4なら1の約4倍）
+    pub fn apply_with(&self, input: Tensor, shifts: usize, overlap: f32) -> Tensor {
         assert_eq!(
             input.dim(),
             3,
@@ -88,11 +178,10 @@ impl Demucs {
         self._apply(
             TensorChunk::new(&input, 0, None),
             ApplyArgs {
-                shifts: 1,
+                shifts,
                 split: true,
-                // プツノイズを減らすため、overlapを増やしてtransition_powerを上げる
-                // overlap: 0.25 -> 0.5: セグメント間のオーバーラップを増やして境界を滑らかに
-                overlap: 0.5,
+                // strideが0にならないように上限を設ける
+                overlap: overlap.clamp(0.0, MAX_OVERLAP),
                 // transition_power: 1.0 -> 2.0: 遷移をより滑らかにしてプツノイズを減らす
                 transition_power: 2.0,
                 device: self.device,
@@ -213,29 +302,76 @@ impl LazyModelLoader {
             model_path,
             device,
             loaded_model: None,
+            verified_paths: HashSet::new(),
         }
     }
 
     /// モデルをロードする（既にロード済みの場合は再利用）
-    pub fn get_or_load(&mut self) -> Result<&mut Demucs> {
+    pub fn get_or_load(&mut self) -> Result<Arc<Demucs>> {
         if self.loaded_model.is_none() {
             eprintln!("[LazyModelLoader] Loading model on demand...");
+            self.verify_model_file()?;
             let model = Demucs::init(&self.model_path, &self.model_info, self.device)?;
-            self.loaded_model = Some(model);
+            self.loaded_model = Some(Arc::new(model));
         } else {
             eprintln!("[LazyModelLoader] Reusing already loaded model");
         }
         
-        Ok(self.loaded_model.as_mut().unwrap())
+        Ok(Arc::clone(self.loaded_model.as_ref().unwrap()))
+    }
+
+    /// 途中で切れたダウンロードなどで壊れた重みをtchに渡さないよう、models.jsonの `sha256` と照合する
+    fn verify_model_file(&mut self) -> Result<()> {
+        if self.verified_paths.contains(&self.model_path) {
+            return Ok(());
+        }
+        if !self.model_path.exists() {
+            return Err(Error::ModelNotFoundError {
+                name: self.model_info.name.clone(),
+            });
+        }
+
+        verify_checksum(&self.model_path, self.model_info.sha256.as_deref()).map_err(|reason| {
+            eprintln!(
+                "[LazyModelLoader] Model file {:?} failed verification: {}",
+                self.model_path, reason
+            );
+            Error::ModelCorruptError {
+                name: self.model_info.name.clone(),
+                path: self.model_path.clone(),
+                reason,
+            }
+        })?;
+
+        self.verified_paths.insert(self.model_path.clone());
+        Ok(())
     }
 
     /// モデルを明示的にアンロードしてメモリを解放
+    ///
+    /// 推論中のリクエストが `Arc` を保持している場合、メモリはその推論が終わった時点で解放される
     pub fn unload(&mut self) {
         if self.loaded_model.is_some() {
             eprintln!("[LazyModelLoader] Unloading model to free memory");
             self.loaded_model = None;
         }
     }
+
+    /// 使用するモデルを切り替え、切り替え前のモデルの情報とパスを返す
+    ///
+    /// ロード済みのモデルは破棄し、次回の分離時に新しいモデルをロードする
+    pub fn set_model(&mut self, model_info: ModelInfo, model_path: PathBuf) -> (ModelInfo, PathBuf) {
+        eprintln!(
+            "[LazyModelLoader] Switching model from {} to {}",
+            self.model_info.name, model_info.name
+        );
+        if self.model_info.name != model_info.name || self.model_path != model_path {
+            self.unload();
+        }
+        let previous_info = std::mem::replace(&mut self.model_info, model_info);
+        let previous_path = std::mem::replace(&mut self.model_path, model_path);
+        (previous_info, previous_path)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -359,3 +495,106 @@ fn center_trim(t: Tensor, length: i64) -> Tensor {
 
     t.i((.., .., .., start..end))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn invalid_model_name(result: Result<ModelInfo>) -> String {
+        match result {
+            Err(Error::InvalidModelConfigError { name, .. }) => name,
+            other => panic!("expected InvalidModelConfigError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn models_reports_the_broken_entry() {
+        let path =
+            std::env::temp_dir().join(format!("stemsplit-models-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[
+                {"name": "htdemucs", "config": {"sample_rate": 44100, "sources": ["drums", "bass", "other", "vocals"], "channels": 2}},
+                {"name": "broken", "config": {"sample_rate": 44100, "channels": 2}}
+            ]"#,
+        )
+        .unwrap();
+
+        let result = models(&path);
+        std::fs::remove_file(&path).ok();
+
+        match result {
+            Err(Error::InvalidModelConfigError { name, reason }) => {
+                assert_eq!(name, "broken");
+                assert!(reason.contains("sources"), "unexpected reason: {}", reason);
+            }
+            other => panic!("expected InvalidModelConfigError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_model_entry_validates_config_values() {
+        let empty_sources = entry(
+            r#"{"name": "a", "config": {"sample_rate": 44100, "sources": [], "channels": 2}}"#,
+        );
+        assert_eq!(invalid_model_name(parse_model_entry(0, empty_sources)), "a");
+
+        let zero_rate = entry(
+            r#"{"name": "b", "config": {"sample_rate": 0, "sources": ["vocals"], "channels": 2}}"#,
+        );
+        assert_eq!(invalid_model_name(parse_model_entry(0, zero_rate)), "b");
+
+        let surround = entry(
+            r#"{"name": "c", "config": {"sample_rate": 44100, "sources": ["vocals"], "channels": 6}}"#,
+        );
+        assert_eq!(invalid_model_name(parse_model_entry(0, surround)), "c");
+
+        // 名前のないエントリは位置で示す
+        let unnamed = entry(r#"{"config": {"sample_rate": 44100, "sources": ["vocals"]}}"#);
+        assert_eq!(invalid_model_name(parse_model_entry(3, unnamed)), "#3");
+    }
+
+    #[test]
+    fn parse_model_entry_fills_in_default_post_process() {
+        let valid = entry(
+            r#"{"name": "htdemucs", "config": {"sample_rate": 44100, "sources": ["vocals", "other"], "channels": 2}}"#,
+        );
+        let info = parse_model_entry(0, valid).unwrap();
+        assert_eq!(info.config.sources, vec!["vocals", "other"]);
+        assert!(info.config.post_process.is_some());
+    }
+
+    #[test]
+    fn find_model_by_sources_picks_the_first_model_with_every_source() {
+        let model = |name: &str, sources: &str| {
+            parse_model_entry(
+                0,
+                entry(&format!(
+                    r#"{{"name": "{}", "config": {{"sample_rate": 44100, "sources": {}, "channels": 2}}}}"#,
+                    name, sources
+                )),
+            )
+            .unwrap()
+        };
+        let models = vec![
+            model("htdemucs", r#"["drums", "bass", "other", "vocals"]"#),
+            model(
+                "htdemucs_6s",
+                r#"["drums", "bass", "other", "vocals", "guitar", "piano"]"#,
+            ),
+            model("htdemucs_ft", r#"["drums", "bass", "other", "vocals"]"#),
+        ];
+
+        let name = |required: &[&str]| {
+            find_model_by_sources(models.clone(), required).map(|info| info.name)
+        };
+        assert_eq!(name(&["vocals"]).as_deref(), Some("htdemucs"));
+        assert_eq!(name(&["vocals", "guitar"]).as_deref(), Some("htdemucs_6s"));
+        assert_eq!(name(&[]).as_deref(), Some("htdemucs"));
+        assert_eq!(name(&["vocals", "strings"]), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, CodecRegistry, Decoder, DecoderOptions, FinalizeResult,
+    CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+/// Opusのデコードは常に48kHzで行う
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+/// 1パケットの最大長（120ms）のチャンネルあたりのサンプル数
+const MAX_FRAME_SAMPLES: usize = 5_760;
+
+/// symphonia標準のコーデックに、Opusのデコーダーを加えたレジストリ
+///
+/// symphonia 0.5はOpusのデコーダーを持たないため、libopusを使う
+pub fn codec_registry() -> &'static CodecRegistry {
+    static REGISTRY: Lazy<CodecRegistry> = Lazy::new(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    });
+
+    &REGISTRY
+}
+
+/// libopusによるsymphonia用のOpusデコーダー（モノラル・ステレオのみ対応）
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // opus::DecoderはSyncではないため、Decoderトレイトの要件を満たすようMutexで包む
+    decoder: Mutex<opus::Decoder>,
+    nb_channels: usize,
+    interleaved: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let Some(channels) = params.channels else {
+            return unsupported_error("opus: missing channel layout");
+        };
+        let (opus_channels, nb_channels) = match channels.count() {
+            1 => (opus::Channels::Mono, 1),
+            2 => (opus::Channels::Stereo, 2),
+            _ => return unsupported_error("opus: only mono and stereo streams are supported"),
+        };
+
+        let decoder = match opus::Decoder::new(OPUS_SAMPLE_RATE, opus_channels) {
+            Ok(decoder) => decoder,
+            Err(_) => return unsupported_error("opus: failed to create decoder"),
+        };
+
+        let mut params = params.clone();
+        params.with_sample_rate(OPUS_SAMPLE_RATE);
+
+        Ok(Self {
+            params,
+            decoder: Mutex::new(decoder),
+            nb_channels,
+            interleaved: vec![0.0; MAX_FRAME_SAMPLES * nb_channels],
+            buf: AudioBuffer::new(
+                MAX_FRAME_SAMPLES as u64,
+                SignalSpec::new(OPUS_SAMPLE_RATE, channels),
+            ),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let decoder = self.decoder.get_mut().unwrap_or_else(|e| e.into_inner());
+        let _ = decoder.reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef> {
+        let decoder = self.decoder.get_mut().unwrap_or_else(|e| e.into_inner());
+        let frames = match decoder.decode_float(&packet.data, &mut self.interleaved, false) {
+            Ok(frames) => frames,
+            Err(_) => return decode_error("opus: invalid packet"),
+        };
+
+        self.buf.clear();
+        self.buf.render_reserved(Some(frames));
+        for ch in 0..self.nb_channels {
+            let plane = self.buf.chan_mut(ch);
+            for (i, sample) in plane.iter_mut().enumerate() {
+                *sample = self.interleaved[i * self.nb_channels + ch];
+            }
+        }
+
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef {
+        self.buf.as_audio_buffer_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::demucs::audio::decode_file;
+
+    const CHANNELS: usize = 2;
+    /// 20msのフレーム
+    const FRAME_SAMPLES: usize = 960;
+    /// libopusの48kHzでの先読み（OpusHeadのpre-skip）
+    const PRE_SKIP: u16 = 312;
+
+    /// Oggのページのチェックサム（多項式0x04c11db7、反転なし）
+    fn ogg_crc(data: &[u8]) -> u32 {
+        data.iter().fold(0u32, |crc, &byte| {
+            (0..8).fold(crc ^ (u32::from(byte) << 24), |crc, _| {
+                if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    /// 1つのパケットだけを含むOggのページ
+    fn ogg_page(packet: &[u8], header_type: u8, granule: u64, sequence: u32) -> Vec<u8> {
+        let mut segments = vec![255u8; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+
+        let mut page = b"OggS".to_vec();
+        page.push(0);
+        page.push(header_type);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&0x5445_5354u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(packet);
+
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    /// インターリーブしたステレオのPCMをlibopusでエンコードし、Ogg Opusファイルとして書き出す
+    fn write_ogg_opus(path: &Path, interleaved: &[f32]) {
+        let mut encoder =
+            opus::Encoder::new(48_000, opus::Channels::Stereo, opus::Application::Audio).unwrap();
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(CHANNELS as u8);
+        head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&4u32.to_le_bytes());
+        tags.extend_from_slice(b"test");
+        tags.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut file = ogg_page(&head, 0x02, 0, 0);
+        file.extend(ogg_page(&tags, 0x00, 0, 1));
+
+        let frames: Vec<&[f32]> = interleaved.chunks(FRAME_SAMPLES * CHANNELS).collect();
+        let mut packet = vec![0u8; 4000];
+        let mut granule = u64::from(PRE_SKIP);
+        for (i, frame) in frames.iter().enumerate() {
+            // 最後のフレームは無音で埋めて20msにする
+            let mut input = frame.to_vec();
+            input.resize(FRAME_SAMPLES * CHANNELS, 0.0);
+            let size = encoder.encode_float(&input, &mut packet).unwrap();
+
+            granule += (frame.len() / CHANNELS) as u64;
+            let header_type = if i + 1 == frames.len() { 0x04 } else { 0x00 };
+            file.extend(ogg_page(
+                &packet[..size],
+                header_type,
+                granule,
+                i as u32 + 2,
+            ));
+        }
+
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn decodes_an_ogg_opus_file() {
+        let path = std::env::temp_dir().join(format!("stemsplit-opus-{}.ogg", std::process::id()));
+        // 0.5秒の440Hzのトーン（左右同じ）
+        let length = 24_000;
+        let interleaved: Vec<f32> = (0..length)
+            .flat_map(|i| {
+                let t = i as f32 / 48_000.0;
+                let sample = 0.5 * (2.0 * std::f32::consts::PI * 440.0 * t).sin();
+                [sample, sample]
+            })
+            .collect();
+        write_ogg_opus(&path, &interleaved);
+
+        let decoded = decode_file(&path);
+        std::fs::remove_file(&path).ok();
+        let decoded = decoded.unwrap();
+
+        assert_eq!(decoded.sample_rate, 48_000);
+        assert_eq!(decoded.nb_channels, 2);
+        // pre-skipと最後のフレームの埋め草を除くかはデマルチプレクサ次第なので、1フレーム分の誤差を許す
+        let expected = length + usize::from(PRE_SKIP);
+        assert!(
+            decoded.length.abs_diff(length) <= FRAME_SAMPLES + usize::from(PRE_SKIP),
+            "decoded {} samples, expected about {} (or {} with pre-skip)",
+            decoded.length,
+            length,
+            expected
+        );
+
+        // 非可逆圧縮なので波形ではなく音量で比べる
+        let middle = &decoded.samples[0][decoded.length / 4..decoded.length * 3 / 4];
+        let rms = (middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32).sqrt();
+        let expected_rms = 0.5 / 2f32.sqrt();
+        assert!(
+            (rms - expected_rms).abs() < expected_rms * 0.1,
+            "decoded RMS {} differs from {}",
+            rms,
+            expected_rms
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::Serialize;
+use snafu::whatever;
+
+use super::analysis::{hann_window, key_semitone_shift};
+use super::audio::{
+    decode_file, encode_pcm_to_wav, resample, PcmAudioData, ResampleQuality, WavBitDepth,
+};
+use super::error::Result;
+
+/// 伸縮できる倍率の範囲（出力の長さ / 入力の長さ）
+pub const MIN_STRETCH_RATIO: f64 = 0.5;
+pub const MAX_STRETCH_RATIO: f64 = 2.0;
+/// 倍率がこの範囲を外れると、トランジェントのにじみや位相の揺れ（phasiness）が聞こえやすくなる
+const ARTIFACT_FREE_RATIO: (f64, f64) = (0.8, 1.25);
+
+/// これより大きく移調すると、フォルマントがずれて声や楽器の質感が不自然になりやすい（半音）
+const ARTIFACT_FREE_SEMITONES: i32 = 3;
+
+/// フェーズボコーダーのフレーム長（サンプル）
+const STRETCH_FRAME_SIZE: usize = 2048;
+/// 合成側のホップ（フレーム長の1/4で、Hann窓の重ね合わせが一定になる）
+const STRETCH_SYNTHESIS_HOP: usize = STRETCH_FRAME_SIZE / 4;
+
+/// `stretch_stem_to_bpm` の結果
+#[derive(Debug, Clone, Serialize)]
+pub struct StretchReport {
+    pub path: PathBuf,
+    pub source_bpm: f64,
+    pub target_bpm: f64,
+    /// 出力の長さ / 入力の長さ
+    pub ratio: f64,
+    /// 倍率が大きく、アーティファクトが目立つ可能性がある
+    pub artifact_warning: bool,
+}
+
+/// stemを `source_bpm` から `target_bpm` にテンポだけ変えて（ピッチはそのまま）WAVに書き出す
+///
+/// 倍率は `MIN_STRETCH_RATIO`〜`MAX_STRETCH_RATIO` に制限する
+pub fn stretch_stem_to_bpm(
+    stem_path: &Path,
+    output_path: &Path,
+    source_bpm: f64,
+    target_bpm: f64,
+) -> Result<StretchReport> {
+    if !(source_bpm.is_finite() && source_bpm > 0.0) {
+        whatever!("source BPM must be a positive number, got {}", source_bpm);
+    }
+    if !(target_bpm.is_finite() && target_bpm > 0.0) {
+        whatever!("target BPM must be a positive number, got {}", target_bpm);
+    }
+
+    let ratio = source_bpm / target_bpm;
+    if !(MIN_STRETCH_RATIO..=MAX_STRETCH_RATIO).contains(&ratio) {
+        whatever!(
+            "stretching {} BPM to {} BPM needs a ratio of {:.3}, outside {}..={}",
+            source_bpm,
+            target_bpm,
+            ratio,
+            MIN_STRETCH_RATIO,
+            MAX_STRETCH_RATIO
+        );
+    }
+    let artifact_warning = !(ARTIFACT_FREE_RATIO.0..=ARTIFACT_FREE_RATIO.1).contains(&ratio);
+    if artifact_warning {
+        eprintln!(
+            "[stretch_stem_to_bpm] Ratio {:.3} is large; smearing and phasiness may be audible",
+            ratio
+        );
+    }
+
+    let stem = decode_file(stem_path)?;
+    let stretched = time_stretch(&stem, ratio);
+    encode_pcm_to_wav(stretched, output_path, WavBitDepth::Float32, None)?;
+    eprintln!(
+        "[stretch_stem_to_bpm] Stretched {:?} from {} BPM to {} BPM (ratio {:.3})",
+        stem_path, source_bpm, target_bpm, ratio
+    );
+
+    Ok(StretchReport {
+        path: output_path.to_path_buf(),
+        source_bpm,
+        target_bpm,
+        ratio,
+        artifact_warning,
+    })
+}
+
+/// `shift_stem_to_key` の結果
+#[derive(Debug, Clone, Serialize)]
+pub struct PitchShiftReport {
+    pub path: PathBuf,
+    pub source_key: String,
+    pub target_key: String,
+    /// 移調した半音数（-6〜5）
+    pub semitones: i32,
+    /// 移調の幅が大きく、フォルマントのずれなどが目立つ可能性がある
+    pub artifact_warning: bool,
+}
+
+/// stemを `source_key` から `target_key` に移調して（長さはそのまま）WAVに書き出す
+///
+/// 半音数は12で割った余りのうち近い方向を選ぶため、最大でも6半音。
+/// 移調しても長調・短調は変わらないため、`match_relative` の場合は平行調（A minorとC majorなど）を同じキーとみなす
+pub fn shift_stem_to_key(
+    stem_path: &Path,
+    output_path: &Path,
+    source_key: &str,
+    target_key: &str,
+    match_relative: bool,
+) -> Result<PitchShiftReport> {
+    let Some(semitones) = key_semitone_shift(source_key, target_key, match_relative) else {
+        whatever!("cannot transpose {:?} to {:?}", source_key, target_key);
+    };
+    let artifact_warning = semitones.abs() > ARTIFACT_FREE_SEMITONES;
+    if artifact_warning {
+        eprintln!(
+            "[shift_stem_to_key] Shifting {} semitones; formant shifts may be audible",
+            semitones
+        );
+    }
+
+    let stem = decode_file(stem_path)?;
+    let shifted = pitch_shift(stem, semitones)?;
+    encode_pcm_to_wav(shifted, output_path, WavBitDepth::Float32, None)?;
+    eprintln!(
+        "[shift_stem_to_key] Shifted {:?} from {} to {} ({:+} semitones)",
+        stem_path, source_key, target_key, semitones
+    );
+
+    Ok(PitchShiftReport {
+        path: output_path.to_path_buf(),
+        source_key: source_key.to_string(),
+        target_key: target_key.to_string(),
+        semitones,
+        artifact_warning,
+    })
+}
+
+/// 長さを変えずにピッチを `semitones` 半音ずらす
+///
+/// フェーズボコーダーで長さを `2^(semitones/12)` 倍にしてから、元の長さに戻るようリサンプリングする
+pub fn pitch_shift(audio: PcmAudioData, semitones: i32) -> Result<PcmAudioData> {
+    if semitones == 0 {
+        return Ok(audio);
+    }
+
+    let (sample_rate, length) = (audio.sample_rate, audio.length);
+    let ratio = 2f64.powf(f64::from(semitones) / 12.0);
+    let mut stretched = time_stretch(&audio, ratio);
+    drop(audio);
+
+    // 伸ばした信号を高いサンプルレートで録音されたものとみなして元のサンプルレートに変換すると、
+    // 長さが元に戻り、ピッチが `ratio` 倍になる（整数への丸めによるずれは1セント未満）
+    stretched.sample_rate = (sample_rate as f64 * ratio).round() as usize;
+    let mut shifted = resample(stretched, sample_rate, ResampleQuality::default())?;
+    shifted.sample_rate = sample_rate;
+    for channel in shifted.samples.iter_mut() {
+        channel.resize(length, 0.0);
+    }
+    shifted.length = length;
+
+    Ok(shifted)
+}
+
+/// フェーズボコーダーで長さを `ratio` 倍にする（ピッチは変えない）
+///
+/// 分析側のホップを `合成側のホップ / ratio` にし、各ビンの位相の進みから瞬時周波数を求めて
+/// 合成側のホップ分だけ位相を進める。チャンネルごとに独立して処理する
+pub fn time_stretch(audio: &PcmAudioData, ratio: f64) -> PcmAudioData {
+    let output_length = (audio.length as f64 * ratio).round() as usize;
+    let samples = audio
+        .samples
+        .iter()
+        .map(|channel| stretch_channel(&channel[..audio.length], ratio, output_length))
+        .collect();
+
+    PcmAudioData {
+        samples,
+        sample_rate: audio.sample_rate,
+        nb_channels: audio.nb_channels,
+        length: output_length,
+    }
+}
+
+fn stretch_channel(input: &[f32], ratio: f64, output_length: usize) -> Vec<f32> {
+    let size = STRETCH_FRAME_SIZE;
+    let hop = STRETCH_SYNTHESIS_HOP;
+    let bins = size / 2 + 1;
+    let window = hann_window(size);
+
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(size);
+    let ifft = planner.plan_fft_inverse(size);
+
+    // 先頭と末尾もフレームの中央で処理されるよう、前後に半フレームの無音を足す
+    let padding = size / 2;
+    let mut padded = vec![0.0f32; padding];
+    padded.extend_from_slice(input);
+    padded.resize(padded.len() + size, 0.0);
+
+    // 入力の先頭は出力では `padding * ratio` の位置になる
+    let skip = (padding as f64 * ratio).round() as usize;
+    let frames = (skip + output_length + size) / hop + 1;
+    let mut output = vec![0.0f32; frames * hop + size];
+    let mut window_sum = vec![0.0f32; output.len()];
+
+    let mut previous_phase = vec![0.0f64; bins];
+    let mut synthesis_phase = vec![0.0f64; bins];
+    let mut buffer = vec![Complex::new(0.0f32, 0.0); size];
+
+    for frame in 0..frames {
+        let analysis_position = frame as f64 * hop as f64 / ratio;
+        let start = analysis_position.round() as usize;
+        if start + size > padded.len() {
+            break;
+        }
+        // 丸めた位置どうしの実際の間隔で位相の進みを計算する
+        let analysis_hop = if frame == 0 {
+            0.0
+        } else {
+            start as f64 - ((frame - 1) as f64 * hop as f64 / ratio).round()
+        };
+
+        for ((slot, &sample), &w) in buffer
+            .iter_mut()
+            .zip(&padded[start..start + size])
+            .zip(&window)
+        {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+
+        for bin in 0..bins {
+            let magnitude = f64::from(buffer[bin].norm());
+            let phase = f64::from(buffer[bin].arg());
+            if frame == 0 {
+                synthesis_phase[bin] = phase;
+            } else {
+                let bin_frequency = 2.0 * PI * bin as f64 / size as f64;
+                let expected = bin_frequency * analysis_hop;
+                let deviation = wrap_phase(phase - previous_phase[bin] - expected);
+                let instantaneous_frequency = if analysis_hop > 0.0 {
+                    bin_frequency + deviation / analysis_hop
+                } else {
+                    bin_frequency
+                };
+                synthesis_phase[bin] += instantaneous_frequency * hop as f64;
+            }
+            previous_phase[bin] = phase;
+
+            let value = Complex::from_polar(magnitude, synthesis_phase[bin]);
+            buffer[bin] = Complex::new(value.re as f32, value.im as f32);
+            // 実信号になるよう、負の周波数側は共役にする
+            if bin > 0 && bin < size - bin {
+                buffer[size - bin] = buffer[bin].conj();
+            }
+        }
+        ifft.process(&mut buffer);
+
+        let offset = frame * hop;
+        for (i, &w) in window.iter().enumerate() {
+            // rustfftの逆変換は正規化されないため、サイズで割る
+            output[offset + i] += buffer[i].re / size as f32 * w;
+            window_sum[offset + i] += w * w;
+        }
+    }
+
+    output
+        .iter()
+        .zip(&window_sum)
+        .skip(skip)
+        .take(output_length)
+        .map(|(&sample, &sum)| if sum > 1e-6 { sample / sum } else { 0.0 })
+        .collect()
+}
+
+/// 位相を -π..π に収める
+fn wrap_phase(phase: f64) -> f64 {
+    phase - 2.0 * PI * (phase / (2.0 * PI)).round()
+}
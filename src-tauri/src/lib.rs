@@ -11,6 +11,7 @@
 
 pub mod data;
 pub mod demucs;
+pub mod logging;
 pub mod routes;
 pub mod util;
 
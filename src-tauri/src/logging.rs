@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::PathBuf;
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Registry};
+
+use crate::util::get_app_directory;
+
+/// ログファイル名（`stemsplit.YYYY-MM-DD.log`）の前後
+const LOG_FILE_PREFIX: &str = "stemsplit";
+const LOG_FILE_SUFFIX: &str = "log";
+/// 残しておくログファイルの数（1日1ファイル）
+const MAX_LOG_FILES: usize = 7;
+
+/// 実行中にログレベルを変更するためのハンドル（`init_logging` で設定される）
+static LEVEL_HANDLE: OnceCell<reload::Handle<LevelFilter, Registry>> = OnceCell::new();
+
+/// ユーザーが選べるログレベル
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Self::ERROR,
+            LogLevel::Warn => Self::WARN,
+            LogLevel::Info => Self::INFO,
+            LogLevel::Debug => Self::DEBUG,
+            LogLevel::Trace => Self::TRACE,
+        }
+    }
+}
+
+/// リリースビルドでログを書き込むディレクトリ
+///
+/// 保存先の設定はログの初期化より後に読み込まれ、外付けディスクなどで使えない場合もあるため、
+/// 常にアプリのディレクトリ（デフォルトの保存先）に置く
+pub fn log_directory() -> PathBuf {
+    get_app_directory().join("logs")
+}
+
+/// 現在書き込んでいる（最も新しい）ログファイル。まだ1つもない場合はNone
+pub fn latest_log_file() -> Option<PathBuf> {
+    fs::read_dir(log_directory())
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| {
+                    name.starts_with(LOG_FILE_PREFIX) && name.ends_with(LOG_FILE_SUFFIX)
+                })
+        })
+        // ファイル名の日付（YYYY-MM-DD）の順に並ぶ
+        .max()
+}
+
+/// tracingのサブスクライバーを初期化する
+///
+/// デバッグビルドではDEBUGレベルで標準エラー出力（Tauriのコンソール）に、
+/// リリースビルドではINFOレベルで `log_directory()` に日ごとのファイルとして書き込み、
+/// 古いファイルは `MAX_LOG_FILES` 個を超えた分から削除する。ファイルを開けない場合は標準エラー出力に戻す
+pub fn init_logging() {
+    let (level, writer) = if cfg!(debug_assertions) {
+        (LevelFilter::DEBUG, BoxMakeWriter::new(std::io::stderr))
+    } else {
+        (LevelFilter::INFO, open_log_file())
+    };
+
+    let (filter, handle) = reload::Layer::new(level);
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(cfg!(debug_assertions))
+        .with_span_events(FmtSpan::ENTER | FmtSpan::CLOSE);
+
+    if tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .try_init()
+        .is_ok()
+    {
+        LEVEL_HANDLE.set(handle).ok();
+        tracing::info!("[init_logging] Tracing subscriber initialized at {}", level);
+    }
+}
+
+/// 実行中にログレベルを変更する（バグを再現するときに詳細なログを取るため）
+pub fn set_log_level(level: LogLevel) -> Result<(), String> {
+    let handle = LEVEL_HANDLE
+        .get()
+        .ok_or_else(|| String::from("logging is not initialized"))?;
+    handle
+        .reload(LevelFilter::from(level))
+        .map_err(|e| e.to_string())?;
+    tracing::info!("[set_log_level] Log level set to {:?}", level);
+
+    Ok(())
+}
+
+fn open_log_file() -> BoxMakeWriter {
+    let directory = log_directory();
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(&directory);
+
+    match appender {
+        Ok(appender) => BoxMakeWriter::new(appender),
+        Err(e) => {
+            eprintln!(
+                "[init_logging] Unable to open a log file in {:?}, logging to stderr: {}",
+                directory, e
+            );
+            BoxMakeWriter::new(std::io::stderr)
+        }
+    }
+}
@@ -3,26 +3,75 @@
 
 use std::{error::Error, fs, io::{self, Write}};
 use tokio::sync::Mutex;
-use tracing::Level;
-use tracing_subscriber::fmt::format::FmtSpan;
 use tauri::Manager;
 
 use stem_split::{
-    data::AppDb,
+    data::{AppDb, AppSettings},
     demucs::{self, get_available_device, LazyModelLoader},
+    logging,
     routes::{
+        analysis::{
+            __cmd__analyze_audio, __cmd__analyze_folder, __cmd__analyze_stem, __cmd__detect_key,
+            __cmd__evaluate_separation, __cmd__get_spectrogram, __cmd__get_stem_info,
+            __cmd__get_waveform_peaks, __cmd__measure_project_loudness,
+            __cmd__refresh_analysis_if_stale, __cmd__verify_project_stems, __cmd__verify_stem,
+            analyze_audio, analyze_folder, analyze_stem, detect_key, evaluate_separation,
+            get_spectrogram, get_stem_info, get_waveform_peaks, measure_project_loudness,
+            refresh_analysis_if_stale, verify_project_stems, verify_stem,
+        },
+        export::{
+            __cmd__apply_eq_preview, __cmd__export_bars, __cmd__export_dj_tags, __cmd__export_stem,
+            __cmd__export_stem_at_bpm, __cmd__export_stem_at_key, __cmd__render_mixdown,
+            __cmd__start_stem_drag, __cmd__transcode_stem, apply_eq_preview, export_bars,
+            export_dj_tags, export_stem, export_stem_at_bpm, export_stem_at_key, render_mixdown,
+            start_stem_drag, transcode_stem,
+        },
+        jobs::{__cmd__get_job_status, get_job_status, JobRegistry},
         project::{
-            __cmd__create_project, __cmd__get_all_projects, create_project, get_all_projects,
+            __cmd__add_project_tag, __cmd__create_project, __cmd__create_project_from_bytes,
+            __cmd__find_projects_by_tag, __cmd__gc_orphaned_projects, __cmd__get_all_projects,
+            __cmd__get_library_stats, __cmd__get_project, __cmd__get_supported_input_extensions,
+            __cmd__probe_audio, __cmd__read_metadata, __cmd__relink_project,
+            __cmd__remove_project_tag, __cmd__rename_project, __cmd__reveal_project_folder,
+            __cmd__set_project_bpm, __cmd__set_project_key, __cmd__update_stem_settings,
+            add_project_tag, create_project, create_project_from_bytes, find_projects_by_tag,
+            gc_orphaned_projects, get_all_projects, get_library_stats, get_project,
+            get_supported_input_extensions, probe_audio, read_metadata, relink_project,
+            remove_project_tag, rename_project, reveal_project_folder, set_project_bpm,
+            set_project_key, update_stem_settings,
+        },
+        settings::{
+            __cmd__get_output_directory, __cmd__set_cpu_threads, __cmd__set_device_preference,
+            __cmd__set_max_segment_secs, __cmd__set_output_directory, get_output_directory,
+            set_cpu_threads, set_device_preference, set_max_segment_secs, set_output_directory,
         },
         split::{
-            __cmd__split_stems, __cmd__split_vocal_instrumental_stems, __cmd__create_stems_zip,
-            split_stems, split_vocal_instrumental_stems, create_stems_zip,
+            __cmd__build_instrumental_from_stems, __cmd__compare_models, __cmd__create_stems_zip,
+            __cmd__download_model, __cmd__get_stems_model_status, __cmd__preview_split,
+            __cmd__resplit_with_model, __cmd__select_model_for_stems,
+            __cmd__split_custom_mix_stems, __cmd__split_stems, __cmd__split_stems_raw,
+            __cmd__split_vocal_instrumental_stems, build_instrumental_from_stems, compare_models,
+            create_stems_zip, download_model, get_stems_model_status, model_file_path,
+            preview_split, resplit_with_model, select_model_for_stems, split_custom_mix_stems,
+            split_stems, split_stems_raw, split_vocal_instrumental_stems,
+        },
+        system::{
+            __cmd__get_device_info, __cmd__get_diagnostics, __cmd__get_log_path,
+            __cmd__run_self_test, __cmd__set_log_level, __cmd__unload_model,
+            __cmd__verify_device_parity, get_device_info, get_diagnostics, get_log_path,
+            run_self_test, set_log_level, unload_model, verify_device_parity,
         },
     },
-    util::get_base_directory,
+    util::{get_app_directory, get_base_directory, set_base_directory_override},
 };
 
 
+/// 起動時に選ぶモデルが出力すべきstem（優先度の高い順）
+const STARTUP_MODEL_SOURCES: [&[&str]; 2] = [
+    &["drums", "bass", "other", "vocals", "guitar", "piano"],
+    &["drums", "bass", "other", "vocals"],
+];
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // 最初に標準出力を確実にフラッシュ
@@ -36,17 +85,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let _ = std::io::stderr().flush();
     
     // デバッグ用にロガーを有効化
-    setup_global_subscriber();
+    logging::init_logging();
 
-    // OpenMPのスレッド数を制限して、複数のOpenMPライブラリ間の競合を防止
-    // libtorchが使用するOpenMPの設定
-    std::env::set_var("OMP_NUM_THREADS", "1");
-    std::env::set_var("MKL_NUM_THREADS", "1");
-    std::env::set_var("NUMEXPR_NUM_THREADS", "1");
-    
     // 動的ライブラリの検索パスからHomebrewのlibompを除外
     // DYLD_LIBRARY_PATHを設定しないことで、libtorchに含まれるOpenMPのみを使用
 
+    // dbは常にアプリのディレクトリに置き、保存先の設定を読み込む
+    let app_dir = get_app_directory();
+    fs::create_dir_all(&app_dir).expect("Unable to ensure app directory exists");
+    let app_db = AppDb::new(app_dir.join("db"));
+    let settings = app_db.get_settings().unwrap_or_else(|e| {
+        eprintln!("[main] Failed to load settings, using defaults: {}", e);
+        AppSettings::default()
+    });
+
+    // OpenMPのスレッド数を設定（既定値は1で、複数のOpenMPライブラリ間の競合を防止）
+    // libtorchがOpenMPを初期化する前に設定する必要があるため、ここより前でtchを使わないこと
+    demucs::configure_cpu_threads(settings.cpu_threads);
+    demucs::set_max_segment_override(settings.max_segment_secs);
+    set_base_directory_override(settings.output_directory.clone());
+
     println!("[main] Creating project_data directory...");
     eprintln!("[main] Creating project_data directory...");
     let base_dir = get_base_directory();
@@ -55,13 +113,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
     std::io::stdout().flush().ok();
     std::io::stderr().flush().ok();
     
-    fs::create_dir_all(base_dir.join("project_data"))
-        .expect("Unable to ensure base_directory exists");
+    // カスタムの保存先が使えない場合（外付けディスクが未接続など）はデフォルトに戻す
+    if let Err(e) = fs::create_dir_all(base_dir.join("project_data")) {
+        eprintln!("[main] Unable to use base directory {:?}: {}, falling back to default", base_dir, e);
+        set_base_directory_override(None);
+        fs::create_dir_all(get_base_directory().join("project_data"))
+            .expect("Unable to ensure base_directory exists");
+    }
 
     eprintln!("[main] Initializing Tauri Builder...");
     let builder = tauri::Builder::default()
         .plugin(tauri_plugin_drag::init())
-        .setup(|app| {
+        .setup(move |app| {
             println!("[setup] Running setup...");
             eprintln!("[setup] Running setup...");
             io::stdout().flush().ok();
@@ -84,71 +147,37 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             eprintln!("[setup] Loading models from: {:?}", models_path);
             let models = demucs::models(&models_path).map_err(|e| {
-                eprintln!("[setup] Error loading models: {:?}", e);
-                io::Error::new(io::ErrorKind::Other, format!("Failed to load models: {:?}", e))
+                eprintln!("[setup] Error loading models: {}", e);
+                io::Error::new(io::ErrorKind::Other, format!("Failed to load models: {}", e))
             })?;
 
-            // htdemucs_6sモデルを優先的に使用（存在する場合）、なければhtdemucsを使用
-            eprintln!("[setup] Finding model (htdemucs_6s or htdemucs)...");
-            
-            // まず、htdemucs_6sモデルファイルが存在するか確認
-            let htdemucs_6s_path = app.path_resolver().resolve_resource("models/htdemucs_6s.pt");
-            let (model_info, model_path) = if let Some(path) = htdemucs_6s_path {
-                // ファイルが実際に存在するか確認
-                if path.exists() {
-                    // htdemucs_6s.ptが存在する場合
-                    eprintln!("[setup] htdemucs_6s.pt found, using htdemucs_6s model");
-                    let info = demucs::find_model(models.clone(), "htdemucs_6s")
-                        .ok_or_else(|| {
-                            let error_msg = "htdemucs_6s model config not found in models.json";
-                            eprintln!("[setup] Error: {}", error_msg);
-                            io::Error::new(io::ErrorKind::NotFound, error_msg)
-                        })?;
-                    (info, path)
-                } else {
-                    // パスは解決できたが、ファイルが存在しない場合
-                    eprintln!("[setup] htdemucs_6s.pt path resolved but file does not exist, using htdemucs model");
-                    let info = demucs::find_model(models, "htdemucs")
-                        .ok_or_else(|| {
-                            let error_msg = "model htdemucs is not available";
-                            eprintln!("[setup] Error: {}", error_msg);
-                            io::Error::new(io::ErrorKind::NotFound, error_msg)
-                        })?;
-                    let path = app
-                        .path_resolver()
-                        .resolve_resource("models/htdemucs.pt")
-                        .ok_or_else(|| {
-                            let error_msg = "failed to resolve models/htdemucs.pt resource";
-                            eprintln!("[setup] Error: {}", error_msg);
-                            io::Error::new(io::ErrorKind::NotFound, error_msg)
-                        })?;
-                    (info, path)
-                }
-            } else {
-                // htdemucs_6s.ptが存在しない場合、htdemucsを使用
-                eprintln!("[setup] htdemucs_6s.pt not found, using htdemucs model");
-                let info = demucs::find_model(models, "htdemucs")
-                    .ok_or_else(|| {
-                        let error_msg = "model htdemucs is not available";
-                        eprintln!("[setup] Error: {}", error_msg);
-                        io::Error::new(io::ErrorKind::NotFound, error_msg)
-                    })?;
-                let path = app
-                    .path_resolver()
-                    .resolve_resource("models/htdemucs.pt")
-                    .ok_or_else(|| {
-                        let error_msg = "failed to resolve models/htdemucs.pt resource";
-                        eprintln!("[setup] Error: {}", error_msg);
-                        io::Error::new(io::ErrorKind::NotFound, error_msg)
-                    })?;
-                (info, path)
-            };
+            // guitar/pianoも出力できる6 stemのモデルを優先し、なければ4 stemのモデルを使用
+            // （モデルファイルが同梱またはダウンロード済みのもののうち、models.jsonで最初に見つかったもの）
+            eprintln!("[setup] Finding model...");
+            let app_handle = app.handle();
+            let available: Vec<_> = models
+                .iter()
+                .filter(|info| model_file_path(&app_handle, &info.name).is_some())
+                .cloned()
+                .collect();
+            let model_info = STARTUP_MODEL_SOURCES
+                .iter()
+                .find_map(|sources| demucs::find_model_by_sources(available.clone(), sources))
+                .or_else(|| demucs::find_model(models, "htdemucs"))
+                .ok_or_else(|| {
+                    let error_msg = "model htdemucs is not available";
+                    eprintln!("[setup] Error: {}", error_msg);
+                    io::Error::new(io::ErrorKind::NotFound, error_msg)
+                })?;
+            // どのモデルファイルもない場合は、ダウンロード先のパスにしておく（分離時にエラーになる）
+            let model_path = model_file_path(&app_handle, &model_info.name)
+                .unwrap_or_else(|| demucs::downloaded_model_path(&model_info.name));
 
             eprintln!("[setup] Using model: {}", model_info.name);
             eprintln!("[setup] Model file path: {:?}", model_path);
 
             eprintln!("[setup] Getting available device...");
-            let device = get_available_device();
+            let device = get_available_device(settings.device.as_deref());
             eprintln!("[setup] Using device: {:?}", device);
 
             // モデルを遅延ロードするように設定（起動時はロードしない）
@@ -158,13 +187,73 @@ async fn main() -> Result<(), Box<dyn Error>> {
             eprintln!("[setup] Setup completed successfully (model not loaded yet to save memory)");
             Ok(())
         })
-        .manage(Mutex::from(AppDb::new(get_base_directory().join("db"))))
+        .manage(Mutex::from(app_db))
+        .manage(JobRegistry::default())
         .invoke_handler(tauri::generate_handler![
+            analyze_audio,
+            analyze_folder,
+            detect_key,
+            verify_stem,
+            verify_project_stems,
+            analyze_stem,
+            get_stem_info,
+            get_waveform_peaks,
+            get_spectrogram,
+            evaluate_separation,
+            measure_project_loudness,
+            refresh_analysis_if_stale,
             create_project,
+            create_project_from_bytes,
             get_all_projects,
+            get_library_stats,
+            get_project,
+            get_supported_input_extensions,
+            read_metadata,
+            probe_audio,
+            gc_orphaned_projects,
+            relink_project,
+            reveal_project_folder,
+            rename_project,
+            set_project_bpm,
+            set_project_key,
+            update_stem_settings,
+            add_project_tag,
+            remove_project_tag,
+            find_projects_by_tag,
             split_stems,
+            resplit_with_model,
+            compare_models,
+            download_model,
+            select_model_for_stems,
+            split_stems_raw,
             split_vocal_instrumental_stems,
+            split_custom_mix_stems,
+            preview_split,
             create_stems_zip,
+            build_instrumental_from_stems,
+            get_stems_model_status,
+            get_job_status,
+            export_stem,
+            start_stem_drag,
+            apply_eq_preview,
+            export_bars,
+            export_stem_at_bpm,
+            export_stem_at_key,
+            export_dj_tags,
+            transcode_stem,
+            render_mixdown,
+            get_output_directory,
+            set_output_directory,
+            set_device_preference,
+            set_cpu_threads,
+            set_max_segment_secs,
+            get_device_info,
+            get_diagnostics,
+            get_log_path,
+            run_self_test,
+            set_log_level,
+            unload_model,
+            verify_device_parity,
         ]);
     
     println!("[main] About to run Tauri application...");
@@ -199,16 +288,3 @@ async fn main() -> Result<(), Box<dyn Error>> {
     eprintln!("[main] Application exited successfully");
     Ok(())
 }
-
-fn setup_global_subscriber() {
-    // デバッグモードでのみロガーを初期化
-    if cfg!(debug_assertions) {
-        // 標準エラー出力に出力（Tauriのコンソールに表示される）
-        tracing_subscriber::fmt()
-            .with_writer(std::io::stderr)
-            .with_span_events(FmtSpan::ENTER | FmtSpan::CLOSE)
-            .with_max_level(Level::DEBUG)
-            .init();
-        eprintln!("[logger] Tracing subscriber initialized");
-    }
-}
@@ -0,0 +1,415 @@
+use snafu::ResultExt;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+use serde::{self, Deserialize, Serialize};
+use tauri::State;
+
+use crate::{
+    data::{AppDb, Project},
+    demucs::{
+        analyze_audio as analyze_audio_file, analyze_folder as analyze_folder_files,
+        audio::{decode_file, probe_audio_info, verify_audio_file, VerifyResult},
+        detect_bpm_from_pcm, detect_key as detect_file_key, detect_key_from_pcm,
+        evaluate_separation as evaluate_stem_separation, measure_file_lufs, measure_lufs,
+        spectrogram as compute_spectrogram, waveform_peaks, AnalyzeResult, AudioAnalysis,
+        BpmEstimate, KeyEstimate, KeyProfile, SpectrogramData,
+    },
+    routes::AnalysisSnafu,
+};
+
+use super::{
+    ensure_separate_stems, ensure_source_available, find_source_or_err, get_project_or_err, Error,
+    Result,
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum AnalyzeAudioResponse {
+    #[serde(alias = "success")]
+    Success { analysis: AudioAnalysis },
+}
+
+/// プロジェクトを作成せず、モデルも実行せずにBPM/Keyなどを解析する
+#[tauri::command]
+#[tracing::instrument]
+pub async fn analyze_audio(path: &str) -> Result<AnalyzeAudioResponse> {
+    let analysis = analyze_audio_file(&PathBuf::from(path)).context(AnalysisSnafu)?;
+
+    Ok(AnalyzeAudioResponse::Success { analysis })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum AnalyzeFolderResponse {
+    #[serde(alias = "success")]
+    Success { results: Vec<AnalyzeResult> },
+}
+
+/// フォルダ直下の音声ファイルをまとめて解析する（分離する曲を選ぶ前のカタログ作成用）
+///
+/// 個別のファイルの失敗は結果の `error` に入り、コマンド自体はフォルダを読めない場合だけ失敗する
+#[tauri::command]
+#[tracing::instrument]
+pub async fn analyze_folder(dir: &str) -> Result<AnalyzeFolderResponse> {
+    let dir = PathBuf::from(dir);
+    let results = tauri::async_runtime::spawn_blocking(move || analyze_folder_files(&dir))
+        .await
+        .map_err(|e| Error::UnexpectedError {
+            message: String::from("Folder analysis was interrupted"),
+            source: Some(Box::new(e)),
+        })?
+        .context(AnalysisSnafu)?;
+
+    Ok(AnalyzeFolderResponse::Success { results })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum DetectKeyResponse {
+    #[serde(alias = "success")]
+    Success { estimate: Option<KeyEstimate> },
+}
+
+/// キープロファイルを指定してKeyを検出する（省略時はKrumhansl）。無音などでKeyが決まらない場合はNone
+#[tauri::command]
+#[tracing::instrument]
+pub async fn detect_key(path: &str, profile: Option<KeyProfile>) -> Result<DetectKeyResponse> {
+    let estimate = detect_file_key(&PathBuf::from(path), profile.unwrap_or_default())
+        .context(AnalysisSnafu)?;
+
+    Ok(DetectKeyResponse::Success { estimate })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum WaveformPeaksResponse {
+    #[serde(alias = "success")]
+    Success { peaks: Vec<f32> },
+}
+
+/// 波形描画用のピーク（`[min0, max0, min1, max1, ...]`）を取得する。元の曲にもstemにも使える
+#[tauri::command]
+#[tracing::instrument]
+pub async fn get_waveform_peaks(path: &str, buckets: usize) -> Result<WaveformPeaksResponse> {
+    let peaks = waveform_peaks(&PathBuf::from(path), buckets).context(AnalysisSnafu)?;
+
+    Ok(WaveformPeaksResponse::Success { peaks })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum SpectrogramResponse {
+    #[serde(alias = "success")]
+    Success { spectrogram: SpectrogramData },
+}
+
+/// スペクトログラム表示用の振幅（dBFS）を取得する。stemの被り（ブリード）の確認に使う
+///
+/// `fft_size` は256〜16384の2のべき乗。長い曲は表示用のサイズまで間引く
+#[tauri::command]
+#[tracing::instrument]
+pub async fn get_spectrogram(path: &str, fft_size: usize, hop: usize) -> Result<SpectrogramResponse> {
+    let spectrogram =
+        compute_spectrogram(&PathBuf::from(path), fft_size, hop).context(AnalysisSnafu)?;
+
+    Ok(SpectrogramResponse::Success { spectrogram })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum EvaluateSeparationResponse {
+    #[serde(alias = "success")]
+    Success { sdr_db: f64 },
+}
+
+/// 分離したstemを単独の参照音源（公式のアカペラなど）と比べ、SDRの目安（dB）を返す
+///
+/// モデルや設定の違いを耳だけでなく数値で比べるためのもの。参照音源に他の音が混ざっていると意味のない値になる
+#[tauri::command]
+#[tracing::instrument]
+pub async fn evaluate_separation(
+    stem_path: String,
+    reference_path: String,
+) -> Result<EvaluateSeparationResponse> {
+    let sdr_db = tauri::async_runtime::spawn_blocking(move || {
+        evaluate_stem_separation(&PathBuf::from(stem_path), &PathBuf::from(reference_path))
+    })
+    .await
+    .map_err(|e| Error::UnexpectedError {
+        message: String::from("Separation evaluation was interrupted"),
+        source: Some(Box::new(e)),
+    })?
+    .context(AnalysisSnafu)?;
+
+    Ok(EvaluateSeparationResponse::Success { sdr_db })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StemLoudness {
+    pub kind: String,
+    pub path: String,
+    /// 無音などで測定できない場合はNone
+    pub lufs: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum ProjectLoudnessResponse {
+    #[serde(alias = "success")]
+    Success {
+        mix_lufs: Option<f64>,
+        stems: Vec<StemLoudness>,
+    },
+}
+
+/// 元の曲と各stemの統合ラウドネス（LUFS）を測定する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn measure_project_loudness(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ProjectLoudnessResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+
+    let song_path = find_source_or_err(&project)?;
+    let mix_lufs = Some(measure_file_lufs(&song_path).context(AnalysisSnafu)?)
+        .filter(|lufs| lufs.is_finite());
+
+    let stems = project
+        .stem_paths
+        .iter()
+        .map(|stem_path| {
+            let path = PathBuf::from(stem_path);
+            let kind = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let lufs = measure_file_lufs(&path)
+                .context(AnalysisSnafu)?;
+
+            Ok(StemLoudness {
+                kind,
+                path: stem_path.clone(),
+                lufs: Some(lufs).filter(|lufs| lufs.is_finite()),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ProjectLoudnessResponse::Success { mix_lufs, stems })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum RefreshAnalysisResponse {
+    #[serde(alias = "success")]
+    Success { project: Project, refreshed: bool },
+}
+
+/// 曲ファイルが解析時から変わっている場合のみBPM/Keyなどを再計算する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn refresh_analysis_if_stale(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<RefreshAnalysisResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_source_available(&project)?;
+
+    let db = app_db_mutex.lock().await;
+    let (project, refreshed) =
+        db.refresh_analysis_if_stale(project_id)
+            .map_err(|message| Error::UnexpectedError {
+                message,
+                source: None,
+            })?;
+
+    Ok(RefreshAnalysisResponse::Success { project, refreshed })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StemAnalysis {
+    pub kind: String,
+    pub path: String,
+    pub bpm: Option<BpmEstimate>,
+    pub key: Option<String>,
+    /// 無音などで測定できない場合はNone
+    pub lufs: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum AnalyzeStemResponse {
+    #[serde(alias = "success")]
+    Success { analysis: StemAnalysis },
+}
+
+/// 分離したstemのBPM/Key/ラウドネスを解析する（結果はプロジェクトに保存しない）
+///
+/// 例えばbassのテンポがプロジェクトのBPMと一致するか、instrumentalのKeyを確認するのに使う
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn analyze_stem(
+    project_id: &str,
+    stem_kind: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<AnalyzeStemResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_separate_stems(&project)?;
+    let path = project
+        .find_stem(stem_kind)
+        .ok_or_else(|| Error::StemNotFoundError {
+            kind: stem_kind.to_string(),
+        })?;
+
+    // 解析ごとにデコードし直さないよう、一度だけデコードする
+    let track = decode_file(&path).context(AnalysisSnafu)?;
+    let bpm = detect_bpm_from_pcm(&track).context(AnalysisSnafu)?;
+    let key = detect_key_from_pcm(&track).context(AnalysisSnafu)?;
+    let lufs =
+        Some(measure_lufs(&track.samples, track.sample_rate)).filter(|lufs| lufs.is_finite());
+
+    Ok(AnalyzeStemResponse::Success {
+        analysis: StemAnalysis {
+            kind: stem_kind.to_string(),
+            path: path.to_string_lossy().to_string(),
+            bpm,
+            key,
+            lufs,
+        },
+    })
+}
+
+/// stemのファイルの問題
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StemProblem {
+    /// ファイルが存在しない
+    Missing,
+    /// 0バイトのファイル（分離が途中で中断された可能性がある）
+    Empty,
+    /// ヘッダーを読み取れない（壊れている可能性がある）
+    Unreadable { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StemInfo {
+    pub kind: String,
+    pub path: String,
+    pub bytes: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<usize>,
+    pub duration_secs: Option<f64>,
+    /// 問題がなければNone
+    pub problem: Option<StemProblem>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum StemInfoResponse {
+    #[serde(alias = "success")]
+    Success { stems: Vec<StemInfo> },
+}
+
+/// 各stemのファイルサイズと形式をヘッダーだけから読み取る（デコードはしない）
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn get_stem_info(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<StemInfoResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+
+    let stems = project
+        .stem_paths
+        .iter()
+        .map(|stem_path| read_stem_info(stem_path))
+        .collect();
+
+    Ok(StemInfoResponse::Success { stems })
+}
+
+fn read_stem_info(stem_path: &str) -> StemInfo {
+    let path = PathBuf::from(stem_path);
+    let mut info = StemInfo {
+        kind: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        path: stem_path.to_string(),
+        bytes: 0,
+        sample_rate: None,
+        channels: None,
+        duration_secs: None,
+        problem: None,
+    };
+
+    match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => info.bytes = metadata.len(),
+        _ => {
+            info.problem = Some(StemProblem::Missing);
+            return info;
+        }
+    }
+    if info.bytes == 0 {
+        info.problem = Some(StemProblem::Empty);
+        return info;
+    }
+
+    match probe_audio_info(&path) {
+        Ok(audio) => {
+            info.sample_rate = audio.sample_rate;
+            info.channels = audio.channels;
+            info.duration_secs = audio.duration_secs;
+        }
+        Err(e) => {
+            eprintln!("[get_stem_info] Failed to read header of {:?}: {}", path, e);
+            info.problem = Some(StemProblem::Unreadable {
+                message: e.to_string(),
+            });
+        }
+    }
+
+    info
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum VerifyStemResponse {
+    #[serde(alias = "success")]
+    Success { result: VerifyResult },
+}
+
+/// 音声ファイルを最後までデコードして、壊れていないか（途中で切れていないか）を確認する
+#[tauri::command]
+#[tracing::instrument]
+pub async fn verify_stem(path: &str) -> Result<VerifyStemResponse> {
+    Ok(VerifyStemResponse::Success {
+        result: verify_audio_file(&PathBuf::from(path)),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum VerifyProjectStemsResponse {
+    #[serde(alias = "success")]
+    Success { results: Vec<VerifyResult> },
+}
+
+/// プロジェクトのすべてのstemを `verify_stem` で確認する（壊れたstemを再書き出しの対象として表示するため）
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn verify_project_stems(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<VerifyProjectStemsResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+
+    let results = project
+        .stem_paths
+        .iter()
+        .map(|stem_path| verify_audio_file(&PathBuf::from(stem_path)))
+        .collect();
+
+    Ok(VerifyProjectStemsResponse::Success { results })
+}
@@ -0,0 +1,667 @@
+use snafu::ResultExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+use serde::{self, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::State;
+use tracing::{info, warn};
+
+use crate::{
+    data::AppDb,
+    demucs::{
+        audio::{decode_file, encode_pcm, ExportConfig, ExportFormat},
+        detect_bpm_from_pcm, detect_key_from_pcm, embed_cover_art, estimate_downbeat,
+        find_cover_image, format_key,
+        metadata::write_dj_tags,
+        normalize_key_name, render_filter_preview, render_mixdown as render_stem_mixdown,
+        shift_stem_to_key, slice_stem_into_bars, stem_source_name, stretch_stem_to_bpm,
+        KeyNotation, MixdownStem, PitchShiftReport, StemFilter, StretchReport,
+    },
+    routes::ExportSnafu,
+    util::generate_random_string,
+};
+
+use super::{
+    ensure_separate_stems, ensure_within_directory, find_source_or_err, get_project_or_err, Error,
+    Result,
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum ExportStemResponse {
+    #[serde(alias = "success")]
+    Success { path: PathBuf },
+}
+
+/// 1つのstemをユーザーが選んだ場所に書き出す
+///
+/// formatを省略した場合、または元のファイルと同じフォーマットの場合はそのままコピーし、
+/// 異なる場合はデコードして指定フォーマットに変換する。
+/// dest_pathがディレクトリの場合は `{stem_kind}.{拡張子}` としてその中に保存する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn export_stem(
+    project_id: &str,
+    stem_kind: &str,
+    dest_path: &str,
+    format: Option<ExportFormat>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ExportStemResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_separate_stems(&project)?;
+
+    let stem_path = project
+        .find_stem(stem_kind)
+        .filter(|path| path.exists())
+        .ok_or_else(|| Error::StemNotFoundError {
+            kind: String::from(stem_kind),
+        })?;
+
+    let source_format = stem_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(ExportFormat::from_extension);
+    let format = format.or(source_format).unwrap_or_default();
+
+    let dest_path = resolve_export_path(Path::new(dest_path), stem_kind, format);
+
+    if source_format == Some(format) {
+        eprintln!("[export_stem] Copying {:?} to {:?}", stem_path, dest_path);
+        fs::copy(&stem_path, &dest_path).map_err(|e| Error::UnexpectedError {
+            message: format!("Failed to copy stem to {}", dest_path.display()),
+            source: Some(Box::new(e)),
+        })?;
+    } else {
+        eprintln!(
+            "[export_stem] Transcoding {:?} to {:?} ({:?})",
+            stem_path, dest_path, format
+        );
+        let audio = decode_file(&stem_path).context(ExportSnafu)?;
+        encode_pcm(audio, &dest_path, format).context(ExportSnafu)?;
+        embed_project_cover(&project.project_dir(), &dest_path, format);
+    }
+
+    Ok(ExportStemResponse::Success { path: dest_path })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum ExportBarsResponse {
+    #[serde(alias = "success")]
+    Success { slices: Vec<PathBuf> },
+}
+
+/// stemをN小節ごとのループ素材に切り分けて `{プロジェクト}/bars/{stem_kind}/` に書き出す
+///
+/// BPMはプロジェクトに保存された値を使い、無い場合は元の曲から検出する。
+/// 最初のダウンビートは元の曲（stemではなくミックス）から推定する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn export_bars(
+    project_id: &str,
+    stem_kind: &str,
+    bars_per_slice: usize,
+    export_config: Option<ExportConfig>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ExportBarsResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_separate_stems(&project)?;
+
+    let stem_path = project
+        .find_stem(stem_kind)
+        .filter(|path| path.exists())
+        .ok_or_else(|| Error::StemNotFoundError {
+            kind: String::from(stem_kind),
+        })?;
+
+    let song_path = find_source_or_err(&project)?;
+    let song = decode_file(&song_path).context(ExportSnafu)?;
+
+    let bpm = match project.bpm {
+        Some(bpm) => bpm,
+        None => detect_bpm_from_pcm(&song)
+            .context(ExportSnafu)?
+            .ok_or_else(|| Error::UnexpectedError {
+                message: String::from("Unable to detect the BPM of this project"),
+                source: None,
+            })?
+            .bpm,
+    };
+    // 解析時に拍の位置を推定できていればそれを使い、なければ音の立ち上がりから推定する
+    let downbeat_secs = project
+        .beat_offset_secs
+        .unwrap_or_else(|| estimate_downbeat(&song));
+    drop(song);
+
+    let output_dir = project.project_dir().join("bars").join(stem_kind);
+    let slices = slice_stem_into_bars(
+        &stem_path,
+        &output_dir,
+        bpm,
+        downbeat_secs,
+        bars_per_slice,
+        &export_config.unwrap_or_default(),
+    )
+    .context(ExportSnafu)?;
+
+    Ok(ExportBarsResponse::Success { slices })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum ExportStemAtBpmResponse {
+    #[serde(alias = "success")]
+    Success { report: StretchReport },
+}
+
+/// stemのテンポを `target_bpm` に合わせて（ピッチは変えずに）`{プロジェクト}/tempo/` に書き出す
+///
+/// 元のBPMはプロジェクトに保存された値を使い、無い場合は元の曲から検出する。
+/// 倍率は0.5〜2.0倍までで、倍率が大きい場合は `artifact_warning` を立てる
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn export_stem_at_bpm(
+    project_id: &str,
+    stem_kind: &str,
+    target_bpm: f64,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ExportStemAtBpmResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_separate_stems(&project)?;
+
+    let stem_path = project
+        .find_stem(stem_kind)
+        .filter(|path| path.exists())
+        .ok_or_else(|| Error::StemNotFoundError {
+            kind: String::from(stem_kind),
+        })?;
+
+    let source_bpm = match project.bpm {
+        Some(bpm) => bpm,
+        None => {
+            let song_path = find_source_or_err(&project)?;
+            let song = decode_file(&song_path).context(ExportSnafu)?;
+            detect_bpm_from_pcm(&song)
+                .context(ExportSnafu)?
+                .ok_or_else(|| Error::UnexpectedError {
+                    message: String::from("Unable to detect the BPM of this project"),
+                    source: None,
+                })?
+                .bpm
+        }
+    };
+
+    let output_dir = project.project_dir().join("tempo");
+    fs::create_dir_all(&output_dir).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to create {}", output_dir.display()),
+        source: Some(Box::new(e)),
+    })?;
+    let output_path = output_dir.join(format!("{}_{}bpm.wav", stem_kind, target_bpm));
+
+    let report = tauri::async_runtime::spawn_blocking(move || {
+        stretch_stem_to_bpm(&stem_path, &output_path, source_bpm, target_bpm)
+    })
+    .await
+    .map_err(|e| Error::UnexpectedError {
+        message: String::from("Time stretching was interrupted"),
+        source: Some(Box::new(e)),
+    })?
+    .context(ExportSnafu)?;
+
+    Ok(ExportStemAtBpmResponse::Success { report })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum ExportStemAtKeyResponse {
+    #[serde(alias = "success")]
+    Success { report: PitchShiftReport },
+}
+
+/// stemを `target_key` に移調して（長さは変えずに）`{プロジェクト}/key/` に書き出す
+///
+/// 元のキーはプロジェクトに保存された値を使い、無い場合は元の曲から検出する。
+/// 移調は近い方向の最大6半音で、3半音を超える場合は `artifact_warning` を立てる。
+/// 既定では主音どうしを合わせ、`match_relative` の場合は平行調（A minorとC majorなど）を同じキーとみなす
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn export_stem_at_key(
+    project_id: &str,
+    stem_kind: &str,
+    target_key: &str,
+    match_relative: Option<bool>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ExportStemAtKeyResponse> {
+    let target_key = normalize_key_name(target_key).ok_or_else(|| Error::InvalidKeyError {
+        key: String::from(target_key),
+    })?;
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_separate_stems(&project)?;
+
+    let stem_path = project
+        .find_stem(stem_kind)
+        .filter(|path| path.exists())
+        .ok_or_else(|| Error::StemNotFoundError {
+            kind: String::from(stem_kind),
+        })?;
+
+    let source_key = match project.key.as_deref().and_then(normalize_key_name) {
+        Some(key) => key,
+        None => {
+            let song_path = find_source_or_err(&project)?;
+            let song = decode_file(&song_path).context(ExportSnafu)?;
+            detect_key_from_pcm(&song)
+                .context(ExportSnafu)?
+                .ok_or_else(|| Error::UnexpectedError {
+                    message: String::from("Unable to detect the key of this project"),
+                    source: None,
+                })?
+        }
+    };
+
+    let output_dir = project.project_dir().join("key");
+    fs::create_dir_all(&output_dir).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to create {}", output_dir.display()),
+        source: Some(Box::new(e)),
+    })?;
+    let output_path = output_dir.join(format!(
+        "{}_{}.wav",
+        stem_kind,
+        target_key.replace(' ', "_")
+    ));
+
+    let match_relative = match_relative.unwrap_or(false);
+    let report = tauri::async_runtime::spawn_blocking(move || {
+        shift_stem_to_key(
+            &stem_path,
+            &output_path,
+            &source_key,
+            &target_key,
+            match_relative,
+        )
+    })
+    .await
+    .map_err(|e| Error::UnexpectedError {
+        message: String::from("Pitch shifting was interrupted"),
+        source: Some(Box::new(e)),
+    })?
+    .context(ExportSnafu)?;
+
+    Ok(ExportStemAtKeyResponse::Success { report })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum ExportDjTagsResponse {
+    #[serde(alias = "success")]
+    Success {
+        path: PathBuf,
+        /// TBPMに書き込んだ値（プロジェクトにBPMがない場合はNone）
+        bpm: Option<f64>,
+        /// TKEYに書き込んだ値（`notation` の表記、プロジェクトにキーがない場合はNone）
+        key: Option<String>,
+    },
+}
+
+/// プロジェクトのBPMとキーを、Rekordbox/Seratoが読むID3タグ（TBPM/TKEY）として元の曲に書き込む
+///
+/// 既定では元の曲を `{プロジェクト}/dj/` にコピーしてそちらに書き込む。
+/// `in_place` の場合はプロジェクト内の曲に直接書き込む（ファイルが変わるため、次の
+/// `refresh_analysis_if_stale` で1回だけ再解析される）。MP3/WAV/AIFFのみ対応
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn export_dj_tags(
+    project_id: &str,
+    notation: Option<KeyNotation>,
+    in_place: Option<bool>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ExportDjTagsResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+
+    let key = match project.key.as_deref() {
+        Some(key) => Some(
+            format_key(key, notation.unwrap_or_default()).ok_or_else(|| {
+                Error::InvalidKeyError {
+                    key: String::from(key),
+                }
+            })?,
+        ),
+        None => None,
+    };
+    if project.bpm.is_none() && key.is_none() {
+        return Err(Error::UnexpectedError {
+            message: String::from("This project has no BPM or key to export"),
+            source: None,
+        });
+    }
+
+    let project_dir = project.project_dir();
+    let song_path = find_source_or_err(&project)?;
+    let path = if in_place.unwrap_or(false) {
+        song_path
+    } else {
+        let output_dir = project_dir.join("dj");
+        fs::create_dir_all(&output_dir).map_err(|e| Error::UnexpectedError {
+            message: format!("Failed to create {}", output_dir.display()),
+            source: Some(Box::new(e)),
+        })?;
+        let copy_path = output_dir.join(song_path.file_name().unwrap_or_default());
+        fs::copy(&song_path, &copy_path).map_err(|e| Error::UnexpectedError {
+            message: format!("Failed to copy {}", song_path.display()),
+            source: Some(Box::new(e)),
+        })?;
+        copy_path
+    };
+
+    write_dj_tags(&path, project.bpm, key.as_deref()).context(ExportSnafu)?;
+    info!(
+        "[export_dj_tags] Wrote BPM {:?} and key {:?} to {:?}",
+        project.bpm, key, path
+    );
+
+    Ok(ExportDjTagsResponse::Success {
+        path,
+        bpm: project.bpm,
+        key,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum TranscodeStemResponse {
+    #[serde(alias = "success")]
+    Success { path: PathBuf },
+}
+
+/// プロジェクト内のstemを別フォーマット（MP3/FLACなど）に変換し、元のファイルと同じ場所に保存する
+///
+/// 元のWAVは残したまま、共有用のコピーを作る。プロジェクトのディレクトリ外のファイルは拒否する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn transcode_stem(
+    src_path: &str,
+    dest_format: ExportFormat,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<TranscodeStemResponse> {
+    let src_path = Path::new(src_path);
+    let projects = app_db_mutex
+        .lock()
+        .await
+        .get_projects()
+        .map_err(|_| Error::GetProjectsError)?;
+
+    let (src_path, project_dir) = projects
+        .iter()
+        .find_map(|project| {
+            let project_dir = project.project_dir();
+            ensure_within_directory(src_path, &project_dir)
+                .ok()
+                .map(|path| (path, project_dir))
+        })
+        .ok_or_else(|| Error::UnexpectedError {
+            message: format!("{} is not inside a project directory", src_path.display()),
+            source: None,
+        })?;
+
+    let dest_path = src_path.with_extension(dest_format.extension());
+    if dest_path == src_path {
+        return Ok(TranscodeStemResponse::Success { path: dest_path });
+    }
+
+    eprintln!(
+        "[transcode_stem] Transcoding {:?} to {:?} ({:?})",
+        src_path, dest_path, dest_format
+    );
+    let audio = decode_file(&src_path).context(ExportSnafu)?;
+    encode_pcm(audio, &dest_path, dest_format).context(ExportSnafu)?;
+    embed_project_cover(&project_dir, &dest_path, dest_format);
+
+    Ok(TranscodeStemResponse::Success { path: dest_path })
+}
+
+/// MP3に書き出した場合、プロジェクトのカバー画像を埋め込む（失敗しても書き出し自体は成功扱い）
+///
+/// 分離したstemはWAVのため、カバー画像はMP3に変換するときにだけ埋め込む
+fn embed_project_cover(project_dir: &Path, path: &Path, format: ExportFormat) {
+    if format != ExportFormat::Mp3 {
+        return;
+    }
+    let Some(cover_path) = find_cover_image(project_dir) else {
+        return;
+    };
+
+    if let Err(e) = embed_cover_art(path, &cover_path) {
+        warn!(
+            "[embed_project_cover] Failed to embed cover into {}: {:?}",
+            path.display(),
+            e
+        );
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum RenderMixdownResponse {
+    #[serde(alias = "success")]
+    Success { path: PathBuf },
+}
+
+/// プロジェクトのstemを、保存されたミキサー設定（ゲイン・ミュート・パン）で1つのファイルにまとめる
+///
+/// 設定のないstemは0dB・センターとして扱う。4stemなどの分離結果に後から作った
+/// instrumentalが含まれている場合は、二重に合算しないようinstrumentalを除外する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn render_mixdown(
+    project_id: &str,
+    output_path: &str,
+    format: Option<ExportFormat>,
+    export_config: Option<ExportConfig>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<RenderMixdownResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    ensure_separate_stems(&project)?;
+
+    let stem_paths: Vec<PathBuf> = project
+        .stem_paths
+        .iter()
+        .map(PathBuf::from)
+        .filter(|path| path.exists())
+        .collect();
+    let kind_of = |path: &PathBuf| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    // ファイル名を変えたstem（lead.wavなど）もあるので、instrumentalかどうかはソース名で判定する
+    let has_separated_stems = stem_paths.iter().any(|path| {
+        !matches!(
+            stem_source_name(path).as_str(),
+            "vocal" | "vocals" | "instrumental"
+        )
+    });
+
+    let stems: Vec<MixdownStem> = stem_paths
+        .iter()
+        .filter_map(|path| {
+            if has_separated_stems && stem_source_name(path) == "instrumental" {
+                return None;
+            }
+
+            let kind = kind_of(path);
+
+            let setting = project.stem_settings.iter().find(|s| s.kind == kind);
+            if setting.map_or(false, |s| s.muted) {
+                return None;
+            }
+
+            Some(MixdownStem {
+                path: path.clone(),
+                gain_db: setting.map_or(0.0, |s| s.gain_db as f32),
+                pan: setting.map_or(0.0, |s| s.pan as f32),
+            })
+        })
+        .collect();
+
+    let format = format
+        .or_else(|| {
+            Path::new(output_path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(ExportFormat::from_extension)
+        })
+        .unwrap_or_default();
+    let output_path = resolve_export_path(Path::new(output_path), "mixdown", format);
+
+    let path = render_stem_mixdown(
+        &stems,
+        &output_path,
+        format,
+        &export_config.unwrap_or_default(),
+    )
+    .context(ExportSnafu)?;
+
+    Ok(RenderMixdownResponse::Success { path })
+}
+
+/// 書き出し先がディレクトリの場合はその中のファイルパスにする
+fn resolve_export_path(dest_path: &Path, stem_kind: &str, format: ExportFormat) -> PathBuf {
+    if dest_path.is_dir() {
+        dest_path.join(format!("{}.{}", stem_kind, format.extension()))
+    } else {
+        dest_path.to_path_buf()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum StemDragResponse {
+    #[serde(alias = "success")]
+    Success {
+        /// ドラッグするstemのファイル（`stem_kinds` の順）
+        files: Vec<PathBuf>,
+        /// ドラッグ中に表示するアイコン（プロジェクトのカバー画像、なければNone）
+        icon: Option<PathBuf>,
+    },
+}
+
+/// DAWなどへドラッグアウトするstemのファイル一覧を用意する
+///
+/// ネイティブのドラッグはマウスイベントを受けたフロントエンドから `tauri-plugin-drag` の `startDrag` で開始するため、
+/// ここでは存在を確認したファイルのパスとアイコンを返す。`stem_kinds` が空の場合はすべてのstemを対象にする
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn start_stem_drag(
+    project_id: &str,
+    stem_kinds: Vec<String>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<StemDragResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+
+    let files = if stem_kinds.is_empty() {
+        project.stem_paths.iter().map(PathBuf::from).collect()
+    } else {
+        ensure_separate_stems(&project)?;
+        stem_kinds
+            .iter()
+            .map(|kind| {
+                project.find_stem(kind).ok_or_else(|| Error::StemNotFoundError {
+                    kind: kind.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    // 存在しないファイルをドラッグするとDAW側で読み込みエラーになるため、先に確認する
+    if let Some(missing) = files.iter().find(|path| !path.is_file()) {
+        return Err(Error::StemNotFoundError {
+            kind: missing
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_else(|| missing.to_string_lossy().to_string()),
+        });
+    }
+    if files.is_empty() {
+        return Err(Error::UnexpectedError {
+            message: String::from("This project has no stems to drag"),
+            source: None,
+        });
+    }
+
+    Ok(StemDragResponse::Success {
+        files,
+        icon: find_cover_image(&project.project_dir()),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum EqPreviewResponse {
+    #[serde(alias = "success")]
+    Success { path: PathBuf },
+}
+
+/// EQプレビューを書き出す一時ディレクトリ
+const EQ_PREVIEW_DIR_NAME: &str = "tune-prism-eq-preview";
+
+/// stemにフィルタを掛けたプレビューを一時ディレクトリのWAVに書き出す（分離し直さずにEQを試聴するため）
+///
+/// 同じstemの以前のプレビューは削除する。ファイル名は毎回変わるため、プレーヤーのキャッシュで古い音が再生されることはない
+#[tauri::command]
+#[tracing::instrument]
+pub async fn apply_eq_preview(stem_path: &str, filters: Vec<StemFilter>) -> Result<EqPreviewResponse> {
+    let stem_path = PathBuf::from(stem_path);
+    let preview_key = preview_key(&stem_path);
+
+    let preview_dir = std::env::temp_dir().join(EQ_PREVIEW_DIR_NAME);
+    fs::create_dir_all(&preview_dir).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to create {}", preview_dir.display()),
+        source: Some(Box::new(e)),
+    })?;
+    remove_previous_previews(&preview_dir, &preview_key);
+
+    let output_path = preview_dir.join(format!("{}-{}.wav", preview_key, generate_random_string()));
+    let path = tauri::async_runtime::spawn_blocking(move || {
+        render_filter_preview(&stem_path, &filters, &output_path)
+    })
+    .await
+    .map_err(|e| Error::UnexpectedError {
+        message: String::from("EQ preview rendering was interrupted"),
+        source: Some(Box::new(e)),
+    })?
+    .context(ExportSnafu)?;
+
+    Ok(EqPreviewResponse::Success { path })
+}
+
+/// プレビューのファイル名の接頭辞
+///
+/// stemのファイル名だけだとプロジェクトをまたいで衝突し、"bass" と "bass-guitar" のような名前も
+/// 前方一致してしまうため、フルパスのハッシュ（固定長の16進数）を使う
+fn preview_key(stem_path: &Path) -> String {
+    Sha256::digest(stem_path.to_string_lossy().as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn remove_previous_previews(preview_dir: &Path, preview_key: &str) {
+    let Ok(entries) = fs::read_dir(preview_dir) else {
+        return;
+    };
+    let prefix = format!("{}-", preview_key);
+    for entry in entries.flatten() {
+        let is_previous = entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&prefix);
+        if is_previous {
+            if let Err(e) = fs::remove_file(entry.path()) {
+                eprintln!("[apply_eq_preview] Failed to remove {:?}: {}", entry.path(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+
+use serde::Serialize;
+use tauri::{AppHandle, Manager, State};
+
+use crate::demucs::{JobStatus, SplitEstimate, SplitProgress};
+
+use super::Result;
+
+/// 状態が変わったときにフロントエンドへ送るイベント名
+pub const JOB_STATUS_EVENT: &str = "job-status";
+/// 推論を始める前に、推論時間の見積もりをフロントエンドへ送るイベント名
+pub const SPLIT_ESTIMATE_EVENT: &str = "split_estimate";
+
+/// プロジェクトごとの分離ジョブの状態
+///
+/// 分離処理中（ワーカースレッドを含む）から同期的に更新するため、tokioではなくstdのMutexを使う
+#[derive(Debug, Default)]
+pub struct JobRegistry {
+    jobs: StdMutex<HashMap<String, JobStatus>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JobStatusEvent {
+    project_id: String,
+    status: JobStatus,
+}
+
+impl JobRegistry {
+    /// 状態を更新し、変化した場合だけイベントを送る
+    pub fn set(&self, app_handle: &AppHandle, project_id: &str, status: JobStatus) {
+        let changed = {
+            let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+            let previous = jobs.insert(project_id.to_string(), status.clone());
+            previous.as_ref() != Some(&status)
+        };
+
+        if changed {
+            eprintln!("[JobRegistry] {}: {:?}", project_id, status);
+            let event = JobStatusEvent {
+                project_id: project_id.to_string(),
+                status,
+            };
+            if let Err(e) = app_handle.emit_all(JOB_STATUS_EVENT, event) {
+                eprintln!("[JobRegistry] Failed to emit job status event: {}", e);
+            }
+        }
+    }
+
+    pub fn get(&self, project_id: &str) -> Option<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(project_id)
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SplitEstimateEvent {
+    project_id: String,
+    estimate: SplitEstimate,
+}
+
+/// 分離の進行状況をレジストリに反映し、フロントエンドへイベントを送る
+pub struct JobProgress<'a> {
+    pub registry: &'a JobRegistry,
+    pub app_handle: &'a AppHandle,
+    pub project_id: &'a str,
+}
+
+impl SplitProgress for JobProgress<'_> {
+    fn status(&self, status: JobStatus) {
+        self.registry.set(self.app_handle, self.project_id, status);
+    }
+
+    fn estimate(&self, estimate: SplitEstimate) {
+        let event = SplitEstimateEvent {
+            project_id: self.project_id.to_string(),
+            estimate,
+        };
+        if let Err(e) = self.app_handle.emit_all(SPLIT_ESTIMATE_EVENT, event) {
+            eprintln!("[JobProgress] Failed to emit split estimate event: {}", e);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum JobStatusResponse {
+    #[serde(alias = "success")]
+    Success { job: Option<JobStatus> },
+}
+
+/// プロジェクトの分離ジョブの状態を取得する（一度も実行していない場合はNone）
+#[tauri::command]
+pub async fn get_job_status(
+    project_id: &str,
+    job_registry: State<'_, JobRegistry>,
+) -> Result<JobStatusResponse> {
+    Ok(JobStatusResponse::Success {
+        job: job_registry.get(project_id),
+    })
+}
@@ -1,10 +1,22 @@
+pub mod analysis;
+pub mod export;
+pub mod jobs;
 pub mod project;
+pub mod settings;
 pub mod split;
+pub mod system;
+
+use std::path::{Path, PathBuf};
 
 use serde::Serialize;
 use snafu::Snafu;
+use tauri::State;
+use tokio::sync::Mutex;
 
-use crate::demucs;
+use crate::{
+    data::{find_song_in_project, AppDb, Project},
+    demucs,
+};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
@@ -22,11 +34,77 @@ pub enum Error {
     #[snafu(display("Failed to fetch projects"))]
     GetProjectsError,
 
+    #[snafu(display("Failed to fetch project"))]
+    GetProjectError,
+
     #[snafu(display("Failed to split track: {source}"))]
     StemSplitError { source: demucs::Error },
 
     #[snafu(display("Failed to save stems"))]
     StemSaveError,
+
+    #[snafu(display("Failed to analyze audio: {source}"))]
+    AnalysisError { source: demucs::Error },
+
+    #[snafu(display("Failed to update settings: {message}"))]
+    SettingsError { message: String },
+
+    #[snafu(display("Project not found: {project_id}"))]
+    ProjectNotFoundError { project_id: String },
+
+    #[snafu(display("Project name must not be empty"))]
+    EmptyProjectNameError,
+
+    #[snafu(display("Tag must not be empty"))]
+    EmptyTagError,
+
+    #[snafu(display("BPM must be between {min} and {max} (got {bpm})"))]
+    InvalidBpmError { bpm: f64, min: f64, max: f64 },
+
+    #[snafu(display("Invalid key \"{key}\" (expected e.g. \"C major\" or \"F# minor\")"))]
+    InvalidKeyError { key: String },
+
+    #[snafu(display("Invalid stem settings: {message}"))]
+    InvalidStemSettingsError { message: String },
+
+    #[snafu(display("Failed to import audio: {message}"))]
+    ImportError { message: String },
+
+    #[snafu(display("Failed to clean up projects: {message}"))]
+    CleanupError { message: String },
+
+    #[snafu(display("Failed to relink project: {message}"))]
+    RelinkError { message: String },
+
+    #[snafu(display(
+        "The source audio of project {project_id} was deleted after splitting; \
+         relink or re-import the audio file to use this feature"
+    ))]
+    SourceDeletedError { project_id: String },
+
+    #[snafu(display(
+        "The stems of project {project_id} were saved as a single multichannel file; \
+         split again with separate stem files to use this feature"
+    ))]
+    MultichannelStemsError { project_id: String },
+
+    #[snafu(display("Project folder {} does not exist", path.display()))]
+    ProjectDirectoryNotFoundError { path: PathBuf },
+
+    #[snafu(display("Stem \"{kind}\" does not exist for this project"))]
+    StemNotFoundError { kind: String },
+
+    #[snafu(display("Model \"{name}\" is not available"))]
+    ModelNotFoundError { name: String },
+
+    #[snafu(display("No available model provides all of {stems:?}"))]
+    NoModelForStemsError { stems: Vec<String> },
+
+    #[snafu(display("Failed to export stem: {source}"))]
+    ExportError { source: demucs::Error },
+
+    #[snafu(display("Failed to download model: {source}"))]
+    ModelDownloadError { source: demucs::Error },
 }
 
 #[derive(Serialize)]
@@ -50,3 +128,79 @@ impl Serialize for Error {
 }
 
 type Result<T> = std::result::Result<T, Error>;
+
+/// プロジェクトを取得（存在しない場合はエラー）
+pub(crate) async fn get_project_or_err(
+    app_db_mutex: &State<'_, Mutex<AppDb>>,
+    project_id: &str,
+) -> Result<Project> {
+    app_db_mutex
+        .lock()
+        .await
+        .get_project_by_id(String::from(project_id))
+        .map_err(|_| Error::GetProjectError)?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })
+}
+
+/// 分割後に曲ファイルを削除したプロジェクトでないことを確認する
+pub(crate) fn ensure_source_available(project: &Project) -> Result<()> {
+    if project.source_deleted {
+        return Err(Error::SourceDeletedError {
+            project_id: project._id.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// stemが1つのマルチチャンネルWAVにまとめられたプロジェクトでないことを確認する
+///
+/// まとめたファイルではstemごとに取り出したり合算したりできないため、stem単位の操作の前に呼ぶ
+pub(crate) fn ensure_separate_stems(project: &Project) -> Result<()> {
+    if project
+        .stem_paths
+        .iter()
+        .any(|path| demucs::is_multichannel_stem_file(Path::new(path)))
+    {
+        return Err(Error::MultichannelStemsError {
+            project_id: project._id.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// プロジェクトの曲ファイルのパスを取得（削除済み・見つからない場合はエラー）
+pub(crate) fn find_source_or_err(project: &Project) -> Result<PathBuf> {
+    ensure_source_available(project)?;
+    find_song_in_project(&project.project_dir()).ok_or_else(|| Error::UnexpectedError {
+        message: format!("Audio file not found for project {}", project._id),
+        source: None,
+    })
+}
+
+/// パスを正規化し、指定したディレクトリ内にあることを確認する（`..`やシンボリックリンクでの脱出を防ぐ）
+pub(crate) fn ensure_within_directory(
+    path: &Path,
+    directory: &Path,
+) -> std::result::Result<PathBuf, String> {
+    let canonical_dir = directory
+        .canonicalize()
+        .map_err(|e| format!("Project directory {} is not accessible: {}", directory.display(), e))?;
+    let canonical_path = path
+        .canonicalize()
+        .map_err(|e| format!("File {} is not accessible: {}", path.display(), e))?;
+
+    if !canonical_path.starts_with(&canonical_dir) {
+        eprintln!(
+            "[ensure_within_directory] Rejected path outside project directory: {}",
+            path.display()
+        );
+        return Err(format!(
+            "File {} is outside of the project directory",
+            path.display()
+        ));
+    }
+
+    Ok(canonical_path)
+}
@@ -2,11 +2,20 @@ use std::path::PathBuf;
 use tokio::sync::Mutex;
 
 use serde::{self, Deserialize, Serialize};
-use tauri::State;
+use tauri::{AppHandle, Manager, State};
+use tracing::info;
 
-use crate::data::{AppDb, Project};
+use crate::data::{
+    analyze_project_song, normalize_tag, AppDb, LibraryStats, OrphanCleanupReport, Project,
+    StemSetting,
+};
+use crate::demucs::audio::{
+    probe_audio as probe_audio_file, supported_input_extensions, AudioProbe,
+};
+use crate::demucs::normalize_key_name;
+use crate::demucs::metadata::{read_metadata as read_track_metadata, TrackMetadata};
 
-use super::{Error, Result};
+use super::{get_project_or_err, Error, Result};
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "status")]
@@ -22,28 +31,507 @@ pub enum GetAllProjectsResponse {
     Success { projects: Vec<Project> },
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum GetProjectResponse {
+    #[serde(alias = "success")]
+    Success { project: Option<Project> },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum RenameProjectResponse {
+    #[serde(alias = "success")]
+    Success { project: Project },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum UpdateStemSettingsResponse {
+    #[serde(alias = "success")]
+    Success { project: Project },
+}
+
+/// 解析が終わったプロジェクト（`Project`）をフロントエンドへ送るイベント名
+pub const PROJECT_ANALYZED_EVENT: &str = "project_analyzed";
+
+/// `use_track_title` を省略またはtrueにすると、タグがあれば "{artist} - {title}" をプロジェクト名にする
+///
+/// `delete_source_after_split` をtrueにすると、分離に成功した後でプロジェクトにコピーした曲ファイルを削除する。
+/// 曲をコピーした時点で返し、BPM/Keyなどの解析はバックグラウンドで行う（完了すると `project_analyzed` イベントを送る）
 #[tauri::command]
 pub async fn create_project(
     audio_filepath: &str,
+    use_track_title: Option<bool>,
+    delete_source_after_split: Option<bool>,
     app_db_mutex: State<'_, Mutex<AppDb>>,
+    app_handle: AppHandle,
 ) -> Result<CreateProjectResponse> {
     let app_db = app_db_mutex.lock().await;
 
-    app_db
-        .create_project(PathBuf::from(audio_filepath))
-        .map_or(Err(Error::ProjectCreationError), |project| {
-            Ok(CreateProjectResponse::Success { project })
-        })
+    let project = app_db
+        .create_project(
+            PathBuf::from(audio_filepath),
+            use_track_title.unwrap_or(true),
+            delete_source_after_split.unwrap_or(false),
+        )
+        .map_err(|_| Error::ProjectCreationError)?;
+    spawn_project_analysis(app_handle, project.clone());
+
+    Ok(CreateProjectResponse::Success { project })
 }
 
+/// メモリ上の音声データ（ドラッグ&ドロップなど）からプロジェクトを作成する
+#[tauri::command]
+pub async fn create_project_from_bytes(
+    name: String,
+    data: Vec<u8>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    app_handle: AppHandle,
+) -> Result<CreateProjectResponse> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::EmptyProjectNameError);
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .create_project_from_bytes(name, &data)
+        .map_err(|message| Error::ImportError { message })?;
+    spawn_project_analysis(app_handle, project.clone());
+
+    Ok(CreateProjectResponse::Success { project })
+}
+
+/// 作成したプロジェクトの曲をバックグラウンドで解析し、結果を保存して `project_analyzed` イベントを送る
+///
+/// 解析中はDBのロックを持たないため、他のコマンドはブロックされない
+fn spawn_project_analysis(app_handle: AppHandle, project: Project) {
+    tauri::async_runtime::spawn(async move {
+        let project_id = project._id.clone();
+        let analysis =
+            tauri::async_runtime::spawn_blocking(move || analyze_project_song(&project)).await;
+        let update_doc = match analysis {
+            Ok(Ok(update_doc)) => update_doc,
+            Ok(Err(e)) => {
+                eprintln!(
+                    "[spawn_project_analysis] Failed to analyze {}: {}",
+                    project_id, e
+                );
+                Default::default()
+            }
+            Err(e) => {
+                eprintln!(
+                    "[spawn_project_analysis] Analysis task for {} failed: {}",
+                    project_id, e
+                );
+                Default::default()
+            }
+        };
+
+        let app_db_mutex = app_handle.state::<Mutex<AppDb>>();
+        let saved = app_db_mutex
+            .lock()
+            .await
+            .save_song_analysis(&project_id, update_doc);
+        match saved {
+            Ok(Some(project)) => {
+                if let Err(e) = app_handle.emit_all(PROJECT_ANALYZED_EVENT, project) {
+                    eprintln!(
+                        "[spawn_project_analysis] Failed to emit project analyzed event: {}",
+                        e
+                    );
+                }
+            }
+            // 解析中に削除されたプロジェクト
+            Ok(None) => {}
+            Err(e) => eprintln!("[spawn_project_analysis] Failed to save analysis: {}", e),
+        }
+    });
+}
+
+/// `tag` を指定した場合は、そのタグが付いたプロジェクトだけを返す
 #[tauri::command]
 pub async fn get_all_projects(
+    tag: Option<String>,
     app_db_mutex: State<'_, Mutex<AppDb>>,
 ) -> Result<GetAllProjectsResponse> {
+    let app_db = app_db_mutex.lock().await;
+    let projects = match tag.as_deref().and_then(normalize_tag) {
+        Some(tag) => app_db.find_projects_by_tag(&tag),
+        None => app_db.get_projects(),
+    };
+
+    projects.map_or(Err(Error::ProjectCreationError), |projects| {
+        Ok(GetAllProjectsResponse::Success { projects })
+    })
+}
+
+#[tauri::command]
+pub async fn get_project(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<GetProjectResponse> {
     let app_db = app_db_mutex.lock().await;
     app_db
-        .get_projects()
-        .map_or(Err(Error::ProjectCreationError), |projects| {
-            Ok(GetAllProjectsResponse::Success { projects })
+        .get_project_by_id(String::from(project_id))
+        .map_or(Err(Error::GetProjectError), |project| {
+            Ok(GetProjectResponse::Success { project })
         })
 }
+
+/// プロジェクト名を変更する（前後の空白は取り除き、空の名前は拒否）
+#[tauri::command]
+pub async fn rename_project(
+    project_id: &str,
+    new_name: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<RenameProjectResponse> {
+    let new_name = new_name.trim();
+    if new_name.is_empty() {
+        return Err(Error::EmptyProjectNameError);
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .rename_project(project_id, new_name)
+        .map_err(|message| Error::UnexpectedError {
+            message,
+            source: None,
+        })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(RenameProjectResponse::Success { project })
+}
+
+/// 手動で設定できるBPMの範囲
+const MIN_USER_BPM: f64 = 40.0;
+const MAX_USER_BPM: f64 = 250.0;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum SetProjectAnalysisResponse {
+    #[serde(alias = "success")]
+    Success { project: Project },
+}
+
+/// 検出したBPMを手動で修正する（以降の再解析ではBPMを上書きしない）
+#[tauri::command]
+pub async fn set_project_bpm(
+    project_id: &str,
+    bpm: f64,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<SetProjectAnalysisResponse> {
+    if !(MIN_USER_BPM..=MAX_USER_BPM).contains(&bpm) {
+        return Err(Error::InvalidBpmError {
+            bpm,
+            min: MIN_USER_BPM,
+            max: MAX_USER_BPM,
+        });
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .set_project_bpm(project_id, bpm)
+        .map_err(|message| Error::UnexpectedError {
+            message,
+            source: None,
+        })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(SetProjectAnalysisResponse::Success { project })
+}
+
+/// 検出したKeyを手動で修正する（"C major" / "F# minor" の形式、大文字小文字は区別しない）
+///
+/// 以降の再解析ではKeyを上書きしない
+#[tauri::command]
+pub async fn set_project_key(
+    project_id: &str,
+    key: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<SetProjectAnalysisResponse> {
+    let key = normalize_key_name(key).ok_or_else(|| Error::InvalidKeyError {
+        key: key.to_string(),
+    })?;
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .set_project_key(project_id, &key)
+        .map_err(|message| Error::UnexpectedError {
+            message,
+            source: None,
+        })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(SetProjectAnalysisResponse::Success { project })
+}
+
+/// ミキサーのstemごとの設定（ゲイン・ミュート・パン）を保存する
+#[tauri::command]
+pub async fn update_stem_settings(
+    project_id: &str,
+    settings: Vec<StemSetting>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<UpdateStemSettingsResponse> {
+    for setting in &settings {
+        setting
+            .validate()
+            .map_err(|message| Error::InvalidStemSettingsError { message })?;
+    }
+    for (i, setting) in settings.iter().enumerate() {
+        if settings[..i].iter().any(|other| other.kind == setting.kind) {
+            return Err(Error::InvalidStemSettingsError {
+                message: format!("duplicate settings for \"{}\"", setting.kind),
+            });
+        }
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .update_stem_settings(project_id, &settings)
+        .map_err(|message| Error::UnexpectedError {
+            message,
+            source: None,
+        })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(UpdateStemSettingsResponse::Success { project })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum SupportedInputExtensionsResponse {
+    #[serde(alias = "success")]
+    Success { extensions: Vec<String> },
+}
+
+/// 読み込みに対応している音声ファイルの拡張子（ファイル選択ダイアログのフィルタ用）
+#[tauri::command]
+pub async fn get_supported_input_extensions() -> Result<SupportedInputExtensionsResponse> {
+    Ok(SupportedInputExtensionsResponse::Success {
+        extensions: supported_input_extensions()
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect(),
+    })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum ReadMetadataResponse {
+    #[serde(alias = "success")]
+    Success { metadata: TrackMetadata },
+}
+
+/// 音声ファイルのタグ（タイトル・アーティストなど）を読む。インポート前にプロジェクト名を補完するため
+#[tauri::command]
+pub async fn read_metadata(path: &str) -> Result<ReadMetadataResponse> {
+    let metadata = read_track_metadata(&PathBuf::from(path)).map_err(|e| Error::ImportError {
+        message: e.to_string(),
+    })?;
+
+    Ok(ReadMetadataResponse::Success { metadata })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum ProbeAudioResponse {
+    #[serde(alias = "success")]
+    Success { probe: AudioProbe },
+}
+
+/// ドロップされたファイルをインポートする前に、読み込めるか確認して形式・長さなどを返す
+///
+/// ファイル全体はデコードしないため、長い曲でもすぐに返る
+#[tauri::command]
+pub async fn probe_audio(path: &str) -> Result<ProbeAudioResponse> {
+    let probe = probe_audio_file(&PathBuf::from(path)).map_err(|e| Error::ImportError {
+        message: e.to_string(),
+    })?;
+
+    Ok(ProbeAudioResponse::Success { probe })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum GcOrphanedProjectsResponse {
+    #[serde(alias = "success")]
+    Success { report: OrphanCleanupReport },
+}
+
+/// DBにないプロジェクトのディレクトリを削除し、ファイルのないプロジェクトに印を付ける
+#[tauri::command]
+pub async fn gc_orphaned_projects(
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<GcOrphanedProjectsResponse> {
+    let app_db = app_db_mutex.lock().await;
+    let report = app_db
+        .gc_orphaned_projects()
+        .map_err(|message| Error::CleanupError { message })?;
+
+    Ok(GcOrphanedProjectsResponse::Success { report })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum LibraryStatsResponse {
+    #[serde(alias = "success")]
+    Success { stats: LibraryStats },
+}
+
+/// プロジェクト数・stem数・ディスク使用量・平均BPMを集計する
+///
+/// ディスク使用量はプロジェクトのディレクトリを辿って測るため、DBのロックを外してから別スレッドで行う
+#[tauri::command]
+pub async fn get_library_stats(
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<LibraryStatsResponse> {
+    let projects = app_db_mutex
+        .lock()
+        .await
+        .get_projects()
+        .map_err(|_| Error::GetProjectsError)?;
+
+    let stats = tauri::async_runtime::spawn_blocking(move || LibraryStats::collect(&projects))
+        .await
+        .map_err(|e| Error::UnexpectedError {
+            message: String::from("Library stats collection was interrupted"),
+            source: Some(Box::new(e)),
+        })?;
+
+    Ok(LibraryStatsResponse::Success { stats })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum RelinkProjectResponse {
+    #[serde(alias = "success")]
+    Success { project: Project },
+}
+
+/// ライブラリのフォルダを移動したプロジェクトを、新しい保存先（`project_data` を含むディレクトリ）に付け替える
+#[tauri::command]
+pub async fn relink_project(
+    project_id: &str,
+    new_base_dir: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<RelinkProjectResponse> {
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .relink_project(project_id, &PathBuf::from(new_base_dir))
+        .map_err(|message| Error::RelinkError { message })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(RelinkProjectResponse::Success { project })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum RevealProjectFolderResponse {
+    #[serde(alias = "success")]
+    Success { path: PathBuf },
+}
+
+/// プロジェクトのフォルダ（`project_data/{id}`）をFinder/エクスプローラーなどのファイルマネージャーで開く
+///
+/// ライブラリを移動した後などでフォルダが見つからない場合はエラーにする（`relink_project` で付け替える）
+#[tauri::command]
+pub async fn reveal_project_folder(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<RevealProjectFolderResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    let path = project.project_dir();
+    if !path.is_dir() {
+        return Err(Error::ProjectDirectoryNotFoundError { path });
+    }
+
+    // ファイルマネージャーが閉じられるまで待たないよう、プロセスを切り離して開く
+    open::that_detached(&path).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to open {}", path.display()),
+        source: Some(Box::new(e)),
+    })?;
+    info!("[reveal_project_folder] Opened {:?}", path);
+
+    Ok(RevealProjectFolderResponse::Success { path })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum ProjectTagsResponse {
+    #[serde(alias = "success")]
+    Success { project: Project },
+}
+
+/// プロジェクトにタグを付ける（小文字・前後の空白なしに正規化して保存）
+#[tauri::command]
+pub async fn add_project_tag(
+    project_id: &str,
+    tag: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ProjectTagsResponse> {
+    let tag = normalize_tag(tag).ok_or(Error::EmptyTagError)?;
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .add_project_tag(project_id, &tag)
+        .map_err(|message| Error::UnexpectedError {
+            message,
+            source: None,
+        })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(ProjectTagsResponse::Success { project })
+}
+
+/// プロジェクトからタグを外す
+#[tauri::command]
+pub async fn remove_project_tag(
+    project_id: &str,
+    tag: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<ProjectTagsResponse> {
+    let tag = normalize_tag(tag).ok_or(Error::EmptyTagError)?;
+
+    let app_db = app_db_mutex.lock().await;
+    let project = app_db
+        .remove_project_tag(project_id, &tag)
+        .map_err(|message| Error::UnexpectedError {
+            message,
+            source: None,
+        })?
+        .ok_or_else(|| Error::ProjectNotFoundError {
+            project_id: String::from(project_id),
+        })?;
+
+    Ok(ProjectTagsResponse::Success { project })
+}
+
+/// 指定したタグが付いたプロジェクトを取得する
+#[tauri::command]
+pub async fn find_projects_by_tag(
+    tag: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<GetAllProjectsResponse> {
+    let tag = normalize_tag(tag).ok_or(Error::EmptyTagError)?;
+
+    let app_db = app_db_mutex.lock().await;
+    let projects = app_db
+        .find_projects_by_tag(&tag)
+        .map_err(|_| Error::GetProjectsError)?;
+
+    Ok(GetAllProjectsResponse::Success { projects })
+}
@@ -0,0 +1,229 @@
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+use serde::{self, Deserialize, Serialize};
+use tauri::State;
+
+use crate::{
+    data::AppDb,
+    demucs::{
+        device_name, get_available_device, max_segment_secs, parse_device,
+        set_max_segment_override, LazyModelLoader, DEFAULT_CPU_THREADS, MIN_MAX_SEGMENT_SECS,
+    },
+    util::{
+        ensure_writable_directory, get_app_directory, get_base_directory,
+        set_base_directory_override,
+    },
+};
+
+use super::{Error, Result};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum OutputDirectoryResponse {
+    #[serde(alias = "success")]
+    Success { path: PathBuf, is_custom: bool },
+}
+
+#[tauri::command]
+pub async fn get_output_directory(
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<OutputDirectoryResponse> {
+    let app_db = app_db_mutex.lock().await;
+    let settings = app_db
+        .get_settings()
+        .map_err(|message| Error::SettingsError { message })?;
+
+    Ok(OutputDirectoryResponse::Success {
+        path: get_base_directory(),
+        is_custom: settings.output_directory.is_some(),
+    })
+}
+
+/// プロジェクトの保存先を変更する（Noneでデフォルトに戻す）
+///
+/// 既存のプロジェクトは作成時の保存先に残り、新しいプロジェクトのみ新しい保存先に作成される
+#[tauri::command]
+pub async fn set_output_directory(
+    path: Option<String>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<OutputDirectoryResponse> {
+    let output_directory = path.map(PathBuf::from);
+    let base_dir = output_directory.clone().unwrap_or_else(get_app_directory);
+
+    // 保存前に書き込み可能か確認
+    ensure_writable_directory(&base_dir.join("project_data"))
+        .map_err(|message| Error::SettingsError { message })?;
+
+    let app_db = app_db_mutex.lock().await;
+    let mut settings = app_db
+        .get_settings()
+        .map_err(|message| Error::SettingsError { message })?;
+    settings.output_directory = output_directory;
+    app_db
+        .save_settings(&settings)
+        .map_err(|message| Error::SettingsError { message })?;
+
+    set_base_directory_override(settings.output_directory.clone());
+    eprintln!("[set_output_directory] Output directory set to: {:?}", base_dir);
+
+    Ok(OutputDirectoryResponse::Success {
+        path: base_dir,
+        is_custom: settings.output_directory.is_some(),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum DevicePreferenceResponse {
+    #[serde(alias = "success")]
+    Success {
+        preference: Option<String>,
+        active_device: String,
+    },
+}
+
+/// 推論に使うデバイスを保存する（Noneで自動検出に戻す）
+///
+/// デバイスが変わった場合はロード済みのモデルを破棄し、次回の分離時に新しいデバイスでロードする
+#[tauri::command]
+pub async fn set_device_preference(
+    device: Option<String>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<DevicePreferenceResponse> {
+    let preference = device.map(|name| name.trim().to_lowercase());
+    if let Some(name) = &preference {
+        if parse_device(name).is_none() {
+            return Err(Error::SettingsError {
+                message: format!("Unknown device: {} (expected cpu, cuda or mps)", name),
+            });
+        }
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let mut settings = app_db
+        .get_settings()
+        .map_err(|message| Error::SettingsError { message })?;
+    settings.device = preference.clone();
+    app_db
+        .save_settings(&settings)
+        .map_err(|message| Error::SettingsError { message })?;
+
+    let device = get_available_device(preference.as_deref());
+    let mut loader = model_loader.lock().await;
+    if loader.device != device {
+        loader.unload();
+        loader.device = device;
+    }
+
+    Ok(DevicePreferenceResponse::Success {
+        preference,
+        active_device: device_name(device),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum CpuThreadsResponse {
+    #[serde(alias = "success")]
+    Success {
+        threads: Option<usize>,
+        /// 現在libtorchが使っているスレッド数
+        active_threads: usize,
+        /// 設定を反映するには再起動が必要
+        restart_required: bool,
+    },
+}
+
+/// CPU推論のスレッド数を保存する（Noneで既定値の1に戻す）
+///
+/// OpenMPはプロセスの起動時にしか設定を読まないため、次回の起動から反映される。
+/// 複数のOpenMPライブラリが読み込まれる環境では、2以上にすると推論中にクラッシュすることがある
+#[tauri::command]
+pub async fn set_cpu_threads(
+    threads: Option<usize>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<CpuThreadsResponse> {
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    if let Some(threads) = threads {
+        if threads == 0 || threads > max_threads {
+            return Err(Error::SettingsError {
+                message: format!(
+                    "CPU threads must be between 1 and {} (the number of logical cores)",
+                    max_threads
+                ),
+            });
+        }
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let mut settings = app_db
+        .get_settings()
+        .map_err(|message| Error::SettingsError { message })?;
+    settings.cpu_threads = threads;
+    app_db
+        .save_settings(&settings)
+        .map_err(|message| Error::SettingsError { message })?;
+
+    let active_threads = tch::get_num_threads().max(1) as usize;
+    eprintln!(
+        "[set_cpu_threads] Saved CPU threads {:?} (active: {})",
+        threads, active_threads
+    );
+
+    Ok(CpuThreadsResponse::Success {
+        threads,
+        active_threads,
+        restart_required: threads.unwrap_or(DEFAULT_CPU_THREADS) != active_threads,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum MaxSegmentResponse {
+    #[serde(alias = "success")]
+    Success {
+        secs: Option<f64>,
+        /// 現在のデバイスで実際に使われる上限（Noneならトラック全体を一度に推論）
+        active_secs: Option<f64>,
+    },
+}
+
+/// 一度に推論するトラックの長さの上限（秒）を保存する（Noneでデバイスごとの既定値に戻す）
+///
+/// 上限より長いトラックはチャンクに分けて推論する。MPSでメモリ不足になる場合は小さくする
+#[tauri::command]
+pub async fn set_max_segment_secs(
+    secs: Option<f64>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<MaxSegmentResponse> {
+    if let Some(secs) = secs {
+        if !secs.is_finite() || secs < MIN_MAX_SEGMENT_SECS {
+            return Err(Error::SettingsError {
+                message: format!(
+                    "Max segment length must be at least {} seconds",
+                    MIN_MAX_SEGMENT_SECS
+                ),
+            });
+        }
+    }
+
+    let app_db = app_db_mutex.lock().await;
+    let mut settings = app_db
+        .get_settings()
+        .map_err(|message| Error::SettingsError { message })?;
+    settings.max_segment_secs = secs;
+    app_db
+        .save_settings(&settings)
+        .map_err(|message| Error::SettingsError { message })?;
+
+    set_max_segment_override(secs);
+    let device = model_loader.lock().await.device;
+
+    Ok(MaxSegmentResponse::Success {
+        secs,
+        active_secs: max_segment_secs(device),
+    })
+}
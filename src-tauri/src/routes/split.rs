@@ -1,57 +1,634 @@
 use snafu::ResultExt;
 use tokio::sync::Mutex;
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf as StdPathBuf;
+use std::io::{BufReader, Seek, Write};
+use std::path::{Path, PathBuf as StdPathBuf};
+use std::sync::Arc;
+use zip::write::{FileOptions, ZipWriter};
+use zip::CompressionMethod;
 
 use serde::{self, Deserialize, Serialize};
-use tauri::State;
+use tauri::{AppHandle, Manager, State};
+use tracing::{info, warn};
+
+use tch::Device;
 
 use crate::{
-    data::AppDb,
-    demucs::{split_track, split_vocal_instrumental, LazyModelLoader},
-    routes::StemSplitSnafu,
-    util::get_base_directory,
+    data::{find_song_in_project, AppDb},
+    demucs::{
+        self, audio::ExportConfig, build_instrumental_from_stems as build_instrumental,
+        downloaded_model_path, ensure_output_writable, file_difference_rms, model::ModelInfo,
+        preview_split as preview_split_track, split_custom_mix, split_track,
+        split_track_with_progress, split_vocal_instrumental, Demucs, JobStatus, LazyModelLoader,
+        LimiterMetering, MixSplitOptions, SplitOptions, SplitOutput,
+    },
+    routes::{
+        jobs::{JobProgress, JobRegistry},
+        AnalysisSnafu, ModelDownloadSnafu, StemSplitSnafu,
+    },
 };
 
-use super::{Error, Result};
+use super::{
+    ensure_separate_stems, ensure_source_available, ensure_within_directory, get_project_or_err,
+    Error, Result,
+};
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "status")]
 pub enum SplitStemsResponse {
     #[serde(alias = "success")]
-    Success { stems: Vec<String> },
+    Success {
+        stems: Vec<String>,
+        /// GPUでの推論に失敗し、CPUで再実行した（UIで処理が遅くなることを知らせる）
+        #[serde(default)]
+        used_cpu_fallback: bool,
+        /// リミッターを有効にした場合の、stemの種類ごとのメータリング
+        #[serde(default)]
+        metering: HashMap<String, LimiterMetering>,
+    },
+}
+
+/// `run_with_model` の実行結果
+struct ModelRun<T> {
+    output: T,
+    /// GPUでの推論に失敗し、CPUで再実行した
+    used_cpu_fallback: bool,
+    /// 推論に使ったモデルの名前
+    model_name: String,
+}
+
+/// モデルを（必要なら）ロードして分離処理を実行する
+///
+/// ローダーのMutexはロードの間だけ保持し、推論はロード済みモデルの `Arc` を使って行うため、
+/// 分離中も他のコマンドはブロックされない。
+/// GPU（MPS/CUDA）でTorchのエラーが発生した場合、`allow_cpu_fallback` が有効なら同じモデルを
+/// 一時的にCPUでロードして1回だけ再試行する。共有のローダーのデバイスは変更しないため、
+/// 次の分離は再びGPUで行われる
+async fn run_with_model<T>(
+    model_loader: &Mutex<LazyModelLoader>,
+    allow_cpu_fallback: bool,
+    mut run: impl FnMut(&Demucs) -> demucs::Result<T>,
+) -> Result<ModelRun<T>> {
+    let (model, model_info, model_path, device) = load_model(model_loader).await?;
+    let model_name = model_info.name.clone();
+    let result = run(&model);
+    drop(model);
+
+    let failure = match result {
+        Err(demucs::Error::TorchError { source })
+            if allow_cpu_fallback && device != Device::Cpu =>
+        {
+            source.to_string()
+        }
+        result => {
+            return Ok(ModelRun {
+                output: result.context(StemSplitSnafu)?,
+                used_cpu_fallback: false,
+                model_name,
+            });
+        }
+    };
+
+    warn!(
+        "[run_with_model] Inference failed on {:?}: {}. Retrying on CPU",
+        device, failure
+    );
+    // 最初の推論と同じモデルを、この再試行のためだけにCPUでロードする（使い終わったら破棄）
+    let cpu_model = Demucs::init(&model_path, &model_info, Device::Cpu).context(StemSplitSnafu)?;
+    let output = run(&cpu_model).context(StemSplitSnafu)?;
+
+    Ok(ModelRun {
+        output,
+        used_cpu_fallback: true,
+        model_name,
+    })
+}
+
+/// モデルを遅延ロード（初回のみロード、2回目以降は再利用）し、ロード時のモデルの情報・パス・デバイスと一緒に返す
+async fn load_model(
+    model_loader: &Mutex<LazyModelLoader>,
+) -> Result<(Arc<Demucs>, ModelInfo, StdPathBuf, Device)> {
+    let mut loader = model_loader.lock().await;
+    let model = loader.get_or_load().map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to load model: {}", e),
+        source: Some(Box::new(e)),
+    })?;
+
+    Ok((
+        model,
+        loader.model_info.clone(),
+        loader.model_path.clone(),
+        loader.device,
+    ))
+}
+
+/// プロジェクトのディレクトリと、その中の曲ファイルのパスを取得
+async fn resolve_project_song(
+    app_db_mutex: &State<'_, Mutex<AppDb>>,
+    project_id: &str,
+) -> Result<(StdPathBuf, StdPathBuf)> {
+    let (project_dir, project) = {
+        let db = app_db_mutex.lock().await;
+        let project_dir = db
+            .get_project_dir(project_id)
+            .map_err(|_| Error::GetProjectError)?;
+        let project = db
+            .get_project_by_id(String::from(project_id))
+            .map_err(|_| Error::GetProjectError)?;
+        (project_dir, project)
+    };
+    if let Some(project) = &project {
+        ensure_source_available(project)?;
+    }
+
+    // コピー時の拡張子（mp3/wav/flacなど）に関わらず、プロジェクト内の曲ファイルを探す
+    let song_path = find_song_in_project(&project_dir).ok_or_else(|| Error::UnexpectedError {
+        message: format!(
+            "Audio file not found in {}. Please upload the audio file first.",
+            project_dir.display()
+        ),
+        source: None,
+    })?;
+
+    Ok((project_dir, song_path))
 }
 
+/// 進行状況は `get_job_status` と `job-status` イベントで確認できる
 #[tauri::command]
-#[tracing::instrument(skip(app_db_mutex, model_loader))]
+#[tracing::instrument(skip(app_db_mutex, model_loader, job_registry, app_handle))]
 pub async fn split_stems(
     project_id: &str,
+    options: Option<SplitOptions>,
     app_db_mutex: State<'_, Mutex<AppDb>>,
     model_loader: State<'_, Mutex<LazyModelLoader>>,
+    job_registry: State<'_, JobRegistry>,
+    app_handle: AppHandle,
 ) -> Result<SplitStemsResponse> {
-    let project_dir = get_base_directory().join("project_data").join(project_id);
+    let result = run_split_stems(
+        project_id,
+        options,
+        &app_db_mutex,
+        &model_loader,
+        &job_registry,
+        &app_handle,
+    )
+    .await;
 
-    let song_path = project_dir.join("main.mp3"); // We're dealing with just MP3 for now.
+    let status = match &result {
+        Ok(_) => JobStatus::Done,
+        Err(e) => JobStatus::Failed {
+            message: e.to_string(),
+        },
+    };
+    job_registry.set(&app_handle, project_id, status);
 
-    // ファイルが存在するかチェック
-    if !song_path.exists() {
-        return Err(Error::UnexpectedError {
-            message: format!(
-                "Audio file not found: {}. Please upload the audio file first.",
-                song_path.display()
-            ),
-            source: None,
-        });
+    result
+}
+
+async fn run_split_stems(
+    project_id: &str,
+    options: Option<SplitOptions>,
+    app_db_mutex: &State<'_, Mutex<AppDb>>,
+    model_loader: &State<'_, Mutex<LazyModelLoader>>,
+    job_registry: &JobRegistry,
+    app_handle: &AppHandle,
+) -> Result<SplitStemsResponse> {
+    job_registry.set(app_handle, project_id, JobStatus::Queued);
+    let (project_dir, song_path) = resolve_project_song(app_db_mutex, project_id).await?;
+
+    let options = options.unwrap_or_default();
+    let progress = JobProgress {
+        registry: job_registry,
+        app_handle,
+        project_id,
+    };
+    let ModelRun {
+        output: SplitOutput {
+            stem_paths,
+            metering,
+        },
+        used_cpu_fallback,
+        model_name,
+    } = run_with_model(model_loader, options.cpu_fallback, |model| {
+        split_track_with_progress(model, &song_path, &project_dir, &options, &progress)
+    })
+    .await?;
+
+    let stems = stem_paths
+        .clone()
+        .into_iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+
+    let app_db = app_db_mutex.lock().await;
+
+    app_db
+        .add_stems_to_project(String::from(project_id), stem_paths, Some(&model_name))
+        .map_err(|_| Error::StemSaveError)?;
+
+    // 曲ファイルの削除に失敗しても分離自体は成功扱い
+    if let Err(e) = app_db.delete_source_if_requested(project_id) {
+        eprintln!("[split_stems] Failed to delete source file: {}", e);
     }
 
-    // モデルを遅延ロード（初回のみロード、2回目以降は再利用）
+    Ok(SplitStemsResponse::Success {
+        stems,
+        used_cpu_fallback,
+        metering,
+    })
+}
+
+/// モデルを切り替えてプロジェクトを分離し直す（「より良いモデルで試す」用）
+///
+/// 成功した場合は新しいモデルを使い続け、新しいモデルが出力しなかった古いstem（6sモデルのguitarなど）を削除する。
+/// 失敗した場合は既存のstemをそのまま残し、ローダーがまだこの呼び出しで切り替えたモデルを指していれば元のモデルに戻す。
+/// 進行状況は `split_stems` と同じく `get_job_status` と `job-status` イベントで確認できる
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex, model_loader, job_registry, app_handle))]
+pub async fn resplit_with_model(
+    project_id: &str,
+    model_name: String,
+    options: Option<SplitOptions>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+    job_registry: State<'_, JobRegistry>,
+    app_handle: AppHandle,
+) -> Result<SplitStemsResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    let (model_info, model_path) = resolve_model(&app_handle, &model_name)?;
+    let installed = (model_info.name.clone(), model_path.clone());
+    let (previous_info, previous_path) = model_loader
+        .lock()
+        .await
+        .set_model(model_info, model_path);
+
+    let result = run_split_stems(
+        project_id,
+        options,
+        &app_db_mutex,
+        &model_loader,
+        &job_registry,
+        &app_handle,
+    )
+    .await;
+
+    let status = match &result {
+        Ok(SplitStemsResponse::Success { stems, .. }) => {
+            remove_stale_stems(&app_db_mutex, project_id, &project.stem_paths, stems).await;
+            JobStatus::Done
+        }
+        Err(e) => {
+            // 分離中に別のリクエストがモデルを切り替えていたら、そちらを上書きしない
+            let mut loader = model_loader.lock().await;
+            if loader.model_info.name == installed.0 && loader.model_path == installed.1 {
+                eprintln!(
+                    "[resplit_with_model] Re-split with {} failed, restoring {}: {}",
+                    model_name, previous_info.name, e
+                );
+                loader.set_model(previous_info, previous_path);
+            } else {
+                eprintln!(
+                    "[resplit_with_model] Re-split with {} failed; {} was selected meanwhile, not restoring: {}",
+                    model_name, loader.model_info.name, e
+                );
+            }
+            JobStatus::Failed {
+                message: e.to_string(),
+            }
+        }
+    };
+    job_registry.set(&app_handle, project_id, status);
+
+    result
+}
+
+/// models.jsonから指定した名前のモデルを探し、モデルファイルのパスと一緒に返す
+fn resolve_model(app_handle: &AppHandle, model_name: &str) -> Result<(ModelInfo, StdPathBuf)> {
+    let info = find_model_info(app_handle, model_name)?;
+    let path =
+        model_file_path(app_handle, &info.name).ok_or_else(|| Error::ModelNotFoundError {
+            name: model_name.to_string(),
+        })?;
+
+    Ok((info, path))
+}
+
+/// モデルファイルのパス（同梱されたファイルを優先し、なければダウンロードしたファイル）
+///
+/// models.jsonに設定があっても、モデルファイルが同梱・ダウンロードされていない場合はNone
+pub fn model_file_path(app_handle: &AppHandle, model_name: &str) -> Option<StdPathBuf> {
+    app_handle
+        .path_resolver()
+        .resolve_resource(format!("models/{}.pt", model_name))
+        .filter(|path| path.exists())
+        .or_else(|| Some(downloaded_model_path(model_name)).filter(|path| path.exists()))
+}
+
+/// models.jsonのすべてのモデルの設定を読み込む
+fn load_models(app_handle: &AppHandle) -> Result<Vec<ModelInfo>> {
+    let models_path = app_handle
+        .path_resolver()
+        .resolve_resource("models/models.json")
+        .ok_or_else(|| Error::UnexpectedError {
+            message: String::from("Failed to resolve models/models.json"),
+            source: None,
+        })?;
+
+    demucs::models(&models_path).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to load models: {}", e),
+        source: Some(Box::new(e)),
+    })
+}
+
+/// models.jsonから指定した名前のモデルの設定を探す（モデルファイルの有無は確認しない）
+fn find_model_info(app_handle: &AppHandle, model_name: &str) -> Result<ModelInfo> {
+    demucs::find_model(load_models(app_handle)?, model_name).ok_or_else(|| {
+        Error::ModelNotFoundError {
+            name: model_name.to_string(),
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status")]
+pub enum SelectModelResponse {
+    #[serde(alias = "success")]
+    Success {
+        model_name: String,
+        /// 選んだモデルが出力するすべてのstem（指定したもの以外も含む）
+        sources: Vec<String>,
+    },
+}
+
+/// 指定したstem（`["vocals", "guitar", "piano"]` など）をすべて出力できるモデルに切り替える
+///
+/// UIがモデル名を知らなくてもよいよう、モデルファイルがあるモデルのうちmodels.jsonで最初に見つかったものを選ぶ
+#[tauri::command]
+#[tracing::instrument(skip(model_loader, app_handle))]
+pub async fn select_model_for_stems(
+    stems: Vec<String>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+    app_handle: AppHandle,
+) -> Result<SelectModelResponse> {
+    let available: Vec<(ModelInfo, StdPathBuf)> = load_models(&app_handle)?
+        .into_iter()
+        .filter_map(|info| model_file_path(&app_handle, &info.name).map(|path| (info, path)))
+        .collect();
+
+    let required: Vec<&str> = stems.iter().map(String::as_str).collect();
+    let info = demucs::find_model_by_sources(
+        available.iter().map(|(info, _)| info.clone()).collect(),
+        &required,
+    )
+    .ok_or_else(|| Error::NoModelForStemsError {
+        stems: stems.clone(),
+    })?;
+    let path = available
+        .into_iter()
+        .find_map(|(candidate, path)| (candidate.name == info.name).then_some(path))
+        .ok_or_else(|| Error::ModelNotFoundError {
+            name: info.name.clone(),
+        })?;
+
+    let model_name = info.name.clone();
+    let sources = info.config.sources.clone();
+    eprintln!(
+        "[select_model_for_stems] Selected {} for {:?}",
+        model_name, stems
+    );
+    model_loader.lock().await.set_model(info, path);
+
+    Ok(SelectModelResponse::Success {
+        model_name,
+        sources,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status")]
+pub enum DownloadModelResponse {
+    #[serde(alias = "success")]
+    Success { model_name: String, path: String },
+}
+
+/// モデルのダウンロードの進行状況を通知するイベント
+pub const MODEL_DOWNLOAD_PROGRESS_EVENT: &str = "model_download_progress";
+
+/// models.jsonの `url` からモデルの重みをダウンロードする
+///
+/// 途中で失敗した場合は再試行し、前回受信した分から再開する。
+/// 現在のモデルのファイルがない状態で起動していた場合は、ダウンロードしたファイルを使うよう切り替える
+#[tauri::command]
+#[tracing::instrument(skip(model_loader, app_handle))]
+pub async fn download_model(
+    model_name: String,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+    app_handle: AppHandle,
+) -> Result<DownloadModelResponse> {
+    let info = find_model_info(&app_handle, &model_name)?;
+
+    let path = demucs::download_model(&info, |progress| {
+        if let Err(e) = app_handle.emit_all(MODEL_DOWNLOAD_PROGRESS_EVENT, progress) {
+            eprintln!("[download_model] Failed to emit download progress event: {}", e);
+        }
+    })
+    .await
+    .context(ModelDownloadSnafu)?;
+
     let mut loader = model_loader.lock().await;
-    let model = loader.get_or_load().map_err(|e| Error::UnexpectedError {
-        message: format!("Failed to load model: {}", e),
+    if loader.model_info.name == info.name && !loader.model_path.exists() {
+        loader.set_model(info, path.clone());
+    }
+
+    Ok(DownloadModelResponse::Success {
+        model_name,
+        path: path.to_string_lossy().to_string(),
+    })
+}
+
+/// 分離し直す前のstemのうち、新しいstemに含まれないファイルを削除する
+async fn remove_stale_stems(
+    app_db_mutex: &State<'_, Mutex<AppDb>>,
+    project_id: &str,
+    old_stems: &[String],
+    new_stems: &[String],
+) {
+    let Ok(project_dir) = app_db_mutex.lock().await.get_project_dir(project_id) else {
+        return;
+    };
+
+    for old_stem in old_stems.iter().filter(|path| !new_stems.contains(path)) {
+        // stem_pathsが書き換えられていても、プロジェクト外のファイルは削除しない
+        let path = match ensure_within_directory(StdPathBuf::from(old_stem).as_path(), &project_dir) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        match std::fs::remove_file(&path) {
+            Ok(()) => eprintln!("[remove_stale_stems] Removed {}", path.display()),
+            Err(e) => eprintln!(
+                "[remove_stale_stems] Failed to remove {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// `compare_models` で比較した一方のモデルの出力
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComparedModel {
+    pub model_name: String,
+    /// `compare/{model_name}/` に書き出したすべてのstem
+    pub stems: Vec<String>,
+    /// 比較対象の種類のstem
+    pub stem_path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum CompareModelsResponse {
+    #[serde(alias = "success")]
+    Success {
+        stem_kind: String,
+        model_a: ComparedModel,
+        model_b: ComparedModel,
+        /// 2つのモデルの `stem_kind` のstemの差分信号のRMS（0なら完全に一致）
+        difference_rms: f64,
+    },
+}
+
+/// 同じ曲を2つのモデルで分離し、指定した種類のstemの違いを比較する（モデル選びのA/B用）
+///
+/// stemは `compare/{モデル名}/` に書き出し、プロジェクトのstemとしては保存しない。
+/// 選択中のモデルは切り替えないため、比較中も他の分離に影響しない
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex, model_loader, app_handle))]
+pub async fn compare_models(
+    project_id: &str,
+    model_a: String,
+    model_b: String,
+    stem_kind: String,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+    app_handle: AppHandle,
+) -> Result<CompareModelsResponse> {
+    let (project_dir, song_path) = resolve_project_song(&app_db_mutex, project_id).await?;
+
+    let models = [
+        resolve_model(&app_handle, &model_a)?,
+        resolve_model(&app_handle, &model_b)?,
+    ];
+    // 分離してから比較できないと分かることがないよう、先に両方のモデルが出力するか確認する
+    if models
+        .iter()
+        .any(|(info, _)| !info.config.sources.contains(&stem_kind))
+    {
+        return Err(Error::StemNotFoundError { kind: stem_kind });
+    }
+
+    // 共有のローダーのモデルは切り替えず、比較用のモデルをそれぞれ同じデバイスに一時的にロードする
+    let device = model_loader.lock().await.device;
+    let mut compared = Vec::with_capacity(models.len());
+    for (info, path) in models {
+        let model_name = info.name.clone();
+        let output_dir = project_dir.join("compare").join(&model_name);
+        match split_for_comparison(info, path, device, song_path.clone(), output_dir).await {
+            Ok(stem_paths) => compared.push((model_name, stem_paths)),
+            Err(e) => {
+                eprintln!("[compare_models] Comparison failed: {}", e);
+                return Err(e);
+            }
+        }
+    }
+
+    let compared: Vec<ComparedModel> = compared
+        .into_iter()
+        .map(|(model_name, stem_paths)| ComparedModel {
+            model_name,
+            stem_path: stem_paths
+                .iter()
+                .find(|path| {
+                    path.file_stem()
+                        .map_or(false, |stem| stem == stem_kind.as_str())
+                })
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            stems: stem_paths
+                .iter()
+                .map(|path| path.to_string_lossy().to_string())
+                .collect(),
+        })
+        .collect();
+    let [model_a, model_b]: [ComparedModel; 2] =
+        compared.try_into().map_err(|_| Error::StemNotFoundError {
+            kind: stem_kind.clone(),
+        })?;
+
+    let difference_rms = file_difference_rms(
+        &StdPathBuf::from(&model_a.stem_path),
+        &StdPathBuf::from(&model_b.stem_path),
+    )
+    .context(AnalysisSnafu)?;
+    eprintln!(
+        "[compare_models] {} vs {} ({}): difference RMS {:.6}",
+        model_a.model_name, model_b.model_name, stem_kind, difference_rms
+    );
+
+    Ok(CompareModelsResponse::Success {
+        stem_kind,
+        model_a,
+        model_b,
+        difference_rms,
+    })
+}
+
+/// 比較用のモデルをロードして曲を分離し、`output_dir` にstemを書き出す（モデルは使い終わったら破棄する）
+///
+/// GPUで失敗してもCPUにフォールバックしない。片方だけCPUで動くと比較の条件が揃わないため
+async fn split_for_comparison(
+    model_info: ModelInfo,
+    model_path: StdPathBuf,
+    device: Device,
+    song_path: StdPathBuf,
+    output_dir: StdPathBuf,
+) -> Result<Vec<StdPathBuf>> {
+    std::fs::create_dir_all(&output_dir).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to create {}", output_dir.display()),
         source: Some(Box::new(e)),
     })?;
 
-    let stem_paths = split_track(model, &song_path, &project_dir).context(StemSplitSnafu)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let model = Demucs::init(&model_path, &model_info, device)?;
+        split_track(&model, &song_path, &output_dir, &SplitOptions::default())
+    })
+    .await
+    .map_err(|e| Error::UnexpectedError {
+        message: String::from("Model comparison was interrupted"),
+        source: Some(Box::new(e)),
+    })?
+    .context(StemSplitSnafu)
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex, model_loader))]
+pub async fn split_vocal_instrumental_stems(
+    project_id: &str,
+    options: Option<MixSplitOptions>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<SplitStemsResponse> {
+    let (project_dir, song_path) = resolve_project_song(&app_db_mutex, project_id).await?;
+
+    let options = options.unwrap_or_default();
+    let ModelRun {
+        output: stem_paths,
+        used_cpu_fallback,
+        model_name,
+    } = run_with_model(&model_loader, options.cpu_fallback, |model| {
+        split_vocal_instrumental(model, &song_path, &project_dir, &options)
+    })
+    .await?;
 
     let stems = stem_paths
         .clone()
@@ -62,41 +639,121 @@ pub async fn split_stems(
     let app_db = app_db_mutex.lock().await;
 
     app_db
-        .add_stems_to_project(String::from(project_id), stem_paths)
+        .add_stems_to_project(String::from(project_id), stem_paths, Some(&model_name))
         .map_or(Err(Error::StemSaveError), |_| {
-            Ok(SplitStemsResponse::Success { stems })
+            Ok(SplitStemsResponse::Success {
+                stems,
+                used_cpu_fallback,
+                metering: HashMap::new(),
+            })
         })
 }
 
+/// 先頭N秒だけを分離して `preview/` に書き出す（プロジェクトのstemとしては保存しない）
 #[tauri::command]
 #[tracing::instrument(skip(app_db_mutex, model_loader))]
-pub async fn split_vocal_instrumental_stems(
+pub async fn preview_split(
     project_id: &str,
+    seconds: f64,
+    options: Option<SplitOptions>,
     app_db_mutex: State<'_, Mutex<AppDb>>,
     model_loader: State<'_, Mutex<LazyModelLoader>>,
 ) -> Result<SplitStemsResponse> {
-    let project_dir = get_base_directory().join("project_data").join(project_id);
+    let (project_dir, song_path) = resolve_project_song(&app_db_mutex, project_id).await?;
 
-    let song_path = project_dir.join("main.mp3");
+    let options = options.unwrap_or_default();
+    let ModelRun {
+        output: stem_paths,
+        used_cpu_fallback,
+        ..
+    } = run_with_model(&model_loader, options.cpu_fallback, |model| {
+        preview_split_track(model, &song_path, &project_dir, seconds, &options)
+    })
+    .await?;
 
-    if !song_path.exists() {
-        return Err(Error::UnexpectedError {
-            message: format!(
-                "Audio file not found: {}. Please upload the audio file first.",
-                song_path.display()
-            ),
-            source: None,
-        });
-    }
+    let stems = stem_paths
+        .into_iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
 
-    // モデルを遅延ロード（初回のみロード、2回目以降は再利用）
-    let mut loader = model_loader.lock().await;
-    let model = loader.get_or_load().map_err(|e| Error::UnexpectedError {
-        message: format!("Failed to load model: {}", e),
+    Ok(SplitStemsResponse::Success {
+        stems,
+        used_cpu_fallback,
+        metering: HashMap::new(),
+    })
+}
+
+/// 後処理をすべて省いたモデルの生の出力を `raw/` に書き出す（UIには出さないデバッグ用のコマンド）
+///
+/// アーティファクトがモデルと後処理のどちらに由来するかを切り分けるためのもので、プロジェクトのstemとしては保存しない
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex, model_loader))]
+pub async fn split_stems_raw(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<SplitStemsResponse> {
+    let (project_dir, song_path) = resolve_project_song(&app_db_mutex, project_id).await?;
+    let raw_dir = project_dir.join("raw");
+    std::fs::create_dir_all(&raw_dir).map_err(|e| Error::UnexpectedError {
+        message: format!("Failed to create {}", raw_dir.display()),
         source: Some(Box::new(e)),
     })?;
 
-    let stem_paths = split_vocal_instrumental(model, &song_path, &project_dir).context(StemSplitSnafu)?;
+    let options = SplitOptions {
+        raw: true,
+        ..SplitOptions::default()
+    };
+    let ModelRun {
+        output: stem_paths,
+        used_cpu_fallback,
+        ..
+    } = run_with_model(&model_loader, options.cpu_fallback, |model| {
+        split_track(model, &song_path, &raw_dir, &options)
+    })
+    .await?;
+
+    let stems = stem_paths
+        .into_iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+
+    Ok(SplitStemsResponse::Success {
+        stems,
+        used_cpu_fallback,
+        metering: HashMap::new(),
+    })
+}
+
+/// 指定したsourceの組み合わせ（例: vocals+piano）とそれ以外の2つに分離する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex, model_loader))]
+pub async fn split_custom_mix_stems(
+    project_id: &str,
+    keep: Vec<String>,
+    rest_name: String,
+    options: Option<MixSplitOptions>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<SplitStemsResponse> {
+    let (project_dir, song_path) = resolve_project_song(&app_db_mutex, project_id).await?;
+
+    let options = options.unwrap_or_default();
+    let ModelRun {
+        output: stem_paths,
+        used_cpu_fallback,
+        model_name,
+    } = run_with_model(&model_loader, options.cpu_fallback, |model| {
+        split_custom_mix(
+            model,
+            &song_path,
+            &project_dir,
+            keep.clone(),
+            rest_name.clone(),
+            &options,
+        )
+    })
+    .await?;
 
     let stems = stem_paths
         .clone()
@@ -107,62 +764,383 @@ pub async fn split_vocal_instrumental_stems(
     let app_db = app_db_mutex.lock().await;
 
     app_db
-        .add_stems_to_project(String::from(project_id), stem_paths)
+        .add_stems_to_project(String::from(project_id), stem_paths, Some(&model_name))
+        .map_or(Err(Error::StemSaveError), |_| {
+            Ok(SplitStemsResponse::Success {
+                stems,
+                used_cpu_fallback,
+                metering: HashMap::new(),
+            })
+        })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum StemsModelStatusResponse {
+    #[serde(alias = "success")]
+    Success {
+        /// stemを生成したモデル（未分離、または記録前に作られたstemの場合はNone）
+        stems_model: Option<String>,
+        active_model: String,
+        /// stemが現在のモデルとは別のモデルで作られている（UIで再分離を促す）
+        is_stale: bool,
+    },
+}
+
+/// プロジェクトのstemが現在のモデルで作られたものかを確認する
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex, model_loader))]
+pub async fn get_stems_model_status(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<StemsModelStatusResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    let active_model = model_loader.lock().await.model_info.name.clone();
+
+    let is_stale = !project.stem_paths.is_empty()
+        && project
+            .model_name
+            .as_ref()
+            .map_or(false, |name| *name != active_model);
+
+    Ok(StemsModelStatusResponse::Success {
+        stems_model: project.model_name,
+        active_model,
+        is_stale,
+    })
+}
+
+/// 既存のstemからinstrumental.wavを再生成する（モデルの再推論は行わない）
+#[tauri::command]
+#[tracing::instrument(skip(app_db_mutex))]
+pub async fn build_instrumental_from_stems(
+    project_id: &str,
+    export_config: Option<ExportConfig>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> Result<SplitStemsResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    // まとめたファイルにはボーカルも含まれるため、そのまま合算するとinstrumentalにならない
+    ensure_separate_stems(&project)?;
+
+    let project_dir = project.project_dir();
+    let mut stem_paths: Vec<StdPathBuf> = project.stem_paths.iter().map(StdPathBuf::from).collect();
+
+    // デコード・合算・エンコードの間はDBをロックせず、非同期ランタイムもブロックしない
+    let instrumental_path = {
+        let stem_paths = stem_paths.clone();
+        let export_config = export_config.unwrap_or_default();
+        tauri::async_runtime::spawn_blocking(move || {
+            build_instrumental(&stem_paths, &project_dir, &export_config)
+        })
+        .await
+        .map_err(|e| Error::UnexpectedError {
+            message: String::from("Building the instrumental was interrupted"),
+            source: Some(Box::new(e)),
+        })?
+        .context(StemSplitSnafu)?
+    };
+
+    if !stem_paths.contains(&instrumental_path) {
+        stem_paths.push(instrumental_path);
+    }
+
+    let stems = stem_paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+
+    app_db_mutex
+        .lock()
+        .await
+        .add_stems_to_project(String::from(project_id), stem_paths, None)
         .map_or(Err(Error::StemSaveError), |_| {
-            Ok(SplitStemsResponse::Success { stems })
+            Ok(SplitStemsResponse::Success {
+                stems,
+                used_cpu_fallback: false,
+                metering: HashMap::new(),
+            })
         })
 }
 
+/// 既に圧縮済みで、Deflateしてもほとんどサイズが減らない形式
+const COMPRESSED_AUDIO_EXTENSIONS: [&str; 6] = ["mp3", "flac", "ogg", "opus", "m4a", "aac"];
+
+/// ZIPの圧縮方式を決定する
+///
+/// 指定がない場合、全stemが圧縮済み形式なら無圧縮（Stored）、それ以外はDeflateを使用
+fn resolve_zip_compression(
+    compression: Option<&str>,
+    stem_paths: &[StdPathBuf],
+) -> std::result::Result<CompressionMethod, String> {
+    match compression {
+        Some("deflate") => Ok(CompressionMethod::Deflated),
+        Some("store") => Ok(CompressionMethod::Stored),
+        Some(other) => Err(format!(
+            "Unknown compression method: {} (expected \"deflate\" or \"store\")",
+            other
+        )),
+        None => {
+            let all_compressed = !stem_paths.is_empty()
+                && stem_paths.iter().all(|path| {
+                    path.extension()
+                        .and_then(|ext| ext.to_str())
+                        .map_or(false, |ext| {
+                            COMPRESSED_AUDIO_EXTENSIONS
+                                .iter()
+                                .any(|c| ext.eq_ignore_ascii_case(c))
+                        })
+                });
+
+            if all_compressed {
+                Ok(CompressionMethod::Stored)
+            } else {
+                Ok(CompressionMethod::Deflated)
+            }
+        }
+    }
+}
+
+/// ZIPに追加できなかったstem
+#[derive(Debug, Serialize)]
+pub struct StemsZipFailure {
+    pub path: String,
+    pub error: String,
+}
+
+/// `create_stems_zip` の結果
+#[derive(Debug, Serialize)]
+pub struct StemsZipReport {
+    /// ZIPに追加したファイル名
+    pub added: Vec<String>,
+    /// 読み込めずに飛ばしたstem（`all_or_nothing` の場合は常に空）
+    pub failed: Vec<StemsZipFailure>,
+}
+
+/// stemをZIPにまとめる
+///
+/// 読み込めないstemがあっても残りのstemでZIPを作り、飛ばしたstemを結果で返す。
+/// `all_or_nothing` を指定すると、1つでも失敗した時点でエラーにする（以前の動作）。
+/// すべてのstemが失敗した場合は、空のZIPを残さずにエラーにする
 #[tauri::command]
 pub async fn create_stems_zip(
-    _project_id: &str,
+    project_id: &str,
     stem_paths: Vec<String>,
     output_path: &str,
-) -> std::result::Result<(), String> {
+    cover_path: Option<String>,
+    compression: Option<String>,
+    all_or_nothing: Option<bool>,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+) -> std::result::Result<StemsZipReport, String> {
     eprintln!("[create_stems_zip] Creating ZIP file, output path: {}", output_path);
     eprintln!("[create_stems_zip] Stem paths: {:?}", stem_paths);
     
-    use zip::write::{FileOptions, ZipWriter};
-    use zip::CompressionMethod;
-    use std::io::{BufWriter, Write};
+    use std::io::BufWriter;
+
+    let all_or_nothing = all_or_nothing.unwrap_or(false);
+    let mut failed = Vec::new();
+    
+    // プロジェクトのディレクトリ外のファイルは読み込まない（ZIPファイルを作成する前に検証）
+    // 存在しないファイルもここで失敗する
+    let project_dir = app_db_mutex
+        .lock()
+        .await
+        .get_project_dir(project_id)?;
+    let mut valid_paths = Vec::new();
+    for stem_path in &stem_paths {
+        match ensure_within_directory(StdPathBuf::from(stem_path).as_path(), &project_dir) {
+            Ok(path) => valid_paths.push(path),
+            Err(e) if all_or_nothing => return Err(e),
+            Err(error) => failed.push(StemsZipFailure {
+                path: stem_path.clone(),
+                error,
+            }),
+        }
+    }
+    let stem_paths = valid_paths;
+    
+    // 圧縮方式を決定（不正な値の場合はZIPファイルを作成する前にエラーを返す）
+    let compression_method = resolve_zip_compression(compression.as_deref(), &stem_paths)?;
+    eprintln!("[create_stems_zip] Compression method: {:?}", compression_method);
     
+    // 書き込めない場所の場合は、ZIPの作成を始める前に分かりやすいエラーを返す
+    let output_dir = StdPathBuf::from(output_path)
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map_or_else(|| StdPathBuf::from("."), StdPathBuf::from);
+    ensure_output_writable(&output_dir).map_err(|e| e.to_string())?;
+
     // ZIPファイルを作成
     let file = File::create(output_path)
         .map_err(|e| format!("Failed to create ZIP file: {}", e))?;
     let mut zip = ZipWriter::new(BufWriter::new(file));
     
     let options = FileOptions::default()
-        .compression_method(CompressionMethod::Deflated)
+        .compression_method(compression_method)
         .unix_permissions(0o755);
     
     // 各stemファイルをZIPに追加
+    let mut added = Vec::new();
     for stem_path in stem_paths {
-        let stem_path_buf = StdPathBuf::from(&stem_path);
-        
-        // ファイル名を取得（パスから）
-        let file_name = stem_path_buf
-            .file_name()
-            .and_then(|n| n.to_str())
-            .ok_or_else(|| format!("Invalid file name: {}", stem_path))?;
-        
-        eprintln!("[create_stems_zip] Adding file to ZIP: {} (from: {})", file_name, stem_path);
-        
-        // ファイルを読み込む
-        let file_data = std::fs::read(&stem_path)
-            .map_err(|e| format!("Failed to read file {}: {}", stem_path, e))?;
-        
-        // ZIPに追加
-        zip.start_file(file_name, options)
-            .map_err(|e| format!("Failed to add file to ZIP: {}", e))?;
-        zip.write_all(&file_data)
-            .map_err(|e| format!("Failed to write file to ZIP: {}", e))?;
+        let mut add_stem = || -> std::result::Result<String, String> {
+            // ファイル名を取得（パスから）
+            let file_name = stem_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| format!("Invalid file name: {}", stem_path.display()))?;
+
+            eprintln!("[create_stems_zip] Adding file to ZIP: {} (from: {})", file_name, stem_path.display());
+            add_file_to_zip(&mut zip, &stem_path, file_name, options)?;
+
+            Ok(file_name.to_string())
+        };
+
+        match add_stem() {
+            Ok(file_name) => added.push(file_name),
+            Err(e) if all_or_nothing => return Err(e),
+            Err(error) => {
+                warn!(
+                    "[create_stems_zip] Skipping {}: {}",
+                    stem_path.display(),
+                    error
+                );
+                failed.push(StemsZipFailure {
+                    path: stem_path.to_string_lossy().to_string(),
+                    error,
+                });
+            }
+        }
+    }
+
+    if added.is_empty() && !failed.is_empty() {
+        drop(zip);
+        std::fs::remove_file(output_path).ok();
+        let errors: Vec<&str> = failed.iter().map(|failure| failure.error.as_str()).collect();
+        return Err(format!("No stems could be added to the ZIP: {}", errors.join("; ")));
     }
     
+    // カバー画像があればZIPに追加（存在しない場合はスキップ）
+    if let Some(cover_path) = cover_path {
+        let cover_path_buf = StdPathBuf::from(&cover_path);
+        let cover_name = cover_path_buf
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| format!("cover.{}", ext.to_lowercase()));
+
+        match cover_name {
+            Some(cover_name) if cover_path_buf.exists() => {
+                let cover_path_buf = ensure_within_directory(&cover_path_buf, &project_dir)?;
+                eprintln!("[create_stems_zip] Adding cover to ZIP: {}", cover_path);
+                add_file_to_zip(&mut zip, &cover_path_buf, &cover_name, options)?;
+            }
+            _ => {
+                eprintln!("[create_stems_zip] Cover image not found, skipping: {}", cover_path);
+            }
+        }
+    }
+
     // ZIPファイルを完了
     zip.finish()
         .map_err(|e| format!("Failed to finish ZIP file: {}", e))?;
     
-    eprintln!("[create_stems_zip] ZIP file created successfully: {}", output_path);
+    info!(
+        "[create_stems_zip] ZIP file created successfully: {} ({} added, {} skipped)",
+        output_path,
+        added.len(),
+        failed.len()
+    );
     
+    Ok(StemsZipReport { added, failed })
+}
+
+/// ファイルを `name` としてZIPに追加する
+///
+/// 全体をメモリに読み込まず、ストリームでZIPに書き込む。書き込みに失敗した場合は途中までのエントリを残さない
+fn add_file_to_zip<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    path: &Path,
+    name: &str,
+    options: FileOptions,
+) -> std::result::Result<(), String> {
+    let mut reader = File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Failed to read file {}: {}", path.display(), e))?;
+
+    zip.start_file(name, options)
+        .map_err(|e| format!("Failed to add file to ZIP: {}", e))?;
+    if let Err(e) = std::io::copy(&mut reader, zip) {
+        zip.abort_file().ok();
+        return Err(format!("Failed to write file to ZIP: {}", e));
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn add_file_to_zip_round_trips_a_large_file() {
+        let dir = std::env::temp_dir().join(format!("stemsplit-zip-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stem_path = dir.join("drums.wav");
+        let zip_path = dir.join("stems.zip");
+
+        // BufReaderのバッファより十分大きく、Deflateで潰れないよう疑似乱数で埋める
+        let mut state = 0x2545_f491u32;
+        let data: Vec<u8> = (0..16 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        std::fs::write(&stem_path, &data).unwrap();
+
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        add_file_to_zip(&mut zip, &stem_path, "drums.wav", options).unwrap();
+        zip.finish().unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 1);
+        let mut entry = archive.by_name("drums.wav").unwrap();
+        let mut contents = Vec::with_capacity(data.len());
+        entry.read_to_end(&mut contents).unwrap();
+        assert!(
+            contents == data,
+            "archived file does not match the original"
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn add_file_to_zip_reports_a_missing_file() {
+        let dir =
+            std::env::temp_dir().join(format!("stemsplit-zip-missing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("stems.zip");
+
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+        let result = add_file_to_zip(
+            &mut zip,
+            &dir.join("missing.wav"),
+            "missing.wav",
+            FileOptions::default(),
+        );
+        assert!(result.is_err());
+        zip.finish().unwrap();
+
+        let archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 0);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
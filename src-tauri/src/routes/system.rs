@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use serde::{self, Deserialize, Serialize};
+use snafu::ResultExt;
+use tauri::State;
+
+use crate::data::AppDb;
+use crate::demucs::{
+    device_name, max_segment_secs, run_self_test as run_model_self_test,
+    verify_device_parity as verify_model_device_parity, DeviceParityReport, LazyModelLoader,
+    SelfTestReport,
+    CPU_THREADS_ENV_VAR, DEVICE_ENV_VAR,
+};
+use crate::logging::{self, latest_log_file, log_directory, LogLevel};
+
+use super::{find_source_or_err, get_project_or_err, Error, Result, StemSplitSnafu};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    /// 現在使用しているデバイス（"cpu" / "cuda" / "mps"）
+    pub active_device: String,
+    pub mps_available: bool,
+    pub cuda_available: bool,
+    /// 環境変数 `TUNE_PRISM_DEVICE` による強制指定
+    pub env_override: Option<String>,
+    /// 一度に推論するトラックの長さの上限（秒、Noneならトラック全体を一度に推論）
+    pub max_segment_secs: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum DeviceInfoResponse {
+    #[serde(alias = "success")]
+    Success { info: DeviceInfo },
+}
+
+#[tauri::command]
+pub async fn get_device_info(
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<DeviceInfoResponse> {
+    let loader = model_loader.lock().await;
+
+    Ok(DeviceInfoResponse::Success {
+        info: DeviceInfo {
+            active_device: device_name(loader.device),
+            mps_available: tch::utils::has_mps(),
+            cuda_available: tch::utils::has_cuda(),
+            env_override: std::env::var(DEVICE_ENV_VAR).ok(),
+            max_segment_secs: max_segment_secs(loader.device),
+        },
+    })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum SelfTestResponse {
+    #[serde(alias = "success")]
+    Success { report: SelfTestReport },
+    /// モデルのロードまたは推論に失敗した（"load" / "inference"）
+    Failed {
+        stage: String,
+        device: String,
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum UnloadModelResponse {
+    #[serde(alias = "success")]
+    Success {
+        /// モデルがロードされていた
+        was_loaded: bool,
+        /// 実行中の推論がモデルを使っているため、メモリはその推論が終わった時点で解放される
+        in_use: bool,
+        /// アンロード前後の常駐メモリの差（バイト）。測定できないOSではNone
+        freed_bytes: Option<u64>,
+    },
+}
+
+/// ロード済みのモデルを破棄してメモリ（RAM/VRAM）を解放する。次の分離時に改めてロードする
+#[tauri::command]
+pub async fn unload_model(
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<UnloadModelResponse> {
+    let resident_before = resident_memory_bytes();
+    let (was_loaded, in_use) = {
+        let mut loader = model_loader.lock().await;
+        let was_loaded = loader.loaded_model.is_some();
+        let in_use = loader
+            .loaded_model
+            .as_ref()
+            .map_or(false, |model| Arc::strong_count(model) > 1);
+        loader.unload();
+        (was_loaded, in_use)
+    };
+
+    // アロケータがOSに返さなかった分は測定できないため、あくまで目安
+    let freed_bytes = resident_before
+        .zip(resident_memory_bytes())
+        .map(|(before, after)| before.saturating_sub(after));
+    eprintln!(
+        "[unload_model] was_loaded: {}, in_use: {}, freed: {:?} bytes",
+        was_loaded, in_use, freed_bytes
+    );
+
+    Ok(UnloadModelResponse::Success {
+        was_loaded,
+        in_use,
+        freed_bytes,
+    })
+}
+
+/// プロセスの常駐メモリ（バイト）。`/proc/self/status` のないOSではNone
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// モデルとlibtorchが動作するかを、1秒のランダムな信号で推論して確認する
+#[tauri::command]
+pub async fn run_self_test(
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<SelfTestResponse> {
+    // ロードの間だけローダーをロックし、推論中は他のコマンドをブロックしない
+    let (device, loaded) = {
+        let mut loader = model_loader.lock().await;
+        (device_name(loader.device), loader.get_or_load())
+    };
+
+    let model = match loaded {
+        Ok(model) => model,
+        Err(e) => {
+            eprintln!("[run_self_test] Failed to load model: {}", e);
+            return Ok(SelfTestResponse::Failed {
+                stage: String::from("load"),
+                device,
+                message: e.to_string(),
+            });
+        }
+    };
+
+    match run_model_self_test(&model) {
+        Ok(report) => Ok(SelfTestResponse::Success { report }),
+        Err(e) => {
+            eprintln!("[run_self_test] Inference failed: {}", e);
+            Ok(SelfTestResponse::Failed {
+                stage: String::from("inference"),
+                device,
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum VerifyDeviceParityResponse {
+    #[serde(alias = "success")]
+    Success { report: DeviceParityReport },
+}
+
+/// プロジェクトの曲の一部をGPUとCPUの両方で分離し、出力の差が許容範囲内かを確認する
+///
+/// GPU（CUDA/MPS）で分離した音がおかしいという報告の切り分け用。CPUで動作している場合はエラー
+#[tauri::command]
+pub async fn verify_device_parity(
+    project_id: &str,
+    app_db_mutex: State<'_, Mutex<AppDb>>,
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<VerifyDeviceParityResponse> {
+    let project = get_project_or_err(&app_db_mutex, project_id).await?;
+    let song_path = find_source_or_err(&project)?;
+
+    // ロードの間だけローダーをロックし、推論中は他のコマンドをブロックしない
+    let (model, model_path, model_info) = {
+        let mut loader = model_loader.lock().await;
+        let model = loader.get_or_load().context(StemSplitSnafu)?;
+        (model, loader.model_path.clone(), loader.model_info.clone())
+    };
+
+    let report = tauri::async_runtime::spawn_blocking(move || {
+        verify_model_device_parity(&model, &model_path, &model_info, &song_path)
+    })
+    .await
+    .map_err(|e| Error::UnexpectedError {
+        message: String::from("Device parity check was interrupted"),
+        source: Some(Box::new(e)),
+    })?
+    .context(StemSplitSnafu)?;
+
+    Ok(VerifyDeviceParityResponse::Success { report })
+}
+
+/// 診断情報に含める、スレッド数やデバイスに関わる環境変数
+const DIAGNOSTIC_ENV_VARS: [&str; 5] = [
+    "OMP_NUM_THREADS",
+    "MKL_NUM_THREADS",
+    "NUMEXPR_NUM_THREADS",
+    CPU_THREADS_ENV_VAR,
+    DEVICE_ENV_VAR,
+];
+
+/// 不具合報告にそのまま貼り付けられる環境の情報
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostics {
+    pub app_version: String,
+    /// libtorchの `build-version` ファイルの内容（見つからない場合はNone）
+    pub libtorch_version: Option<String>,
+    /// 実行時の `LIBTORCH`、なければビルド時に解決したパス
+    pub libtorch_path: Option<String>,
+    pub torch_threads: i32,
+    pub active_device: String,
+    pub mps_available: bool,
+    pub cuda_available: bool,
+    pub cuda_device_count: i64,
+    pub max_segment_secs: Option<f64>,
+    pub active_model: String,
+    pub model_loaded: bool,
+    /// 環境変数名と値（未設定の場合はNone）
+    pub env: BTreeMap<String, Option<String>>,
+    pub os: String,
+    pub arch: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum DiagnosticsResponse {
+    #[serde(alias = "success")]
+    Success { diagnostics: Diagnostics },
+}
+
+/// libtorch・デバイス・モデル・OSの情報をまとめて返す（不具合報告用）
+#[tauri::command]
+pub async fn get_diagnostics(
+    model_loader: State<'_, Mutex<LazyModelLoader>>,
+) -> Result<DiagnosticsResponse> {
+    let (active_device, max_segment_secs, active_model, model_loaded) = {
+        let loader = model_loader.lock().await;
+        (
+            device_name(loader.device),
+            max_segment_secs(loader.device),
+            loader.model_info.name.clone(),
+            loader.loaded_model.is_some(),
+        )
+    };
+
+    let libtorch_path = std::env::var("LIBTORCH")
+        .ok()
+        .or_else(|| option_env!("LIBTORCH").map(String::from));
+    let libtorch_version = libtorch_path.as_ref().and_then(|path| {
+        std::fs::read_to_string(PathBuf::from(path).join("build-version"))
+            .ok()
+            .map(|version| version.trim().to_string())
+    });
+
+    let env = DIAGNOSTIC_ENV_VARS
+        .iter()
+        .map(|name| (name.to_string(), std::env::var(name).ok()))
+        .collect();
+
+    Ok(DiagnosticsResponse::Success {
+        diagnostics: Diagnostics {
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            libtorch_version,
+            libtorch_path,
+            torch_threads: tch::get_num_threads(),
+            active_device,
+            mps_available: tch::utils::has_mps(),
+            cuda_available: tch::utils::has_cuda(),
+            cuda_device_count: tch::Cuda::device_count(),
+            max_segment_secs,
+            active_model,
+            model_loaded,
+            env,
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        },
+    })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum SetLogLevelResponse {
+    #[serde(alias = "success")]
+    Success {
+        level: LogLevel,
+        /// ログを書き込んでいるファイル（デバッグビルドでは標準エラー出力なのでNone）
+        log_file: Option<PathBuf>,
+    },
+}
+
+/// ログの詳細度を変更する（不具合を再現するときに一時的に上げる）。再起動すると既定値に戻る
+#[tauri::command]
+pub async fn set_log_level(level: LogLevel) -> Result<SetLogLevelResponse> {
+    logging::set_log_level(level).map_err(|message| Error::UnexpectedError {
+        message: format!("Failed to set log level: {}", message),
+        source: None,
+    })?;
+
+    Ok(SetLogLevelResponse::Success {
+        level,
+        log_file: current_log_file(),
+    })
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status")]
+pub enum GetLogPathResponse {
+    #[serde(alias = "success")]
+    Success {
+        /// ログファイルを置くディレクトリ（日ごとにファイルが分かれる）
+        directory: PathBuf,
+        /// ログを書き込んでいるファイル（デバッグビルドでは標準エラー出力なのでNone）
+        file: Option<PathBuf>,
+    },
+}
+
+/// ログファイルの場所を返す（不具合報告に添付してもらうため）
+#[tauri::command]
+pub async fn get_log_path() -> Result<GetLogPathResponse> {
+    Ok(GetLogPathResponse::Success {
+        directory: log_directory(),
+        file: current_log_file(),
+    })
+}
+
+fn current_log_file() -> Option<PathBuf> {
+    if cfg!(debug_assertions) {
+        None
+    } else {
+        latest_log_file()
+    }
+}
@@ -1,7 +1,12 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// ユーザーが設定したプロジェクト保存先（未設定の場合はアプリのディレクトリを使用）
+static BASE_DIRECTORY_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
+
 pub fn current_unix_timestamp() -> i64 {
     match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(duration) => duration.as_secs() as i64,
@@ -26,27 +31,44 @@ fn get_home_directory() -> PathBuf {
     PathBuf::from(homedir_path_result.expect("No home directory found.")) // No way to recover from this.
 }
 
-pub fn get_base_directory() -> PathBuf {
+/// アプリ自身のデータ（db など）を置くディレクトリ。保存先の設定には影響されない
+pub fn get_app_directory() -> PathBuf {
     let homedir = get_home_directory();
     homedir.join("stemsplit")
 }
 
+/// プロジェクトデータの保存先。カスタムの保存先が設定されていればそちらを返す
+pub fn get_base_directory() -> PathBuf {
+    BASE_DIRECTORY_OVERRIDE
+        .read()
+        .ok()
+        .and_then(|dir| dir.clone())
+        .unwrap_or_else(get_app_directory)
+}
+
+/// カスタムの保存先を設定する（Noneでデフォルトに戻す）
+pub fn set_base_directory_override(path: Option<PathBuf>) {
+    if let Ok(mut dir) = BASE_DIRECTORY_OVERRIDE.write() {
+        *dir = path;
+    }
+}
+
+/// ディレクトリを作成し、実際にファイルを書き込めるか確認する
+pub fn ensure_writable_directory(path: &Path) -> Result<(), String> {
+    fs::create_dir_all(path)
+        .map_err(|e| format!("Unable to create directory {}: {}", path.display(), e))?;
+
+    let probe_path = path.join(".stemsplit_write_test");
+    fs::write(&probe_path, b"")
+        .map_err(|e| format!("Directory {} is not writable: {}", path.display(), e))?;
+    fs::remove_file(&probe_path).ok();
+
+    Ok(())
+}
+
+/// 8文字の16進数のランダムな文字列（プロジェクトのIDや一時ファイル名に使う）
+///
+/// 以前は現在時刻（秒）から作っていたため、同じ秒に作成したプロジェクトのIDが衝突していた
 pub fn generate_random_string() -> String {
-    let now = SystemTime::now();
-    let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards"); // Handle this more gracefully in a real app
-    let timestamp = since_the_epoch.as_secs(); // Get the current UNIX timestamp as seconds
-
-    // Convert the timestamp to a hexadecimal string
-    let hex_string = format!("{:x}", timestamp);
-
-    // Take the last 8 characters to ensure the string is of the desired length
-    // This is a simplistic approach and might need adjustment based on your needs
-    hex_string
-        .chars()
-        .rev()
-        .take(8)
-        .collect::<String>()
-        .chars()
-        .rev()
-        .collect()
+    format!("{:08x}", rand::random::<u32>())
 }
@@ -46,7 +46,15 @@ const ProjectItem = ({
             >
                 {project.name}
             </Box>
-            <Box fontSize="12px">{formatDate(project.created_at)}</Box>
+            <Box fontSize="12px">
+                {formatDate(project.created_at)}
+                {project.model_name ? ` · ${project.model_name}` : ""}
+            </Box>
+            {project.source_clipped && (
+                <Box fontSize="11px" color="#D9A441" mt="2px">
+                    Source audio is clipped; results may be poor.
+                </Box>
+            )}
         </Flex>
     )
 }
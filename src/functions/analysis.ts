@@ -0,0 +1,202 @@
+import { invoke } from "@tauri-apps/api/tauri"
+import { Project } from "../store/project/types"
+
+export type BpmEstimate = {
+    bpm: number
+    // 半分・倍のテンポの候補（検出範囲外の場合はnull）
+    half: number | null
+    double: number | null
+    // 最初の強拍の位置（秒、拍を検出できない場合はnull）
+    beat_offset_secs?: number | null
+    // beat_offset_secs の信頼度が低い（拍の位置のばらつきが大きい）
+    beat_offset_low_confidence?: boolean
+}
+
+export type AudioAnalysis = {
+    bpm: BpmEstimate | null
+    key: string | null
+    duration_secs: number
+    sample_rate: number
+    channels: number
+}
+
+export async function analyzeAudio(path: string): Promise<AudioAnalysis> {
+    const result: any = await invoke("analyze_audio", { path })
+    if (result.status === "Success") {
+        return result.analysis as AudioAnalysis
+    } else {
+        console.log("Unable to analyze audio", result)
+        throw new Error(result.message ?? "Unable to analyze audio.")
+    }
+}
+
+// 解析に失敗したファイルは analysis が null になり、error に理由が入る
+export type AnalyzeResult = {
+    path: string
+    analysis: AudioAnalysis | null
+    error: string | null
+}
+
+// フォルダ直下の音声ファイルをまとめて解析する（サブフォルダは対象外）
+export async function analyzeFolder(dir: string): Promise<AnalyzeResult[]> {
+    const result: any = await invoke("analyze_folder", { dir })
+    if (result.status === "Success") {
+        return result.results as AnalyzeResult[]
+    } else {
+        throw new Error(result.message ?? "Unable to analyze folder.")
+    }
+}
+
+export type KeyProfile = "krumhansl" | "temperley" | "shaath"
+
+export type KeyEstimate = {
+    key: string
+    profile: KeyProfile
+    correlation: number
+}
+
+// profile を省略すると Krumhansl。無音などで Key が決まらない場合は null
+export async function detectKey(path: string, profile?: KeyProfile): Promise<KeyEstimate | null> {
+    const result: any = await invoke("detect_key", { path, profile })
+    if (result.status === "Success") {
+        return (result.estimate as KeyEstimate | null) ?? null
+    } else {
+        throw new Error(result.message ?? "Unable to detect key.")
+    }
+}
+
+// [min0, max0, min1, max1, ...] の形式で buckets * 2 個の値を返す
+export async function getWaveformPeaks(path: string, buckets: number): Promise<number[]> {
+    const result: any = await invoke("get_waveform_peaks", { path, buckets })
+    if (result.status === "Success") {
+        return result.peaks as number[]
+    } else {
+        throw new Error(result.message ?? "Unable to get waveform peaks.")
+    }
+}
+
+export type SpectrogramData = {
+    columns: number
+    rows: number
+    // columns * rows 個の振幅（dBFS）。列ごとに 0Hz から max_freq までの行が並ぶ
+    magnitudes_db: number[]
+    secs_per_column: number
+    max_freq: number
+    duration_secs: number
+}
+
+// fftSize は 256〜16384 の2のべき乗。長い曲は表示用のサイズまで間引かれる
+export async function getSpectrogram(path: string, fftSize: number, hop: number): Promise<SpectrogramData> {
+    const result: any = await invoke("get_spectrogram", { path, fftSize, hop })
+    if (result.status === "Success") {
+        return result.spectrogram as SpectrogramData
+    } else {
+        throw new Error(result.message ?? "Unable to compute spectrogram.")
+    }
+}
+
+// stem を単独の参照音源（公式のアカペラなど）と比べた SDR の目安（dB、高いほど参照に近い）
+// 参照音源に他の音が混ざっていると意味のない値になるので、クリーンな参照が必要
+export async function evaluateSeparation(stemPath: string, referencePath: string): Promise<number> {
+    const result: any = await invoke("evaluate_separation", { stemPath, referencePath })
+    if (result.status === "Success") {
+        return result.sdr_db
+    } else {
+        throw new Error(result.message ?? "Unable to evaluate separation.")
+    }
+}
+
+export type StemLoudness = {
+    kind: string
+    path: string
+    lufs: number | null
+}
+
+export async function measureProjectLoudness(projectId: string): Promise<{ mixLufs: number | null, stems: StemLoudness[] }> {
+    const result: any = await invoke("measure_project_loudness", { projectId })
+    if (result.status === "Success") {
+        return { mixLufs: result.mix_lufs, stems: result.stems as StemLoudness[] }
+    } else {
+        throw new Error(result.message ?? "Unable to measure loudness.")
+    }
+}
+
+export async function refreshAnalysisIfStale(projectId: string): Promise<{ project: Project, refreshed: boolean }> {
+    const result: any = await invoke("refresh_analysis_if_stale", { projectId })
+    if (result.status === "Success") {
+        return { project: result.project as Project, refreshed: result.refreshed }
+    } else {
+        throw new Error(result.message ?? "Unable to refresh analysis.")
+    }
+}
+
+export type StemAnalysis = {
+    kind: string
+    path: string
+    bpm: BpmEstimate | null
+    key: string | null
+    lufs: number | null
+}
+
+// 分離したstemを解析する（結果はプロジェクトに保存されない）
+export async function analyzeStem(projectId: string, stemKind: string): Promise<StemAnalysis> {
+    const result: any = await invoke("analyze_stem", { projectId, stemKind })
+    if (result.status === "Success") {
+        return result.analysis as StemAnalysis
+    } else {
+        throw new Error(result.message ?? "Unable to analyze stem.")
+    }
+}
+
+export type StemProblem =
+    | { kind: 'missing' | 'empty' }
+    | { kind: 'unreadable', message: string }
+
+export type StemInfo = {
+    kind: string
+    path: string
+    bytes: number
+    sample_rate: number | null
+    channels: number | null
+    duration_secs: number | null
+    // 欠けている・0バイト・壊れているstem（問題がなければnull）
+    problem: StemProblem | null
+}
+
+export async function getStemInfo(projectId: string): Promise<StemInfo[]> {
+    const result: any = await invoke("get_stem_info", { projectId })
+    if (result.status === "Success") {
+        return result.stems as StemInfo[]
+    } else {
+        throw new Error(result.message ?? "Unable to get stem info.")
+    }
+}
+
+export type VerifyResult = {
+    path: string
+    ok: boolean
+    // ヘッダーに記録されたフレーム数（記録されない形式ではnull）
+    header_frames: number | null
+    decoded_frames: number | null
+    // 途中で切れている・デコードできないなどの詳細
+    error: string | null
+}
+
+// ファイルを最後までデコードして壊れていないかを確認する
+export async function verifyStem(path: string): Promise<VerifyResult> {
+    const result: any = await invoke("verify_stem", { path })
+    if (result.status === "Success") {
+        return result.result as VerifyResult
+    } else {
+        throw new Error(result.message ?? "Unable to verify stem.")
+    }
+}
+
+export async function verifyProjectStems(projectId: string): Promise<VerifyResult[]> {
+    const result: any = await invoke("verify_project_stems", { projectId })
+    if (result.status === "Success") {
+        return result.results as VerifyResult[]
+    } else {
+        throw new Error(result.message ?? "Unable to verify stems.")
+    }
+}
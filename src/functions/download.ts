@@ -1,11 +1,26 @@
 import { save } from '@tauri-apps/api/dialog'
 import { invoke } from '@tauri-apps/api/tauri'
 
+export type StemsZipReport = {
+    // ZIPに追加したファイル名
+    added: string[]
+    // 読み込めずに飛ばしたstem（allOrNothing の場合は常に空）
+    failed: { path: string; error: string }[]
+}
+
 /**
  * stemファイルをZIPファイルとしてダウンロード（保存ダイアログを表示して保存）
  * バックエンドでZIPファイルを作成してからダウンロード
+ *
+ * 読み込めないstemは飛ばして残りをZIPにし、飛ばしたstemを結果で返す（キャンセルした場合はnull）。
+ * allOrNothing を指定すると、1つでも失敗した時点でエラーにする
  */
-export async function downloadStemsAsZip(projectId: string, stemPaths: string[]): Promise<void> {
+export async function downloadStemsAsZip(
+    projectId: string,
+    stemPaths: string[],
+    coverPath?: string,
+    allOrNothing: boolean = false
+): Promise<StemsZipReport | null> {
     try {
         if (stemPaths.length === 0) {
             throw new Error('ダウンロードするstemがありません')
@@ -37,17 +52,23 @@ export async function downloadStemsAsZip(projectId: string, stemPaths: string[])
 
         if (!filePath) {
             // ユーザーがキャンセルした場合
-            return
+            return null
         }
 
         // バックエンドでZIPファイルを作成
-        await invoke('create_stems_zip', {
+        const report: StemsZipReport = await invoke('create_stems_zip', {
             projectId,
             stemPaths,
-            outputPath: filePath
+            outputPath: filePath,
+            coverPath: coverPath ?? null,
+            allOrNothing
         })
         
         console.log(`Stems saved to ZIP: ${filePath}`)
+        if (report.failed.length > 0) {
+            console.warn('Some stems were skipped:', report.failed)
+        }
+        return report
     } catch (error) {
         console.error('Error downloading stems as ZIP:', error)
         throw error
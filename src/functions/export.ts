@@ -0,0 +1,205 @@
+import { invoke } from "@tauri-apps/api/tauri"
+import { startDrag } from "@crabnebula/tauri-plugin-drag"
+import { ExportConfig, StemFilter } from "./split"
+
+export type ExportFormat = 'wav' | 'flac' | 'mp3'
+
+/**
+ * 1つのstemを指定した場所に書き出す（formatが元のファイルと異なる場合は変換する）
+ */
+export async function exportStem(
+    projectId: string,
+    stemKind: string,
+    destPath: string,
+    format?: ExportFormat
+): Promise<string> {
+    const result: any = await invoke("export_stem", {
+        projectId,
+        stemKind,
+        destPath,
+        format: format ?? null
+    })
+    if (result.status === "Success") {
+        return result.path as string
+    } else {
+        throw new Error(result.message ?? "Unable to export stem.")
+    }
+}
+
+/**
+ * stemをN小節ごとのWAVに切り分ける（BPMとダウンビートは元の曲から取得）
+ */
+export async function exportBars(
+    projectId: string,
+    stemKind: string,
+    barsPerSlice: number,
+    exportConfig?: ExportConfig
+): Promise<string[]> {
+    const result: any = await invoke("export_bars", {
+        projectId,
+        stemKind,
+        barsPerSlice,
+        exportConfig: exportConfig ?? null
+    })
+    if (result.status === "Success") {
+        return result.slices as string[]
+    } else {
+        throw new Error(result.message ?? "Unable to export bars.")
+    }
+}
+
+export type StretchReport = {
+    path: string
+    source_bpm: number
+    target_bpm: number
+    // 出力の長さ / 入力の長さ（0.5〜2.0）
+    ratio: number
+    // 倍率が大きく、にじみなどのアーティファクトが目立つ可能性がある
+    artifact_warning: boolean
+}
+
+/**
+ * stemのテンポを targetBpm に合わせて（ピッチは変えずに）書き出す（元のBPMはプロジェクトの値か元の曲から検出）
+ */
+export async function exportStemAtBpm(
+    projectId: string,
+    stemKind: string,
+    targetBpm: number
+): Promise<StretchReport> {
+    const result: any = await invoke("export_stem_at_bpm", {
+        projectId,
+        stemKind,
+        targetBpm
+    })
+    if (result.status === "Success") {
+        return result.report as StretchReport
+    } else {
+        throw new Error(result.message ?? "Unable to export stem at BPM.")
+    }
+}
+
+export type PitchShiftReport = {
+    path: string
+    source_key: string
+    target_key: string
+    // 移調した半音数（近い方向の -6〜5）
+    semitones: number
+    // 移調の幅が大きく、フォルマントのずれなどが目立つ可能性がある
+    artifact_warning: boolean
+}
+
+/**
+ * stemを targetKey（"C# minor" など）に移調して（長さは変えずに）書き出す（元のキーはプロジェクトの値か元の曲から検出）
+ * 既定では主音どうしを合わせ、matchRelative の場合は平行調（A minor と C major など）を同じキーとみなす
+ */
+export async function exportStemAtKey(
+    projectId: string,
+    stemKind: string,
+    targetKey: string,
+    matchRelative?: boolean
+): Promise<PitchShiftReport> {
+    const result: any = await invoke("export_stem_at_key", {
+        projectId,
+        stemKind,
+        targetKey,
+        matchRelative: matchRelative ?? null
+    })
+    if (result.status === "Success") {
+        return result.report as PitchShiftReport
+    } else {
+        throw new Error(result.message ?? "Unable to export stem at key.")
+    }
+}
+
+// DJソフト向けのキーの表記（standard: "Am"、camelot: "8A"、open_key: "1m"）
+export type KeyNotation = 'standard' | 'camelot' | 'open_key'
+
+export type DjTagsResult = {
+    path: string
+    // TBPM に書き込んだ値（プロジェクトに BPM がない場合は null）
+    bpm: number | null
+    // TKEY に書き込んだ値（プロジェクトにキーがない場合は null）
+    key: string | null
+}
+
+/**
+ * プロジェクトの BPM とキーを Rekordbox/Serato が読む ID3 タグ（TBPM/TKEY）として元の曲に書き込む
+ * 既定では `{プロジェクト}/dj/` のコピーに書き込み、inPlace の場合はプロジェクト内の曲に直接書き込む（MP3/WAV/AIFF のみ）
+ */
+export async function exportDjTags(
+    projectId: string,
+    notation: KeyNotation = 'camelot',
+    inPlace: boolean = false
+): Promise<DjTagsResult> {
+    const result: any = await invoke("export_dj_tags", { projectId, notation, inPlace })
+    if (result.status === "Success") {
+        return {
+            path: result.path,
+            bpm: result.bpm ?? null,
+            key: result.key ?? null,
+        }
+    } else {
+        throw new Error(result.message ?? "Unable to export DJ tags.")
+    }
+}
+
+/**
+ * プロジェクト内のstemを別フォーマットに変換し、元のファイルと同じ場所に保存する
+ */
+export async function transcodeStem(srcPath: string, destFormat: ExportFormat): Promise<string> {
+    const result: any = await invoke("transcode_stem", { srcPath, destFormat })
+    if (result.status === "Success") {
+        return result.path as string
+    } else {
+        throw new Error(result.message ?? "Unable to transcode stem.")
+    }
+}
+
+/**
+ * 保存されたミキサー設定（ゲイン・ミュート・パン）でstemを1つのファイルにまとめる
+ */
+export async function renderMixdown(
+    projectId: string,
+    outputPath: string,
+    format?: ExportFormat,
+    exportConfig?: ExportConfig
+): Promise<string> {
+    const result: any = await invoke("render_mixdown", {
+        projectId,
+        outputPath,
+        format: format ?? null,
+        exportConfig: exportConfig ?? null
+    })
+    if (result.status === "Success") {
+        return result.path as string
+    } else {
+        throw new Error(result.message ?? "Unable to render mixdown.")
+    }
+}
+
+/**
+ * 指定したstem（空の場合はすべて）をDAWなどへドラッグアウトする
+ * ドラッグ開始のイベントハンドラ（onDragStart）から呼ぶ。カバー画像がない場合は fallbackIcon を使う
+ */
+export async function startStemDrag(projectId: string, stemKinds: string[], fallbackIcon: string): Promise<string[]> {
+    const result: any = await invoke("start_stem_drag", { projectId, stemKinds })
+    if (result.status === "Success") {
+        const files = result.files as string[]
+        await startDrag({ item: files, icon: (result.icon as string | null) ?? fallbackIcon })
+        return files
+    } else {
+        throw new Error(result.message ?? "Unable to drag stems.")
+    }
+}
+
+/**
+ * stemにフィルタを掛けたプレビューWAVを一時ディレクトリに書き出し、そのパスを返す（分離し直さない）
+ */
+export async function applyEqPreview(stemPath: string, filters: StemFilter[]): Promise<string> {
+    const result: any = await invoke("apply_eq_preview", { stemPath, filters })
+    if (result.status === "Success") {
+        return result.path as string
+    } else {
+        throw new Error(result.message ?? "Unable to render EQ preview.")
+    }
+}
@@ -1,9 +1,19 @@
 import { invoke } from "@tauri-apps/api/tauri"
-import { Project } from "../store/project/types"
+import { listen, UnlistenFn } from "@tauri-apps/api/event"
+import { Project, StemSetting } from "../store/project/types"
 
-export async function createProject(filepath: string): Promise<Project> {
+// useTrackTitle が true（デフォルト）の場合、タグがあれば "アーティスト - タイトル" をプロジェクト名にする
+// deleteSourceAfterSplit が true の場合、分離に成功した後でコピーした曲ファイルを削除する（再分離はできなくなる）
+// BPM/Keyは返した時点ではまだ解析中（analysis_pending）。解析が終わると "project_analyzed" イベントで届く
+export async function createProject(
+    filepath: string,
+    useTrackTitle: boolean = true,
+    deleteSourceAfterSplit: boolean = false,
+): Promise<Project> {
     const result: any = await invoke("create_project", {
         audioFilepath: filepath,
+        useTrackTitle,
+        deleteSourceAfterSplit,
     })
     if (result.status === "Success") {
         return result.project as Project
@@ -13,8 +23,28 @@ export async function createProject(filepath: string): Promise<Project> {
     }
 }
 
-export async function getAllProjects(): Promise<Project[]> {
-    const result: any = await invoke("get_all_projects", {})
+// バックグラウンドの解析が終わったプロジェクトを受け取る（戻り値を呼ぶと購読を解除する）
+export function onProjectAnalyzed(callback: (project: Project) => void): Promise<UnlistenFn> {
+    return listen<Project>("project_analyzed", (event) => callback(event.payload))
+}
+
+// ファイルパスの無い音声データ（ドラッグ&ドロップなど）からプロジェクトを作成する
+export async function createProjectFromBytes(name: string, data: Uint8Array): Promise<Project> {
+    const result: any = await invoke("create_project_from_bytes", {
+        name,
+        data: Array.from(data),
+    })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        console.log("Unable to create project:", result)
+        throw new Error(result.message ?? "Unable to create project")
+    }
+}
+
+// tag を指定すると、そのタグが付いたプロジェクトだけを返す
+export async function getAllProjects(tag?: string): Promise<Project[]> {
+    const result: any = await invoke("get_all_projects", { tag: tag ?? null })
     if (result.status === "Success") {
         return result.projects as Project[]
     } else {
@@ -22,3 +52,190 @@ export async function getAllProjects(): Promise<Project[]> {
         throw new Error("Unable to get projects.")
     }
 }
+
+export async function getProject(projectId: string): Promise<Project | null> {
+    const result: any = await invoke("get_project", { projectId })
+    if (result.status === "Success") {
+        return (result.project as Project | null) ?? null
+    } else {
+        console.log("Unable to get project", result)
+        throw new Error("Unable to get project.")
+    }
+}
+
+export async function renameProject(projectId: string, newName: string): Promise<Project> {
+    const result: any = await invoke("rename_project", { projectId, newName })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        console.log("Unable to rename project", result)
+        throw new Error(result.message ?? "Unable to rename project.")
+    }
+}
+
+// 検出したBPMを手動で修正する（40〜250）。以降の再解析では上書きされない
+export async function setProjectBpm(projectId: string, bpm: number): Promise<Project> {
+    const result: any = await invoke("set_project_bpm", { projectId, bpm })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        throw new Error(result.message ?? "Unable to set BPM.")
+    }
+}
+
+// 検出したKeyを手動で修正する（"C major" / "F# minor" の形式）。以降の再解析では上書きされない
+export async function setProjectKey(projectId: string, key: string): Promise<Project> {
+    const result: any = await invoke("set_project_key", { projectId, key })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        throw new Error(result.message ?? "Unable to set key.")
+    }
+}
+
+export async function updateStemSettings(projectId: string, settings: StemSetting[]): Promise<Project> {
+    const result: any = await invoke("update_stem_settings", { projectId, settings })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        console.log("Unable to update stem settings", result)
+        throw new Error(result.message ?? "Unable to update stem settings.")
+    }
+}
+
+// ファイル選択ダイアログのフィルタに使う（例: [{ name: "Audio", extensions }]）
+export async function getSupportedInputExtensions(): Promise<string[]> {
+    const result: any = await invoke("get_supported_input_extensions", {})
+    if (result.status === "Success") {
+        return result.extensions as string[]
+    } else {
+        throw new Error(result.message ?? "Unable to get supported extensions.")
+    }
+}
+
+// タグがない項目は null
+export type TrackMetadata = {
+    title: string | null
+    artist: string | null
+    album: string | null
+    year: number | null
+    genre: string | null
+    track_number: number | null
+    has_cover: boolean
+}
+
+// インポート前にタグを読み、プロジェクト名を補完するのに使う
+export async function readMetadata(path: string): Promise<TrackMetadata> {
+    const result: any = await invoke("read_metadata", { path })
+    if (result.status === "Success") {
+        return result.metadata as TrackMetadata
+    } else {
+        throw new Error(result.message ?? "Unable to read metadata.")
+    }
+}
+
+export type AudioProbe = {
+    // コンテナの形式（"wav" / "flac" / "mp3" / "mp4" など）
+    format: string
+    // コーデック（"pcm_s16le" / "mp3" / "aac" など）
+    codec: string
+    sample_rate: number
+    channels: number
+    // 長さがヘッダーにない形式では null
+    duration_secs: number | null
+    // 平均ビットレート（bps、長さが分からない場合は null）
+    bitrate: number | null
+}
+
+// ドロップされたファイルをインポート前に確認する（対応していない・壊れたファイルは理由付きでエラー）
+export async function probeAudio(path: string): Promise<AudioProbe> {
+    const result: any = await invoke("probe_audio", { path })
+    if (result.status === "Success") {
+        return result.probe as AudioProbe
+    } else {
+        throw new Error(result.message ?? "Unable to read audio file.")
+    }
+}
+
+export type OrphanCleanupReport = {
+    removed_dirs: string[]
+    missing_file_projects: string[]
+    restored_projects: string[]
+}
+
+// DBにないプロジェクトのディレクトリを削除し、ファイルのないプロジェクトに missing_files を立てる
+export async function gcOrphanedProjects(): Promise<OrphanCleanupReport> {
+    const result: any = await invoke("gc_orphaned_projects", {})
+    if (result.status === "Success") {
+        return result.report as OrphanCleanupReport
+    } else {
+        console.log("Unable to clean up projects", result)
+        throw new Error(result.message ?? "Unable to clean up projects.")
+    }
+}
+
+export type LibraryStats = {
+    project_count: number
+    total_stems: number
+    // すべてのプロジェクトのディレクトリの合計サイズ（バイト）
+    total_disk_bytes: number
+    // BPM が分かっているプロジェクトがない場合は null
+    avg_bpm: number | null
+}
+
+// ダッシュボード用のライブラリ全体の統計（ディスク使用量はディレクトリを辿って測るので少し時間がかかる）
+export async function getLibraryStats(): Promise<LibraryStats> {
+    const result: any = await invoke("get_library_stats", {})
+    if (result.status === "Success") {
+        return result.stats as LibraryStats
+    } else {
+        throw new Error(result.message ?? "Unable to get library stats.")
+    }
+}
+
+// ライブラリのフォルダを移動したプロジェクトを新しい保存先に付け替える
+export async function relinkProject(projectId: string, newBaseDir: string): Promise<Project> {
+    const result: any = await invoke("relink_project", { projectId, newBaseDir })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        throw new Error(result.message ?? "Unable to relink project.")
+    }
+}
+
+// プロジェクトのフォルダをファイルマネージャー（Finder/エクスプローラーなど）で開く
+export async function revealProjectFolder(projectId: string): Promise<string> {
+    const result: any = await invoke("reveal_project_folder", { projectId })
+    if (result.status === "Success") {
+        return result.path as string
+    } else {
+        throw new Error(result.message ?? "Unable to open project folder.")
+    }
+}
+
+export async function addProjectTag(projectId: string, tag: string): Promise<Project> {
+    const result: any = await invoke("add_project_tag", { projectId, tag })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        throw new Error(result.message ?? "Unable to add tag.")
+    }
+}
+
+export async function removeProjectTag(projectId: string, tag: string): Promise<Project> {
+    const result: any = await invoke("remove_project_tag", { projectId, tag })
+    if (result.status === "Success") {
+        return result.project as Project
+    } else {
+        throw new Error(result.message ?? "Unable to remove tag.")
+    }
+}
+
+export async function findProjectsByTag(tag: string): Promise<Project[]> {
+    const result: any = await invoke("find_projects_by_tag", { tag })
+    if (result.status === "Success") {
+        return result.projects as Project[]
+    } else {
+        throw new Error(result.message ?? "Unable to find projects.")
+    }
+}
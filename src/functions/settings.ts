@@ -0,0 +1,74 @@
+import { invoke } from "@tauri-apps/api/tauri"
+
+export type OutputDirectory = {
+    path: string
+    is_custom: boolean
+}
+
+export async function getOutputDirectory(): Promise<OutputDirectory> {
+    const result: any = await invoke("get_output_directory", {})
+    if (result.status === "Success") {
+        return { path: result.path, is_custom: result.is_custom }
+    } else {
+        throw new Error(result.message ?? "Unable to get output directory.")
+    }
+}
+
+/**
+ * プロジェクトの保存先を変更する（nullでデフォルトに戻す）
+ */
+export async function setOutputDirectory(path: string | null): Promise<OutputDirectory> {
+    const result: any = await invoke("set_output_directory", { path })
+    if (result.status === "Success") {
+        return { path: result.path, is_custom: result.is_custom }
+    } else {
+        throw new Error(result.message ?? "Unable to set output directory.")
+    }
+}
+
+/**
+ * 推論に使うデバイスを設定する（nullで自動検出に戻す）
+ */
+export async function setDevicePreference(device: 'cpu' | 'cuda' | 'mps' | null): Promise<string> {
+    const result: any = await invoke("set_device_preference", { device })
+    if (result.status === "Success") {
+        return result.active_device as string
+    } else {
+        throw new Error(result.message ?? "Unable to set device.")
+    }
+}
+
+export type CpuThreads = {
+    threads: number | null
+    // 現在使われているスレッド数
+    active_threads: number
+    // 設定を反映するには再起動が必要
+    restart_required: boolean
+}
+
+/**
+ * CPU推論のスレッド数を設定する（nullで既定値の1に戻す、次回の起動から反映）
+ * 環境によっては2以上にすると推論中にクラッシュすることがある
+ */
+export async function setCpuThreads(threads: number | null): Promise<CpuThreads> {
+    const result: any = await invoke("set_cpu_threads", { threads })
+    if (result.status === "Success") {
+        return result as CpuThreads
+    } else {
+        throw new Error(result.message ?? "Unable to set CPU threads.")
+    }
+}
+
+/**
+ * 一度に推論するトラックの長さの上限（秒）を設定する（nullでデバイスごとの既定値に戻す）
+ * 上限より長いトラックは分割して推論する。MPSでメモリ不足になる場合は小さくする（最小30秒）
+ * 戻り値は現在のデバイスで実際に使われる上限（nullならトラック全体を一度に推論）
+ */
+export async function setMaxSegmentSecs(secs: number | null): Promise<number | null> {
+    const result: any = await invoke("set_max_segment_secs", { secs })
+    if (result.status === "Success") {
+        return result.active_secs as number | null
+    } else {
+        throw new Error(result.message ?? "Unable to set max segment length.")
+    }
+}
@@ -1,8 +1,21 @@
 import { invoke } from "@tauri-apps/api/tauri"
+import { listen, UnlistenFn } from "@tauri-apps/api/event"
 
 type StemSplitSuccessResult = {
     status: 'Success',
     stems: string[]
+    // GPUでの推論に失敗し、CPUで再実行した
+    used_cpu_fallback: boolean
+    // limiter を有効にした場合の、stemの種類ごとのメータリング
+    metering?: Record<string, LimiterMetering>
+}
+
+export type LimiterMetering = {
+    peak_before: number
+    peak_after: number
+    // ピークでのゲインリダクション（dB、0なら圧縮されていない）
+    gain_reduction_db: number
+    limited_samples: number
 }
 
 type StemSplitErrorResult = {
@@ -12,21 +25,187 @@ type StemSplitErrorResult = {
 
 export type StemSplitResult = StemSplitSuccessResult | StemSplitErrorResult
 
-export async function splitStems(projectId: string): Promise<string[]> {
+export type WavBitDepth = 'int16' | 'int24' | 'float32'
+
+// WAV の書き出し設定（省略した項目はデフォルト: float32、ディザあり）
+export type ExportConfig = {
+    bit_depth?: WavBitDepth
+    // 16bit で書き出す場合に量子化の前に TPDF ディザを加える（24bit/32bit float では無視）
+    dither?: boolean
+}
+
+export type NoiseReductionMode = 'off' | 'moving_average' | 'spectral_gate'
+
+export type StemFilter =
+    | { type: 'high_pass', cutoff_hz: number }
+    | { type: 'low_pass', cutoff_hz: number }
+    | { type: 'band_pass', low_hz: number, high_hz: number }
+
+// 省略した場合はモデルごとのデフォルト（htdemucs_6s では other にスペクトラルゲートを使う）
+export type PostProcessConfig = {
+    // stemの種類ごとのノイズ除去の方式（省略時は other のみ moving_average）
+    noise_reduction?: Record<string, NoiseReductionMode>
+    // stemの種類ごとの帯域フィルタ（省略時は other/bass/vocals/guitar/piano に従来のフィルタ）
+    filters?: Record<string, StemFilter>
+    // drumsのアタックを強調する量（0〜1、デフォルト: 0 = 無効）
+    transient_amount?: number
+}
+
+export type ClickRemovalConfig = {
+    // この振幅を超えたサンプルだけをクリックの候補にする（デフォルト: 0.9）
+    threshold?: number
+    // 前後1msの平均振幅の何倍を超えたらクリックとみなすか（デフォルト: 3）
+    neighbor_ratio?: number
+    // クリック除去を行わないstemの種類（例: ['drums']）
+    disabled_stems?: string[]
+}
+
+export type ResampleQuality = 'fast' | 'balanced' | 'best'
+
+export type SplitOptions = {
+    parallel_encode?: boolean
+    bit_depth?: WavBitDepth
+    post_process?: PostProcessConfig
+    // stemを左右平均のモノラルWAVとして書き出す
+    mono?: boolean
+    // GPUでの推論に失敗した場合にCPUで再試行する（デフォルト: true）
+    cpu_fallback?: boolean
+    // stemの先頭・末尾の無音を切り取る（元の位置は stem_offsets.json に記録）
+    trim_silence?: boolean
+    // 無音とみなす閾値（dBFS、デフォルト: -60）
+    silence_threshold_db?: number
+    click_removal?: ClickRemovalConfig
+    // ランダムにずらした入力で推論して平均する回数（デフォルト: 1、0でずらさない）
+    // 品質が上がる代わりに推論時間がおおよそ回数に比例して増える
+    shifts?: number
+    // 推論セグメント間のオーバーラップ（0〜0.95、デフォルト: 0.5）
+    overlap?: number
+    // 44.1kHz以外の音源のリサンプリング品質（デフォルト: best）
+    // fast は best の約4倍、balanced は約2倍速い（プレビュー向け）
+    resample_quality?: ResampleQuality
+    // 書き出す直前にソフトリミッターを掛ける（デフォルト: false）
+    limiter?: boolean
+    // 書き出すWAVのサンプルレート（例: 48000）。省略時はモデルのサンプルレート（44.1kHz）
+    output_sample_rate?: number
+    // 後処理をすべて省き、モデルの出力をそのままFloat32で書き出す（デバッグ用、デフォルト: false）
+    raw?: boolean
+    // ソース名から書き出すファイル名（拡張子なし）への対応（例: { other: "accompaniment", drums: "01_drums" }）
+    // 指定しないソースはソース名のまま。重複するファイル名はエラーになる
+    naming?: Record<string, string>
+    // "single_multichannel" の場合、すべての stem を stems.wav（例: 4 ソースのステレオなら 8ch）にまとめる
+    // チャンネルはソースの順に [vocals L, vocals R, drums L, drums R, ...]。対応は stems_channels.json に書き出される
+    output_layout?: 'separate' | 'single_multichannel'
+    // stem の先頭と末尾に掛けるフェードの長さ（ミリ秒、例: 5）。trim_silence の切り口のクリックを防ぐ
+    fade_ms?: number
+    // 16bit で書き出す場合に量子化の前に TPDF ディザを加える（デフォルト: true、24bit/32bit float では無視）
+    dither?: boolean
+}
+
+export async function splitStems(projectId: string, options?: SplitOptions): Promise<string[]> {
+    const result: StemSplitResult = await invoke('split_stems', {
+        projectId: projectId,
+        options: options ?? null
+    })
+
+    if (result.status === 'Success') {
+        if (result.used_cpu_fallback) {
+            console.warn('GPU inference failed, stems were separated on CPU')
+        }
+        return result.stems
+    } else{
+        throw new Error(result.message)
+    }
+}
+
+// splitStems と同じだが、リミッターのメータリングも返す
+export async function splitStemsWithMetering(projectId: string, options?: SplitOptions): Promise<{ stems: string[], metering: Record<string, LimiterMetering> }> {
     const result: StemSplitResult = await invoke('split_stems', {
-        projectId: projectId
+        projectId: projectId,
+        options: options ?? null
+    })
+
+    if (result.status === 'Success') {
+        return { stems: result.stems, metering: result.metering ?? {} }
+    } else{
+        throw new Error(result.message)
+    }
+}
+
+// モデルを切り替えて分離し直す（失敗した場合は元のモデルに戻る）
+export async function resplitWithModel(projectId: string, modelName: string, options?: SplitOptions): Promise<string[]> {
+    const result: StemSplitResult = await invoke('resplit_with_model', {
+        projectId: projectId,
+        modelName: modelName,
+        options: options ?? null
     })
 
     if (result.status === 'Success') {
+        if (result.used_cpu_fallback) {
+            console.warn('GPU inference failed, stems were separated on CPU')
+        }
         return result.stems
     } else{
         throw new Error(result.message)
     }
 }
 
-export async function splitVocalInstrumental(projectId: string): Promise<string[]> {
+export type ComparedModel = {
+    model_name: string
+    stems: string[]
+    stem_path: string
+}
+
+export type ModelComparison = {
+    stem_kind: string
+    model_a: ComparedModel
+    model_b: ComparedModel
+    difference_rms: number
+}
+
+// 同じ曲を2つのモデルで分離し、stemKind のstemの違いを比較する（stemは compare/{モデル名}/ に書き出す）
+export async function compareModels(projectId: string, modelA: string, modelB: string, stemKind: string): Promise<ModelComparison> {
+    const result: any = await invoke('compare_models', {
+        projectId: projectId,
+        modelA: modelA,
+        modelB: modelB,
+        stemKind: stemKind
+    })
+
+    if (result.status === 'Success') {
+        return result as ModelComparison
+    } else{
+        throw new Error(result.message)
+    }
+}
+
+// 複数のsourceを合算したミックスの作り方
+// raw_outputs: モデルの出力を合算してから後処理する（デフォルト。位相の打ち消し合いが起きにくい）
+// processed_stems: sourceごとに後処理してから合算する（個別のstemの和と一致するが、わずかにこもることがある）
+export type MixSumMode = 'raw_outputs' | 'processed_stems'
+
+// splitVocalInstrumental / splitCustomMix の設定（省略した項目はデフォルト）
+export type MixSplitOptions = ExportConfig & {
+    sum_mode?: MixSumMode
+    click_removal?: ClickRemovalConfig
+    // GPUでの推論に失敗した場合にCPUで再試行する（デフォルト: true）
+    cpu_fallback?: boolean
+}
+
+export async function splitVocalInstrumental(
+    projectId: string,
+    sumMode?: MixSumMode,
+    cpuFallback?: boolean,
+    clickRemoval?: ClickRemovalConfig,
+    exportConfig?: ExportConfig
+): Promise<string[]> {
     const result: StemSplitResult = await invoke('split_vocal_instrumental_stems', {
-        projectId: projectId
+        projectId: projectId,
+        options: {
+            ...exportConfig,
+            sum_mode: sumMode,
+            click_removal: clickRemoval,
+            cpu_fallback: cpuFallback
+        }
     })
 
     if (result.status === 'Success') {
@@ -34,4 +213,146 @@ export async function splitVocalInstrumental(projectId: string): Promise<string[
     } else{
         throw new Error(result.message)
     }
-}
\ No newline at end of file
+}
+export async function buildInstrumentalFromStems(projectId: string, exportConfig?: ExportConfig): Promise<string[]> {
+    const result: StemSplitResult = await invoke('build_instrumental_from_stems', {
+        projectId: projectId,
+        exportConfig: exportConfig ?? null
+    })
+
+    if (result.status === 'Success') {
+        return result.stems
+    } else{
+        throw new Error(result.message)
+    }
+}
+
+/**
+ * 指定したsourceの組み合わせ（例: ['vocals', 'piano']）と、それ以外（restName）の2つに分離
+ */
+export async function splitCustomMix(
+    projectId: string,
+    keep: string[],
+    restName: string,
+    sumMode?: MixSumMode,
+    cpuFallback?: boolean,
+    clickRemoval?: ClickRemovalConfig,
+    exportConfig?: ExportConfig
+): Promise<string[]> {
+    const result: StemSplitResult = await invoke('split_custom_mix_stems', {
+        projectId: projectId,
+        keep: keep,
+        restName: restName,
+        options: {
+            ...exportConfig,
+            sum_mode: sumMode,
+            click_removal: clickRemoval,
+            cpu_fallback: cpuFallback
+        }
+    })
+
+    if (result.status === 'Success') {
+        return result.stems
+    } else{
+        throw new Error(result.message)
+    }
+}
+
+/**
+ * 先頭の数秒だけを分離してプレビュー用のstemを作成（プロジェクトには保存されない）
+ */
+export async function previewSplit(projectId: string, seconds: number, options?: SplitOptions): Promise<string[]> {
+    const result: StemSplitResult = await invoke('preview_split', {
+        projectId: projectId,
+        seconds: seconds,
+        options: options ?? null
+    })
+
+    if (result.status === 'Success') {
+        return result.stems
+    } else{
+        throw new Error(result.message)
+    }
+}
+
+export type StemsModelStatus = {
+    stemsModel: string | null
+    activeModel: string
+    // stemが現在のモデルとは別のモデルで作られている（再分離を促す）
+    isStale: boolean
+}
+
+export async function getStemsModelStatus(projectId: string): Promise<StemsModelStatus> {
+    const result: any = await invoke('get_stems_model_status', { projectId })
+    if (result.status === 'Success') {
+        return {
+            stemsModel: result.stems_model ?? null,
+            activeModel: result.active_model,
+            isStale: result.is_stale,
+        }
+    } else {
+        throw new Error(result.message ?? 'Unable to get stems model status.')
+    }
+}
+
+export type SelectedModel = {
+    modelName: string
+    // 選ばれたモデルが出力するすべての stem（指定したもの以外も含む）
+    sources: string[]
+}
+
+// 指定した stem（例: ["vocals", "guitar", "piano"]）をすべて出力できるモデルに切り替える
+// モデル名を知らなくても "6 stem で分けたい" のように選べる。該当するモデルがない場合はエラー
+export async function selectModelForStems(stems: string[]): Promise<SelectedModel> {
+    const result: any = await invoke("select_model_for_stems", { stems })
+    if (result.status === "Success") {
+        return { modelName: result.model_name, sources: result.sources }
+    } else {
+        throw new Error(result.message ?? "Unable to select a model.")
+    }
+}
+
+export type JobStatus =
+    | { state: 'queued' | 'decoding' | 'inferring' | 'post_processing' | 'encoding' | 'done' }
+    | { state: 'failed', message: string }
+
+// 状態が変わるたびに "job-status" イベント（{ project_id, status }）も送られる
+export async function getJobStatus(projectId: string): Promise<JobStatus | null> {
+    const result: any = await invoke("get_job_status", { projectId })
+    if (result.status === "Success") {
+        return result.job as JobStatus | null
+    } else {
+        throw new Error(result.message ?? "Unable to get job status.")
+    }
+}
+
+// 推論を始める直前に "split_estimate" イベント（{ project_id, estimate }）で送られる
+export type SplitEstimate = {
+    device: string
+    audio_secs: number
+    estimated_secs: number
+    // このデバイスでの実測値から見積もったか（falseなら初期値による大まかな見積もり）
+    calibrated: boolean
+}
+
+// ダウンロード中は "model_download_progress" イベントで送られる
+export type ModelDownloadProgress = {
+    model_name: string
+    // 前回途中まで受信した分を含む
+    downloaded_bytes: number
+    total_bytes: number | null
+}
+
+// models.json の url からモデルをダウンロードし、保存先のパスを返す（失敗しても次回は続きから再開する）
+export async function downloadModel(modelName: string): Promise<string> {
+    const result: any = await invoke("download_model", { modelName })
+    if (result.status === "Success") {
+        return result.path
+    } else {
+        throw new Error(result.message ?? "Unable to download model.")
+    }
+}
+
+export function onModelDownloadProgress(callback: (progress: ModelDownloadProgress) => void): Promise<UnlistenFn> {
+    return listen<ModelDownloadProgress>("model_download_progress", (event) => callback(event.payload))
+}
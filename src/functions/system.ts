@@ -0,0 +1,145 @@
+import { invoke } from "@tauri-apps/api/tauri"
+
+export type DeviceInfo = {
+    active_device: string
+    mps_available: boolean
+    cuda_available: boolean
+    env_override: string | null
+    // 一度に推論するトラックの長さの上限（秒、nullならトラック全体を一度に推論）
+    max_segment_secs: number | null
+}
+
+export async function getDeviceInfo(): Promise<DeviceInfo> {
+    const result: any = await invoke("get_device_info", {})
+    if (result.status === "Success") {
+        return result.info as DeviceInfo
+    } else {
+        throw new Error(result.message ?? "Unable to get device info.")
+    }
+}
+
+export type SelfTestResult =
+    | { ok: true, device: string, elapsedMs: number }
+    | { ok: false, stage: 'load' | 'inference', device: string, message: string }
+
+// モデルとlibtorchが動作するかを確認する（トラックを読み込む前の診断用）
+export async function runSelfTest(): Promise<SelfTestResult> {
+    const result: any = await invoke("run_self_test", {})
+    if (result.status === "Success") {
+        return { ok: true, device: result.report.device, elapsedMs: result.report.elapsed_ms }
+    } else if (result.status === "Failed") {
+        return { ok: false, stage: result.stage, device: result.device, message: result.message }
+    } else {
+        throw new Error(result.message ?? "Unable to run self test.")
+    }
+}
+
+export type Diagnostics = {
+    app_version: string
+    libtorch_version: string | null
+    libtorch_path: string | null
+    torch_threads: number
+    active_device: string
+    mps_available: boolean
+    cuda_available: boolean
+    cuda_device_count: number
+    max_segment_secs: number | null
+    active_model: string
+    model_loaded: boolean
+    env: Record<string, string | null>
+    os: string
+    arch: string
+}
+
+// 不具合報告にコピーできる環境の情報（JSON.stringify(diagnostics, null, 2) で貼り付ける想定）
+export async function getDiagnostics(): Promise<Diagnostics> {
+    const result: any = await invoke("get_diagnostics", {})
+    if (result.status === "Success") {
+        return result.diagnostics as Diagnostics
+    } else {
+        throw new Error(result.message ?? "Unable to get diagnostics.")
+    }
+}
+
+export type UnloadModelResult = {
+    was_loaded: boolean
+    // 実行中の推論がモデルを使っている場合、メモリはその推論が終わった時点で解放される
+    in_use: boolean
+    // 解放された常駐メモリの目安（測定できない OS では null）
+    freed_bytes: number | null
+}
+
+// ロード済みのモデルを破棄してメモリを解放する（次の分離時に改めてロードされる）
+export async function unloadModel(): Promise<UnloadModelResult> {
+    const result: any = await invoke("unload_model", {})
+    if (result.status === "Success") {
+        return {
+            was_loaded: result.was_loaded,
+            in_use: result.in_use,
+            freed_bytes: result.freed_bytes ?? null,
+        }
+    } else {
+        throw new Error(result.message ?? "Unable to unload model.")
+    }
+}
+
+export type LogLevel = "error" | "warn" | "info" | "debug" | "trace"
+
+// ログの詳細度を変更する（不具合の再現時に一時的に上げる。再起動で既定値に戻る）
+// リリースビルドではログを書き込んでいるファイルのパスを返す
+export async function setLogLevel(level: LogLevel): Promise<string | null> {
+    const result: any = await invoke("set_log_level", { level })
+    if (result.status === "Success") {
+        return result.log_file ?? null
+    } else {
+        throw new Error(result.message ?? "Unable to set log level.")
+    }
+}
+
+export type LogPath = {
+    // ログファイルを置くディレクトリ（日ごとにファイルが分かれる）
+    directory: string
+    // ログを書き込んでいるファイル（開発ビルドでは標準エラー出力なので null）
+    file: string | null
+}
+
+// ログファイルの場所を取得する（不具合報告に添付するため）
+export async function getLogPath(): Promise<LogPath> {
+    const result: any = await invoke("get_log_path", {})
+    if (result.status === "Success") {
+        return {
+            directory: result.directory,
+            file: result.file ?? null,
+        }
+    } else {
+        throw new Error(result.message ?? "Unable to get log path.")
+    }
+}
+
+export type SourceParity = {
+    source: string
+    max_abs_diff: number
+    mean_abs_diff: number
+    // 差分信号のRMS
+    rms_diff: number
+}
+
+export type DeviceParityReport = {
+    // CPUと比較したデバイス（"cuda" / "mps"）
+    device: string
+    segment_start_secs: number
+    segment_secs: number
+    sources: SourceParity[]
+    // すべてのsourceの差が許容範囲内
+    within_tolerance: boolean
+}
+
+// 曲の一部をGPUとCPUの両方で分離し、出力の差を比較する（GPUで音がおかしいときの切り分け用）
+export async function verifyDeviceParity(projectId: string): Promise<DeviceParityReport> {
+    const result: any = await invoke("verify_device_parity", { projectId })
+    if (result.status === "Success") {
+        return result.report
+    } else {
+        throw new Error(result.message ?? "Unable to verify device parity.")
+    }
+}
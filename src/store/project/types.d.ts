@@ -1,11 +1,45 @@
+export type StemSetting = {
+    kind: string
+    gain_db: number
+    muted: boolean
+    // -1.0（左）〜 1.0（右）
+    pan: number
+}
+
 export type Project = {
     _id: string
     base_dir: string
     created_at: number
+    updated_at: number
     name: string
     stem_paths: string[]
     bpm?: number | null
+    bpm_half?: number | null
+    bpm_double?: number | null
+    // 最初の強拍の位置（秒、信頼度が低い場合は null）
+    beat_offset_secs?: number | null
     key?: string | null
+    duration_secs?: number | null
+    sample_rate?: number | null
+    lufs?: number | null
+    stem_settings?: StemSetting[]
+    model_name?: string | null
+    audio_hash?: string | null
+    // 元の曲がクリップしている（分離結果もクリップする）
+    source_clipped?: boolean
+    // 曲ファイルが見つからない（gcOrphanedProjectsで設定）
+    missing_files?: boolean
+    // 小文字・前後の空白なしに正規化されたタグ
+    tags?: string[]
+    // 分離に成功したらプロジェクトにコピーした曲ファイルを削除する
+    delete_source_after_split?: boolean
+    // 曲ファイルを削除済み（再分離・プレビューはできない）
+    source_deleted?: boolean
+    // 作成直後のBPM/Keyなどの解析がバックグラウンドで実行中（完了すると "project_analyzed" イベントが送られる）
+    analysis_pending?: boolean
+    // BPM/Keyをユーザーが手動で設定した（再解析で上書きしない）
+    bpm_user_set?: boolean
+    key_user_set?: boolean
 }
 
 export interface StoreState {